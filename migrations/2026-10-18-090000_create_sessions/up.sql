@@ -0,0 +1,8 @@
+CREATE TABLE
+    sessions (
+        id VARCHAR(64) NOT NULL,
+        data TEXT NOT NULL,
+        expires_at DATETIME NOT NULL,
+        PRIMARY KEY (id),
+        INDEX sessions_expires_at (expires_at)
+    );
@@ -0,0 +1,15 @@
+CREATE TABLE
+    email_outbox (
+        id INT NOT NULL AUTO_INCREMENT,
+        recipient VARCHAR(100) NOT NULL,
+        subject VARCHAR(255) NOT NULL,
+        body TEXT NOT NULL,
+        status VARCHAR(16) NOT NULL DEFAULT 'pending',
+        attempts INT NOT NULL DEFAULT 0,
+        last_error TEXT,
+        next_attempt_at DATETIME NOT NULL,
+        created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+        sent_at DATETIME,
+        PRIMARY KEY (id),
+        INDEX email_outbox_due (status, next_attempt_at)
+    );
@@ -0,0 +1,4 @@
+ALTER TABLE
+    email_tokens
+DROP
+    created_at;
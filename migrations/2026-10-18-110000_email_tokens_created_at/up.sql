@@ -0,0 +1,4 @@
+ALTER TABLE
+    email_tokens
+ADD
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP;
@@ -0,0 +1,19 @@
+ALTER TABLE
+    sessions
+DROP
+    INDEX sessions_user_id,
+DROP
+    user_id;
+
+DELETE FROM email_tokens
+WHERE
+    purpose != 'verify';
+
+ALTER TABLE
+    email_tokens
+DROP
+    PRIMARY KEY,
+ADD
+    PRIMARY KEY (user_id),
+DROP
+    purpose;
@@ -0,0 +1,15 @@
+ALTER TABLE
+    email_tokens
+ADD
+    purpose VARCHAR(16) NOT NULL DEFAULT 'verify',
+DROP
+    PRIMARY KEY,
+ADD
+    PRIMARY KEY (user_id, purpose);
+
+ALTER TABLE
+    sessions
+ADD
+    user_id INT,
+ADD
+    INDEX sessions_user_id (user_id);
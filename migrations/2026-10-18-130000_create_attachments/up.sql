@@ -0,0 +1,20 @@
+CREATE TABLE
+    attachments (
+        id INT NOT NULL AUTO_INCREMENT,
+        upload_id VARCHAR(64) NOT NULL,
+        uploader_id INT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+        room_id INT REFERENCES rooms (id) ON DELETE CASCADE,
+        direct_id INT REFERENCES directs (id) ON DELETE CASCADE,
+        message_id INT REFERENCES messages (message_id) ON DELETE SET NULL,
+        direct_message_id INT REFERENCES direct_messages (id) ON DELETE SET NULL,
+        mime_type VARCHAR(127) NOT NULL,
+        size BIGINT NOT NULL,
+        received BIGINT NOT NULL DEFAULT 0,
+        sha256 CHAR(64),
+        created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+        completed_at DATETIME,
+        PRIMARY KEY (id),
+        UNIQUE INDEX attachments_upload_id (upload_id),
+        INDEX attachments_message_id (message_id),
+        INDEX attachments_direct_message_id (direct_message_id)
+    );
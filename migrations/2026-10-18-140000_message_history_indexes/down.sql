@@ -0,0 +1,9 @@
+ALTER TABLE
+    direct_messages
+DROP
+    INDEX direct_messages_chat_history;
+
+ALTER TABLE
+    messages
+DROP
+    INDEX messages_room_history;
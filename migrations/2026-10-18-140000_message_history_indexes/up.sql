@@ -0,0 +1,9 @@
+ALTER TABLE
+    messages
+ADD
+    INDEX messages_room_history (room_id, message_time, message_id);
+
+ALTER TABLE
+    direct_messages
+ADD
+    INDEX direct_messages_chat_history (chat_id, message_time, id);
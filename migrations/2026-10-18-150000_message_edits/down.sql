@@ -0,0 +1,20 @@
+DROP TABLE message_revisions;
+
+ALTER TABLE
+    rooms_users
+DROP
+    moderator;
+
+ALTER TABLE
+    direct_messages
+DROP
+    edited_at,
+DROP
+    deleted_at;
+
+ALTER TABLE
+    messages
+DROP
+    edited_at,
+DROP
+    deleted_at;
@@ -0,0 +1,31 @@
+ALTER TABLE
+    messages
+ADD
+    edited_at DATETIME,
+ADD
+    deleted_at DATETIME;
+
+ALTER TABLE
+    direct_messages
+ADD
+    edited_at DATETIME,
+ADD
+    deleted_at DATETIME;
+
+ALTER TABLE
+    rooms_users
+ADD
+    moderator BOOLEAN NOT NULL DEFAULT FALSE;
+
+CREATE TABLE
+    message_revisions (
+        id INT NOT NULL AUTO_INCREMENT,
+        message_id INT REFERENCES messages (message_id) ON DELETE CASCADE,
+        direct_message_id INT REFERENCES direct_messages (id) ON DELETE CASCADE,
+        changed_by INT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+        content TEXT NOT NULL,
+        replaced_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+        PRIMARY KEY (id),
+        INDEX message_revisions_message_id (message_id),
+        INDEX message_revisions_direct_message_id (direct_message_id)
+    );
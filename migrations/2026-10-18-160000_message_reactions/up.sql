@@ -0,0 +1,12 @@
+CREATE TABLE
+    message_reactions (
+        id INT NOT NULL AUTO_INCREMENT,
+        message_id INT REFERENCES messages (message_id) ON DELETE CASCADE,
+        direct_message_id INT REFERENCES direct_messages (id) ON DELETE CASCADE,
+        user_id INT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+        emoji VARCHAR(32) CHARACTER SET utf8mb4 COLLATE utf8mb4_bin NOT NULL,
+        created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+        PRIMARY KEY (id),
+        UNIQUE KEY message_reactions_message (message_id, user_id, emoji),
+        UNIQUE KEY message_reactions_direct_message (direct_message_id, user_id, emoji)
+    );
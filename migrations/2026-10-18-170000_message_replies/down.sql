@@ -0,0 +1,17 @@
+ALTER TABLE
+    direct_messages
+DROP
+    INDEX direct_messages_thread,
+DROP
+    thread_id,
+DROP
+    reply_to_id;
+
+ALTER TABLE
+    messages
+DROP
+    INDEX messages_thread,
+DROP
+    thread_id,
+DROP
+    reply_to_id;
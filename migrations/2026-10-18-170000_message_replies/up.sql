@@ -0,0 +1,17 @@
+ALTER TABLE
+    messages
+ADD
+    reply_to_id INT REFERENCES messages (message_id) ON DELETE SET NULL,
+ADD
+    thread_id INT REFERENCES messages (message_id) ON DELETE SET NULL,
+ADD
+    INDEX messages_thread (thread_id, message_time, message_id);
+
+ALTER TABLE
+    direct_messages
+ADD
+    reply_to_id INT REFERENCES direct_messages (id) ON DELETE SET NULL,
+ADD
+    thread_id INT REFERENCES direct_messages (id) ON DELETE SET NULL,
+ADD
+    INDEX direct_messages_thread (thread_id, message_time, id);
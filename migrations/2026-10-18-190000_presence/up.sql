@@ -0,0 +1,7 @@
+CREATE TABLE
+    presence (
+        user_id INT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+        status VARCHAR(16) NOT NULL DEFAULT 'online',
+        last_seen_at DATETIME,
+        PRIMARY KEY (user_id)
+    );
@@ -0,0 +1,13 @@
+ALTER TABLE
+    direct_messages
+DROP
+    INDEX direct_messages_client_msg_id,
+DROP
+    client_msg_id;
+
+ALTER TABLE
+    messages
+DROP
+    INDEX messages_client_msg_id,
+DROP
+    client_msg_id;
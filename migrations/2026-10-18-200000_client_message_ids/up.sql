@@ -0,0 +1,13 @@
+ALTER TABLE
+    messages
+ADD
+    client_msg_id VARCHAR(64),
+ADD
+    UNIQUE KEY messages_client_msg_id (user_id, room_id, client_msg_id);
+
+ALTER TABLE
+    direct_messages
+ADD
+    client_msg_id VARCHAR(64),
+ADD
+    UNIQUE KEY direct_messages_client_msg_id (sender_id, chat_id, client_msg_id);
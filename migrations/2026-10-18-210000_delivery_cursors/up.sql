@@ -0,0 +1,12 @@
+CREATE TABLE
+    delivery_cursors (
+        id INT NOT NULL AUTO_INCREMENT,
+        user_id INT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+        room_id INT REFERENCES rooms (id) ON DELETE CASCADE,
+        direct_id INT REFERENCES directs (id) ON DELETE CASCADE,
+        last_delivered_id INT NOT NULL,
+        updated_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP ON UPDATE CURRENT_TIMESTAMP,
+        PRIMARY KEY (id),
+        UNIQUE KEY delivery_cursors_room (user_id, room_id),
+        UNIQUE KEY delivery_cursors_direct (user_id, direct_id)
+    );
@@ -0,0 +1,11 @@
+-- Tokens can't be recovered from their hash, so links sent so far stop working
+DELETE FROM email_tokens;
+
+ALTER TABLE
+    email_tokens
+DROP
+    INDEX email_tokens_token_hash,
+DROP
+    token_hash,
+ADD
+    token TEXT NOT NULL;
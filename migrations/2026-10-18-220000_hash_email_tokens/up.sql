@@ -0,0 +1,18 @@
+ALTER TABLE
+    email_tokens
+ADD
+    token_hash CHAR(64) NOT NULL DEFAULT '';
+
+UPDATE
+    email_tokens
+SET
+    token_hash = SHA2(token, 256);
+
+ALTER TABLE
+    email_tokens
+DROP
+    token,
+ALTER
+    token_hash DROP DEFAULT,
+ADD
+    UNIQUE INDEX email_tokens_token_hash (token_hash);
@@ -0,0 +1,590 @@
+use crate::chats::{is_member, MessageKind, Scope};
+use crate::config::ConfigError;
+use crate::crypto::random_token;
+use crate::db::DbPool;
+use crate::models::AttachmentDB;
+use crate::schema::{attachments, direct_messages, messages};
+use chrono::{NaiveDateTime, Utc};
+use diesel::mysql::MysqlConnection;
+use diesel::prelude::*;
+use rocket::fairing::AdHoc;
+use rocket::serde::Deserialize;
+use rocket::tokio::fs::{self, File, OpenOptions};
+use rocket::tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use rocket::tokio::sync::{Mutex, MutexGuard};
+use rocket::tokio::time;
+use sha2::{Digest, Sha256};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::io::{self, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+// Uploads share this many locks, see `BlobStore::lock_upload`
+const UPLOAD_LOCKS: usize = 64;
+
+// Attachment settings, the `attachments` table of the configuration
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct AttachmentConfig {
+    pub dir: PathBuf,
+    // Largest attachment accepted, in bytes
+    pub max_size: i64,
+    // Largest chunk accepted by a single upload request, in bytes
+    pub chunk_size: u64,
+    // MIME type prefixes clients may declare
+    pub allowed_types: Vec<String>,
+    // Uploads still incomplete this long after they started are deleted
+    pub abandon_after_hours: i64,
+}
+
+impl Default for AttachmentConfig {
+    fn default() -> Self {
+        AttachmentConfig {
+            dir: PathBuf::from("attachments"),
+            max_size: 50 * 1024 * 1024,
+            chunk_size: 1024 * 1024,
+            allowed_types: vec![
+                String::from("image/"),
+                String::from("video/"),
+                String::from("audio/"),
+            ],
+            abandon_after_hours: 24,
+        }
+    }
+}
+
+impl AttachmentConfig {
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |name, expected| Err(ConfigError::Invalid(name, expected));
+        if self.max_size <= 0 {
+            return invalid("attachments.max_size", "positive");
+        }
+        if self.chunk_size == 0 {
+            return invalid("attachments.chunk_size", "positive");
+        }
+        if self.abandon_after_hours <= 0 {
+            return invalid("attachments.abandon_after_hours", "positive");
+        }
+        Ok(())
+    }
+
+    pub fn allows(&self, mime_type: &str) -> bool {
+        self.allowed_types
+            .iter()
+            .any(|prefix| mime_type.starts_with(prefix.as_str()))
+    }
+}
+
+// The room or direct chat an attachment was posted to
+fn scope_of(attachment: &AttachmentDB) -> Option<Scope> {
+    match (attachment.room_id, attachment.direct_id) {
+        (Some(room_id), None) => Some(Scope::Room(room_id)),
+        (None, Some(direct_id)) => Some(Scope::Direct(direct_id)),
+        _ => None,
+    }
+}
+
+// Whether the message the attachment was posted with is gone
+fn message_deleted(
+    connection: &mut MysqlConnection,
+    attachment: &AttachmentDB,
+) -> QueryResult<bool> {
+    let deleted_at = match (attachment.message_id, attachment.direct_message_id) {
+        (Some(message_id), _) => messages::table
+            .filter(messages::message_id.eq(message_id))
+            .select(messages::deleted_at)
+            .first::<Option<NaiveDateTime>>(connection)
+            .optional()?,
+        (None, Some(message_id)) => direct_messages::table
+            .filter(direct_messages::id.eq(message_id))
+            .select(direct_messages::deleted_at)
+            .first::<Option<NaiveDateTime>>(connection)
+            .optional()?,
+        (None, None) => return Ok(false),
+    };
+    // A message that doesn't exist anymore counts as deleted
+    Ok(!matches!(deleted_at, Some(None)))
+}
+
+// Whether the user may read the attachment: the uploader always can, others
+// need to belong to the room or direct chat it was posted to. Nobody can once
+// its message was deleted
+pub fn can_access(
+    connection: &mut MysqlConnection,
+    user_id: i32,
+    attachment: &AttachmentDB,
+) -> QueryResult<bool> {
+    if message_deleted(connection, attachment)? {
+        return Ok(false);
+    }
+    if attachment.uploader_id == user_id {
+        return Ok(true);
+    }
+    match scope_of(attachment) {
+        Some(scope) => is_member(connection, user_id, scope),
+        None => Ok(false),
+    }
+}
+
+// Start an upload, returning the new attachment. Its start time comes from
+// here, like the cutoff `purge_abandoned` compares it with
+pub fn create(
+    connection: &mut MysqlConnection,
+    uploader_id: i32,
+    scope: Scope,
+    mime_type: &str,
+    size: i64,
+) -> QueryResult<AttachmentDB> {
+    let upload_id = random_token();
+    let (room_id, direct_id) = match scope {
+        Scope::Room(room_id) => (Some(room_id), None),
+        Scope::Direct(direct_id) => (None, Some(direct_id)),
+    };
+    diesel::insert_into(attachments::table)
+        .values((
+            attachments::upload_id.eq(&upload_id),
+            attachments::uploader_id.eq(uploader_id),
+            attachments::room_id.eq(room_id),
+            attachments::direct_id.eq(direct_id),
+            attachments::mime_type.eq(mime_type),
+            attachments::size.eq(size),
+            attachments::created_at.eq(Utc::now().naive_utc()),
+        ))
+        .execute(connection)?;
+    attachments::table
+        .filter(attachments::upload_id.eq(&upload_id))
+        .select(AttachmentDB::as_select())
+        .first(connection)
+}
+
+pub fn find(
+    connection: &mut MysqlConnection,
+    upload_id: &str,
+) -> QueryResult<Option<AttachmentDB>> {
+    attachments::table
+        .filter(attachments::upload_id.eq(upload_id))
+        .select(AttachmentDB::as_select())
+        .first(connection)
+        .optional()
+}
+
+// Move the upload forward from `from` to `to` bytes. Returns false when
+// another request moved it first
+pub fn record_progress(
+    connection: &mut MysqlConnection,
+    id: i32,
+    from: i64,
+    to: i64,
+) -> QueryResult<bool> {
+    diesel::update(
+        attachments::table
+            .filter(attachments::id.eq(id))
+            .filter(attachments::received.eq(from)),
+    )
+    .set(attachments::received.eq(to))
+    .execute(connection)
+    .map(|updated| updated == 1)
+}
+
+pub fn mark_complete(
+    connection: &mut MysqlConnection,
+    id: i32,
+    sha256: &str,
+) -> QueryResult<usize> {
+    diesel::update(attachments::table.filter(attachments::id.eq(id)))
+        .set((
+            attachments::sha256.eq(Some(sha256)),
+            attachments::completed_at.eq(Some(Utc::now().naive_utc())),
+        ))
+        .execute(connection)
+}
+
+// The message an attachment is posted with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageRef {
+    Room(i32),
+    Direct(i32),
+}
+
+// Attach completed uploads of `uploader_id` in `scope` to a saved message.
+// Ids that don't match, or are already attached, are ignored
+pub fn link(
+    connection: &mut MysqlConnection,
+    upload_ids: &[String],
+    uploader_id: i32,
+    scope: Scope,
+    message: MessageRef,
+) -> QueryResult<usize> {
+    if upload_ids.is_empty() {
+        return Ok(0);
+    }
+    let pending = attachments::table
+        .filter(attachments::upload_id.eq_any(upload_ids))
+        .filter(attachments::uploader_id.eq(uploader_id))
+        .filter(attachments::completed_at.is_not_null())
+        .filter(attachments::message_id.is_null())
+        .filter(attachments::direct_message_id.is_null());
+    match (scope, message) {
+        (Scope::Room(room_id), MessageRef::Room(message_id)) => {
+            diesel::update(pending.filter(attachments::room_id.eq(room_id)))
+                .set(attachments::message_id.eq(Some(message_id)))
+                .execute(connection)
+        }
+        (Scope::Direct(direct_id), MessageRef::Direct(message_id)) => {
+            diesel::update(pending.filter(attachments::direct_id.eq(direct_id)))
+                .set(attachments::direct_message_id.eq(Some(message_id)))
+                .execute(connection)
+        }
+        _ => Ok(0),
+    }
+}
+
+// Attachments of the given messages, grouped by message id
+pub fn for_messages(
+    connection: &mut MysqlConnection,
+    kind: MessageKind,
+    message_ids: &[i32],
+) -> QueryResult<HashMap<i32, Vec<AttachmentDB>>> {
+    let mut grouped: HashMap<i32, Vec<AttachmentDB>> = HashMap::new();
+    if message_ids.is_empty() {
+        return Ok(grouped);
+    }
+    let found: Vec<AttachmentDB> = match kind {
+        MessageKind::Room => attachments::table
+            .filter(attachments::message_id.eq_any(message_ids))
+            .select(AttachmentDB::as_select())
+            .load(connection)?,
+        MessageKind::Direct => attachments::table
+            .filter(attachments::direct_message_id.eq_any(message_ids))
+            .select(AttachmentDB::as_select())
+            .load(connection)?,
+    };
+    for attachment in found {
+        let message_id = match kind {
+            MessageKind::Room => attachment.message_id,
+            MessageKind::Direct => attachment.direct_message_id,
+        };
+        if let Some(message_id) = message_id {
+            grouped.entry(message_id).or_default().push(attachment);
+        }
+    }
+    Ok(grouped)
+}
+
+// Uploads started before `before` and never completed
+fn abandoned(
+    connection: &mut MysqlConnection,
+    before: NaiveDateTime,
+) -> QueryResult<Vec<AttachmentDB>> {
+    attachments::table
+        .filter(attachments::completed_at.is_null())
+        .filter(attachments::created_at.lt(before))
+        .select(AttachmentDB::as_select())
+        .load(connection)
+}
+
+// Delete uploads left incomplete for `ttl` along with their partial files,
+// returning how many were removed
+pub async fn purge_abandoned(
+    db: &DbPool,
+    store: &BlobStore,
+    ttl: chrono::Duration,
+) -> Result<usize, String> {
+    let before = Utc::now().naive_utc() - ttl;
+    let found = db
+        .run(move |connection| abandoned(connection, before))
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| e.to_string())?;
+
+    let mut purged = 0;
+    for attachment in found {
+        let _writing = store.lock_upload(&attachment.upload_id).await;
+        let id = attachment.id;
+        // The last chunk may have come in since
+        let deleted = db
+            .run(move |connection| {
+                diesel::delete(
+                    attachments::table
+                        .filter(attachments::id.eq(id))
+                        .filter(attachments::completed_at.is_null()),
+                )
+                .execute(connection)
+            })
+            .await
+            .map_err(|e| e.to_string())?
+            .map_err(|e| e.to_string())?;
+        if deleted == 1 {
+            store
+                .remove_partial(&attachment.upload_id)
+                .await
+                .map_err(|e| e.to_string())?;
+            purged += 1;
+        }
+    }
+    Ok(purged)
+}
+
+// Fairing that periodically purges abandoned uploads once the server is up
+pub fn purge_fairing(
+    db: DbPool,
+    store: BlobStore,
+    ttl: chrono::Duration,
+    every: Duration,
+) -> AdHoc {
+    AdHoc::on_liftoff("Abandoned upload purge", move |_| {
+        Box::pin(async move {
+            rocket::tokio::spawn(async move {
+                let mut interval = time::interval(every);
+                loop {
+                    interval.tick().await;
+                    if let Err(err) = purge_abandoned(&db, &store, ttl).await {
+                        eprintln!("Failed to purge abandoned uploads: {}", err);
+                    }
+                }
+            });
+        })
+    })
+}
+
+// Content-addressed blob store. Uploads are written to `partial/<upload id>`
+// and moved to `blobs/<aa>/<bb>/<sha256>` once complete, so identical files
+// are stored once. Clones share the same files and locks
+#[derive(Clone)]
+pub struct BlobStore {
+    root: PathBuf,
+    uploads: Arc<Vec<Mutex<()>>>,
+}
+
+impl BlobStore {
+    pub fn new(root: &Path) -> io::Result<BlobStore> {
+        std::fs::create_dir_all(root.join("partial"))?;
+        std::fs::create_dir_all(root.join("blobs"))?;
+        Ok(BlobStore {
+            root: root.to_path_buf(),
+            uploads: Arc::new((0..UPLOAD_LOCKS).map(|_| Mutex::new(())).collect()),
+        })
+    }
+
+    // Held while a request writes to or completes an upload, so concurrent
+    // requests for the same upload take turns instead of mixing their
+    // chunks in the partial file
+    pub async fn lock_upload(&self, upload_id: &str) -> MutexGuard<'_, ()> {
+        let mut hasher = DefaultHasher::new();
+        upload_id.hash(&mut hasher);
+        self.uploads[hasher.finish() as usize % self.uploads.len()]
+            .lock()
+            .await
+    }
+
+    // Upload ids come from `random_token`, so they are safe as file names
+    fn partial_path(&self, upload_id: &str) -> PathBuf {
+        self.root.join("partial").join(upload_id)
+    }
+
+    pub fn blob_path(&self, sha256: &str) -> PathBuf {
+        self.root
+            .join("blobs")
+            .join(&sha256[..2])
+            .join(&sha256[2..4])
+            .join(sha256)
+    }
+
+    // Write a chunk at `offset`, dropping anything past it left by an
+    // interrupted request
+    pub async fn write_chunk(&self, upload_id: &str, offset: u64, chunk: &[u8]) -> io::Result<()> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(self.partial_path(upload_id))
+            .await?;
+        file.set_len(offset).await?;
+        file.seek(SeekFrom::Start(offset)).await?;
+        file.write_all(chunk).await?;
+        file.sync_data().await
+    }
+
+    // Drop the partial file of an upload, if it got one
+    pub async fn remove_partial(&self, upload_id: &str) -> io::Result<()> {
+        match fs::remove_file(self.partial_path(upload_id)).await {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        }
+    }
+
+    // Hash a finished upload and move it into place, returning its hash
+    pub async fn finish(&self, upload_id: &str) -> io::Result<String> {
+        let partial = self.partial_path(upload_id);
+        let mut file = File::open(&partial).await?;
+        let mut hasher = Sha256::new();
+        let mut buffer = vec![0u8; 64 * 1024];
+        loop {
+            let read = file.read(&mut buffer).await?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..read]);
+        }
+        let sha256: String = hasher
+            .finalize()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+
+        let blob = self.blob_path(&sha256);
+        if fs::try_exists(&blob).await? {
+            fs::remove_file(&partial).await?;
+        } else {
+            if let Some(dir) = blob.parent() {
+                fs::create_dir_all(dir).await?;
+            }
+            fs::rename(&partial, &blob).await?;
+        }
+        Ok(sha256)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::testing;
+    use tempfile::TempDir;
+
+    fn store() -> (TempDir, BlobStore) {
+        let dir = tempfile::tempdir().unwrap();
+        let store = BlobStore::new(dir.path()).unwrap();
+        (dir, store)
+    }
+
+    // A completed upload of `uploader_id` in `scope`
+    fn upload(connection: &mut MysqlConnection, uploader_id: i32, scope: Scope) -> AttachmentDB {
+        let attachment = create(connection, uploader_id, scope, "image/png", 3).unwrap();
+        mark_complete(connection, attachment.id, &"0".repeat(64)).unwrap();
+        attachment
+    }
+
+    #[rocket::async_test]
+    async fn rewritten_offsets_drop_what_followed() {
+        let (_dir, store) = store();
+        store.write_chunk("up", 0, b"abcdef").await.unwrap();
+        store.write_chunk("up", 2, b"XY").await.unwrap();
+        assert_eq!(fs::read(store.partial_path("up")).await.unwrap(), b"abXY");
+    }
+
+    #[rocket::async_test]
+    async fn identical_uploads_share_one_blob() {
+        let (_dir, store) = store();
+        store.write_chunk("first", 0, b"same").await.unwrap();
+        store.write_chunk("second", 0, b"same").await.unwrap();
+
+        let first = store.finish("first").await.unwrap();
+        let second = store.finish("second").await.unwrap();
+        assert_eq!(first, second);
+        assert_eq!(first, format!("{:x}", Sha256::digest(b"same")));
+        assert_eq!(fs::read(store.blob_path(&first)).await.unwrap(), b"same");
+        assert!(!fs::try_exists(store.partial_path("first")).await.unwrap());
+        assert!(!fs::try_exists(store.partial_path("second")).await.unwrap());
+    }
+
+    #[test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    fn access_follows_the_chat_and_the_message() {
+        let mut connection = testing::connection();
+        let uploader = testing::user(&mut connection, "attach_uploader");
+        let member = testing::user(&mut connection, "attach_member");
+        let outsider = testing::user(&mut connection, "attach_outsider");
+        let room_id = testing::room(&mut connection, "attach", &[uploader, member]);
+        let attachment = upload(&mut connection, uploader, Scope::Room(room_id));
+        let message_id = testing::room_message(&mut connection, room_id, uploader, "file");
+        let upload_ids = [attachment.upload_id.clone()];
+        link(
+            &mut connection,
+            &upload_ids,
+            uploader,
+            Scope::Room(room_id),
+            MessageRef::Room(message_id),
+        )
+        .unwrap();
+        let attachment = find(&mut connection, &attachment.upload_id)
+            .unwrap()
+            .unwrap();
+
+        assert!(can_access(&mut connection, uploader, &attachment).unwrap());
+        assert!(can_access(&mut connection, member, &attachment).unwrap());
+        assert!(!can_access(&mut connection, outsider, &attachment).unwrap());
+
+        diesel::update(messages::table.filter(messages::message_id.eq(message_id)))
+            .set(messages::deleted_at.eq(Some(Utc::now().naive_utc())))
+            .execute(&mut connection)
+            .unwrap();
+        assert!(!can_access(&mut connection, uploader, &attachment).unwrap());
+        assert!(!can_access(&mut connection, member, &attachment).unwrap());
+    }
+
+    #[test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    fn link_only_takes_own_completed_uploads_of_the_chat() {
+        let mut connection = testing::connection();
+        let sender = testing::user(&mut connection, "link_sender");
+        let other = testing::user(&mut connection, "link_other");
+        let direct_id = testing::direct(&mut connection, sender, other);
+        let room_id = testing::room(&mut connection, "link", &[sender, other]);
+        let scope = Scope::Direct(direct_id);
+        let own = upload(&mut connection, sender, scope);
+        let theirs = upload(&mut connection, other, scope);
+        let elsewhere = upload(&mut connection, sender, Scope::Room(room_id));
+        let unfinished = create(&mut connection, sender, scope, "image/png", 3).unwrap();
+        let message_id = testing::direct_message(&mut connection, direct_id, sender, "files");
+        let upload_ids: Vec<String> = [&own, &theirs, &elsewhere, &unfinished]
+            .iter()
+            .map(|attachment| attachment.upload_id.clone())
+            .collect();
+
+        let message = MessageRef::Direct(message_id);
+        assert_eq!(
+            link(&mut connection, &upload_ids, sender, scope, message).unwrap(),
+            1
+        );
+        // Already attached
+        assert_eq!(
+            link(&mut connection, &upload_ids, sender, scope, message).unwrap(),
+            0
+        );
+        let linked = for_messages(&mut connection, MessageKind::Direct, &[message_id]).unwrap();
+        let linked: Vec<&str> = linked[&message_id]
+            .iter()
+            .map(|attachment| attachment.upload_id.as_str())
+            .collect();
+        assert_eq!(linked, vec![own.upload_id.as_str()]);
+    }
+
+    #[test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    fn uploads_are_abandoned_only_after_their_ttl() {
+        let mut connection = testing::connection();
+        let uploader = testing::user(&mut connection, "abandon_uploader");
+        let room_id = testing::room(&mut connection, "abandon", &[uploader]);
+        let started = create(
+            &mut connection,
+            uploader,
+            Scope::Room(room_id),
+            "image/png",
+            3,
+        )
+        .unwrap();
+        let now = Utc::now().naive_utc();
+
+        let found = |connection: &mut MysqlConnection, before| {
+            abandoned(connection, before)
+                .unwrap()
+                .iter()
+                .any(|attachment| attachment.id == started.id)
+        };
+        assert!(!found(&mut connection, now - chrono::Duration::hours(1)));
+        assert!(found(&mut connection, now + chrono::Duration::minutes(1)));
+    }
+}
@@ -0,0 +1,53 @@
+use crate::session::SessionOwner;
+use chrono::{DateTime, Utc};
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::serde::{Deserialize, Serialize};
+use rocket_session_store::Session;
+
+// The logged in user, as stored in the session when they log in
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(crate = "rocket::serde")]
+pub struct AuthUser {
+    pub id: i32,
+    pub username: String,
+    pub is_admin: bool,
+    pub session_created: DateTime<Utc>,
+}
+
+impl AuthUser {
+    pub fn new(id: i32, username: String, is_admin: bool) -> AuthUser {
+        AuthUser {
+            id,
+            username,
+            is_admin,
+            session_created: Utc::now(),
+        }
+    }
+}
+
+impl SessionOwner for AuthUser {
+    fn owner_id(&self) -> i32 {
+        self.id
+    }
+}
+
+// Request guard reading the session once: routes taking an `AuthUser` fail
+// with 401 when there is no valid session
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AuthUser {
+    type Error = &'static str;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let session = match request.guard::<Session<'_, AuthUser>>().await {
+            Outcome::Success(session) => session,
+            _ => return Outcome::Error((Status::Unauthorized, "no valid session")),
+        };
+
+        match session.get().await {
+            Ok(Some(user)) => Outcome::Success(user),
+            Ok(None) => Outcome::Error((Status::Unauthorized, "no valid session")),
+            Err(_) => Outcome::Error((Status::InternalServerError, "session store error")),
+        }
+    }
+}
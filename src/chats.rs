@@ -0,0 +1,186 @@
+use crate::models::DirectDB;
+use crate::schema::{directs, rooms_users};
+use diesel::mysql::MysqlConnection;
+use diesel::prelude::*;
+
+// A conversation: a room, or a direct chat by its `directs` id
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Scope {
+    Room(i32),
+    Direct(i32),
+}
+
+// Which table a message id refers to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageKind {
+    Room,
+    Direct,
+}
+
+// Where a new message is addressed: a room, or a user to write to directly
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Recipient {
+    Room(i32),
+    User(i32),
+}
+
+// Why a user may not post where they tried to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NotPostable {
+    NotMember,
+    NoDirect,
+}
+
+impl NotPostable {
+    pub const fn message(&self) -> &'static str {
+        match self {
+            NotPostable::NotMember => "not a member of this room",
+            NotPostable::NoDirect => "no direct chat with this user",
+        }
+    }
+
+    // Stable name for clients, e.g. in websocket error frames
+    pub const fn code(&self) -> &'static str {
+        match self {
+            NotPostable::NotMember => "forbidden",
+            NotPostable::NoDirect => "not_found",
+        }
+    }
+}
+
+// The direct chat between two users, if any
+pub fn direct_between(
+    connection: &mut MysqlConnection,
+    a: i32,
+    b: i32,
+) -> QueryResult<Option<DirectDB>> {
+    directs::table
+        .filter(
+            (directs::user1_id.eq(a).and(directs::user2_id.eq(b)))
+                .or(directs::user1_id.eq(b).and(directs::user2_id.eq(a))),
+        )
+        .select(DirectDB::as_select())
+        .first(connection)
+        .optional()
+}
+
+// Whether the user belongs to the room or direct chat
+pub fn is_member(
+    connection: &mut MysqlConnection,
+    user_id: i32,
+    scope: Scope,
+) -> QueryResult<bool> {
+    match scope {
+        Scope::Room(room_id) => diesel::select(diesel::dsl::exists(
+            rooms_users::table
+                .filter(rooms_users::room_id.eq(room_id))
+                .filter(rooms_users::user_id.eq(user_id)),
+        ))
+        .get_result(connection),
+        Scope::Direct(direct_id) => diesel::select(diesel::dsl::exists(
+            directs::table.filter(directs::id.eq(direct_id)).filter(
+                directs::user1_id
+                    .eq(user_id)
+                    .or(directs::user2_id.eq(user_id)),
+            ),
+        ))
+        .get_result(connection),
+    }
+}
+
+// Whether the user moderates the room. The user who created a room is its
+// first moderator
+pub fn is_moderator(
+    connection: &mut MysqlConnection,
+    user_id: i32,
+    room_id: i32,
+) -> QueryResult<bool> {
+    diesel::select(diesel::dsl::exists(
+        rooms_users::table
+            .filter(rooms_users::room_id.eq(room_id))
+            .filter(rooms_users::user_id.eq(user_id))
+            .filter(rooms_users::moderator.eq(true)),
+    ))
+    .get_result(connection)
+}
+
+// The chat a new message of `sender` goes to: a room they belong to, or their
+// direct chat with the recipient. Direct chats are never opened this way
+pub fn destination(
+    connection: &mut MysqlConnection,
+    sender: i32,
+    recipient: Recipient,
+) -> QueryResult<Result<Scope, NotPostable>> {
+    match recipient {
+        Recipient::Room(room_id) => {
+            let scope = Scope::Room(room_id);
+            Ok(if is_member(connection, sender, scope)? {
+                Ok(scope)
+            } else {
+                Err(NotPostable::NotMember)
+            })
+        }
+        Recipient::User(peer) => Ok(direct_between(connection, sender, peer)?
+            .map(|direct| Scope::Direct(direct.id))
+            .ok_or(NotPostable::NoDirect)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::testing;
+
+    #[test]
+    fn refusals_keep_their_codes() {
+        assert_eq!(NotPostable::NotMember.code(), "forbidden");
+        assert_eq!(NotPostable::NoDirect.code(), "not_found");
+    }
+
+    #[test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    fn members_post_to_their_rooms_only() {
+        let mut connection = testing::connection();
+        let member = testing::user(&mut connection, "post_member");
+        let outsider = testing::user(&mut connection, "post_outsider");
+        let room_id = testing::room(&mut connection, "posting", &[member]);
+
+        assert_eq!(
+            destination(&mut connection, member, Recipient::Room(room_id)).unwrap(),
+            Ok(Scope::Room(room_id))
+        );
+        assert_eq!(
+            destination(&mut connection, outsider, Recipient::Room(room_id)).unwrap(),
+            Err(NotPostable::NotMember)
+        );
+        assert_eq!(
+            destination(&mut connection, member, Recipient::Room(room_id + 1000)).unwrap(),
+            Err(NotPostable::NotMember)
+        );
+    }
+
+    #[test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    fn direct_messages_need_a_direct_chat() {
+        let mut connection = testing::connection();
+        let sender = testing::user(&mut connection, "post_sender");
+        let friend = testing::user(&mut connection, "post_friend");
+        let stranger = testing::user(&mut connection, "post_stranger");
+        let direct_id = testing::direct(&mut connection, friend, sender);
+
+        // Either user of the chat may have opened it
+        assert_eq!(
+            destination(&mut connection, sender, Recipient::User(friend)).unwrap(),
+            Ok(Scope::Direct(direct_id))
+        );
+        assert_eq!(
+            destination(&mut connection, sender, Recipient::User(stranger)).unwrap(),
+            Err(NotPostable::NoDirect)
+        );
+        // Nor is one opened by trying
+        assert_eq!(
+            direct_between(&mut connection, sender, stranger).unwrap(),
+            None
+        );
+    }
+}
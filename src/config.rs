@@ -0,0 +1,389 @@
+use crate::attachments::AttachmentConfig;
+use crate::db::DatabaseConfig;
+use crate::keys::KeyConfig;
+use dotenv::dotenv;
+use rocket::figment::providers::Env;
+use rocket::figment::Figment;
+use rocket::serde::Deserialize;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Application settings, read from the top level of Rocket.toml and from
+// `ROCKET_*` or `CHAT_*` environment variables (`CHAT_MAIL__TOKEN` sets
+// `mail.token`). Every secret `x` can also be read from the file named by
+// `x_file`, e.g. a mounted container secret
+#[derive(Clone, Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct ChatConfig {
+    pub pepper: String,
+    pub pepper_file: Option<PathBuf>,
+    // Base URL used to build links sent by email, without the trailing slash
+    pub public_url: String,
+    pub lobby_room: String,
+    pub mail: MailConfig,
+    pub outbox: OutboxConfig,
+    pub rsa: KeyConfig,
+    pub database: DatabaseConfig,
+    pub attachments: AttachmentConfig,
+    // Not `limits`, which Rocket uses for its request body limits
+    pub chat_limits: LimitsConfig,
+}
+
+impl Default for ChatConfig {
+    fn default() -> Self {
+        ChatConfig {
+            pepper: String::new(),
+            pepper_file: None,
+            public_url: String::from("http://localhost:8000"),
+            lobby_room: String::from("lobby"),
+            mail: MailConfig::default(),
+            outbox: OutboxConfig::default(),
+            rsa: KeyConfig::default(),
+            database: DatabaseConfig::default(),
+            attachments: AttachmentConfig::default(),
+            chat_limits: LimitsConfig::default(),
+        }
+    }
+}
+
+// How emails are delivered, selected by `mail.transport`
+#[derive(Clone, Default, Deserialize)]
+#[serde(crate = "rocket::serde", tag = "transport", rename_all = "lowercase")]
+pub enum MailConfig {
+    Smtp {
+        host: String,
+        port: Option<u16>,
+        #[serde(default)]
+        security: SmtpSecurity,
+        username: Option<String>,
+        #[serde(default)]
+        password: String,
+        password_file: Option<PathBuf>,
+        #[serde(default = "default_from")]
+        from: String,
+    },
+    // POST to an HTTP mail API
+    Http {
+        url: String,
+        #[serde(default)]
+        token: String,
+        token_file: Option<PathBuf>,
+    },
+    // Write every email as an .eml file in `dir`
+    File {
+        dir: PathBuf,
+        #[serde(default = "default_from")]
+        from: String,
+    },
+    #[default]
+    Stdout,
+}
+
+fn default_from() -> String {
+    String::from("Rocket Chat <noreply@localhost>")
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum SmtpSecurity {
+    // Implicit TLS, port 465 by default
+    Tls,
+    // STARTTLS, port 587 by default
+    #[default]
+    StartTls,
+    // Plain text, only for a local relay
+    None,
+}
+
+// Delivery of queued emails, see `outbox`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct OutboxConfig {
+    pub poll_interval_secs: u64,
+    pub batch_size: i64,
+    pub max_attempts: i32,
+    // Delay before the first retry, doubled after every failed attempt
+    pub base_backoff_secs: u64,
+    pub max_backoff_secs: u64,
+}
+
+impl OutboxConfig {
+    // A zero poll interval would panic in the worker, zero batches or
+    // attempts would never send anything
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |name, expected| Err(ConfigError::Invalid(name, expected));
+        if self.poll_interval_secs == 0 {
+            return invalid("outbox.poll_interval_secs", "positive");
+        }
+        if self.batch_size <= 0 {
+            return invalid("outbox.batch_size", "positive");
+        }
+        if self.max_attempts <= 0 {
+            return invalid("outbox.max_attempts", "positive");
+        }
+        if self.max_backoff_secs < self.base_backoff_secs {
+            return invalid("outbox.max_backoff_secs", "at least base_backoff_secs");
+        }
+        Ok(())
+    }
+}
+
+impl Default for OutboxConfig {
+    fn default() -> Self {
+        OutboxConfig {
+            poll_interval_secs: 5,
+            batch_size: 20,
+            max_attempts: 8,
+            base_backoff_secs: 30,
+            max_backoff_secs: 3600,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct LimitsConfig {
+    // Longest websocket or posted message accepted, in bytes
+    pub max_message_len: usize,
+    pub session_days: u64,
+    pub verification_ttl_hours: i64,
+    pub reset_ttl_minutes: i64,
+    // Minimum delay between two verification or reset emails for the same account
+    pub email_resend_secs: i64,
+    // Messages per chat in the initial room and direct lists, and per history
+    // page when the client gives no limit
+    pub history_page_size: i64,
+    pub max_history_page_size: i64,
+    // Typing notifications end by themselves after this long without news,
+    // and are forwarded at most once per interval and chat
+    pub typing_ttl_secs: u64,
+    pub typing_interval_ms: u64,
+    // Most messages sent per chat to a client catching up after reconnecting
+    pub catch_up_limit: i64,
+    // Websockets are pinged this often, and closed when nothing came from the
+    // client, not even a pong, for the idle timeout
+    pub ping_interval_secs: u64,
+    pub idle_timeout_secs: u64,
+}
+
+impl LimitsConfig {
+    // Refuse values that would disable a feature by accident, e.g. a zero
+    // idle timeout closing every websocket at once
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |name, expected| Err(ConfigError::Invalid(name, expected));
+        if self.max_message_len == 0 {
+            return invalid("chat_limits.max_message_len", "positive");
+        }
+        if self.session_days == 0 {
+            return invalid("chat_limits.session_days", "positive");
+        }
+        if self.verification_ttl_hours <= 0 {
+            return invalid("chat_limits.verification_ttl_hours", "positive");
+        }
+        if self.reset_ttl_minutes <= 0 {
+            return invalid("chat_limits.reset_ttl_minutes", "positive");
+        }
+        if self.email_resend_secs < 0 {
+            return invalid("chat_limits.email_resend_secs", "zero or more");
+        }
+        if self.max_history_page_size <= 0 {
+            return invalid("chat_limits.max_history_page_size", "positive");
+        }
+        if self.history_page_size <= 0 || self.history_page_size > self.max_history_page_size {
+            return invalid(
+                "chat_limits.history_page_size",
+                "positive and at most max_history_page_size",
+            );
+        }
+        if self.typing_ttl_secs == 0 {
+            return invalid("chat_limits.typing_ttl_secs", "positive");
+        }
+        if self.catch_up_limit <= 0 {
+            return invalid("chat_limits.catch_up_limit", "positive");
+        }
+        if self.ping_interval_secs == 0 {
+            return invalid("chat_limits.ping_interval_secs", "positive");
+        }
+        if self.idle_timeout_secs <= self.ping_interval_secs {
+            return invalid(
+                "chat_limits.idle_timeout_secs",
+                "longer than ping_interval_secs",
+            );
+        }
+        Ok(())
+    }
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig {
+            max_message_len: 16 * 1024,
+            session_days: 3,
+            verification_ttl_hours: 24,
+            reset_ttl_minutes: 30,
+            email_resend_secs: 120,
+            history_page_size: 50,
+            max_history_page_size: 200,
+            typing_ttl_secs: 5,
+            typing_interval_ms: 2000,
+            catch_up_limit: 200,
+            ping_interval_secs: 30,
+            idle_timeout_secs: 75,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Extract(String),
+    MissingSecret(&'static str),
+    SecretFile(PathBuf, std::io::Error),
+    // A setting with a value the server can't work with, and what it must be
+    Invalid(&'static str, &'static str),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Extract(msg) => write!(f, "invalid configuration: {}", msg),
+            ConfigError::MissingSecret(name) => write!(
+                f,
+                "missing secret `{}`: set it in Rocket.toml, CHAT_{} or `{}_file`",
+                name,
+                name.replace('.', "__").to_uppercase(),
+                name
+            ),
+            ConfigError::SecretFile(path, err) => {
+                write!(f, "unable to read secret file {}: {}", path.display(), err)
+            }
+            ConfigError::Invalid(name, expected) => {
+                write!(f, "invalid setting `{}`: must be {}", name, expected)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl ChatConfig {
+    // Rocket's own figment plus the `CHAT_*` environment variables, which may
+    // also come from the .env file
+    pub fn figment() -> Figment {
+        dotenv().ok();
+        rocket::Config::figment().merge(Env::prefixed("CHAT_").split("__").global())
+    }
+
+    // Extract the settings and resolve the secrets, failing when one is
+    // missing or a limit is out of range
+    pub fn from_figment(figment: &Figment) -> Result<ChatConfig, ConfigError> {
+        let mut config: ChatConfig = figment
+            .extract()
+            .map_err(|e| ConfigError::Extract(e.to_string()))?;
+
+        config.pepper = resolve_secret("pepper", &config.pepper, config.pepper_file.as_deref())?;
+        match &mut config.mail {
+            MailConfig::Smtp {
+                username: Some(_),
+                password,
+                password_file,
+                ..
+            } => {
+                *password = resolve_secret("mail.password", password, password_file.as_deref())?;
+            }
+            MailConfig::Http {
+                token, token_file, ..
+            } => {
+                *token = resolve_secret("mail.token", token, token_file.as_deref())?;
+            }
+            _ => {}
+        }
+        config.public_url = config.public_url.trim_end_matches('/').to_string();
+        config.chat_limits.validate()?;
+        config.outbox.validate()?;
+        config.attachments.validate()?;
+        config.rsa.validate()?;
+
+        Ok(config)
+    }
+
+    // Absolute link to a path of the application, e.g. `/verify-email/<token>`
+    pub fn public_link(&self, path: &str) -> String {
+        format!("{}{}", self.public_url, path)
+    }
+}
+
+fn resolve_secret(
+    name: &'static str,
+    value: &str,
+    file: Option<&Path>,
+) -> Result<String, ConfigError> {
+    let secret = match file {
+        Some(path) => fs::read_to_string(path)
+            .map_err(|e| ConfigError::SecretFile(path.to_path_buf(), e))?
+            .trim()
+            .to_string(),
+        None => value.trim().to_string(),
+    };
+    if secret.is_empty() {
+        Err(ConfigError::MissingSecret(name))
+    } else {
+        Ok(secret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::figment::providers::Serialized;
+
+    fn with_setting(key: &str, value: i64) -> Result<ChatConfig, ConfigError> {
+        let figment = Figment::new()
+            .merge(Serialized::default("pepper", "pepper"))
+            .merge(Serialized::default(key, value));
+        ChatConfig::from_figment(&figment)
+    }
+
+    fn refused(key: &str, value: i64) -> bool {
+        matches!(
+            with_setting(key, value),
+            Err(ConfigError::Invalid(name, _)) if name == key
+        )
+    }
+
+    #[test]
+    fn defaults_are_valid() {
+        assert!(LimitsConfig::default().validate().is_ok());
+        assert!(with_setting("chat_limits.history_page_size", 200).is_ok());
+    }
+
+    #[test]
+    fn out_of_range_limits_are_refused() {
+        assert!(refused("chat_limits.idle_timeout_secs", 0));
+        assert!(refused("chat_limits.idle_timeout_secs", 30));
+        assert!(refused("chat_limits.ping_interval_secs", 0));
+        assert!(refused("chat_limits.history_page_size", 0));
+        assert!(refused("chat_limits.history_page_size", -1));
+        assert!(refused("chat_limits.history_page_size", 201));
+        assert!(refused("chat_limits.max_history_page_size", 0));
+        assert!(refused("chat_limits.catch_up_limit", 0));
+        assert!(refused("chat_limits.max_message_len", 0));
+        assert!(refused("chat_limits.reset_ttl_minutes", 0));
+        assert!(refused("chat_limits.email_resend_secs", -1));
+    }
+
+    #[test]
+    fn other_sections_are_checked_too() {
+        assert!(refused("attachments.max_size", 0));
+        assert!(refused("attachments.chunk_size", 0));
+        assert!(refused("attachments.abandon_after_hours", -1));
+        assert!(refused("attachments.abandon_after_hours", 0));
+        assert!(refused("outbox.batch_size", 0));
+        assert!(refused("outbox.max_attempts", 0));
+        assert!(refused("outbox.poll_interval_secs", 0));
+        assert!(refused("outbox.max_backoff_secs", 10));
+        assert!(refused("rsa.key_bits", 1024));
+        assert!(with_setting("rsa.key_bits", 2048).is_ok());
+        assert!(with_setting("outbox.batch_size", 1).is_ok());
+    }
+}
@@ -0,0 +1,250 @@
+use crate::keys::KeyRing;
+use base64::prelude::*;
+use rand::RngCore;
+use rocket::serde::json::serde_json;
+use rocket::serde::{Deserialize, Serialize};
+use rsa::pkcs8::DecodePublicKey;
+use rsa::{Oaep, Pkcs1v15Encrypt, RsaPublicKey};
+use sha2::Sha256;
+use std::fmt;
+
+pub const ENVELOPE_VERSION: u8 = 1;
+pub const ALG_OAEP_SHA256: &str = "RSA-OAEP-256";
+pub const ALG_PKCS1V15: &str = "RSA1_5";
+
+// Versioned wrapper around RSA ciphertexts, sent as a JSON string:
+// `{"v":1,"kid":"...","alg":"RSA-OAEP-256","ct":"<base64>"}`.
+// `kid` names the server key used by the client and is omitted when the
+// server encrypts for a client key
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(crate = "rocket::serde")]
+pub struct Envelope {
+    pub v: u8,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kid: Option<String>,
+    pub alg: String,
+    pub ct: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RsaError {
+    Malformed,
+    UnsupportedVersion,
+    UnsupportedAlgorithm,
+    UnknownKey,
+    InvalidPublicKey,
+    Decrypt,
+    Encrypt,
+    NotUtf8,
+}
+
+impl RsaError {
+    pub fn message(self) -> &'static str {
+        match self {
+            RsaError::Malformed => "malformed encrypted payload",
+            RsaError::UnsupportedVersion => "unsupported envelope version",
+            RsaError::UnsupportedAlgorithm => "unsupported encryption algorithm",
+            RsaError::UnknownKey => "unknown server key id",
+            RsaError::InvalidPublicKey => "invalid client public key",
+            RsaError::Decrypt => "unable to decrypt payload",
+            RsaError::Encrypt => "unable to encrypt payload",
+            RsaError::NotUtf8 => "decrypted payload is not valid UTF-8",
+        }
+    }
+}
+
+impl fmt::Display for RsaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.message())
+    }
+}
+
+impl std::error::Error for RsaError {}
+
+// Decrypt a payload sent by a client. PKCS#1 v1.5, including the bare
+// `<base64>` and `<kid>:<base64>` formats used by older clients, is only
+// accepted when `allow_pkcs1v15` is set
+pub fn decrypt(keys: &KeyRing, payload: &str, allow_pkcs1v15: bool) -> Result<String, RsaError> {
+    let envelope = match serde_json::from_str::<Envelope>(payload) {
+        Ok(envelope) => envelope,
+        Err(_) if allow_pkcs1v15 => legacy_envelope(payload),
+        Err(_) => return Err(RsaError::Malformed),
+    };
+    if envelope.v != ENVELOPE_VERSION {
+        return Err(RsaError::UnsupportedVersion);
+    }
+
+    let key = keys
+        .find(envelope.kid.as_deref())
+        .ok_or(RsaError::UnknownKey)?;
+    let ct = BASE64_STANDARD
+        .decode(&envelope.ct)
+        .map_err(|_| RsaError::Malformed)?;
+
+    let plain = match envelope.alg.as_str() {
+        ALG_OAEP_SHA256 => key.priv_key.decrypt(Oaep::new::<Sha256>(), &ct),
+        ALG_PKCS1V15 if allow_pkcs1v15 => key.priv_key.decrypt(Pkcs1v15Encrypt, &ct),
+        _ => return Err(RsaError::UnsupportedAlgorithm),
+    }
+    .map_err(|_| RsaError::Decrypt)?;
+
+    String::from_utf8(plain).map_err(|_| RsaError::NotUtf8)
+}
+
+fn legacy_envelope(payload: &str) -> Envelope {
+    let (kid, ct) = match payload.split_once(':') {
+        Some((kid, ct)) => (Some(kid.to_string()), ct),
+        None => (None, payload),
+    };
+    Envelope {
+        v: ENVELOPE_VERSION,
+        kid,
+        alg: ALG_PKCS1V15.to_string(),
+        ct: ct.to_string(),
+    }
+}
+
+// Encrypt a message for a client, given its PEM encoded public key, returning
+// the envelope as a JSON string
+pub fn encrypt_for_client(message: &str, public_key_pem: &str) -> Result<String, RsaError> {
+    let public_key = RsaPublicKey::from_public_key_pem(public_key_pem)
+        .map_err(|_| RsaError::InvalidPublicKey)?;
+
+    let mut rng = rand::thread_rng();
+    let ct = public_key
+        .encrypt(&mut rng, Oaep::new::<Sha256>(), message.as_bytes())
+        .map_err(|_| RsaError::Encrypt)?;
+
+    serde_json::to_string(&Envelope {
+        v: ENVELOPE_VERSION,
+        kid: None,
+        alg: ALG_OAEP_SHA256.to_string(),
+        ct: BASE64_STANDARD.encode(ct),
+    })
+    .map_err(|_| RsaError::Encrypt)
+}
+
+// 256 random bits, URL safe so they can go in links and paths
+pub fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    BASE64_URL_SAFE_NO_PAD.encode(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::KeyConfig;
+    use rsa::pkcs8::{EncodePublicKey, LineEnding};
+    use tempfile::TempDir;
+
+    fn keys() -> (TempDir, KeyRing) {
+        let dir = tempfile::tempdir().unwrap();
+        let keys = KeyRing::load_or_generate(&KeyConfig {
+            key_path: dir.path().join("server_key.pem"),
+            key_bits: 1024,
+            allow_pkcs1v15: false,
+        })
+        .unwrap();
+        (dir, keys)
+    }
+
+    fn envelope(v: u8, kid: Option<&str>, alg: &str, ct: &str) -> String {
+        serde_json::to_string(&Envelope {
+            v,
+            kid: kid.map(str::to_string),
+            alg: alg.to_string(),
+            ct: ct.to_string(),
+        })
+        .unwrap()
+    }
+
+    fn pkcs1v15(keys: &KeyRing, message: &str) -> String {
+        let ct = keys
+            .current
+            .pub_key
+            .encrypt(&mut rand::thread_rng(), Pkcs1v15Encrypt, message.as_bytes())
+            .unwrap();
+        BASE64_STANDARD.encode(ct)
+    }
+
+    #[test]
+    fn oaep_envelopes_round_trip() {
+        let (_dir, keys) = keys();
+        let pem = keys
+            .current
+            .pub_key
+            .to_public_key_pem(LineEnding::LF)
+            .unwrap();
+        let sealed = encrypt_for_client("secret", &pem).unwrap();
+        let parsed: Envelope = serde_json::from_str(&sealed).unwrap();
+        assert_eq!(parsed.alg, ALG_OAEP_SHA256);
+        assert_eq!(parsed.kid, None);
+        assert_eq!(decrypt(&keys, &sealed, false), Ok("secret".to_string()));
+
+        // Tagged with the key id, as clients send it
+        let tagged = envelope(1, Some(&keys.current.kid), ALG_OAEP_SHA256, &parsed.ct);
+        assert_eq!(decrypt(&keys, &tagged, false), Ok("secret".to_string()));
+        assert_eq!(
+            encrypt_for_client("secret", "not a key"),
+            Err(RsaError::InvalidPublicKey)
+        );
+    }
+
+    #[test]
+    fn unknown_key_ids_are_refused() {
+        let (_dir, keys) = keys();
+        let payload = envelope(1, Some("0123456789abcdef"), ALG_OAEP_SHA256, "AAAA");
+        assert_eq!(decrypt(&keys, &payload, false), Err(RsaError::UnknownKey));
+    }
+
+    #[test]
+    fn pkcs1v15_needs_to_be_allowed() {
+        let (_dir, keys) = keys();
+        let ct = pkcs1v15(&keys, "legacy");
+        let enveloped = envelope(1, Some(&keys.current.kid), ALG_PKCS1V15, &ct);
+        let tagged = format!("{}:{}", keys.current.kid, ct);
+
+        assert_eq!(
+            decrypt(&keys, &enveloped, false),
+            Err(RsaError::UnsupportedAlgorithm)
+        );
+        assert_eq!(decrypt(&keys, &tagged, false), Err(RsaError::Malformed));
+        assert_eq!(decrypt(&keys, &ct, false), Err(RsaError::Malformed));
+
+        for payload in [&enveloped, &tagged, &ct] {
+            assert_eq!(decrypt(&keys, payload, true), Ok("legacy".to_string()));
+        }
+    }
+
+    #[test]
+    fn bad_payloads_are_errors() {
+        let (_dir, keys) = keys();
+        assert_eq!(decrypt(&keys, "{\"v\":1", false), Err(RsaError::Malformed));
+        assert_eq!(decrypt(&keys, "", false), Err(RsaError::Malformed));
+        assert_eq!(
+            decrypt(&keys, &envelope(2, None, ALG_OAEP_SHA256, "AAAA"), false),
+            Err(RsaError::UnsupportedVersion)
+        );
+        assert_eq!(
+            decrypt(
+                &keys,
+                &envelope(1, None, ALG_OAEP_SHA256, "not base64!"),
+                false
+            ),
+            Err(RsaError::Malformed)
+        );
+        assert_eq!(
+            decrypt(&keys, &envelope(1, None, "RSA-OAEP", "AAAA"), false),
+            Err(RsaError::UnsupportedAlgorithm)
+        );
+        assert_eq!(
+            decrypt(&keys, &envelope(1, None, ALG_OAEP_SHA256, "AAAA"), false),
+            Err(RsaError::Decrypt)
+        );
+        // Legacy payloads that are neither base64 nor ciphertexts
+        let not_base64 = format!("{}:%%%", keys.current.kid);
+        assert_eq!(decrypt(&keys, &not_base64, true), Err(RsaError::Malformed));
+        assert_eq!(decrypt(&keys, "AAAA", true), Err(RsaError::Decrypt));
+    }
+}
@@ -0,0 +1,325 @@
+use diesel::mysql::MysqlConnection;
+use diesel::prelude::*;
+use diesel::r2d2::{self, ConnectionManager, CustomizeConnection, Pool, PoolError};
+use dotenv::dotenv;
+use rocket::serde::Deserialize;
+use rocket::tokio::task::{self, JoinError};
+use std::env;
+use std::fmt;
+use std::time::Duration;
+
+pub type MysqlPool = Pool<ConnectionManager<MysqlConnection>>;
+
+diesel::define_sql_function! {
+    // Id generated by the last insert on this connection
+    fn last_insert_id() -> diesel::sql_types::Unsigned<diesel::sql_types::BigInt>;
+}
+
+// Pool settings, the `database` table of the configuration
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct DatabaseConfig {
+    pub url: Option<String>,
+    pub pool_size: u32,
+    pub min_idle: Option<u32>,
+    pub connect_timeout: u64,
+    pub idle_timeout: Option<u64>,
+    pub max_lifetime: Option<u64>,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        DatabaseConfig {
+            url: None,
+            pool_size: 10,
+            min_idle: None,
+            connect_timeout: 5,
+            idle_timeout: Some(300),
+            max_lifetime: Some(1800),
+        }
+    }
+}
+
+impl DatabaseConfig {
+    // The url in Rocket.toml wins, otherwise fall back to DATABASE_URL like diesel_cli does
+    fn database_url(&self) -> Result<String, DbError> {
+        if let Some(url) = &self.url {
+            return Ok(url.clone());
+        }
+        dotenv().ok();
+        env::var("DATABASE_URL")
+            .map_err(|_| DbError::Config("DATABASE_URL must be set".to_string()))
+    }
+}
+
+// Every connection speaks UTC, so times filled in by MySQL, like
+// `CURRENT_TIMESTAMP` defaults, agree with `Utc::now()` on the Rust side
+// whatever the time zone of the server
+#[derive(Debug, Clone, Copy)]
+pub struct UtcSession;
+
+impl UtcSession {
+    pub fn apply(connection: &mut MysqlConnection) -> QueryResult<()> {
+        diesel::sql_query("SET time_zone = '+00:00'")
+            .execute(connection)
+            .map(|_| ())
+    }
+}
+
+impl CustomizeConnection<MysqlConnection, r2d2::Error> for UtcSession {
+    fn on_acquire(&self, connection: &mut MysqlConnection) -> Result<(), r2d2::Error> {
+        UtcSession::apply(connection).map_err(r2d2::Error::QueryError)
+    }
+}
+
+#[derive(Debug)]
+pub enum DbError {
+    Config(String),
+    Pool(PoolError),
+    Task(JoinError),
+}
+
+impl fmt::Display for DbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DbError::Config(msg) => write!(f, "database configuration error: {}", msg),
+            DbError::Pool(err) => write!(f, "database pool error: {}", err),
+            DbError::Task(err) => write!(f, "database task failed: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for DbError {}
+
+// Connection pool managed as Rocket state. Diesel is synchronous, so every
+// query goes through `run`, which executes it on the blocking thread pool
+#[derive(Clone)]
+pub struct DbPool {
+    pool: MysqlPool,
+}
+
+impl DbPool {
+    pub fn new(config: &DatabaseConfig) -> Result<DbPool, DbError> {
+        let manager = ConnectionManager::<MysqlConnection>::new(config.database_url()?);
+        let pool = Pool::builder()
+            .max_size(config.pool_size)
+            .min_idle(config.min_idle)
+            .connection_timeout(Duration::from_secs(config.connect_timeout))
+            .idle_timeout(config.idle_timeout.map(Duration::from_secs))
+            .max_lifetime(config.max_lifetime.map(Duration::from_secs))
+            .connection_customizer(Box::new(UtcSession))
+            .build(manager)
+            .map_err(DbError::Pool)?;
+        Ok(DbPool { pool })
+    }
+
+    pub async fn run<F, R>(&self, f: F) -> Result<R, DbError>
+    where
+        F: FnOnce(&mut MysqlConnection) -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let pool = self.pool.clone();
+        task::spawn_blocking(move || {
+            let mut connection = pool.get().map_err(DbError::Pool)?;
+            Ok(f(&mut connection))
+        })
+        .await
+        .map_err(DbError::Task)?
+    }
+
+    // Blocking access, only meant for startup code that runs before the server launches
+    pub fn run_blocking<F, R>(&self, f: F) -> Result<R, DbError>
+    where
+        F: FnOnce(&mut MysqlConnection) -> R,
+    {
+        let mut connection = self.pool.get().map_err(DbError::Pool)?;
+        Ok(f(&mut connection))
+    }
+}
+
+// Helpers for tests that need MySQL. They connect to TEST_DATABASE_URL, a
+// database with every migration applied, and never commit anything: the
+// single connection stays inside a transaction that is rolled back once the
+// connection is dropped
+#[cfg(test)]
+pub mod testing {
+    use super::*;
+    use crate::schema::{direct_messages, directs, messages, rooms, rooms_users, users};
+    use diesel::r2d2::TestCustomizer;
+
+    fn database_url() -> String {
+        dotenv().ok();
+        env::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL must be set")
+    }
+
+    // A `UtcSession` inside a test transaction
+    #[derive(Debug)]
+    struct TestSession;
+
+    impl CustomizeConnection<MysqlConnection, r2d2::Error> for TestSession {
+        fn on_acquire(&self, connection: &mut MysqlConnection) -> Result<(), r2d2::Error> {
+            UtcSession.on_acquire(connection)?;
+            TestCustomizer.on_acquire(connection)
+        }
+    }
+
+    // A pool of one connection, so every query of a test sees the others
+    pub fn pool() -> DbPool {
+        let pool = Pool::builder()
+            .max_size(1)
+            .connection_customizer(Box::new(TestSession))
+            .build(ConnectionManager::<MysqlConnection>::new(database_url()))
+            .expect("test database unreachable");
+        DbPool { pool }
+    }
+
+    pub fn connection() -> MysqlConnection {
+        let mut connection =
+            MysqlConnection::establish(&database_url()).expect("test database unreachable");
+        UtcSession::apply(&mut connection).expect("failed to set the time zone");
+        connection
+            .begin_test_transaction()
+            .expect("failed to start the test transaction");
+        connection
+    }
+
+    pub fn user(connection: &mut MysqlConnection, username: &str) -> i32 {
+        diesel::insert_into(users::table)
+            .values((
+                users::full_name.eq(username),
+                users::surname.eq(username),
+                users::email.eq(format!("{}@example.com", username)),
+                users::username.eq(username),
+                users::passwd.eq(""),
+                users::salt.eq(""),
+            ))
+            .execute(connection)
+            .unwrap();
+        diesel::select(last_insert_id())
+            .get_result::<u64>(connection)
+            .unwrap() as i32
+    }
+
+    pub fn room(connection: &mut MysqlConnection, name: &str, members: &[i32]) -> i32 {
+        diesel::insert_into(rooms::table)
+            .values((
+                rooms::room_name.eq(name),
+                rooms::require_password.eq(false),
+                rooms::hidden_room.eq(false),
+                rooms::aes_key.eq(""),
+                rooms::salt.eq(""),
+            ))
+            .execute(connection)
+            .unwrap();
+        let room_id = diesel::select(last_insert_id())
+            .get_result::<u64>(connection)
+            .unwrap() as i32;
+        for &user_id in members {
+            diesel::insert_into(rooms_users::table)
+                .values((
+                    rooms_users::room_id.eq(room_id),
+                    rooms_users::user_id.eq(user_id),
+                ))
+                .execute(connection)
+                .unwrap();
+        }
+        room_id
+    }
+
+    pub fn direct(connection: &mut MysqlConnection, user1_id: i32, user2_id: i32) -> i32 {
+        diesel::insert_into(directs::table)
+            .values((
+                directs::user1_id.eq(user1_id),
+                directs::user2_id.eq(user2_id),
+                directs::aes_key.eq(""),
+            ))
+            .execute(connection)
+            .unwrap();
+        diesel::select(last_insert_id())
+            .get_result::<u64>(connection)
+            .unwrap() as i32
+    }
+
+    pub fn room_message(
+        connection: &mut MysqlConnection,
+        room_id: i32,
+        user_id: i32,
+        content: &str,
+    ) -> i32 {
+        diesel::insert_into(messages::table)
+            .values((
+                messages::room_id.eq(room_id),
+                messages::user_id.eq(user_id),
+                messages::content.eq(content),
+            ))
+            .execute(connection)
+            .unwrap();
+        diesel::select(last_insert_id())
+            .get_result::<u64>(connection)
+            .unwrap() as i32
+    }
+
+    pub fn direct_message(
+        connection: &mut MysqlConnection,
+        direct_id: i32,
+        sender_id: i32,
+        content: &str,
+    ) -> i32 {
+        diesel::insert_into(direct_messages::table)
+            .values((
+                direct_messages::chat_id.eq(direct_id),
+                direct_messages::sender_id.eq(sender_id),
+                direct_messages::message.eq(content),
+            ))
+            .execute(connection)
+            .unwrap();
+        diesel::select(last_insert_id())
+            .get_result::<u64>(connection)
+            .unwrap() as i32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema::messages;
+    use chrono::{NaiveDateTime, Utc};
+
+    // The pool the server uses, on the test database
+    fn server_pool() -> DbPool {
+        dotenv().ok();
+        DbPool::new(&DatabaseConfig {
+            url: Some(env::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL must be set")),
+            pool_size: 1,
+            ..DatabaseConfig::default()
+        })
+        .expect("test database unreachable")
+    }
+
+    #[test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    fn saved_messages_are_timed_in_utc() {
+        let saved_at = server_pool()
+            .run_blocking(|connection| {
+                // Rolled back once the pool, and its only connection, is dropped
+                connection.begin_test_transaction().unwrap();
+                let user_id = testing::user(connection, "utc_writer");
+                let room_id = testing::room(connection, "utc", &[user_id]);
+                let message_id = testing::room_message(connection, room_id, user_id, "now");
+                messages::table
+                    .filter(messages::message_id.eq(message_id))
+                    .select(messages::message_time)
+                    .first::<Option<NaiveDateTime>>(connection)
+                    .unwrap()
+                    .unwrap()
+            })
+            .unwrap();
+        let drift = (Utc::now().naive_utc() - saved_at).num_seconds().abs();
+        assert!(
+            drift < 60,
+            "message time {} is {}s off UTC",
+            saved_at,
+            drift
+        );
+    }
+}
@@ -0,0 +1,340 @@
+use crate::chats::Scope;
+use crate::history;
+use crate::models::{DirectMessageDB, MessageDB, UserDB};
+use crate::schema::{delivery_cursors, directs, rooms_users};
+use diesel::mysql::MysqlConnection;
+use diesel::prelude::*;
+use std::collections::HashMap;
+
+// Delivery cursors hold, per user and conversation, the id of the newest
+// message known to have reached one of the user's devices. A client that
+// reconnects without saying what it saw is caught up from there
+
+// The chats of a user
+pub struct Chats {
+    pub rooms: Vec<i32>,
+    // Direct chat ids, with the other user of each
+    pub directs: Vec<(i32, i32)>,
+}
+
+pub fn chats(connection: &mut MysqlConnection, user_id: i32) -> QueryResult<Chats> {
+    let rooms = rooms_users::table
+        .filter(rooms_users::user_id.eq(user_id))
+        .select(rooms_users::room_id)
+        .load(connection)?;
+    let directs = directs::table
+        .filter(
+            directs::user1_id
+                .eq(user_id)
+                .or(directs::user2_id.eq(user_id)),
+        )
+        .select((directs::id, directs::user1_id, directs::user2_id))
+        .load::<(i32, i32, i32)>(connection)?
+        .into_iter()
+        .map(|(id, user1, user2)| (id, if user1 == user_id { user2 } else { user1 }))
+        .collect();
+    Ok(Chats { rooms, directs })
+}
+
+// Every cursor of `user_id`
+pub fn cursors(connection: &mut MysqlConnection, user_id: i32) -> QueryResult<HashMap<Scope, i32>> {
+    let rows: Vec<(Option<i32>, Option<i32>, i32)> = delivery_cursors::table
+        .filter(delivery_cursors::user_id.eq(user_id))
+        .select((
+            delivery_cursors::room_id,
+            delivery_cursors::direct_id,
+            delivery_cursors::last_delivered_id,
+        ))
+        .load(connection)?;
+    Ok(rows
+        .into_iter()
+        .filter_map(|(room_id, direct_id, last)| match (room_id, direct_id) {
+            (Some(room_id), _) => Some((Scope::Room(room_id), last)),
+            (_, Some(direct_id)) => Some((Scope::Direct(direct_id), last)),
+            _ => None,
+        })
+        .collect())
+}
+
+// Move the cursor of `user_id` up to `message_id`, never back
+pub fn advance(
+    connection: &mut MysqlConnection,
+    user_id: i32,
+    scope: Scope,
+    message_id: i32,
+) -> QueryResult<()> {
+    let (room_id, direct_id) = match scope {
+        Scope::Room(room_id) => (Some(room_id), None),
+        Scope::Direct(direct_id) => (None, Some(direct_id)),
+    };
+    diesel::insert_or_ignore_into(delivery_cursors::table)
+        .values((
+            delivery_cursors::user_id.eq(user_id),
+            delivery_cursors::room_id.eq(room_id),
+            delivery_cursors::direct_id.eq(direct_id),
+            delivery_cursors::last_delivered_id.eq(message_id),
+        ))
+        .execute(connection)?;
+    let behind = delivery_cursors::table
+        .filter(delivery_cursors::user_id.eq(user_id))
+        .filter(delivery_cursors::last_delivered_id.lt(message_id));
+    match scope {
+        Scope::Room(room_id) => {
+            diesel::update(behind.filter(delivery_cursors::room_id.eq(room_id)))
+                .set(delivery_cursors::last_delivered_id.eq(message_id))
+                .execute(connection)?
+        }
+        Scope::Direct(direct_id) => {
+            diesel::update(behind.filter(delivery_cursors::direct_id.eq(direct_id)))
+                .set(delivery_cursors::last_delivered_id.eq(message_id))
+                .execute(connection)?
+        }
+    };
+    Ok(())
+}
+
+// The newest message written to one connection in each chat: rooms by id,
+// direct chats by the other user's id
+#[derive(Debug, Clone, Default)]
+pub struct Delivered {
+    rooms: HashMap<i32, i32>,
+    directs: HashMap<i32, i32>,
+}
+
+impl Delivered {
+    pub fn room(&mut self, room_id: i32, message_id: i32) {
+        let newest = self.rooms.entry(room_id).or_insert(message_id);
+        *newest = (*newest).max(message_id);
+    }
+
+    pub fn direct(&mut self, peer: i32, message_id: i32) {
+        let newest = self.directs.entry(peer).or_insert(message_id);
+        *newest = (*newest).max(message_id);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rooms.is_empty() && self.directs.is_empty()
+    }
+
+    pub fn merge(&mut self, other: Delivered) {
+        for (room_id, message_id) in other.rooms {
+            self.room(room_id, message_id);
+        }
+        for (peer, message_id) in other.directs {
+            self.direct(peer, message_id);
+        }
+    }
+}
+
+// Move the cursors of `user_id` up to what was written to one of its
+// connections, while it is open and once it closed. Messages still queued for
+// a dead connection don't count, so the next resume sends them
+pub fn record(
+    connection: &mut MysqlConnection,
+    user_id: i32,
+    delivered: &Delivered,
+) -> QueryResult<()> {
+    if delivered.is_empty() {
+        return Ok(());
+    }
+    let Chats { rooms, directs } = chats(connection, user_id)?;
+    connection.transaction(|connection| {
+        for room_id in rooms {
+            if let Some(newest) = delivered.rooms.get(&room_id) {
+                advance(connection, user_id, Scope::Room(room_id), *newest)?;
+            }
+        }
+        for (direct_id, peer) in directs {
+            if let Some(newest) = delivered.directs.get(&peer) {
+                advance(connection, user_id, Scope::Direct(direct_id), *newest)?;
+            }
+        }
+        Ok(())
+    })
+}
+
+// What a reconnecting client missed in one chat, oldest first. `truncated`
+// when older messages were left out
+pub enum Missed {
+    Room {
+        room_id: i32,
+        page: Vec<(MessageDB, UserDB)>,
+        truncated: bool,
+    },
+    Direct {
+        peer: i32,
+        page: Vec<DirectMessageDB>,
+        truncated: bool,
+    },
+}
+
+// The newest `limit` messages of each chat of `user_id` after the id the
+// client names for it, rooms by id and direct chats by the other user's id,
+// or else after the stored cursor. Chats with neither, or nothing new, are
+// left out. Also gives the newest id sent in each chat
+pub fn missed(
+    connection: &mut MysqlConnection,
+    user_id: i32,
+    rooms: &HashMap<i32, i32>,
+    directs: &HashMap<i32, i32>,
+    limit: i64,
+) -> QueryResult<(Vec<Missed>, Delivered)> {
+    let chats = chats(connection, user_id)?;
+    let cursors = cursors(connection, user_id)?;
+    let mut missed = Vec::new();
+    let mut delivered = Delivered::default();
+    for room_id in chats.rooms {
+        let scope = Scope::Room(room_id);
+        let Some(after) = rooms.get(&room_id).or(cursors.get(&scope)).copied() else {
+            continue;
+        };
+        let (page, truncated) = history::room_after(connection, room_id, after, limit)?;
+        let Some(newest) = page.last().map(|(m, _)| m.message_id) else {
+            continue;
+        };
+        delivered.room(room_id, newest);
+        missed.push(Missed::Room {
+            room_id,
+            page,
+            truncated,
+        });
+    }
+    for (direct_id, peer) in chats.directs {
+        let scope = Scope::Direct(direct_id);
+        let Some(after) = directs.get(&peer).or(cursors.get(&scope)).copied() else {
+            continue;
+        };
+        let (page, truncated) = history::direct_after(connection, direct_id, after, limit)?;
+        let Some(newest) = page.last().map(|m| m.id) else {
+            continue;
+        };
+        delivered.direct(peer, newest);
+        missed.push(Missed::Direct {
+            peer,
+            page,
+            truncated,
+        });
+    }
+    Ok((missed, delivered))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::testing;
+
+    fn ids(chat: &Missed) -> Vec<i32> {
+        match chat {
+            Missed::Room { page, .. } => page.iter().map(|(m, _)| m.message_id).collect(),
+            Missed::Direct { page, .. } => page.iter().map(|m| m.id).collect(),
+        }
+    }
+
+    #[test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    fn missed_keeps_the_newest_messages_up_to_the_limit() {
+        let mut connection = testing::connection();
+        let reader = testing::user(&mut connection, "missed_reader");
+        let writer = testing::user(&mut connection, "missed_writer");
+        let room_id = testing::room(&mut connection, "missed", &[reader, writer]);
+        let first = testing::room_message(&mut connection, room_id, writer, "one");
+        let second = testing::room_message(&mut connection, room_id, writer, "two");
+        let third = testing::room_message(&mut connection, room_id, writer, "three");
+        let fourth = testing::room_message(&mut connection, room_id, writer, "four");
+        let rooms = HashMap::from([(room_id, first)]);
+
+        let (found, delivered) =
+            missed(&mut connection, reader, &rooms, &HashMap::new(), 2).unwrap();
+        assert_eq!(found.len(), 1);
+        assert!(matches!(
+            found[0],
+            Missed::Room {
+                truncated: true,
+                ..
+            }
+        ));
+        assert_eq!(ids(&found[0]), vec![third, fourth]);
+        assert_eq!(delivered.rooms.get(&room_id), Some(&fourth));
+
+        // Everything fits
+        let (found, _) = missed(&mut connection, reader, &rooms, &HashMap::new(), 3).unwrap();
+        assert!(matches!(
+            found[0],
+            Missed::Room {
+                truncated: false,
+                ..
+            }
+        ));
+        assert_eq!(ids(&found[0]), vec![second, third, fourth]);
+    }
+
+    #[test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    fn missed_resumes_from_the_client_then_the_cursor() {
+        let mut connection = testing::connection();
+        let reader = testing::user(&mut connection, "resume_reader");
+        let friend = testing::user(&mut connection, "resume_friend");
+        let quiet = testing::user(&mut connection, "resume_quiet");
+        let direct_id = testing::direct(&mut connection, reader, friend);
+        let quiet_direct = testing::direct(&mut connection, quiet, reader);
+        let first = testing::direct_message(&mut connection, direct_id, friend, "one");
+        let second = testing::direct_message(&mut connection, direct_id, friend, "two");
+        let third = testing::direct_message(&mut connection, direct_id, reader, "three");
+        // Without a cursor nor an id from the client, a chat is left out
+        testing::direct_message(&mut connection, quiet_direct, quiet, "unseen");
+        advance(&mut connection, reader, Scope::Direct(direct_id), second).unwrap();
+
+        let (found, delivered) = missed(
+            &mut connection,
+            reader,
+            &HashMap::new(),
+            &HashMap::new(),
+            10,
+        )
+        .unwrap();
+        assert_eq!(found.len(), 1);
+        assert!(matches!(found[0], Missed::Direct { peer, .. } if peer == friend));
+        assert_eq!(ids(&found[0]), vec![third]);
+        assert_eq!(delivered.directs.get(&friend), Some(&third));
+
+        // The id the client names wins over the cursor
+        let directs = HashMap::from([(friend, first)]);
+        let (found, _) = missed(&mut connection, reader, &HashMap::new(), &directs, 10).unwrap();
+        assert_eq!(ids(&found[0]), vec![second, third]);
+
+        // Nothing newer, nothing sent
+        let directs = HashMap::from([(friend, third)]);
+        let (found, delivered) =
+            missed(&mut connection, reader, &HashMap::new(), &directs, 10).unwrap();
+        assert!(found.is_empty());
+        assert!(delivered.is_empty());
+    }
+
+    #[test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    fn recorded_cursors_only_move_forward() {
+        let mut connection = testing::connection();
+        let reader = testing::user(&mut connection, "record_reader");
+        let writer = testing::user(&mut connection, "record_writer");
+        let room_id = testing::room(&mut connection, "recorded", &[reader, writer]);
+        let first = testing::room_message(&mut connection, room_id, writer, "one");
+        let second = testing::room_message(&mut connection, room_id, writer, "two");
+        let scope = Scope::Room(room_id);
+
+        let mut delivered = Delivered::default();
+        delivered.room(room_id, second);
+        record(&mut connection, reader, &delivered).unwrap();
+        assert_eq!(
+            cursors(&mut connection, reader).unwrap().get(&scope),
+            Some(&second)
+        );
+
+        let mut older = Delivered::default();
+        older.room(room_id, first);
+        record(&mut connection, reader, &older).unwrap();
+        assert_eq!(
+            cursors(&mut connection, reader).unwrap().get(&scope),
+            Some(&second)
+        );
+    }
+}
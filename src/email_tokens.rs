@@ -0,0 +1,259 @@
+use crate::crypto::random_token;
+use crate::models::EmailTokenDB;
+use crate::schema::email_tokens;
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::mysql::MysqlConnection;
+use diesel::prelude::*;
+use sha2::{Digest, Sha256};
+
+// What a token is for. A user has at most one live token per purpose
+pub const PURPOSE_VERIFY: &str = "verify";
+pub const PURPOSE_RESET: &str = "reset";
+
+// Result of redeeming a token
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Redeemed {
+    // Valid for this user
+    User(i32),
+    Expired,
+    Invalid,
+}
+
+fn now() -> NaiveDateTime {
+    Utc::now().naive_utc()
+}
+
+// Only the SHA-256 of a token is stored, so a leaked table holds no usable
+// links. Tokens are random, a plain hash is enough
+fn digest(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+// Store a new token for the user, replacing the previous one with the same
+// purpose, and return it
+pub fn issue(connection: &mut MysqlConnection, user_id: i32, purpose: &str) -> QueryResult<String> {
+    let token = random_token();
+    diesel::replace_into(email_tokens::table)
+        .values((
+            email_tokens::user_id.eq(user_id),
+            email_tokens::purpose.eq(purpose),
+            email_tokens::token_hash.eq(digest(&token)),
+            email_tokens::created_at.eq(now()),
+        ))
+        .execute(connection)?;
+    Ok(token)
+}
+
+// When the user's token for `purpose` was issued, if they have one
+pub fn issued_at(
+    connection: &mut MysqlConnection,
+    user_id: i32,
+    purpose: &str,
+) -> QueryResult<Option<NaiveDateTime>> {
+    email_tokens::table
+        .filter(email_tokens::user_id.eq(user_id))
+        .filter(email_tokens::purpose.eq(purpose))
+        .select(email_tokens::created_at)
+        .first(connection)
+        .optional()
+}
+
+// What `redeem` would answer for a token, without using it up
+pub fn check(
+    connection: &mut MysqlConnection,
+    token: &str,
+    purpose: &str,
+    ttl: Duration,
+) -> QueryResult<Redeemed> {
+    let found = email_tokens::table
+        .filter(email_tokens::token_hash.eq(digest(token)))
+        .filter(email_tokens::purpose.eq(purpose))
+        .select(EmailTokenDB::as_select())
+        .first(connection)
+        .optional()?;
+    Ok(match found {
+        None => Redeemed::Invalid,
+        Some(found) if found.created_at + ttl < now() => Redeemed::Expired,
+        Some(found) => Redeemed::User(found.user_id),
+    })
+}
+
+// Redeem a token: it is deleted whether it was still valid or not, so a link
+// only ever works once. Callers run it in a transaction, so the row stays
+// locked until what the token allows is done
+pub fn redeem(
+    connection: &mut MysqlConnection,
+    token: &str,
+    purpose: &str,
+    ttl: Duration,
+) -> QueryResult<Redeemed> {
+    let token_hash = digest(token);
+    let found = email_tokens::table
+        .filter(email_tokens::token_hash.eq(&token_hash))
+        .filter(email_tokens::purpose.eq(purpose))
+        .select(EmailTokenDB::as_select())
+        .for_update()
+        .first(connection)
+        .optional()?;
+    let found = match found {
+        Some(found) => found,
+        None => return Ok(Redeemed::Invalid),
+    };
+
+    let deleted = diesel::delete(
+        email_tokens::table
+            .filter(email_tokens::user_id.eq(found.user_id))
+            .filter(email_tokens::purpose.eq(purpose))
+            .filter(email_tokens::token_hash.eq(&token_hash)),
+    )
+    .execute(connection)?;
+    // Another request redeemed it first
+    if deleted != 1 {
+        return Ok(Redeemed::Invalid);
+    }
+    if found.created_at + ttl < now() {
+        Ok(Redeemed::Expired)
+    } else {
+        Ok(Redeemed::User(found.user_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::testing;
+    use crate::verification::{self, ResendOutcome};
+
+    const HOUR: Duration = Duration::hours(1);
+
+    fn age(connection: &mut MysqlConnection, user_id: i32, by: Duration) {
+        diesel::update(email_tokens::table.filter(email_tokens::user_id.eq(user_id)))
+            .set(email_tokens::created_at.eq(now() - by))
+            .execute(connection)
+            .unwrap();
+    }
+
+    #[test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    fn tokens_are_stored_hashed() {
+        let mut connection = testing::connection();
+        let user_id = testing::user(&mut connection, "token_hashed");
+        let token = issue(&mut connection, user_id, PURPOSE_VERIFY).unwrap();
+        let stored: String = email_tokens::table
+            .filter(email_tokens::user_id.eq(user_id))
+            .select(email_tokens::token_hash)
+            .first(&mut connection)
+            .unwrap();
+        assert_eq!(stored.len(), 64);
+        assert_ne!(stored, token);
+        assert_eq!(stored, digest(&token));
+    }
+
+    #[test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    fn tokens_work_once() {
+        let mut connection = testing::connection();
+        let user_id = testing::user(&mut connection, "token_once");
+        let token = issue(&mut connection, user_id, PURPOSE_VERIFY).unwrap();
+        assert_eq!(
+            redeem(&mut connection, &token, PURPOSE_RESET, HOUR).unwrap(),
+            Redeemed::Invalid
+        );
+        assert_eq!(
+            redeem(&mut connection, &token, PURPOSE_VERIFY, HOUR).unwrap(),
+            Redeemed::User(user_id)
+        );
+        assert_eq!(
+            redeem(&mut connection, &token, PURPOSE_VERIFY, HOUR).unwrap(),
+            Redeemed::Invalid
+        );
+    }
+
+    #[test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    fn checking_leaves_tokens_usable() {
+        let mut connection = testing::connection();
+        let user_id = testing::user(&mut connection, "token_checked");
+        let token = issue(&mut connection, user_id, PURPOSE_RESET).unwrap();
+        assert_eq!(
+            check(&mut connection, "not a token", PURPOSE_RESET, HOUR).unwrap(),
+            Redeemed::Invalid
+        );
+        assert_eq!(
+            check(&mut connection, &token, PURPOSE_VERIFY, HOUR).unwrap(),
+            Redeemed::Invalid
+        );
+        assert_eq!(
+            check(&mut connection, &token, PURPOSE_RESET, HOUR).unwrap(),
+            Redeemed::User(user_id)
+        );
+        assert_eq!(
+            redeem(&mut connection, &token, PURPOSE_RESET, HOUR).unwrap(),
+            Redeemed::User(user_id)
+        );
+
+        let token = issue(&mut connection, user_id, PURPOSE_RESET).unwrap();
+        age(&mut connection, user_id, HOUR + Duration::seconds(1));
+        assert_eq!(
+            check(&mut connection, &token, PURPOSE_RESET, HOUR).unwrap(),
+            Redeemed::Expired
+        );
+    }
+
+    #[test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    fn new_tokens_replace_older_ones() {
+        let mut connection = testing::connection();
+        let user_id = testing::user(&mut connection, "token_replaced");
+        let old = issue(&mut connection, user_id, PURPOSE_RESET).unwrap();
+        let new = issue(&mut connection, user_id, PURPOSE_RESET).unwrap();
+        assert_eq!(
+            redeem(&mut connection, &old, PURPOSE_RESET, HOUR).unwrap(),
+            Redeemed::Invalid
+        );
+        assert_eq!(
+            redeem(&mut connection, &new, PURPOSE_RESET, HOUR).unwrap(),
+            Redeemed::User(user_id)
+        );
+    }
+
+    #[test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    fn expired_tokens_are_refused_and_spent() {
+        let mut connection = testing::connection();
+        let user_id = testing::user(&mut connection, "token_expired");
+        let token = issue(&mut connection, user_id, PURPOSE_VERIFY).unwrap();
+        age(&mut connection, user_id, HOUR + Duration::seconds(1));
+        assert_eq!(
+            redeem(&mut connection, &token, PURPOSE_VERIFY, HOUR).unwrap(),
+            Redeemed::Expired
+        );
+        assert_eq!(
+            redeem(&mut connection, &token, PURPOSE_VERIFY, HOUR).unwrap(),
+            Redeemed::Invalid
+        );
+    }
+
+    #[test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    fn resending_waits_for_the_cooldown() {
+        let mut connection = testing::connection();
+        let user_id = testing::user(&mut connection, "token_cooldown");
+        let address = "token_cooldown@example.com";
+        let cooldown = Duration::minutes(2);
+        let resend = |connection: &mut MysqlConnection| {
+            verification::resend(connection, address, cooldown, "/verify-email/").unwrap()
+        };
+        assert_eq!(resend(&mut connection), ResendOutcome::Queued);
+        assert_eq!(resend(&mut connection), ResendOutcome::TooSoon);
+        age(&mut connection, user_id, cooldown + Duration::seconds(1));
+        assert_eq!(resend(&mut connection), ResendOutcome::Queued);
+        assert_eq!(
+            verification::resend(&mut connection, "nobody@example.com", cooldown, "").unwrap(),
+            ResendOutcome::Ignored
+        );
+    }
+}
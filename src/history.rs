@@ -0,0 +1,504 @@
+use crate::chats::{MessageKind, Scope};
+use crate::models::{DirectMessageDB, MessageDB, UserDB};
+use crate::schema::{direct_messages, messages, users};
+use diesel::dsl::count_star;
+use diesel::mysql::MysqlConnection;
+use diesel::prelude::*;
+use std::collections::HashMap;
+
+// Pages of message history, ordered by (message_time, id). A page holds the
+// `limit` messages right before the `before` message, or the newest ones
+// without a cursor, and is returned oldest first. A cursor that is not a
+// message of the chat gives `NotFound`. With a `thread`, only the replies in
+// the thread started by that message are paged, leaving out deleted ones
+
+pub fn room_page(
+    connection: &mut MysqlConnection,
+    room_id: i32,
+    thread: Option<i32>,
+    before: Option<i32>,
+    limit: i64,
+) -> QueryResult<Vec<(MessageDB, UserDB)>> {
+    let mut query = messages::table
+        .inner_join(users::table)
+        .filter(messages::room_id.eq(room_id))
+        .select((MessageDB::as_select(), UserDB::as_select()))
+        .order((messages::message_time.desc(), messages::message_id.desc()))
+        .limit(limit)
+        .into_boxed();
+    if let Some(thread) = thread {
+        query = query
+            .filter(messages::thread_id.eq(thread))
+            .filter(messages::deleted_at.is_null());
+    }
+    if let Some(before) = before {
+        let cursor = messages::table
+            .filter(messages::room_id.eq(room_id))
+            .filter(messages::message_id.eq(before))
+            .select(messages::message_time)
+            .first::<Option<chrono::NaiveDateTime>>(connection)?;
+        // Rows without a time sort before every other one
+        query = match cursor {
+            Some(time) => query.filter(
+                messages::message_time
+                    .lt(time)
+                    .or(messages::message_time
+                        .eq(time)
+                        .and(messages::message_id.lt(before)))
+                    .or(messages::message_time.is_null()),
+            ),
+            None => query.filter(
+                messages::message_time
+                    .is_null()
+                    .and(messages::message_id.lt(before)),
+            ),
+        };
+    }
+    let mut page = query.load(connection)?;
+    page.reverse();
+    Ok(page)
+}
+
+pub fn direct_page(
+    connection: &mut MysqlConnection,
+    direct_id: i32,
+    thread: Option<i32>,
+    before: Option<i32>,
+    limit: i64,
+) -> QueryResult<Vec<DirectMessageDB>> {
+    let mut query = direct_messages::table
+        .filter(direct_messages::chat_id.eq(direct_id))
+        .select(DirectMessageDB::as_select())
+        .order((
+            direct_messages::message_time.desc(),
+            direct_messages::id.desc(),
+        ))
+        .limit(limit)
+        .into_boxed();
+    if let Some(thread) = thread {
+        query = query
+            .filter(direct_messages::thread_id.eq(thread))
+            .filter(direct_messages::deleted_at.is_null());
+    }
+    if let Some(before) = before {
+        let cursor = direct_messages::table
+            .filter(direct_messages::chat_id.eq(direct_id))
+            .filter(direct_messages::id.eq(before))
+            .select(direct_messages::message_time)
+            .first::<Option<chrono::NaiveDateTime>>(connection)?;
+        query = match cursor {
+            Some(time) => query.filter(
+                direct_messages::message_time
+                    .lt(time)
+                    .or(direct_messages::message_time
+                        .eq(time)
+                        .and(direct_messages::id.lt(before)))
+                    .or(direct_messages::message_time.is_null()),
+            ),
+            None => query.filter(
+                direct_messages::message_time
+                    .is_null()
+                    .and(direct_messages::id.lt(before)),
+            ),
+        };
+    }
+    let mut page = query.load(connection)?;
+    page.reverse();
+    Ok(page)
+}
+
+// The newest `limit` messages of each of the given chats, oldest first, the
+// same as a page without a cursor. Chats without messages are left out. The
+// ranking needs window functions, so MySQL 8
+
+pub fn latest_room_pages(
+    connection: &mut MysqlConnection,
+    room_ids: &[i32],
+    limit: i64,
+) -> QueryResult<HashMap<i32, Vec<(MessageDB, UserDB)>>> {
+    let ids = latest_ids(
+        connection,
+        "messages",
+        "message_id",
+        "room_id",
+        room_ids,
+        limit,
+    )?;
+    let rows: Vec<(MessageDB, UserDB)> = messages::table
+        .inner_join(users::table)
+        .filter(messages::message_id.eq_any(&ids))
+        .select((MessageDB::as_select(), UserDB::as_select()))
+        .order((messages::message_time.asc(), messages::message_id.asc()))
+        .load(connection)?;
+    let mut pages: HashMap<i32, Vec<(MessageDB, UserDB)>> = HashMap::new();
+    for row in rows {
+        pages.entry(row.0.room_id).or_default().push(row);
+    }
+    Ok(pages)
+}
+
+pub fn latest_direct_pages(
+    connection: &mut MysqlConnection,
+    direct_ids: &[i32],
+    limit: i64,
+) -> QueryResult<HashMap<i32, Vec<DirectMessageDB>>> {
+    let ids = latest_ids(
+        connection,
+        "direct_messages",
+        "id",
+        "chat_id",
+        direct_ids,
+        limit,
+    )?;
+    let rows: Vec<DirectMessageDB> = direct_messages::table
+        .filter(direct_messages::id.eq_any(&ids))
+        .select(DirectMessageDB::as_select())
+        .order((
+            direct_messages::message_time.asc(),
+            direct_messages::id.asc(),
+        ))
+        .load(connection)?;
+    let mut pages: HashMap<i32, Vec<DirectMessageDB>> = HashMap::new();
+    for row in rows {
+        pages.entry(row.chat_id).or_default().push(row);
+    }
+    Ok(pages)
+}
+
+#[derive(QueryableByName)]
+struct Ranked {
+    #[diesel(sql_type = diesel::sql_types::Integer)]
+    id: i32,
+}
+
+// Ids of the newest `limit` rows of `table` per `chat` column value. The
+// names are ours, only the chat ids and the limit are bound
+fn latest_ids(
+    connection: &mut MysqlConnection,
+    table: &str,
+    id: &str,
+    chat: &str,
+    chat_ids: &[i32],
+    limit: i64,
+) -> QueryResult<Vec<i32>> {
+    if chat_ids.is_empty() {
+        return Ok(Vec::new());
+    }
+    let placeholders = vec!["?"; chat_ids.len()].join(", ");
+    let mut query = diesel::sql_query(format!(
+        "SELECT {id} AS id FROM (\
+            SELECT {id}, ROW_NUMBER() OVER (\
+                PARTITION BY {chat} ORDER BY message_time DESC, {id} DESC\
+            ) AS position FROM {table} WHERE {chat} IN ({placeholders})\
+        ) ranked WHERE position <= ?"
+    ))
+    .into_boxed();
+    for chat_id in chat_ids {
+        query = query.bind::<diesel::sql_types::Integer, _>(*chat_id);
+    }
+    let ranked: Vec<Ranked> = query
+        .bind::<diesel::sql_types::BigInt, _>(limit)
+        .load(connection)?;
+    Ok(ranked.into_iter().map(|row| row.id).collect())
+}
+
+// The messages a client that saw the `after` message missed, oldest first.
+// Ids grow with time, so these are the ones with a greater id. Only the
+// `limit` newest are returned, with whether older ones were left out
+
+pub fn room_after(
+    connection: &mut MysqlConnection,
+    room_id: i32,
+    after: i32,
+    limit: i64,
+) -> QueryResult<(Vec<(MessageDB, UserDB)>, bool)> {
+    let mut page: Vec<(MessageDB, UserDB)> = messages::table
+        .inner_join(users::table)
+        .filter(messages::room_id.eq(room_id))
+        .filter(messages::message_id.gt(after))
+        .select((MessageDB::as_select(), UserDB::as_select()))
+        .order(messages::message_id.desc())
+        .limit(limit + 1)
+        .load(connection)?;
+    let truncated = page.len() as i64 > limit;
+    page.truncate(limit as usize);
+    page.reverse();
+    Ok((page, truncated))
+}
+
+pub fn direct_after(
+    connection: &mut MysqlConnection,
+    direct_id: i32,
+    after: i32,
+    limit: i64,
+) -> QueryResult<(Vec<DirectMessageDB>, bool)> {
+    let mut page: Vec<DirectMessageDB> = direct_messages::table
+        .filter(direct_messages::chat_id.eq(direct_id))
+        .filter(direct_messages::id.gt(after))
+        .select(DirectMessageDB::as_select())
+        .order(direct_messages::id.desc())
+        .limit(limit + 1)
+        .load(connection)?;
+    let truncated = page.len() as i64 > limit;
+    page.truncate(limit as usize);
+    page.reverse();
+    Ok((page, truncated))
+}
+
+// The thread a reply to `message_id` belongs to: the message itself, or the
+// first message of its thread when it is already a reply. `None` when the
+// message is not part of the chat
+pub fn thread_of(
+    connection: &mut MysqlConnection,
+    scope: Scope,
+    message_id: i32,
+) -> QueryResult<Option<i32>> {
+    let parent = match scope {
+        Scope::Room(room_id) => messages::table
+            .filter(messages::room_id.eq(room_id))
+            .filter(messages::message_id.eq(message_id))
+            .select(messages::thread_id)
+            .first::<Option<i32>>(connection)
+            .optional()?,
+        Scope::Direct(direct_id) => direct_messages::table
+            .filter(direct_messages::chat_id.eq(direct_id))
+            .filter(direct_messages::id.eq(message_id))
+            .select(direct_messages::thread_id)
+            .first::<Option<i32>>(connection)
+            .optional()?,
+    };
+    Ok(parent.map(|thread_id| thread_id.unwrap_or(message_id)))
+}
+
+// Number of replies in the thread started by each of the given messages, for
+// those that have any. Deleted replies don't count
+pub fn reply_counts(
+    connection: &mut MysqlConnection,
+    kind: MessageKind,
+    message_ids: &[i32],
+) -> QueryResult<HashMap<i32, i64>> {
+    if message_ids.is_empty() {
+        return Ok(HashMap::new());
+    }
+    let counts: Vec<(Option<i32>, i64)> = match kind {
+        MessageKind::Room => messages::table
+            .filter(messages::thread_id.eq_any(message_ids))
+            .filter(messages::deleted_at.is_null())
+            .group_by(messages::thread_id)
+            .select((messages::thread_id, count_star()))
+            .load(connection)?,
+        MessageKind::Direct => direct_messages::table
+            .filter(direct_messages::thread_id.eq_any(message_ids))
+            .filter(direct_messages::deleted_at.is_null())
+            .group_by(direct_messages::thread_id)
+            .select((direct_messages::thread_id, count_star()))
+            .load(connection)?,
+    };
+    Ok(counts
+        .into_iter()
+        .filter_map(|(parent, count)| parent.map(|parent| (parent, count)))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::testing;
+    use diesel::result::Error::NotFound;
+
+    fn room_ids(page: &[(MessageDB, UserDB)]) -> Vec<i32> {
+        page.iter().map(|(m, _)| m.message_id).collect()
+    }
+
+    fn direct_ids(page: &[DirectMessageDB]) -> Vec<i32> {
+        page.iter().map(|m| m.id).collect()
+    }
+
+    // A room message answering `reply_to`, threaded like the server does
+    fn room_reply(
+        connection: &mut MysqlConnection,
+        room_id: i32,
+        user_id: i32,
+        reply_to: i32,
+    ) -> i32 {
+        let thread = thread_of(connection, Scope::Room(room_id), reply_to)
+            .unwrap()
+            .unwrap();
+        let message_id = testing::room_message(connection, room_id, user_id, "reply");
+        diesel::update(messages::table.filter(messages::message_id.eq(message_id)))
+            .set((
+                messages::reply_to_id.eq(Some(reply_to)),
+                messages::thread_id.eq(Some(thread)),
+            ))
+            .execute(connection)
+            .unwrap();
+        message_id
+    }
+
+    #[test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    fn messages_of_the_same_time_are_paged_by_id() {
+        let mut connection = testing::connection();
+        let writer = testing::user(&mut connection, "page_tie_writer");
+        let room_id = testing::room(&mut connection, "page_ties", &[writer]);
+        let first = testing::room_message(&mut connection, room_id, writer, "one");
+        let second = testing::room_message(&mut connection, room_id, writer, "two");
+        let third = testing::room_message(&mut connection, room_id, writer, "three");
+        let time = chrono::Utc::now().naive_utc();
+        diesel::update(messages::table.filter(messages::room_id.eq(room_id)))
+            .set(messages::message_time.eq(Some(time)))
+            .execute(&mut connection)
+            .unwrap();
+
+        let newest = room_page(&mut connection, room_id, None, None, 2).unwrap();
+        assert_eq!(room_ids(&newest), vec![second, third]);
+        let older = room_page(&mut connection, room_id, None, Some(second), 2).unwrap();
+        assert_eq!(room_ids(&older), vec![first]);
+    }
+
+    #[test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    fn pages_hold_at_most_the_limit() {
+        let mut connection = testing::connection();
+        let reader = testing::user(&mut connection, "page_limit_reader");
+        let writer = testing::user(&mut connection, "page_limit_writer");
+        let direct_id = testing::direct(&mut connection, reader, writer);
+        let first = testing::direct_message(&mut connection, direct_id, writer, "one");
+        let second = testing::direct_message(&mut connection, direct_id, reader, "two");
+        let third = testing::direct_message(&mut connection, direct_id, writer, "three");
+        let fourth = testing::direct_message(&mut connection, direct_id, reader, "four");
+
+        let page = direct_page(&mut connection, direct_id, None, None, 3).unwrap();
+        assert_eq!(direct_ids(&page), vec![second, third, fourth]);
+        let page = direct_page(&mut connection, direct_id, None, Some(fourth), 1).unwrap();
+        assert_eq!(direct_ids(&page), vec![third]);
+        let page = direct_page(&mut connection, direct_id, None, Some(second), 5).unwrap();
+        assert_eq!(direct_ids(&page), vec![first]);
+    }
+
+    #[test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    fn cursors_of_another_chat_are_not_found() {
+        let mut connection = testing::connection();
+        let writer = testing::user(&mut connection, "cursor_writer");
+        let friend = testing::user(&mut connection, "cursor_friend");
+        let third = testing::user(&mut connection, "cursor_third");
+        let room_id = testing::room(&mut connection, "cursor_room", &[writer]);
+        let other_room = testing::room(&mut connection, "cursor_other", &[writer]);
+        let direct_id = testing::direct(&mut connection, writer, friend);
+        let other_direct = testing::direct(&mut connection, friend, third);
+        testing::room_message(&mut connection, room_id, writer, "here");
+        let elsewhere = testing::room_message(&mut connection, other_room, writer, "there");
+        testing::direct_message(&mut connection, direct_id, writer, "here");
+        let elsewhere_direct =
+            testing::direct_message(&mut connection, other_direct, friend, "there");
+
+        assert_eq!(
+            room_page(&mut connection, room_id, None, Some(elsewhere), 10).unwrap_err(),
+            NotFound
+        );
+        assert_eq!(
+            direct_page(&mut connection, direct_id, None, Some(elsewhere_direct), 10).unwrap_err(),
+            NotFound
+        );
+    }
+
+    #[test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    fn catching_up_reports_what_was_left_out() {
+        let mut connection = testing::connection();
+        let reader = testing::user(&mut connection, "after_reader");
+        let writer = testing::user(&mut connection, "after_writer");
+        let room_id = testing::room(&mut connection, "after_room", &[reader, writer]);
+        let direct_id = testing::direct(&mut connection, reader, writer);
+        let seen = testing::room_message(&mut connection, room_id, writer, "seen");
+        let first = testing::room_message(&mut connection, room_id, writer, "one");
+        let second = testing::room_message(&mut connection, room_id, writer, "two");
+        let seen_direct = testing::direct_message(&mut connection, direct_id, writer, "seen");
+        let direct_first = testing::direct_message(&mut connection, direct_id, writer, "one");
+
+        let (page, truncated) = room_after(&mut connection, room_id, seen, 1).unwrap();
+        assert_eq!((room_ids(&page), truncated), (vec![second], true));
+        let (page, truncated) = room_after(&mut connection, room_id, seen, 2).unwrap();
+        assert_eq!((room_ids(&page), truncated), (vec![first, second], false));
+
+        let (page, truncated) = direct_after(&mut connection, direct_id, seen_direct, 1).unwrap();
+        assert_eq!((direct_ids(&page), truncated), (vec![direct_first], false));
+        let (page, truncated) = direct_after(&mut connection, direct_id, direct_first, 1).unwrap();
+        assert_eq!((direct_ids(&page), truncated), (vec![], false));
+    }
+
+    #[test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    fn latest_pages_match_the_newest_page_of_each_chat() {
+        let mut connection = testing::connection();
+        let writer = testing::user(&mut connection, "latest_writer");
+        let busy = testing::room(&mut connection, "latest_busy", &[writer]);
+        let quiet = testing::room(&mut connection, "latest_quiet", &[writer]);
+        let empty = testing::room(&mut connection, "latest_empty", &[writer]);
+        for content in ["one", "two", "three"] {
+            testing::room_message(&mut connection, busy, writer, content);
+        }
+        let only = testing::room_message(&mut connection, quiet, writer, "only");
+
+        let pages = latest_room_pages(&mut connection, &[busy, quiet, empty], 2).unwrap();
+        let newest = room_page(&mut connection, busy, None, None, 2).unwrap();
+        assert_eq!(room_ids(&pages[&busy]), room_ids(&newest));
+        assert_eq!(room_ids(&pages[&quiet]), vec![only]);
+        assert!(!pages.contains_key(&empty));
+    }
+
+    #[test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    fn replies_to_replies_join_the_first_thread() {
+        let mut connection = testing::connection();
+        let writer = testing::user(&mut connection, "thread_writer");
+        let room_id = testing::room(&mut connection, "threads", &[writer]);
+        let other_room = testing::room(&mut connection, "threads_other", &[writer]);
+        let root = testing::room_message(&mut connection, room_id, writer, "root");
+        let reply = room_reply(&mut connection, room_id, writer, root);
+        let nested = room_reply(&mut connection, room_id, writer, reply);
+        let elsewhere = testing::room_message(&mut connection, other_room, writer, "there");
+
+        let scope = Scope::Room(room_id);
+        assert_eq!(thread_of(&mut connection, scope, root).unwrap(), Some(root));
+        assert_eq!(
+            thread_of(&mut connection, scope, reply).unwrap(),
+            Some(root)
+        );
+        assert_eq!(
+            thread_of(&mut connection, scope, nested).unwrap(),
+            Some(root)
+        );
+        assert_eq!(thread_of(&mut connection, scope, elsewhere).unwrap(), None);
+    }
+
+    #[test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    fn threads_hold_their_live_replies_only() {
+        let mut connection = testing::connection();
+        let writer = testing::user(&mut connection, "reply_writer");
+        let room_id = testing::room(&mut connection, "replies", &[writer]);
+        let root = testing::room_message(&mut connection, room_id, writer, "root");
+        let other_root = testing::room_message(&mut connection, room_id, writer, "other");
+        let unasked = testing::room_message(&mut connection, room_id, writer, "unasked");
+        let first = room_reply(&mut connection, room_id, writer, root);
+        let deleted = room_reply(&mut connection, room_id, writer, first);
+        let last = room_reply(&mut connection, room_id, writer, root);
+        room_reply(&mut connection, room_id, writer, other_root);
+        room_reply(&mut connection, room_id, writer, unasked);
+        diesel::update(messages::table.filter(messages::message_id.eq(deleted)))
+            .set(messages::deleted_at.eq(Some(chrono::Utc::now().naive_utc())))
+            .execute(&mut connection)
+            .unwrap();
+
+        let counts = reply_counts(&mut connection, MessageKind::Room, &[root, other_root]).unwrap();
+        assert_eq!(counts, HashMap::from([(root, 2), (other_root, 1)]));
+
+        let thread = room_page(&mut connection, room_id, Some(root), None, 10).unwrap();
+        assert_eq!(room_ids(&thread), vec![first, last]);
+        // The main history keeps everything
+        let history = room_page(&mut connection, room_id, None, None, 10).unwrap();
+        assert_eq!(history.len(), 8);
+    }
+}
@@ -0,0 +1,153 @@
+use rocket::tokio::sync::mpsc::UnboundedSender;
+use std::collections::HashMap;
+
+// Identifies one websocket connection, unique for the life of the server
+pub type ConnectionId = u64;
+
+// The open connections of every user. A user may be connected from several
+// devices at once, each with its own sender
+pub struct Hub<T> {
+    next_id: ConnectionId,
+    connections: HashMap<i32, HashMap<ConnectionId, UnboundedSender<T>>>,
+}
+
+impl<T: Clone> Hub<T> {
+    pub fn new() -> Hub<T> {
+        Hub {
+            next_id: 0,
+            connections: HashMap::new(),
+        }
+    }
+
+    // Register a new connection of the user, returning its id
+    pub fn connect(&mut self, user_id: i32, sender: UnboundedSender<T>) -> ConnectionId {
+        self.next_id += 1;
+        self.connections
+            .entry(user_id)
+            .or_default()
+            .insert(self.next_id, sender);
+        self.next_id
+    }
+
+    // Forget a closed connection, leaving the other devices of the user
+    // alone. Returns whether the user is still connected
+    pub fn disconnect(&mut self, user_id: i32, connection: ConnectionId) -> bool {
+        let Some(devices) = self.connections.get_mut(&user_id) else {
+            return false;
+        };
+        devices.remove(&connection);
+        if devices.is_empty() {
+            self.connections.remove(&user_id);
+            return false;
+        }
+        true
+    }
+
+    // Forget every connection of the user, returning their senders so they
+    // can be told why
+    pub fn remove_user(&mut self, user_id: i32) -> Vec<UnboundedSender<T>> {
+        self.connections
+            .remove(&user_id)
+            .map(|devices| devices.into_values().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn is_connected(&self, user_id: i32) -> bool {
+        self.connections.contains_key(&user_id)
+    }
+
+    // Send to every device of the user but `skip`. Devices whose connection
+    // is closing are left to be removed by their own task
+    pub fn send(&self, user_id: i32, message: &T, skip: Option<ConnectionId>) -> usize {
+        let Some(devices) = self.connections.get(&user_id) else {
+            return 0;
+        };
+        devices
+            .iter()
+            .filter(|(id, _)| Some(**id) != skip)
+            .filter(|(_, sender)| sender.send(message.clone()).is_ok())
+            .count()
+    }
+
+    // Send to every device of every user, e.g. to close them all on shutdown
+    pub fn send_all(&self, message: &T) -> usize {
+        self.connections
+            .values()
+            .flat_map(|devices| devices.values())
+            .filter(|sender| sender.send(message.clone()).is_ok())
+            .count()
+    }
+}
+
+impl<T: Clone> Default for Hub<T> {
+    fn default() -> Self {
+        Hub::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::tokio::sync::mpsc::unbounded_channel;
+
+    #[test]
+    fn every_device_of_a_user_receives_but_the_skipped_one() {
+        let mut hub = Hub::new();
+        let (phone, mut phone_rx) = unbounded_channel();
+        let (laptop, mut laptop_rx) = unbounded_channel();
+        let phone_id = hub.connect(1, phone);
+        let laptop_id = hub.connect(1, laptop);
+        assert_ne!(phone_id, laptop_id);
+
+        assert_eq!(hub.send(1, &"hi", None), 2);
+        assert_eq!(phone_rx.try_recv(), Ok("hi"));
+        assert_eq!(laptop_rx.try_recv(), Ok("hi"));
+
+        assert_eq!(hub.send(1, &"from phone", Some(phone_id)), 1);
+        assert!(phone_rx.try_recv().is_err());
+        assert_eq!(laptop_rx.try_recv(), Ok("from phone"));
+
+        assert_eq!(hub.send(2, &"nobody", None), 0);
+    }
+
+    #[test]
+    fn users_stay_connected_until_their_last_device_leaves() {
+        let mut hub = Hub::new();
+        let (phone, _phone_rx) = unbounded_channel::<&str>();
+        let (laptop, _laptop_rx) = unbounded_channel();
+        let phone_id = hub.connect(1, phone);
+        let laptop_id = hub.connect(1, laptop);
+
+        assert!(hub.disconnect(1, phone_id));
+        assert!(hub.is_connected(1));
+        assert!(!hub.disconnect(1, laptop_id));
+        assert!(!hub.is_connected(1));
+        assert!(!hub.disconnect(1, laptop_id));
+    }
+
+    #[test]
+    fn removing_a_user_returns_every_device() {
+        let mut hub = Hub::new();
+        let (phone, _phone_rx) = unbounded_channel::<&str>();
+        let (laptop, _laptop_rx) = unbounded_channel();
+        let (other, mut other_rx) = unbounded_channel();
+        hub.connect(1, phone);
+        hub.connect(1, laptop);
+        hub.connect(2, other);
+
+        assert_eq!(hub.remove_user(1).len(), 2);
+        assert!(!hub.is_connected(1));
+        assert_eq!(hub.send_all(&"bye"), 1);
+        assert_eq!(other_rx.try_recv(), Ok("bye"));
+    }
+
+    #[test]
+    fn closed_devices_are_not_counted() {
+        let mut hub = Hub::new();
+        let (phone, phone_rx) = unbounded_channel();
+        hub.connect(1, phone);
+        drop(phone_rx);
+        assert_eq!(hub.send(1, &"lost", None), 0);
+        assert!(hub.is_connected(1));
+    }
+}
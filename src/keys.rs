@@ -0,0 +1,256 @@
+use crate::config::ConfigError;
+use rocket::serde::Deserialize;
+use rsa::pkcs1::EncodeRsaPublicKey;
+use rsa::pkcs8::{DecodePrivateKey, EncodePrivateKey, LineEnding};
+use rsa::{RsaPrivateKey, RsaPublicKey};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+// RSA settings, the `rsa` table of the configuration
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct KeyConfig {
+    pub key_path: PathBuf,
+    pub key_bits: usize,
+    // Accept PKCS#1 v1.5 payloads from clients predating OAEP envelopes
+    pub allow_pkcs1v15: bool,
+}
+
+impl Default for KeyConfig {
+    fn default() -> Self {
+        KeyConfig {
+            key_path: PathBuf::from("keys/server_key.pem"),
+            key_bits: 4096,
+            allow_pkcs1v15: false,
+        }
+    }
+}
+
+// Smallest key size still considered safe
+const MIN_KEY_BITS: usize = 2048;
+
+impl KeyConfig {
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.key_bits < MIN_KEY_BITS {
+            return Err(ConfigError::Invalid("rsa.key_bits", "at least 2048"));
+        }
+        Ok(())
+    }
+
+    // The key replaced by the last rotation lives next to the current one
+    pub fn previous_key_path(&self) -> PathBuf {
+        let stem = self
+            .key_path
+            .file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        self.key_path
+            .with_file_name(format!("{}.previous.pem", stem))
+    }
+}
+
+#[derive(Debug)]
+pub enum KeyError {
+    Io(std::io::Error),
+    Pem(String),
+    Generate(rsa::Error),
+}
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyError::Io(err) => write!(f, "rsa key file error: {}", err),
+            KeyError::Pem(msg) => write!(f, "invalid rsa key file: {}", msg),
+            KeyError::Generate(err) => write!(f, "failed to generate rsa key: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for KeyError {}
+
+#[derive(Clone)]
+pub struct ServerKey {
+    pub kid: String,
+    pub pub_key: RsaPublicKey,
+    pub priv_key: RsaPrivateKey,
+}
+
+impl ServerKey {
+    fn new(priv_key: RsaPrivateKey) -> ServerKey {
+        let pub_key = RsaPublicKey::from(&priv_key);
+        ServerKey {
+            kid: key_id(&pub_key),
+            pub_key,
+            priv_key,
+        }
+    }
+
+    fn generate(bits: usize) -> Result<ServerKey, KeyError> {
+        let mut rng = rand::thread_rng();
+        let priv_key = RsaPrivateKey::new(&mut rng, bits).map_err(KeyError::Generate)?;
+        Ok(ServerKey::new(priv_key))
+    }
+
+    fn load(path: &Path) -> Result<ServerKey, KeyError> {
+        let pem = fs::read_to_string(path).map_err(KeyError::Io)?;
+        let priv_key =
+            RsaPrivateKey::from_pkcs8_pem(&pem).map_err(|e| KeyError::Pem(e.to_string()))?;
+        Ok(ServerKey::new(priv_key))
+    }
+
+    fn save(&self, path: &Path) -> Result<(), KeyError> {
+        let pem = self
+            .priv_key
+            .to_pkcs8_pem(LineEnding::LF)
+            .map_err(|e| KeyError::Pem(e.to_string()))?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(KeyError::Io)?;
+        }
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options.open(path).map_err(KeyError::Io)?;
+        file.write_all(pem.as_bytes()).map_err(KeyError::Io)
+    }
+
+    pub fn public_pem(&self) -> String {
+        self.pub_key
+            .to_pkcs1_pem(LineEnding::default())
+            .unwrap_or_default()
+    }
+}
+
+// Key ID: first 16 hex chars of the SHA-256 of the public key, so every
+// instance sharing the key file reports the same ID
+fn key_id(pub_key: &RsaPublicKey) -> String {
+    let der = pub_key
+        .to_pkcs1_der()
+        .map(|d| d.as_bytes().to_vec())
+        .unwrap_or_default();
+    Sha256::digest(der)
+        .iter()
+        .take(8)
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+// The server key pair plus the one it replaced, so payloads encrypted by
+// clients that fetched the key before a rotation still decrypt
+#[derive(Clone)]
+pub struct KeyRing {
+    pub current: ServerKey,
+    pub previous: Option<ServerKey>,
+}
+
+impl KeyRing {
+    // Load the keys from disk, generating and saving a new pair on first run
+    pub fn load_or_generate(config: &KeyConfig) -> Result<KeyRing, KeyError> {
+        let current = if config.key_path.exists() {
+            ServerKey::load(&config.key_path)?
+        } else {
+            let key = ServerKey::generate(config.key_bits)?;
+            key.save(&config.key_path)?;
+            key
+        };
+        let previous_path = config.previous_key_path();
+        let previous = if previous_path.exists() {
+            Some(ServerKey::load(&previous_path)?)
+        } else {
+            None
+        };
+        Ok(KeyRing { current, previous })
+    }
+
+    // Generate a new current key, keeping the old one as previous
+    pub fn rotate(config: &KeyConfig) -> Result<KeyRing, KeyError> {
+        let previous = if config.key_path.exists() {
+            let old = ServerKey::load(&config.key_path)?;
+            old.save(&config.previous_key_path())?;
+            Some(old)
+        } else {
+            None
+        };
+        let current = ServerKey::generate(config.key_bits)?;
+        current.save(&config.key_path)?;
+        Ok(KeyRing { current, previous })
+    }
+
+    // Find the key a payload was encrypted with. Untagged payloads come from
+    // clients that predate key IDs and always use the current key
+    pub fn find(&self, kid: Option<&str>) -> Option<&ServerKey> {
+        match kid {
+            None => Some(&self.current),
+            Some(kid) if kid == self.current.kid => Some(&self.current),
+            Some(kid) => self.previous.as_ref().filter(|k| k.kid == kid),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    // Small keys, they only need to be generated quickly
+    fn config(dir: &TempDir) -> KeyConfig {
+        KeyConfig {
+            key_path: dir.path().join("keys").join("server_key.pem"),
+            key_bits: 1024,
+            allow_pkcs1v15: false,
+        }
+    }
+
+    #[test]
+    fn generated_keys_are_saved_and_reloaded() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(&dir);
+        let generated = KeyRing::load_or_generate(&config).unwrap();
+        assert!(config.key_path.exists());
+        assert!(generated.previous.is_none());
+
+        let reloaded = KeyRing::load_or_generate(&config).unwrap();
+        assert_eq!(reloaded.current.kid, generated.current.kid);
+        assert_eq!(reloaded.current.priv_key, generated.current.priv_key);
+        assert!(reloaded.previous.is_none());
+    }
+
+    #[test]
+    fn rotation_keeps_the_previous_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(&dir);
+        let old = KeyRing::load_or_generate(&config).unwrap().current;
+
+        let rotated = KeyRing::rotate(&config).unwrap();
+        assert_ne!(rotated.current.kid, old.kid);
+        assert_eq!(rotated.find(Some(&old.kid)).unwrap().kid, old.kid);
+        assert_eq!(
+            rotated.find(Some(&rotated.current.kid)).unwrap().kid,
+            rotated.current.kid
+        );
+        // Untagged payloads use the current key, unknown ids none
+        assert_eq!(rotated.find(None).unwrap().kid, rotated.current.kid);
+        assert!(rotated.find(Some("0123456789abcdef")).is_none());
+
+        // Both survive a restart
+        let reloaded = KeyRing::load_or_generate(&config).unwrap();
+        assert_eq!(reloaded.current.kid, rotated.current.kid);
+        assert_eq!(reloaded.previous.unwrap().kid, old.kid);
+    }
+
+    #[test]
+    fn key_ids_come_from_the_public_key() {
+        let key = ServerKey::generate(1024).unwrap();
+        assert_eq!(key.kid.len(), 16);
+        assert!(key.kid.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_eq!(ServerKey::new(key.priv_key.clone()).kid, key.kid);
+        assert_eq!(key_id(&key.pub_key), key.kid);
+        assert_ne!(ServerKey::generate(1024).unwrap().kid, key.kid);
+    }
+}
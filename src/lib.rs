@@ -1,39 +1,25 @@
+pub mod attachments;
+pub mod auth;
+pub mod chats;
+pub mod config;
+pub mod crypto;
+pub mod db;
+pub mod delivery;
+pub mod email_tokens;
+pub mod history;
+pub mod hub;
+pub mod keys;
+pub mod mail;
+pub mod message_edits;
 pub mod models;
+pub mod outbox;
+pub mod password;
+pub mod password_reset;
+pub mod presence;
+pub mod protocol;
+pub mod reactions;
+pub mod read_markers;
 pub mod schema;
-use diesel::mysql::MysqlConnection;
-use diesel::prelude::*;
-use dotenv::dotenv;
-use std::env;
-use std::future::Future;
-
-pub fn establish_connection() -> MysqlConnection {
-    dotenv().ok();
-    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-    MysqlConnection::establish(&database_url)
-        .expect(&format!("Error connecting to {}", database_url))
-}
-
-pub async fn send_email(
-    recipient: &str,
-    subject: &str,
-    body: &str,
-) -> impl Future<Output = Result<String, reqwest::Error>> {
-    let mut map = std::collections::HashMap::new();
-    map.insert("to", recipient);
-    map.insert("subject", subject);
-    map.insert("content", body);
-    map.insert(
-        "auth",
-        "ziVGVjaIriAADFTMHhintrIqB9qrkyR75tuocO27SeL4XldiIIBUtHoGUK45A1jm",
-    );
-
-    let client = reqwest::Client::new();
-    let res = client
-        .post("https://bridi.altervista.org/mail_api.php")
-        .json(&map)
-        .send()
-        .await
-        .unwrap();
-
-    res.text()
-}
+pub mod session;
+pub mod typing;
+pub mod verification;
@@ -0,0 +1,323 @@
+use crate::config::{MailConfig, SmtpSecurity};
+use lettre::message::header::ContentType;
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use rand::Rng;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// A plain text email
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+impl Email {
+    pub fn new(to: &str, subject: &str, body: &str) -> Email {
+        Email {
+            to: to.to_string(),
+            subject: subject.to_string(),
+            body: body.to_string(),
+        }
+    }
+
+    // RFC 5322 message, as sent over SMTP or written by the file sink
+    fn to_message(&self, from: &Mailbox) -> Result<Message, MailError> {
+        let to: Mailbox = self
+            .to
+            .parse()
+            .map_err(|_| MailError::Address(self.to.clone()))?;
+        Message::builder()
+            .from(from.clone())
+            .to(to)
+            .subject(self.subject.as_str())
+            .header(ContentType::TEXT_PLAIN)
+            .body(self.body.clone())
+            .map_err(|e| MailError::Build(e.to_string()))
+    }
+}
+
+#[derive(Debug)]
+pub enum MailError {
+    Config(String),
+    Address(String),
+    Build(String),
+    Send(String),
+    Io(std::io::Error),
+}
+
+impl fmt::Display for MailError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MailError::Config(msg) => write!(f, "mail configuration error: {}", msg),
+            MailError::Address(addr) => write!(f, "invalid email address: {}", addr),
+            MailError::Build(msg) => write!(f, "unable to build email: {}", msg),
+            MailError::Send(msg) => write!(f, "unable to send email: {}", msg),
+            MailError::Io(err) => write!(f, "unable to write email: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for MailError {}
+
+// Something able to deliver an `Email`
+#[rocket::async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, email: &Email) -> Result<(), MailError>;
+}
+
+pub type SharedMailer = Arc<dyn Mailer>;
+
+// Build the transport selected by `mail.transport`
+pub fn from_config(config: &MailConfig) -> Result<SharedMailer, MailError> {
+    Ok(match config {
+        MailConfig::Smtp {
+            host,
+            port,
+            security,
+            username,
+            password,
+            from,
+            ..
+        } => Arc::new(SmtpMailer::new(
+            host,
+            *port,
+            *security,
+            username.as_deref().map(|u| (u, password.as_str())),
+            from,
+        )?),
+        MailConfig::Http { url, token, .. } => Arc::new(HttpMailer::new(url, token)),
+        MailConfig::File { dir, from } => Arc::new(FileMailer::new(dir.clone(), from)?),
+        MailConfig::Stdout => Arc::new(StdoutMailer),
+    })
+}
+
+fn parse_from(from: &str) -> Result<Mailbox, MailError> {
+    from.parse()
+        .map_err(|_| MailError::Config(format!("invalid sender address: {}", from)))
+}
+
+pub struct SmtpMailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+impl SmtpMailer {
+    pub fn new(
+        host: &str,
+        port: Option<u16>,
+        security: SmtpSecurity,
+        credentials: Option<(&str, &str)>,
+        from: &str,
+    ) -> Result<SmtpMailer, MailError> {
+        let mut builder = match security {
+            SmtpSecurity::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(host),
+            SmtpSecurity::StartTls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host),
+            SmtpSecurity::None => Ok(AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(
+                host,
+            )),
+        }
+        .map_err(|e| MailError::Config(e.to_string()))?;
+        if let Some(port) = port {
+            builder = builder.port(port);
+        }
+        if let Some((username, password)) = credentials {
+            builder =
+                builder.credentials(Credentials::new(username.to_string(), password.to_string()));
+        }
+        Ok(SmtpMailer {
+            transport: builder.build(),
+            from: parse_from(from)?,
+        })
+    }
+}
+
+#[rocket::async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, email: &Email) -> Result<(), MailError> {
+        let message = email.to_message(&self.from)?;
+        self.transport
+            .send(message)
+            .await
+            .map(|_| ())
+            .map_err(|e| MailError::Send(e.to_string()))
+    }
+}
+
+// The original mail API: a JSON POST authenticated by a token
+pub struct HttpMailer {
+    client: reqwest::Client,
+    url: String,
+    token: String,
+}
+
+impl HttpMailer {
+    pub fn new(url: &str, token: &str) -> HttpMailer {
+        HttpMailer {
+            client: reqwest::Client::new(),
+            url: url.to_string(),
+            token: token.to_string(),
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl Mailer for HttpMailer {
+    async fn send(&self, email: &Email) -> Result<(), MailError> {
+        let mut map = std::collections::HashMap::new();
+        map.insert("to", email.to.as_str());
+        map.insert("subject", email.subject.as_str());
+        map.insert("content", email.body.as_str());
+        map.insert("auth", self.token.as_str());
+
+        self.client
+            .post(&self.url)
+            .json(&map)
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .map(|_| ())
+            .map_err(|e| MailError::Send(e.to_string()))
+    }
+}
+
+// Development sink writing every email to `<dir>/<timestamp>-<random>.eml`
+pub struct FileMailer {
+    dir: PathBuf,
+    from: Mailbox,
+}
+
+impl FileMailer {
+    pub fn new(dir: PathBuf, from: &str) -> Result<FileMailer, MailError> {
+        std::fs::create_dir_all(&dir).map_err(MailError::Io)?;
+        Ok(FileMailer {
+            dir,
+            from: parse_from(from)?,
+        })
+    }
+}
+
+#[rocket::async_trait]
+impl Mailer for FileMailer {
+    async fn send(&self, email: &Email) -> Result<(), MailError> {
+        let message = email.to_message(&self.from)?;
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or_default();
+        let suffix: u32 = rand::thread_rng().gen();
+        let path = self.dir.join(format!("{}-{:08x}.eml", millis, suffix));
+        rocket::tokio::fs::write(path, message.formatted())
+            .await
+            .map_err(MailError::Io)
+    }
+}
+
+// Development sink printing every email to stdout
+pub struct StdoutMailer;
+
+#[rocket::async_trait]
+impl Mailer for StdoutMailer {
+    async fn send(&self, email: &Email) -> Result<(), MailError> {
+        println!(
+            "----- email to {} -----\nSubject: {}\n\n{}\n-----",
+            email.to, email.subject, email.body
+        );
+        Ok(())
+    }
+}
+
+// Keeps every email in memory, so tests can assert on them
+#[derive(Clone, Default)]
+pub struct MemoryMailer {
+    sent: Arc<Mutex<Vec<Email>>>,
+}
+
+impl MemoryMailer {
+    pub fn new() -> MemoryMailer {
+        MemoryMailer::default()
+    }
+
+    pub fn sent(&self) -> Vec<Email> {
+        self.sent.lock().map(|s| s.clone()).unwrap_or_default()
+    }
+
+    pub fn last(&self) -> Option<Email> {
+        self.sent.lock().ok().and_then(|s| s.last().cloned())
+    }
+
+    pub fn clear(&self) {
+        if let Ok(mut sent) = self.sent.lock() {
+            sent.clear();
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl Mailer for MemoryMailer {
+    async fn send(&self, email: &Email) -> Result<(), MailError> {
+        self.sent
+            .lock()
+            .map_err(|_| MailError::Send("mailbox lock poisoned".to_string()))?
+            .push(email.clone());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[rocket::async_test]
+    async fn memory_mailer_keeps_what_was_sent() {
+        let memory = MemoryMailer::new();
+        let mailer: SharedMailer = Arc::new(memory.clone());
+        let first = Email::new("a@example.com", "First", "one");
+        let second = Email::new("b@example.com", "Second", "two");
+        mailer.send(&first).await.unwrap();
+        mailer.send(&second).await.unwrap();
+        assert_eq!(memory.sent(), vec![first, second.clone()]);
+        assert_eq!(memory.last(), Some(second));
+        memory.clear();
+        assert!(memory.sent().is_empty());
+        assert_eq!(memory.last(), None);
+    }
+
+    #[test]
+    fn messages_need_valid_addresses() {
+        let from = parse_from("Rocket Chat <noreply@localhost>").unwrap();
+        assert!(Email::new("a@example.com", "Hi", "body")
+            .to_message(&from)
+            .is_ok());
+        assert!(matches!(
+            Email::new("not an address", "Hi", "body").to_message(&from),
+            Err(MailError::Address(_))
+        ));
+        assert!(matches!(parse_from("nobody"), Err(MailError::Config(_))));
+    }
+
+    #[rocket::async_test]
+    async fn file_mailer_writes_one_eml_per_email() {
+        let dir = std::env::temp_dir().join(format!("rocket-chat-mail-{}", rand::random::<u64>()));
+        let mailer = FileMailer::new(dir.clone(), "Rocket Chat <noreply@localhost>").unwrap();
+        mailer
+            .send(&Email::new(
+                "a@example.com",
+                "Link",
+                "https://example.com/x",
+            ))
+            .await
+            .unwrap();
+        let files: Vec<_> = std::fs::read_dir(&dir).unwrap().collect();
+        assert_eq!(files.len(), 1);
+        let written = std::fs::read_to_string(files[0].as_ref().unwrap().path()).unwrap();
+        assert!(written.contains("Subject: Link"));
+        assert!(written.contains("https://example.com/x"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -2,16 +2,22 @@
 extern crate rocket;
 
 use base64::prelude::*;
+use chrono::{NaiveDateTime, SecondsFormat};
+use diesel::mysql::MysqlConnection;
 use diesel::prelude::*;
 use rand::Rng;
+use rocket::http::private::cookie::CookieBuilder;
 use rocket::serde::json::serde_json;
 use rocket::tokio;
-use rocket::tokio::sync::mpsc::{self, unbounded_channel, UnboundedReceiver, UnboundedSender};
+use rocket::tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
 use rocket::tokio::sync::RwLock;
 use rocket::{
+    data::{Data, ToByteUnit},
+    fairing::AdHoc,
+    figment::Figment,
     form::{self, Form},
     fs::{relative, FileServer, NamedFile},
-    http::Status,
+    http::{ContentType, Status},
     response::{
         status,
         stream::{Event, EventStream},
@@ -22,46 +28,60 @@ use rocket::{
         select,
         sync::broadcast::{channel, error::RecvError, Sender},
     },
-    Shutdown, State,
+    Build, Rocket, Shutdown, State,
 };
+use rocket_chat::attachments::{self, BlobStore, MessageRef};
+use rocket_chat::auth::AuthUser;
+use rocket_chat::chats::{self, MessageKind, NotPostable, Recipient, Scope};
+use rocket_chat::config::ChatConfig;
+use rocket_chat::crypto::{self, RsaError};
+use rocket_chat::db::{last_insert_id, DbPool};
+use rocket_chat::delivery::{self, Delivered, Missed};
+use rocket_chat::email_tokens::Redeemed;
+use rocket_chat::history;
+use rocket_chat::hub::{ConnectionId, Hub};
+use rocket_chat::keys::KeyRing;
+use rocket_chat::mail;
+use rocket_chat::message_edits::{self, Denied};
 use rocket_chat::models::*;
-use rocket_session_store::{memory::MemoryStore, Session, SessionStore};
-use rsa::{
-    pkcs1::EncodeRsaPublicKey,
-    pkcs8::{DecodePublicKey, LineEnding},
-    Pkcs1v15Encrypt, RsaPrivateKey, RsaPublicKey,
-};
-use sha2::{Digest, Sha512};
+use rocket_chat::outbox;
+use rocket_chat::password::{self, Verification};
+use rocket_chat::password_reset::{self, ResetOutcome};
+use rocket_chat::presence;
+use rocket_chat::protocol::{self, Envelope, Frame, Request, Unreadable};
+use rocket_chat::reactions;
+use rocket_chat::read_markers;
+use rocket_chat::session::{self as db_session, DbStore};
+use rocket_chat::typing::Typing;
+use rocket_chat::verification::{self, VerifyOutcome};
+use rocket_session_store::{Session, SessionStore};
 use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
-use std::{error::Error, path::PathBuf, sync::Mutex, time::Duration};
+use std::{
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+use ws::frame::{CloseCode, CloseFrame};
 use ws::Message;
-use rocket::http::private::cookie::CookieBuilder;
 
-const PEPPER: &str = "Zk4pGkvF9n5FPXSvrccl0XR33ach0+Vf/rliGZUUc+U=";
+const SESSION_PURGE_INTERVAL: Duration = Duration::from_secs(60 * 10);
+const UPLOAD_PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
 
-struct KeyPair {
-    pub pub_key: RsaPublicKey,
-    pub priv_key: RsaPrivateKey,
-}
-
-impl Clone for KeyPair {
-    fn clone(&self) -> Self {
-        KeyPair {
-            pub_key: self.pub_key.clone(),
-            priv_key: self.priv_key.clone(),
-        }
-    }
+struct AppState {
+    keys: KeyRing,
+    allow_pkcs1v15: bool,
 }
 
-struct AppState {
-    keys: Mutex<Option<KeyPair>>,
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(crate = "rocket::serde")]
+struct ServerPubKey {
+    kid: String,
+    key: String,
 }
 
 #[derive(Debug, Clone, FromForm, Serialize, Deserialize, PartialEq)]
 #[serde(crate = "rocket::serde")]
 struct GetPersonalChats {
-    user_id: i32,
     rsa_key: String,
 }
 
@@ -97,6 +117,28 @@ struct SignupUser {
     password: String,
 }
 
+#[derive(Debug, Clone, FromForm, Serialize, Deserialize, PartialEq)]
+#[serde(crate = "rocket::serde")]
+struct ResendVerification {
+    #[field(validate = structval(1, 100).or_else(msg!("email must be between 1 and 100 chars")))]
+    email: String,
+}
+
+#[derive(Debug, Clone, FromForm, Serialize, Deserialize, PartialEq)]
+#[serde(crate = "rocket::serde")]
+struct ForgotPassword {
+    #[field(validate = structval(1, 100).or_else(msg!("email must be between 1 and 100 chars")))]
+    email: String,
+}
+
+#[derive(Debug, Clone, FromForm, Serialize, Deserialize, PartialEq)]
+#[serde(crate = "rocket::serde")]
+struct ResetPassword {
+    token: String,
+    #[field(validate = len(8..))]
+    new_password: String,
+}
+
 #[derive(Debug, Clone, FromForm, Serialize, Deserialize, PartialEq)]
 #[serde(crate = "rocket::serde")]
 struct UserId {
@@ -106,20 +148,55 @@ struct UserId {
 #[derive(Debug, Clone, FromForm, Serialize, Deserialize, PartialEq)]
 #[serde(crate = "rocket::serde")]
 struct GroupMessage {
+    // Not part of the posted form
+    #[field(default = 0)]
+    id: i32,
     room_id: i32,
     user_id: i32,
     user_name: String,
     #[field(validate = len(1..))]
     message: String,
+    sent_at: Option<String>,
+    edited_at: Option<String>,
+    deleted_at: Option<String>,
+    attachments: Vec<AttachmentRef>,
+    reactions: Vec<ReactionCount>,
+    // The message this one answers, and the first message of its thread
+    reply_to_id: Option<i32>,
+    thread_id: Option<i32>,
+    // Number of replies in the thread, for its first message
+    #[field(default = 0)]
+    reply_count: i64,
 }
 
 impl GroupMessage {
-    fn new(room_id: i32, user_id: i32, user_name: String, message: String) -> GroupMessage {
+    // Deleted messages keep their place in the history, without their files
+    fn from_db(
+        m: MessageDB,
+        user_name: String,
+        files: Option<Vec<AttachmentDB>>,
+        reactions: Option<Vec<MessageReactionDB>>,
+        reply_count: i64,
+    ) -> GroupMessage {
+        let deleted = m.deleted_at.is_some();
         GroupMessage {
-            room_id: room_id,
-            user_id: user_id,
-            user_name: user_name,
-            message: message,
+            id: m.message_id,
+            room_id: m.room_id,
+            user_id: m.user_id,
+            user_name,
+            message: m.content,
+            sent_at: rfc3339(m.message_time),
+            edited_at: rfc3339(m.edited_at),
+            deleted_at: rfc3339(m.deleted_at),
+            attachments: if deleted {
+                Vec::new()
+            } else {
+                AttachmentRef::list(files)
+            },
+            reactions: ReactionCount::list(reactions),
+            reply_to_id: m.reply_to_id,
+            thread_id: m.thread_id,
+            reply_count,
         }
     }
 }
@@ -127,25 +204,12 @@ impl GroupMessage {
 #[derive(Debug, Clone, FromForm, Serialize, Deserialize, PartialEq)]
 #[serde(crate = "rocket::serde")]
 struct ChangePassword {
-    user_id: i32,
     #[field(validate = len(8..))]
     old_password: String,
     #[field(validate = len(8..))]
     new_password: String,
 }
 
-#[derive(Debug, Clone, FromForm, Serialize, Deserialize, PartialEq)]
-#[serde(crate = "rocket::serde")]
-struct Room {
-    room_id: i32,
-    room_name: String,
-    password: Option<String>,
-    require_password: bool,
-    hidden: bool,
-    user_id: i32,
-    rsa_client_key: String,
-}
-
 #[derive(Debug, Clone, FromForm, Serialize, Deserialize, PartialEq)]
 #[serde(crate = "rocket::serde")]
 struct AddRoom {
@@ -153,7 +217,6 @@ struct AddRoom {
     password: Option<String>,
     require_password: bool,
     hidden: bool,
-    user_id: i32,
     rsa_client_key: String,
 }
 
@@ -161,7 +224,6 @@ struct AddRoom {
 #[serde(crate = "rocket::serde")]
 struct ToRemoveRoom {
     room_id: i32,
-    user_id: i32,
 }
 
 #[derive(Debug, Clone, FromForm, Serialize, Deserialize, PartialEq)]
@@ -176,9 +238,9 @@ struct SearchRoom {
 impl SearchRoom {
     fn new(room_id: i32, room_name: String, require_password: bool) -> SearchRoom {
         SearchRoom {
-            room_id: room_id,
-            room_name: room_name,
-            require_password: require_password,
+            room_id,
+            room_name,
+            require_password,
         }
     }
 }
@@ -190,15 +252,21 @@ struct PubRoom {
     room_name: String,
     key: String,
     messages: Vec<GroupMessage>,
+    // The last message the user has read, and how many came after it
+    last_read_id: Option<i32>,
+    #[field(default = 0)]
+    unread: i64,
 }
 
 impl PubRoom {
     fn new(room_id: i32, room_name: String, key: String, messages: Vec<GroupMessage>) -> PubRoom {
         PubRoom {
-            room_id: room_id,
-            room_name: room_name,
-            key: key,
-            messages: messages,
+            room_id,
+            room_name,
+            key,
+            messages,
+            last_read_id: None,
+            unread: 0,
         }
     }
 }
@@ -210,15 +278,23 @@ struct Direct {
     user_name: String,
     key: String,
     messages: Vec<DirectMessage>,
+    last_read_id: Option<i32>,
+    #[field(default = 0)]
+    unread: i64,
+    // The last message the other user has read
+    peer_last_read_id: Option<i32>,
 }
 
 impl Direct {
     fn new(user_id: i32, user_name: String, key: String, messages: Vec<DirectMessage>) -> Direct {
         Direct {
-            user_id: user_id,
-            user_name: user_name,
-            key: key,
-            messages: messages,
+            user_id,
+            user_name,
+            key,
+            messages,
+            last_read_id: None,
+            unread: 0,
+            peer_last_read_id: None,
         }
     }
 }
@@ -226,15 +302,142 @@ impl Direct {
 #[derive(Debug, Clone, FromForm, Serialize, Deserialize, PartialEq)]
 #[serde(crate = "rocket::serde")]
 struct DirectMessage {
+    id: i32,
     user_id: i32,
     message: String,
+    sent_at: Option<String>,
+    edited_at: Option<String>,
+    deleted_at: Option<String>,
+    attachments: Vec<AttachmentRef>,
+    reactions: Vec<ReactionCount>,
+    // The message this one answers, and the first message of its thread
+    reply_to_id: Option<i32>,
+    thread_id: Option<i32>,
+    // Number of replies in the thread, for its first message
+    #[field(default = 0)]
+    reply_count: i64,
 }
 
 impl DirectMessage {
-    fn new(user_id: i32, message: String) -> DirectMessage {
+    fn from_db(
+        m: DirectMessageDB,
+        files: Option<Vec<AttachmentDB>>,
+        reactions: Option<Vec<MessageReactionDB>>,
+        reply_count: i64,
+    ) -> DirectMessage {
+        let deleted = m.deleted_at.is_some();
         DirectMessage {
-            user_id: user_id,
-            message: message,
+            id: m.id,
+            user_id: m.sender_id,
+            message: m.message,
+            sent_at: rfc3339(m.message_time),
+            edited_at: rfc3339(m.edited_at),
+            deleted_at: rfc3339(m.deleted_at),
+            attachments: if deleted {
+                Vec::new()
+            } else {
+                AttachmentRef::list(files)
+            },
+            reactions: ReactionCount::list(reactions),
+            reply_to_id: m.reply_to_id,
+            thread_id: m.thread_id,
+            reply_count,
+        }
+    }
+}
+
+// Message times as sent to clients, RFC 3339 in UTC
+fn rfc3339(time: Option<NaiveDateTime>) -> Option<String> {
+    time.map(|t| t.and_utc().to_rfc3339_opts(SecondsFormat::Secs, true))
+}
+
+// An attachment as listed in the history, downloaded from
+// `/attachments/<id>/content`
+#[derive(Debug, Clone, FromForm, Serialize, Deserialize, PartialEq)]
+#[serde(crate = "rocket::serde")]
+struct AttachmentRef {
+    id: String,
+    mime_type: String,
+    size: i64,
+}
+
+impl AttachmentRef {
+    fn list(found: Option<Vec<AttachmentDB>>) -> Vec<AttachmentRef> {
+        found
+            .unwrap_or_default()
+            .into_iter()
+            .map(|a| AttachmentRef {
+                id: a.upload_id,
+                mime_type: a.mime_type,
+                size: a.size,
+            })
+            .collect()
+    }
+}
+
+// The users who reacted to a message with the same emoji
+#[derive(Debug, Clone, FromForm, Serialize, Deserialize, PartialEq)]
+#[serde(crate = "rocket::serde")]
+struct ReactionCount {
+    emoji: String,
+    count: usize,
+    user_ids: Vec<i32>,
+}
+
+impl ReactionCount {
+    // Group reactions by emoji, in the order each emoji was first used
+    fn list(found: Option<Vec<MessageReactionDB>>) -> Vec<ReactionCount> {
+        let mut counts: Vec<ReactionCount> = Vec::new();
+        for reaction in found.unwrap_or_default() {
+            match counts.iter_mut().find(|c| c.emoji == reaction.emoji) {
+                Some(count) => {
+                    count.count += 1;
+                    count.user_ids.push(reaction.user_id);
+                }
+                None => counts.push(ReactionCount {
+                    emoji: reaction.emoji,
+                    count: 1,
+                    user_ids: vec![reaction.user_id],
+                }),
+            }
+        }
+        counts
+    }
+}
+
+// Start an upload in a room (`room_id`) or in the direct chat with `recipient_id`
+#[derive(Debug, Clone, FromForm, Serialize, Deserialize, PartialEq)]
+#[serde(crate = "rocket::serde")]
+struct NewAttachment {
+    room_id: Option<i32>,
+    recipient_id: Option<i32>,
+    #[field(validate = structval(1, 127).or_else(msg!("mime type must be between 1 and 127 chars")))]
+    mime_type: String,
+    #[field(validate = range(1..))]
+    size: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(crate = "rocket::serde")]
+struct AttachmentStatus {
+    id: String,
+    mime_type: String,
+    size: i64,
+    // Bytes stored so far, where the next chunk starts
+    received: i64,
+    complete: bool,
+    chunk_size: u64,
+}
+
+impl AttachmentStatus {
+    fn new(attachment: &AttachmentDB, chunk_size: u64) -> AttachmentStatus {
+        AttachmentStatus {
+            id: attachment.upload_id.clone(),
+            mime_type: attachment.mime_type.clone(),
+            size: attachment.size,
+            received: attachment.received,
+            complete: attachment.completed_at.is_some(),
+            chunk_size,
         }
     }
 }
@@ -242,7 +445,6 @@ impl DirectMessage {
 #[derive(Debug, Clone, FromForm, Serialize, Deserialize, PartialEq)]
 #[serde(crate = "rocket::serde")]
 struct AddDirect {
-    user_id: i32,
     username: String,
     rsa_key: String,
 }
@@ -257,11 +459,10 @@ struct DirectToAdd {
 #[derive(Debug, Clone, FromForm, Serialize, Deserialize, PartialEq)]
 #[serde(crate = "rocket::serde")]
 struct DeleteDirect {
-    user_id: i32,
     recipient_id: i32,
 }
 
-fn structval<'v>(val: &String, min: usize, max: usize) -> form::Result<'v, ()> {
+fn structval<'v>(val: &str, min: usize, max: usize) -> form::Result<'v, ()> {
     let trimmed = val.trim();
     if trimmed.len() < min || trimmed.len() > max {
         Err(rocket::form::Error::validation("invalid string"))?;
@@ -269,56 +470,611 @@ fn structval<'v>(val: &String, min: usize, max: usize) -> form::Result<'v, ()> {
     Ok(())
 }
 
-type Users = Arc<RwLock<HashMap<i32, mpsc::UnboundedSender<Message>>>>;
+// Run a blocking Diesel closure on the pool, mapping pool failures to a 500
+async fn run_db<F, R>(db: &DbPool, f: F) -> Result<R, status::Custom<&'static str>>
+where
+    F: FnOnce(&mut MysqlConnection) -> Result<R, status::Custom<&'static str>> + Send + 'static,
+    R: Send + 'static,
+{
+    match db.run(f).await {
+        Ok(result) => result,
+        Err(err) => {
+            eprintln!("{}", err);
+            Err(status::Custom(
+                Status::InternalServerError,
+                "Database error",
+            ))
+        }
+    }
+}
+
+// Run Argon2 on the blocking thread pool without holding a database
+// connection, so slow hashes can't starve the pool
+async fn run_hashing<F, R>(f: F) -> Result<R, status::Custom<&'static str>>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    tokio::task::spawn_blocking(f).await.map_err(|err| {
+        eprintln!("Password hashing task failed: {}", err);
+        status::Custom(Status::InternalServerError, "Password hashing error")
+    })
+}
+
+// Open websocket connections, every device of every user, see `Hub`
+type Users = Arc<RwLock<Hub<Message>>>;
 type Groups = Arc<RwLock<HashMap<i32, HashSet<i32>>>>;
 
-#[derive(Serialize, Deserialize)]
+// Messages exchanged over the websocket. `id` and `sent_at` are set by the
+// server once the message is saved, and the sender from the session; whatever
+// the client puts there is ignored.
+// Edits, deletions and reactions name the message by `id`, the server fills
+// in who made them and when
+#[derive(Clone, Serialize, Deserialize)]
 #[serde(crate = "rocket::serde")]
 enum ChatMessage {
     Direct {
+        #[serde(default)]
+        id: Option<i32>,
+        #[serde(default)]
         sender: i32,
         recipient: i32,
         content: String,
+        // Ids of completed uploads sent with the message
+        #[serde(default)]
+        attachments: Vec<String>,
+        // The message answered. The server fills in the first message of
+        // its thread
+        #[serde(default)]
+        reply_to_id: Option<i32>,
+        #[serde(default)]
+        thread_id: Option<i32>,
+        #[serde(default)]
+        sent_at: Option<String>,
     },
     Group {
+        #[serde(default)]
+        id: Option<i32>,
+        #[serde(default)]
         sender_id: i32,
+        #[serde(default)]
         sender_name: String,
         group_id: i32,
         content: String,
+        #[serde(default)]
+        attachments: Vec<String>,
+        #[serde(default)]
+        reply_to_id: Option<i32>,
+        #[serde(default)]
+        thread_id: Option<i32>,
+        #[serde(default)]
+        sent_at: Option<String>,
+    },
+    DirectEdit {
+        id: i32,
+        #[serde(default)]
+        sender: i32,
+        recipient: i32,
+        content: String,
+        #[serde(default)]
+        edited_at: Option<String>,
+    },
+    DirectDelete {
+        id: i32,
+        #[serde(default)]
+        sender: i32,
+        recipient: i32,
+        #[serde(default)]
+        deleted_at: Option<String>,
+    },
+    GroupEdit {
+        id: i32,
+        group_id: i32,
+        content: String,
+        #[serde(default)]
+        edited_at: Option<String>,
+    },
+    GroupDelete {
+        id: i32,
+        group_id: i32,
+        #[serde(default)]
+        deleted_at: Option<String>,
+    },
+    DirectReactionAdd {
+        id: i32,
+        #[serde(default)]
+        sender: i32,
+        recipient: i32,
+        emoji: String,
+    },
+    DirectReactionRemove {
+        id: i32,
+        #[serde(default)]
+        sender: i32,
+        recipient: i32,
+        emoji: String,
+    },
+    GroupReactionAdd {
+        id: i32,
+        group_id: i32,
+        #[serde(default)]
+        user_id: i32,
+        emoji: String,
+    },
+    GroupReactionRemove {
+        id: i32,
+        group_id: i32,
+        #[serde(default)]
+        user_id: i32,
+        emoji: String,
     },
+    // The user has read up to message `id`. The server answers with the
+    // marker, which never moves back
+    DirectRead {
+        id: i32,
+        #[serde(default)]
+        sender: i32,
+        recipient: i32,
+    },
+    GroupRead {
+        id: i32,
+        group_id: i32,
+        #[serde(default)]
+        user_id: i32,
+    },
+    // The user started or stopped typing. Only passed on, never saved
+    DirectTyping {
+        #[serde(default)]
+        sender: i32,
+        recipient: i32,
+        typing: bool,
+    },
+    GroupTyping {
+        group_id: i32,
+        #[serde(default)]
+        user_id: i32,
+        #[serde(default)]
+        user_name: String,
+        typing: bool,
+    },
+    // The presence of a user. Clients send the status they pick, the server
+    // announces it when they connect and `offline` when they leave
+    Presence {
+        #[serde(default)]
+        user_id: i32,
+        status: presence::Status,
+        #[serde(default)]
+        last_seen_at: Option<String>,
+    },
+    // Sent by the server when it refuses a message, see `Refused`
+    Error {
+        code: String,
+        message: String,
+    },
+}
+
+// A chat someone types in: a room, or the direct chat with a user
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum TypingTarget {
+    Room(i32),
+    User(i32),
+}
+
+impl TypingTarget {
+    fn recipient(self) -> Recipient {
+        match self {
+            TypingTarget::Room(room_id) => Recipient::Room(room_id),
+            TypingTarget::User(peer) => Recipient::User(peer),
+        }
+    }
+
+    // The notification `user_id` sends to the chat
+    fn notification(self, user_id: i32, user_name: &str, typing: bool) -> ChatMessage {
+        match self {
+            TypingTarget::Room(group_id) => ChatMessage::GroupTyping {
+                group_id,
+                user_id,
+                user_name: user_name.to_string(),
+                typing,
+            },
+            TypingTarget::User(recipient) => ChatMessage::DirectTyping {
+                sender: user_id,
+                recipient,
+                typing,
+            },
+        }
+    }
+}
+
+impl ChatMessage {
+    // The chat a typing notification is about, and whether typing started
+    fn typing(&self) -> Option<(TypingTarget, bool)> {
+        match self {
+            ChatMessage::DirectTyping {
+                recipient, typing, ..
+            } => Some((TypingTarget::User(*recipient), *typing)),
+            ChatMessage::GroupTyping {
+                group_id, typing, ..
+            } => Some((TypingTarget::Room(*group_id), *typing)),
+            _ => None,
+        }
+    }
+
+    // The chat a new message is sent to
+    fn target(&self) -> Option<TypingTarget> {
+        match self {
+            ChatMessage::Direct { recipient, .. } => Some(TypingTarget::User(*recipient)),
+            ChatMessage::Group { group_id, .. } => Some(TypingTarget::Room(*group_id)),
+            _ => None,
+        }
+    }
+
+    // The chat of a saved new message as seen by `user_id`, and its id
+    fn delivered_to(&self, user_id: i32) -> Option<(TypingTarget, i32)> {
+        match self {
+            ChatMessage::Direct {
+                id: Some(id),
+                sender,
+                recipient,
+                ..
+            } => {
+                let peer = if *sender == user_id {
+                    *recipient
+                } else {
+                    *sender
+                };
+                Some((TypingTarget::User(peer), *id))
+            }
+            ChatMessage::Group {
+                id: Some(id),
+                group_id,
+                ..
+            } => Some((TypingTarget::Room(*group_id), *id)),
+            _ => None,
+        }
+    }
+
+    // Who sent a new message
+    fn sender(&self) -> Option<i32> {
+        match self {
+            ChatMessage::Direct { sender, .. } => Some(*sender),
+            ChatMessage::Group { sender_id, .. } => Some(*sender_id),
+            _ => None,
+        }
+    }
+
+    // Sign a new message with the user of the connection it came from
+    fn sent_by(&mut self, user_id: i32, user_name: &str) {
+        match self {
+            ChatMessage::Direct { sender, .. } => *sender = user_id,
+            ChatMessage::Group {
+                sender_id,
+                sender_name,
+                ..
+            } => {
+                *sender_id = user_id;
+                *sender_name = user_name.to_string();
+            }
+            _ => {}
+        }
+    }
+
+    // Stamp the message with the id, time and thread it was saved with
+    fn saved(&mut self, saved: &SavedMessage) {
+        if let ChatMessage::Direct {
+            id,
+            sent_at,
+            thread_id,
+            ..
+        }
+        | ChatMessage::Group {
+            id,
+            sent_at,
+            thread_id,
+            ..
+        } = self
+        {
+            *id = Some(saved.id);
+            *sent_at = rfc3339(saved.sent_at);
+            *thread_id = saved.thread_id;
+        }
+    }
+}
+
+// Deliver a serialized message to every device of everyone it concerns but
+// the `skip` connection: both users of a direct chat or the connected
+// members of a room. Typing notifications leave out their author
+async fn fan_out(
+    users: &Users,
+    groups: &Arc<RwLock<Groups>>,
+    message: &ChatMessage,
+    text: &str,
+    skip: Option<ConnectionId>,
+) {
+    let mut targets: Vec<i32> = match message {
+        ChatMessage::Direct {
+            sender, recipient, ..
+        }
+        | ChatMessage::DirectEdit {
+            sender, recipient, ..
+        }
+        | ChatMessage::DirectDelete {
+            sender, recipient, ..
+        }
+        | ChatMessage::DirectReactionAdd {
+            sender, recipient, ..
+        }
+        | ChatMessage::DirectReactionRemove {
+            sender, recipient, ..
+        }
+        | ChatMessage::DirectRead {
+            sender, recipient, ..
+        } => vec![*sender, *recipient],
+        ChatMessage::DirectTyping { recipient, .. } => vec![*recipient],
+        // Sent by `announce_presence`, which looks up who shares a chat, and
+        // to the connection at fault
+        ChatMessage::Presence { .. } | ChatMessage::Error { .. } => Vec::new(),
+        // Only the user's own devices care about where they read a room
+        ChatMessage::GroupRead { user_id, .. } => vec![*user_id],
+        ChatMessage::Group { group_id, .. }
+        | ChatMessage::GroupEdit { group_id, .. }
+        | ChatMessage::GroupDelete { group_id, .. }
+        | ChatMessage::GroupReactionAdd { group_id, .. }
+        | ChatMessage::GroupReactionRemove { group_id, .. }
+        | ChatMessage::GroupTyping { group_id, .. } => {
+            let groups_lock = groups.read().await;
+            let groups = groups_lock.read().await;
+            groups
+                .get(group_id)
+                .map(|members| members.iter().copied().collect())
+                .unwrap_or_default()
+        }
+    };
+    if let ChatMessage::GroupTyping { user_id, .. } = message {
+        targets.retain(|target| target != user_id);
+    }
+    let message = Message::text(text.to_string());
+    let users = users.read().await;
+    for target in targets {
+        users.send(target, &message, skip);
+    }
+}
+
+// Deliver a typing notification to the chat
+async fn send_typing(users: &Users, groups: &Arc<RwLock<Groups>>, notification: ChatMessage) {
+    if let Ok(text) = serde_json::to_string(&notification) {
+        fan_out(users, groups, &notification, &text, None).await;
+    }
+}
+
+// Deliver the presence of `user_id` to their connections and to the
+// connected users sharing a room or direct chat with them
+async fn announce_presence(db: &DbPool, users: &Users, user_id: i32, status: presence::Status) {
+    let last_seen_at = if status == presence::Status::Offline {
+        match db
+            .run(move |connection| presence::seen(connection, user_id))
+            .await
+        {
+            Ok(Ok(seen)) => Some(seen),
+            _ => None,
+        }
+    } else {
+        None
+    };
+    let contacts = match db
+        .run(move |connection| presence::contacts(connection, user_id))
+        .await
+    {
+        Ok(Ok(contacts)) => contacts,
+        _ => {
+            eprintln!("Failed to load the contacts of user {}", user_id);
+            return;
+        }
+    };
+    let notification = ChatMessage::Presence {
+        user_id,
+        status,
+        last_seen_at: rfc3339(last_seen_at),
+    };
+    let Ok(text) = serde_json::to_string(&notification) else {
+        return;
+    };
+    let message = Message::text(text);
+    let users = users.read().await;
+    for target in contacts.into_iter().chain(std::iter::once(user_id)) {
+        users.send(target, &message, None);
+    }
+}
+
+// Whether the user may tell `target` they are typing: connected members of
+// the room, or users they have a direct chat with
+async fn may_type(
+    db: &DbPool,
+    groups: &Arc<RwLock<Groups>>,
+    user_id: i32,
+    target: TypingTarget,
+) -> bool {
+    match target {
+        TypingTarget::Room(room_id) => {
+            let groups_lock = groups.read().await;
+            let groups = groups_lock.read().await;
+            groups
+                .get(&room_id)
+                .is_some_and(|members| members.contains(&user_id))
+        }
+        TypingTarget::User(peer) => matches!(
+            db.run(move |connection| chats::direct_between(connection, user_id, peer))
+                .await,
+            Ok(Ok(Some(_)))
+        ),
+    }
 }
 
-#[get("/messages/<user_id>")]
+// Apply an edit, deletion, reaction or read marker of `user_id`, returning
+// it stamped with its author and time. Room messages can only be changed by
+// members, admins excepted
+fn change_message(
+    connection: &mut MysqlConnection,
+    user_id: i32,
+    is_admin: bool,
+    mut change: ChatMessage,
+) -> QueryResult<Result<ChatMessage, Denied>> {
+    let scope = match &mut change {
+        ChatMessage::DirectEdit {
+            sender, recipient, ..
+        }
+        | ChatMessage::DirectDelete {
+            sender, recipient, ..
+        }
+        | ChatMessage::DirectReactionAdd {
+            sender, recipient, ..
+        }
+        | ChatMessage::DirectReactionRemove {
+            sender, recipient, ..
+        }
+        | ChatMessage::DirectRead {
+            sender, recipient, ..
+        } => {
+            *sender = user_id;
+            match chats::direct_between(connection, user_id, *recipient)? {
+                Some(direct) => Scope::Direct(direct.id),
+                None => return Ok(Err(Denied::NotFound)),
+            }
+        }
+        ChatMessage::GroupReactionAdd {
+            group_id,
+            user_id: reactor,
+            ..
+        }
+        | ChatMessage::GroupReactionRemove {
+            group_id,
+            user_id: reactor,
+            ..
+        }
+        | ChatMessage::GroupRead {
+            group_id,
+            user_id: reactor,
+            ..
+        } => {
+            *reactor = user_id;
+            let scope = Scope::Room(*group_id);
+            if !is_admin && !chats::is_member(connection, user_id, scope)? {
+                return Ok(Err(Denied::Forbidden));
+            }
+            scope
+        }
+        ChatMessage::GroupEdit { group_id, .. } | ChatMessage::GroupDelete { group_id, .. } => {
+            let scope = Scope::Room(*group_id);
+            if !is_admin && !chats::is_member(connection, user_id, scope)? {
+                return Ok(Err(Denied::Forbidden));
+            }
+            scope
+        }
+        ChatMessage::Direct { .. } | ChatMessage::Group { .. } => return Ok(Err(Denied::NotFound)),
+        ChatMessage::DirectTyping { .. }
+        | ChatMessage::GroupTyping { .. }
+        | ChatMessage::Presence { .. }
+        | ChatMessage::Error { .. } => return Ok(Err(Denied::Invalid)),
+    };
+    match &mut change {
+        ChatMessage::DirectEdit {
+            id,
+            content,
+            edited_at,
+            ..
+        }
+        | ChatMessage::GroupEdit {
+            id,
+            content,
+            edited_at,
+            ..
+        } => match message_edits::edit(connection, scope, *id, user_id, content)? {
+            Ok(at) => *edited_at = rfc3339(Some(at)),
+            Err(denied) => return Ok(Err(denied)),
+        },
+        ChatMessage::DirectDelete { id, deleted_at, .. }
+        | ChatMessage::GroupDelete { id, deleted_at, .. } => {
+            match message_edits::delete(connection, scope, *id, user_id, is_admin)? {
+                Ok(at) => *deleted_at = rfc3339(Some(at)),
+                Err(denied) => return Ok(Err(denied)),
+            }
+        }
+        // Adding a reaction twice, or removing one that isn't there, is
+        // still delivered so every client ends up in the same state
+        ChatMessage::DirectReactionAdd { id, emoji, .. }
+        | ChatMessage::GroupReactionAdd { id, emoji, .. } => {
+            match reactions::canonical_emoji(emoji) {
+                Some(canonical) => *emoji = canonical.to_string(),
+                None => return Ok(Err(Denied::Invalid)),
+            }
+            if let Err(denied) = reactions::add(connection, scope, *id, user_id, emoji)? {
+                return Ok(Err(denied));
+            }
+        }
+        ChatMessage::DirectReactionRemove { id, emoji, .. }
+        | ChatMessage::GroupReactionRemove { id, emoji, .. } => {
+            if let Some(canonical) = reactions::canonical_emoji(emoji) {
+                *emoji = canonical.to_string();
+            }
+            if let Err(denied) = reactions::remove(connection, scope, *id, user_id, emoji)? {
+                return Ok(Err(denied));
+            }
+        }
+        ChatMessage::DirectRead { id, .. } | ChatMessage::GroupRead { id, .. } => {
+            match read_markers::advance(connection, user_id, scope, *id)? {
+                Ok(marker) => *id = marker,
+                Err(denied) => return Ok(Err(denied)),
+            }
+        }
+        _ => {}
+    }
+    Ok(Ok(change))
+}
+
+// The websocket of a user's device. `v` picks the protocol version, see
+// `protocol`
+#[get("/messages/<user_id>?<v>")]
+#[allow(clippy::too_many_arguments)]
 async fn messages<'r>(
     user_id: i32,
+    v: Option<u32>,
     ws: ws::WebSocket,
     users: &'r State<Users>,
     groups: &'r State<Arc<RwLock<Groups>>>,
-    session: Session<'_, (i32, i32, String)>,
+    db: &State<DbPool>,
+    config: &State<ChatConfig>,
+    user: AuthUser,
 ) -> Result<ws::Channel<'r>, status::Custom<&'static str>> {
     use rocket::futures::{SinkExt, StreamExt};
 
-    if let Ok(Some(user)) = session.get().await {
-        if user.0 == user_id {
+    let version = protocol::negotiate(v);
+    let max_message_len = config.chat_limits.max_message_len;
+    let typing_ttl = Duration::from_secs(config.chat_limits.typing_ttl_secs);
+    let typing_interval = Duration::from_millis(config.chat_limits.typing_interval_ms);
+    let catch_up_limit = config.chat_limits.catch_up_limit.max(1);
+    let ping_interval = Duration::from_secs(config.chat_limits.ping_interval_secs);
+    let idle_timeout = Duration::from_secs(config.chat_limits.idle_timeout_secs);
+
+    if user.id != user_id {
+        return Err(status::Custom(
+            Status::Unauthorized,
+            "session not matching user",
+        ));
+    }
+
+    let db = db.inner().clone();
+    Ok(ws.channel(move |mut stream| {
+        Box::pin(async move {
+            // Registered only once the upgrade happened, so a failed handshake
+            // leaves nothing behind
             let (tx, mut rx): (UnboundedSender<Message>, UnboundedReceiver<Message>) =
                 unbounded_channel();
-            users.write().await.insert(user_id, tx);
+            let connection_id = users.write().await.connect(user_id, tx);
             {
+                let groups_for_user = load_user_groups(&db, user_id).await;
                 let group_write_lock = groups.write().await;
                 let mut groups = group_write_lock.write().await;
-
-                if !users.read().await.contains_key(&user_id) {
-                    return Err(status::Custom(
-                        Status::InternalServerError,
-                        "error inserting message",
-                    ));
-                }
-
-                if let Ok(groups_for_user) = rocket_chat::schema::rooms_users::table
-                    .filter(rocket_chat::schema::rooms_users::user_id.eq(user_id))
-                    .select(RoomUserDB::as_select())
-                    .load(&mut rocket_chat::establish_connection())
-                {
+                if let Some(groups_for_user) = groups_for_user {
                     for group in groups_for_user {
                         if let Some(group_members) = groups.get_mut(&group.room_id) {
                             group_members.insert(user_id);
@@ -331,78 +1087,507 @@ async fn messages<'r>(
                 }
             }
 
-            Ok(ws.channel(move |mut stream| {
-                Box::pin(async move {
-                    loop {
-                        tokio::select! {
-                            Some(Ok(msg)) = stream.next() => {
-                                if let Message::Text(text) = msg {
-                                    if let Ok(chat_message) = serde_json::from_str::<ChatMessage>(&text) {
-                                        match chat_message {
-                                            ChatMessage::Direct { recipient, .. } => {
-                                                let users = users.read().await;
-                                                if let Some(sender) = users.get(&recipient) {
-                                                    if let Err(err) = sender.send(Message::text(text.clone())) {
-                                                        eprintln!("Failed to send message to recipient: {:?}", err);
-                                                    }
-                                                }
-                                            },
-                                            ChatMessage::Group { group_id, .. } => {
-                                                let groups_lock = groups.read().await;
-                                                let groups = groups_lock.read().await;
-                                                if let Some(members) = groups.get(&group_id) {
-                                                    let users = users.read().await;
-                                                    for member in members {
-                                                        if member != &user_id {
-                                                            if let Some(sender) = users.get(member) {
-                                                                if let Err(err) = sender.send(Message::text(text.clone())) {
-                                                                    eprintln!("Failed to send message to group member: {:?}", err);
-                                                                }
-                                                            }
-                                                        }
-                                                    }
+            let status = match db.run(move |connection| presence::chosen(connection, user_id)).await {
+                Ok(Ok(status)) => status,
+                _ => presence::Status::Online,
+            };
+            announce_presence(&db, users, user_id, status).await;
+
+            if version != protocol::LEGACY {
+                let hello = Envelope::<ChatMessage>::new(Frame::Hello {
+                    latest: protocol::LATEST,
+                });
+                // A failed send ends the loop below, which cleans up
+                let _ = stream.send(Message::text(hello.to_text())).await;
+            }
+
+            let mut typing: Typing<TypingTarget> = Typing::new(typing_ttl, typing_interval);
+            let mut typing_tick = tokio::time::interval(Duration::from_secs(1));
+            let mut ping_tick = tokio::time::interval(ping_interval);
+            let mut last_heard = Instant::now();
+            let mut delivered = Delivered::default();
+            loop {
+                tokio::select! {
+                    msg = stream.next() => {
+                        // The client went away or broke the protocol
+                        let Some(Ok(msg)) = msg else {
+                            break;
+                        };
+                        last_heard = Instant::now();
+                        if let Message::Text(text) = msg {
+                            let (client_msg_id, read) = if text.len() > max_message_len {
+                                (None, Err(Refused::TOO_LARGE))
+                            } else {
+                                read_frame(&text, version)
+                            };
+                            let outcome = match read {
+                                Ok(Request::Chat(chat_message)) => {
+                                    receive(&db, users, groups, &user, &mut typing, client_msg_id.clone(), chat_message).await
+                                }
+                                // Live messages wait in `rx` until the client is caught up
+                                Ok(Request::Resume { rooms, directs }) => {
+                                    match catch_up(&db, user_id, rooms, directs, catch_up_limit).await {
+                                        Ok((frames, caught_up)) => {
+                                            let mut sent = true;
+                                            for frame in frames {
+                                                if stream.send(frame).await.is_err() {
+                                                    sent = false;
+                                                    break;
                                                 }
-                                            },
+                                            }
+                                            if !sent {
+                                                break;
+                                            }
+                                            delivered.merge(caught_up);
+                                            continue;
+                                        }
+                                        Err(refused) => Err(refused),
+                                    }
+                                }
+                                Err(refused) => Err(refused),
+                            };
+                            let answer = match outcome {
+                                Ok(received) => {
+                                    if let Received::Deliver(chat_message) = &received {
+                                        if let Ok(text) = serde_json::to_string(chat_message) {
+                                            fan_out(users, groups, chat_message, &text, Some(connection_id)).await;
                                         }
-                                        save_msg_db(chat_message);
-                                    } else {
-                                        eprintln!("Failed to deserialize incoming message: {:?}", text);
                                     }
+                                    // Tells the sender the id and time its message was saved with
+                                    acknowledge(version, client_msg_id, received)
                                 }
-                            },
-                            Some(msg) = rx.recv() => {
-                                if stream.send(msg).await.is_err() {
+                                Err(refused) => {
+                                    eprintln!("Refused message from user {}: {}", user_id, refused.message);
+                                    Some(refused.frame(version, client_msg_id))
+                                }
+                            };
+                            if let Some(answer) = answer {
+                                if stream.send(answer).await.is_err() {
                                     break;
                                 }
                             }
+                        } else if msg.is_close() {
+                            break;
                         }
-                    }
-
-                    {
-                        users.write().await.remove(&user_id);
-                        let group_write_lock = groups.write().await;
-                        let mut groups = group_write_lock.write().await;
-                        if let Ok(groups_for_user) = rocket_chat::schema::rooms_users::table
-                            .filter(rocket_chat::schema::rooms_users::user_id.eq(&user_id))
-                            .select(RoomUserDB::as_select())
-                            .load(&mut rocket_chat::establish_connection())
-                        {
-                            for group in groups_for_user {
-                                if let Some(group_members) = groups.get_mut(&group.room_id) {
-                                    group_members.remove(&user_id);
+                    },
+                    Some(msg) = rx.recv() => {
+                        let written = match &msg {
+                            Message::Text(text) => serde_json::from_str::<ChatMessage>(text)
+                                .ok()
+                                .and_then(|chat_message| chat_message.delivered_to(user_id)),
+                            _ => None,
+                        };
+                        let msg = match msg {
+                            Message::Text(text) if version != protocol::LEGACY => {
+                                match protocol::wrap_event(&text) {
+                                    Some(event) => Message::text(event),
+                                    None => continue,
                                 }
                             }
+                            msg => msg,
+                        };
+                        // A close frame from the server, e.g. after a password reset
+                        let closing = msg.is_close();
+                        if stream.send(msg).await.is_err() || closing {
+                            break;
+                        }
+                        match written {
+                            Some((TypingTarget::Room(room_id), id)) => delivered.room(room_id, id),
+                            Some((TypingTarget::User(peer), id)) => delivered.direct(peer, id),
+                            None => {}
                         }
                     }
-
-                    Ok(())
-                })
-            }))
-        } else {
-            return Err(status::Custom(Status::Unauthorized, "no valid session"));
+                    _ = typing_tick.tick() => {
+                        for target in typing.expired(Instant::now()) {
+                            send_typing(users, groups, target.notification(user_id, &user.username, false)).await;
+                        }
+                    }
+                    // Half-open connections never answer, and are only noticed this way
+                    _ = ping_tick.tick() => {
+                        if last_heard.elapsed() > idle_timeout {
+                            eprintln!("Closing idle websocket of user {}", user_id);
+                            let _ = stream
+                                .send(Message::Close(Some(CloseFrame {
+                                    code: CloseCode::Away,
+                                    reason: "idle timeout".into(),
+                                })))
+                                .await;
+                            break;
+                        }
+                        if stream.send(Message::Ping(Vec::new())).await.is_err() {
+                            break;
+                        }
+                        // So a server that goes down loses little of what was sent
+                        record_delivered(&db, user_id, &mut delivered).await;
+                    }
+                }
+            }
+
+            for target in typing.drain() {
+                send_typing(users, groups, target.notification(user_id, &user.username, false)).await;
+            }
+            record_delivered(&db, user_id, &mut delivered).await;
+
+            // The user stays in their rooms while another device is connected
+            if users.write().await.disconnect(user_id, connection_id) {
+                return Ok(());
+            }
+            {
+                let groups_for_user = load_user_groups(&db, user_id).await;
+                let group_write_lock = groups.write().await;
+                let mut groups = group_write_lock.write().await;
+                if let Some(groups_for_user) = groups_for_user {
+                    for group in groups_for_user {
+                        if let Some(group_members) = groups.get_mut(&group.room_id) {
+                            group_members.remove(&user_id);
+                        }
+                    }
+                }
+            }
+            announce_presence(&db, users, user_id, presence::Status::Offline).await;
+
+            Ok(())
+        })
+    }))
+}
+
+// Why the websocket refused a message: a stable `code` for clients and a
+// message for people, sent back as an `Error` frame
+struct Refused {
+    code: &'static str,
+    message: &'static str,
+}
+
+impl Refused {
+    const TOO_LARGE: Refused = Refused {
+        code: "too_large",
+        message: "message too long",
+    };
+    const MALFORMED: Refused = Refused {
+        code: "malformed",
+        message: "not a chat message",
+    };
+    const NOT_MEMBER: Refused = Refused::not_postable(NotPostable::NotMember);
+    const NO_DIRECT: Refused = Refused::not_postable(NotPostable::NoDirect);
+    const FAILED: Refused = Refused {
+        code: "internal",
+        message: "failed to handle the message",
+    };
+    const WRONG_VERSION: Refused = Refused {
+        code: "unsupported_version",
+        message: "frame not in the version of this connection",
+    };
+    const WRONG_TYPE: Refused = Refused {
+        code: "unsupported_type",
+        message: "clients only send chat and resume frames",
+    };
+    const LONG_CLIENT_ID: Refused = Refused {
+        code: "invalid",
+        message: "client_msg_id too long",
+    };
+    const REUSED_CLIENT_ID: Refused = Refused {
+        code: "invalid",
+        message: "client_msg_id already used in another chat",
+    };
+
+    const fn not_postable(not_postable: NotPostable) -> Refused {
+        Refused {
+            code: not_postable.code(),
+            message: not_postable.message(),
         }
-    } else {
-        return Err(status::Custom(Status::Unauthorized, "no valid session"));
+    }
+
+    fn denied(denied: Denied) -> Refused {
+        Refused {
+            code: denied.code(),
+            message: denied.message(),
+        }
+    }
+
+    // The error frame for a connection speaking `version`
+    fn frame(&self, version: u32, client_msg_id: Option<String>) -> Message {
+        if version == protocol::LEGACY {
+            let error = ChatMessage::Error {
+                code: self.code.to_string(),
+                message: self.message.to_string(),
+            };
+            return Message::text(serde_json::to_string(&error).unwrap_or_default());
+        }
+        Message::text(
+            Envelope::<ChatMessage>::new(Frame::Error {
+                client_msg_id,
+                code: self.code.to_string(),
+                message: self.message.to_string(),
+            })
+            .to_text(),
+        )
+    }
+}
+
+// Read a frame from a connection speaking `version`, returning the
+// `client_msg_id` it carries, if any
+fn read_frame(text: &str, version: u32) -> (Option<String>, Result<Request<ChatMessage>, Refused>) {
+    if version == protocol::LEGACY {
+        return (
+            None,
+            serde_json::from_str(text)
+                .map(Request::Chat)
+                .map_err(|_| Refused::MALFORMED),
+        );
+    }
+    match protocol::parse_request(text, version) {
+        Ok((client_msg_id, request)) => (client_msg_id, Ok(request)),
+        Err((client_msg_id, unreadable)) => (
+            client_msg_id,
+            Err(match unreadable {
+                Unreadable::Malformed => Refused::MALFORMED,
+                Unreadable::WrongVersion => Refused::WRONG_VERSION,
+                Unreadable::WrongType => Refused::WRONG_TYPE,
+            }),
+        ),
+    }
+}
+
+// The frames catching up a client that reconnects: a `missed` frame per chat
+// with messages after the id the client saw, or after the stored delivery
+// cursor for chats it doesn't name, then `resumed`. Messages saved while
+// catching up may come again live, clients skip the ids they have. The
+// cursors only move once the frames were written, see `Delivered`
+async fn catch_up(
+    db: &DbPool,
+    user_id: i32,
+    rooms: HashMap<i32, i32>,
+    directs: HashMap<i32, i32>,
+    limit: i64,
+) -> Result<(Vec<Message>, Delivered), Refused> {
+    let missed = db
+        .run(move |connection| missed_messages(connection, user_id, &rooms, &directs, limit))
+        .await;
+    match missed {
+        Ok(Ok((mut frames, delivered))) => {
+            frames.push(Frame::Resumed);
+            let frames = frames
+                .into_iter()
+                .map(|frame| Message::text(Envelope::<ChatMessage>::new(frame).to_text()))
+                .collect();
+            Ok((frames, delivered))
+        }
+        _ => Err(Refused::FAILED),
+    }
+}
+
+// Move the delivery cursors of `user_id` up to what the connection wrote
+// since the last time. What failed to be recorded is tried again next time
+async fn record_delivered(db: &DbPool, user_id: i32, delivered: &mut Delivered) {
+    if delivered.is_empty() {
+        return;
+    }
+    let written = delivered.clone();
+    match db
+        .run(move |connection| delivery::record(connection, user_id, &written))
+        .await
+    {
+        Ok(Ok(())) => *delivered = Delivered::default(),
+        _ => eprintln!("Failed to record the delivery cursors of user {}", user_id),
+    }
+}
+
+// The `missed` frames of what the client missed, see `delivery::missed`
+fn missed_messages(
+    connection: &mut MysqlConnection,
+    user_id: i32,
+    rooms: &HashMap<i32, i32>,
+    directs: &HashMap<i32, i32>,
+    limit: i64,
+) -> QueryResult<(Vec<Frame<ChatMessage>>, Delivered)> {
+    let (missed, delivered) = delivery::missed(connection, user_id, rooms, directs, limit)?;
+    let mut frames = Vec::new();
+    for chat in missed {
+        frames.push(match chat {
+            Missed::Room {
+                room_id,
+                page,
+                truncated,
+            } => Frame::Missed {
+                room_id: Some(room_id),
+                user_id: None,
+                messages: serde_json::to_value(room_details(connection, page)?).unwrap_or_default(),
+                truncated,
+            },
+            Missed::Direct {
+                peer,
+                page,
+                truncated,
+            } => Frame::Missed {
+                room_id: None,
+                user_id: Some(peer),
+                messages: serde_json::to_value(direct_details(connection, page)?)
+                    .unwrap_or_default(),
+                truncated,
+            },
+        });
+    }
+    Ok((frames, delivered))
+}
+
+// What became of a client message
+enum Received {
+    // Nothing to deliver, e.g. a typing notification
+    Nothing,
+    // A new message or a change, for everyone in the chat
+    Deliver(ChatMessage),
+    // A retry of a message already saved, only for its sender
+    Duplicate(ChatMessage),
+}
+
+// The answer to a client message: the message itself for version 1 clients,
+// an `ack` for the others
+fn acknowledge(version: u32, client_msg_id: Option<String>, received: Received) -> Option<Message> {
+    let (body, duplicate) = match received {
+        Received::Nothing => (None, false),
+        Received::Deliver(chat_message) => (Some(chat_message), false),
+        Received::Duplicate(chat_message) => (Some(chat_message), true),
+    };
+    if version == protocol::LEGACY {
+        return body
+            .and_then(|chat_message| serde_json::to_string(&chat_message).ok())
+            .map(Message::text);
+    }
+    let (id, sent_at) = match &body {
+        Some(ChatMessage::Direct { id, sent_at, .. })
+        | Some(ChatMessage::Group { id, sent_at, .. }) => (*id, sent_at.clone()),
+        _ => (None, None),
+    };
+    Some(Message::text(
+        Envelope::new(Frame::Ack {
+            client_msg_id,
+            id,
+            sent_at,
+            duplicate,
+            body,
+        })
+        .to_text(),
+    ))
+}
+
+// Handle a message from a websocket connection of `user`, whoever the client
+// claims to be
+async fn receive(
+    db: &DbPool,
+    users: &Users,
+    groups: &Arc<RwLock<Groups>>,
+    user: &AuthUser,
+    typing: &mut Typing<TypingTarget>,
+    client_msg_id: Option<String>,
+    mut chat_message: ChatMessage,
+) -> Result<Received, Refused> {
+    let user_id = user.id;
+    if client_msg_id
+        .as_ref()
+        .is_some_and(|id| id.len() > protocol::MAX_CLIENT_MSG_ID_LEN)
+    {
+        return Err(Refused::LONG_CLIENT_ID);
+    }
+    // A status picked by the user is remembered, then announced
+    if let ChatMessage::Presence { status, .. } = chat_message {
+        return match db
+            .run(move |connection| presence::choose(connection, user_id, status))
+            .await
+        {
+            Ok(Ok(true)) => {
+                announce_presence(db, users, user_id, status).await;
+                Ok(Received::Nothing)
+            }
+            Ok(Ok(false)) => Err(Refused::denied(Denied::Invalid)),
+            _ => Err(Refused::FAILED),
+        };
+    }
+    // Typing notifications are only passed on, never saved or echoed
+    if let Some((target, is_typing)) = chat_message.typing() {
+        let now = Instant::now();
+        // Checked before anything is recorded, so chats the user is not in
+        // never take room. Too many checks drop the notification
+        if is_typing && typing.due(target, now) {
+            if !typing.may_check(now) {
+                return Ok(Received::Nothing);
+            }
+            if !may_type(db, groups, user_id, target).await {
+                return Err(match target {
+                    TypingTarget::Room(_) => Refused::NOT_MEMBER,
+                    TypingTarget::User(_) => Refused::NO_DIRECT,
+                });
+            }
+        }
+        if typing.update(target, is_typing, now) {
+            let notification = target.notification(user_id, &user.username, is_typing);
+            send_typing(users, groups, notification).await;
+        }
+        return Ok(Received::Nothing);
+    }
+    // Only saved messages and changes are delivered, carrying their id and time
+    if let Some(target) = chat_message.target() {
+        chat_message.sent_by(user_id, &user.username);
+        let saved = save_msg_db(db, chat_message.clone(), client_msg_id).await?;
+        chat_message.saved(&saved);
+        if saved.duplicate {
+            return Ok(Received::Duplicate(chat_message));
+        }
+        // Clients stop showing the sender as typing once the message arrives
+        typing.clear(target);
+        return Ok(Received::Deliver(chat_message));
+    }
+    let is_admin = user.is_admin;
+    match db
+        .run(move |connection| change_message(connection, user_id, is_admin, chat_message))
+        .await
+    {
+        Ok(Ok(Ok(changed))) => Ok(Received::Deliver(changed)),
+        Ok(Ok(Err(denied))) => Err(Refused::denied(denied)),
+        _ => Err(Refused::FAILED),
+    }
+}
+
+// Rooms the user belongs to, used to keep the `Groups` fanout map in sync
+async fn load_user_groups(db: &DbPool, user_id: i32) -> Option<Vec<RoomUserDB>> {
+    db.run(move |connection| {
+        rocket_chat::schema::rooms_users::table
+            .filter(rocket_chat::schema::rooms_users::user_id.eq(user_id))
+            .select(RoomUserDB::as_select())
+            .load(connection)
+            .ok()
+    })
+    .await
+    .ok()
+    .flatten()
+}
+
+// Close every websocket with "server restarting" when the server shuts down
+fn websocket_shutdown_fairing(users: Users) -> AdHoc {
+    AdHoc::on_liftoff("Websocket shutdown", move |rocket| {
+        let shutdown = rocket.shutdown();
+        Box::pin(async move {
+            tokio::spawn(async move {
+                shutdown.await;
+                let closed = users
+                    .read()
+                    .await
+                    .send_all(&Message::Close(Some(CloseFrame {
+                        code: CloseCode::Restart,
+                        reason: "server restarting".into(),
+                    })));
+                println!("Closing {} websockets for shutdown", closed);
+            });
+        })
+    })
+}
+
+// Close every live connection of a user with `reason`
+async fn disconnect_user(users: &Users, user_id: i32, reason: &'static str) {
+    for sender in users.write().await.remove_user(user_id) {
+        let _ = sender.send(Message::Close(Some(CloseFrame {
+            code: CloseCode::Policy,
+            reason: reason.into(),
+        })));
     }
 }
 
@@ -410,39 +1595,50 @@ async fn messages<'r>(
 async fn post(
     form: Form<GroupMessage>,
     queue: &State<Sender<GroupMessage>>,
-    session: Session<'_, (i32, i32, String)>,
+    db: &State<DbPool>,
+    config: &State<ChatConfig>,
+    user: AuthUser,
 ) -> Result<(), status::Custom<&'static str>> {
-    use diesel::insert_into;
-
-    if let Ok(Some(user)) = session.get().await {
-        let connection = &mut rocket_chat::establish_connection();
-        let message = form.into_inner();
-
-        if user.1 == 1 {
-            if let Ok(_) = insert_into(rocket_chat::schema::messages::dsl::messages)
-                .values((
-                    rocket_chat::schema::messages::room_id.eq(&message.room_id),
-                    rocket_chat::schema::messages::user_id.eq(&message.user_id),
-                    rocket_chat::schema::messages::content.eq(&message.message),
-                ))
-                .execute(connection)
-            {
-                let _res = queue.send(message);
-                Ok(())
-            } else {
-                Err(status::Custom(
-                    Status::InternalServerError,
-                    "error inserting message",
-                ))
-            }
-        } else {
-            Err(status::Custom(
-                Status::Unauthorized,
-                "session not maching username",
-            ))
+    if user.is_admin {
+        let mut message = form.into_inner();
+        if message.message.len() > config.chat_limits.max_message_len {
+            return Err(status::Custom(Status::PayloadTooLarge, "message too long"));
         }
+        message.user_id = user.id;
+        message.user_name = user.username;
+        // Uploads are only linked to websocket messages
+        message.attachments.clear();
+
+        let to_insert = message.clone();
+        let saved = run_db(db, move |connection| {
+            connection
+                .transaction(|connection| {
+                    insert_room_message(
+                        connection,
+                        to_insert.room_id,
+                        to_insert.user_id,
+                        &to_insert.message,
+                        &[],
+                        to_insert.reply_to_id,
+                        None,
+                    )
+                })
+                .map_err(|err| match err {
+                    diesel::result::Error::NotFound => {
+                        status::Custom(Status::NotFound, "message not found")
+                    }
+                    _ => status::Custom(Status::InternalServerError, "error inserting message"),
+                })
+        })
+        .await?;
+        message.id = saved.id;
+        message.sent_at = rfc3339(saved.sent_at);
+        message.thread_id = saved.thread_id;
+        message.reply_count = 0;
+        let _res = queue.send(message);
+        Ok(())
     } else {
-        Err(status::Custom(Status::Unauthorized, "no valid session"))
+        Err(status::Custom(Status::Forbidden, "admin only"))
     }
 }
 
@@ -450,271 +1646,238 @@ async fn post(
 async fn add_room(
     form: Form<AddRoom>,
     state: &State<AppState>,
-    session: Session<'_, (i32, i32, String)>,
+    config: &State<ChatConfig>,
+    user: AuthUser,
     groups: &State<Arc<RwLock<Groups>>>,
+    db: &State<DbPool>,
 ) -> Result<Json<PubRoom>, status::Custom<&'static str>> {
     use rocket_chat::schema::rooms::dsl::*;
     use rocket_chat::schema::rooms_users::dsl::*;
-    let connection = &mut rocket_chat::establish_connection();
 
-    if let Ok(Some(user)) = session.get().await {
-        let room = form.into_inner();
+    let room = form.into_inner();
 
-        if user.0 == room.user_id {
-            if let Ok(roomsdb) = rooms
-                .filter(rocket_chat::schema::rooms::room_name.eq(&room.room_name))
-                .select(RoomDB::as_select())
-                .load::<RoomDB>(connection)
-            {
-                // Stanza già esistente
-                for r in roomsdb {
-                    if (room.require_password.clone()
-                        && r.passwd
-                            == Some(hash_password(format!(
-                                "{}{}{}",
-                                decrypt_rsa(room.password.clone().unwrap(), state),
-                                r.salt.clone(),
-                                PEPPER,
-                            ))))
-                        || !r.require_password
-                    {
-                        let result = diesel::insert_into(rooms_users)
-                            .values((
-                                rocket_chat::schema::rooms_users::room_id.eq(r.id),
-                                rocket_chat::schema::rooms_users::user_id.eq(&room.user_id),
-                            ))
-                            .execute(connection);
-                        if result == Ok(1) {
-                            let group_write_lock = groups.write().await;
-                            let mut groups = group_write_lock.write().await;
-                            if let Some(group_members) = groups.get_mut(&r.id) {
-                                group_members.insert(room.user_id);
-                            } else {
-                                let mut new_group = HashSet::new();
-                                new_group.insert(room.user_id);
-                                groups.insert(r.id, new_group);
-                            }
-                            if let Ok(enc) =
-                                encrypt_rsa(r.aes_key.clone(), room.rsa_client_key.clone())
-                            {
-                                if let Ok(messages_with_user) = rocket_chat::schema::messages::table
-                                    .filter(rocket_chat::schema::messages::room_id.eq(r.id))
-                                    .inner_join(rocket_chat::schema::users::table)
-                                    .select((MessageDB::as_select(), UserDB::as_select()))
-                                    .load::<(MessageDB, UserDB)>(connection)
-                                {
-                                    return Ok(Json(PubRoom::new(
-                                        r.id,
-                                        room.room_name,
-                                        enc,
-                                        messages_with_user
-                                            .iter()
-                                            .map(|(m, u)| {
-                                                GroupMessage::new(
-                                                    m.room_id,
-                                                    m.user_id,
-                                                    u.username.clone(),
-                                                    m.content.clone(),
-                                                )
-                                            })
-                                            .collect::<Vec<GroupMessage>>(),
-                                    )));
-                                } else {
-                                    return Err(status::Custom(
-                                        Status::InternalServerError,
-                                        "Database error",
-                                    ));
-                                }
-                            } else {
-                                return Err(status::Custom(
-                                    Status::InternalServerError,
-                                    "RSA error",
-                                ));
-                            }
-                        } else {
-                            return Err(status::Custom(
-                                Status::InternalServerError,
-                                "Database error",
-                            ));
+    let plain_password = match &room.password {
+        Some(p) if p != "null" => Some(decrypt_rsa(p.clone(), state).map_err(rsa_error)?),
+        _ => None,
+    };
+    let joining_user = user.id;
+    let pepper = config.pepper.clone();
+    let page_size = config.chat_limits.history_page_size;
+    let name = room.room_name.clone();
+    let existing = run_db(db, move |connection| {
+        rooms
+            .filter(rocket_chat::schema::rooms::room_name.eq(&name))
+            .select(RoomDB::as_select())
+            .first::<RoomDB>(connection)
+            .optional()
+            .map_err(database_error)
+    })
+    .await?;
+
+    let pub_room = match existing {
+        // Stanza già esistente
+        Some(r) => {
+            let mut rehashed = None;
+            if r.require_password {
+                let (stored, legacy_salt) = (r.passwd.clone(), r.salt.clone());
+                let (verification, fresh) = run_hashing(move || {
+                    let verification = match (&plain_password, &stored) {
+                        (Some(p), Some(stored)) => {
+                            password::verify(p, stored, &legacy_salt, &pepper)
                         }
-                    } else {
-                        return Err(status::Custom(Status::Unauthorized, "Wrong password"));
-                    }
+                        _ => Verification::Invalid,
+                    };
+                    let fresh = match (&plain_password, verification) {
+                        (Some(p), Verification::ValidNeedsRehash) => {
+                            password::hash(p, &pepper).ok()
+                        }
+                        _ => None,
+                    };
+                    (verification, fresh)
+                })
+                .await?;
+                if !verification.is_valid() {
+                    return Err(status::Custom(Status::Unauthorized, "Wrong password"));
                 }
-
-                // Stanza da creare
+                rehashed = fresh;
+            }
+            run_db(db, move |connection| {
+                if let Some(rehashed) = rehashed {
+                    let _ = diesel::update(rooms.filter(rocket_chat::schema::rooms::id.eq(r.id)))
+                        .set(rocket_chat::schema::rooms::passwd.eq(rehashed))
+                        .execute(connection);
+                }
+                let result = diesel::insert_into(rooms_users)
+                    .values((
+                        rocket_chat::schema::rooms_users::room_id.eq(r.id),
+                        rocket_chat::schema::rooms_users::user_id.eq(joining_user),
+                    ))
+                    .execute(connection);
+                if result != Ok(1) {
+                    return Err(status::Custom(
+                        Status::InternalServerError,
+                        "Database error",
+                    ));
+                }
+                let enc = encrypt_rsa(r.aes_key.clone(), room.rsa_client_key.clone())
+                    .map_err(rsa_error)?;
+                let history = room_history(connection, r.id, None, None, page_size)
+                    .map_err(database_error)?;
+                Ok(PubRoom::new(r.id, room.room_name, enc, history))
+            })
+            .await?
+        }
+        // Stanza da creare
+        None => {
+            let hashed = run_hashing(move || {
+                plain_password
+                    .map(|p| password::hash(&p, &pepper))
+                    .transpose()
+            })
+            .await?
+            .map_err(|_| status::Custom(Status::InternalServerError, "Password hashing error"))?;
+            run_db(db, move |connection| {
                 let key = generate_32_byte_random();
-                let sale = generate_32_byte_random();
                 let insert_room = diesel::insert_into(rooms)
                     .values((
                         rocket_chat::schema::rooms::room_name.eq(&room.room_name),
-                        rocket_chat::schema::rooms::passwd.eq(
-                            if room.password != Some("null".to_string()) {
-                                Some(hash_password(format!(
-                                    "{}{}{}",
-                                    decrypt_rsa(room.password.unwrap(), state),
-                                    sale,
-                                    PEPPER,
-                                )))
-                            } else {
-                                None
-                            },
-                        ),
+                        rocket_chat::schema::rooms::passwd.eq(hashed),
                         rocket_chat::schema::rooms::require_password.eq(room.require_password),
                         rocket_chat::schema::rooms::hidden_room.eq(room.hidden),
                         rocket_chat::schema::rooms::aes_key.eq(&key),
-                        rocket_chat::schema::rooms::salt.eq(sale),
+                        rocket_chat::schema::rooms::salt.eq(""),
                     ))
                     .execute(connection);
                 let inserted_id = rocket_chat::schema::rooms::table
                     .filter(rocket_chat::schema::rooms::room_name.eq(&room.room_name))
                     .select(rocket_chat::schema::rooms::id)
                     .first::<i32>(connection);
-                let insert_room_user = diesel::insert_into(rooms_users)
-                    .values((
-                        rocket_chat::schema::rooms_users::room_id.eq(inserted_id.as_ref().unwrap()),
-                        rocket_chat::schema::rooms_users::user_id.eq(&room.user_id),
-                    ))
-                    .execute(connection);
-                if insert_room == Ok(1) && insert_room_user == Ok(1) {
-                    let group_write_lock = groups.write().await;
-                    let mut groups = group_write_lock.write().await;
-                    let mut new_group = HashSet::new();
-                    new_group.insert(room.user_id);
-                    groups.insert(*inserted_id.as_ref().unwrap(), new_group);
-                    if let Ok(enc) = encrypt_rsa(key, room.rsa_client_key) {
-                        return Ok(Json(PubRoom::new(
-                            inserted_id.unwrap(),
+                if let (Ok(1), Ok(inserted_id)) = (insert_room, inserted_id) {
+                    let insert_room_user = diesel::insert_into(rooms_users)
+                        .values((
+                            rocket_chat::schema::rooms_users::room_id.eq(inserted_id),
+                            rocket_chat::schema::rooms_users::user_id.eq(joining_user),
+                            // The creator moderates the room
+                            rocket_chat::schema::rooms_users::moderator.eq(true),
+                        ))
+                        .execute(connection);
+                    if insert_room_user == Ok(1) {
+                        let enc = encrypt_rsa(key, room.rsa_client_key).map_err(rsa_error)?;
+                        return Ok(PubRoom::new(
+                            inserted_id,
                             room.room_name,
                             enc,
                             Vec::<GroupMessage>::new(),
-                        )));
-                    } else {
-                        return Err(status::Custom(Status::InternalServerError, "RSA error"));
+                        ));
                     }
-                } else {
-                    Err(status::Custom(
-                        Status::InternalServerError,
-                        "Database error",
-                    ))
                 }
-            } else {
                 Err(status::Custom(
                     Status::InternalServerError,
                     "Database error",
                 ))
-            }
-        } else {
-            Err(status::Custom(
-                Status::Unauthorized,
-                "session not matching username",
-            ))
+            })
+            .await?
         }
-    } else {
-        Err(status::Custom(Status::Unauthorized, "no valid session"))
-    }
+    };
+
+    let group_write_lock = groups.write().await;
+    let mut groups = group_write_lock.write().await;
+    groups
+        .entry(pub_room.room_id)
+        .or_default()
+        .insert(joining_user);
+    Ok(Json(pub_room))
 }
 
 #[post("/remove-room", data = "<form>")]
 async fn remove_room(
     form: Form<ToRemoveRoom>,
-    session: Session<'_, (i32, i32, String)>,
+    user: AuthUser,
     groups: &State<Arc<RwLock<Groups>>>,
+    db: &State<DbPool>,
 ) -> Result<(), status::Custom<&'static str>> {
     use rocket_chat::schema::rooms_users::dsl::*;
 
-    let connection = &mut rocket_chat::establish_connection();
-
-    if let Ok(Some(user)) = session.get().await {
-        let room = form.room_id;
-        let for_user = form.user_id;
-        if user.0 == for_user {
-            if let Ok(_) = diesel::delete(
-                rocket_chat::schema::rooms_users::table
-                    .filter(room_id.eq(room).and(user_id.eq(for_user))),
-            )
-            .execute(connection)
-            {
-                let group_write_lock = groups.write().await;
-                let mut groups = group_write_lock.write().await;
-                if let Some(group_members) = groups.get_mut(&room) {
-                    group_members.remove(&for_user);
-                }
-                Ok(())
-            } else {
-                Err(status::Custom(Status::Unauthorized, "can't"))
-            }
-        } else {
-            Err(status::Custom(
-                Status::Unauthorized,
-                "session not matching user",
-            ))
+    let room = form.room_id;
+    let for_user = user.id;
+    let deleted = run_db(db, move |connection| {
+        Ok(diesel::delete(
+            rocket_chat::schema::rooms_users::table
+                .filter(room_id.eq(room).and(user_id.eq(for_user))),
+        )
+        .execute(connection)
+        .is_ok())
+    })
+    .await?;
+    if deleted {
+        let group_write_lock = groups.write().await;
+        let mut groups = group_write_lock.write().await;
+        if let Some(group_members) = groups.get_mut(&room) {
+            group_members.remove(&for_user);
         }
+        Ok(())
     } else {
-        Err(status::Custom(Status::Unauthorized, "no valid session"))
+        Err(status::Custom(Status::Unauthorized, "can't"))
     }
 }
 
 #[get("/get-rooms")]
-fn search_rooms() -> Result<Json<Vec<SearchRoom>>, status::Custom<&'static str>> {
+async fn search_rooms(
+    db: &State<DbPool>,
+) -> Result<Json<Vec<SearchRoom>>, status::Custom<&'static str>> {
     use rocket_chat::schema::rooms::dsl::*;
-    let connection = &mut rocket_chat::establish_connection();
 
-    if let Ok(roomsdb) = rooms
-        .filter(hidden_room.eq(false))
-        .select(RoomDB::as_select())
-        .load(connection)
-    {
-        let pub_rooms = roomsdb
-            .iter()
-            .map(|room| SearchRoom::new(room.id, room.room_name.clone(), room.require_password))
-            .collect::<Vec<SearchRoom>>();
+    run_db(db, |connection| {
+        if let Ok(roomsdb) = rooms
+            .filter(hidden_room.eq(false))
+            .select(RoomDB::as_select())
+            .load(connection)
+        {
+            let pub_rooms = roomsdb
+                .iter()
+                .map(|room| SearchRoom::new(room.id, room.room_name.clone(), room.require_password))
+                .collect::<Vec<SearchRoom>>();
 
-        Ok(Json(pub_rooms))
-    } else {
-        Err(status::Custom(
-            Status::InternalServerError,
-            "Database error",
-        ))
-    }
+            Ok(Json(pub_rooms))
+        } else {
+            Err(status::Custom(
+                Status::InternalServerError,
+                "Database error",
+            ))
+        }
+    })
+    .await
 }
 
 #[post("/add-direct", data = "<form>")]
 async fn add_direct(
     form: Form<AddDirect>,
-    session: Session<'_, (i32, i32, String)>,
+    user: AuthUser,
+    db: &State<DbPool>,
 ) -> Result<Json<DirectToAdd>, status::Custom<&'static str>> {
     use rocket_chat::schema::directs::dsl::*;
-    let connection = &mut rocket_chat::establish_connection();
 
-    if let Ok(Some(user)) = session.get().await {
-        let userform = form.into_inner();
-        if user.0 == userform.user_id {
-            if let Ok(recipient) = rocket_chat::schema::users::table
-                .filter(rocket_chat::schema::users::username.eq(userform.username))
-                .select(UserDB::as_select())
-                .limit(1)
-                .load::<UserDB>(connection)
+    let userform = form.into_inner();
+    run_db(db, move |connection| {
+        if let Ok(recipient) = rocket_chat::schema::users::table
+            .filter(rocket_chat::schema::users::username.eq(userform.username))
+            .select(UserDB::as_select())
+            .first::<UserDB>(connection)
+        {
+            let key = generate_32_byte_random();
+            if diesel::insert_into(directs)
+                .values((
+                    user1_id.eq(user.id),
+                    user2_id.eq(recipient.id),
+                    aes_key.eq(&key),
+                ))
+                .execute(connection)
+                .is_ok()
             {
-                let key = generate_32_byte_random();
-                if let Ok(_) = diesel::insert_into(directs)
-                    .values((
-                        user1_id.eq(userform.user_id),
-                        user2_id.eq(recipient[0].id),
-                        aes_key.eq(&key),
-                    ))
-                    .execute(connection)
-                {
-                    Ok(Json(DirectToAdd {
-                        id: recipient[0].id,
-                        key: encrypt_rsa(key, userform.rsa_key).unwrap(),
-                    }))
-                } else {
-                    Err(status::Custom(
-                        Status::InternalServerError,
-                        "Database error",
-                    ))
-                }
+                Ok(Json(DirectToAdd {
+                    id: recipient.id,
+                    key: encrypt_rsa(key, userform.rsa_key).map_err(rsa_error)?,
+                }))
             } else {
                 Err(status::Custom(
                     Status::InternalServerError,
@@ -722,208 +1885,720 @@ async fn add_direct(
                 ))
             }
         } else {
-            Err(status::Custom(
-                Status::Unauthorized,
-                "session not matching user",
-            ))
+            Err(status::Custom(Status::NotFound, "User not found"))
         }
-    } else {
-        Err(status::Custom(Status::Unauthorized, "no valid session"))
-    }
+    })
+    .await
 }
 
 #[post("/delete-direct", data = "<form>")]
 async fn delete_direct(
     form: Form<DeleteDirect>,
-    session: Session<'_, (i32, i32, String)>,
+    user: AuthUser,
+    db: &State<DbPool>,
 ) -> Result<(), status::Custom<&'static str>> {
     use rocket_chat::schema::directs::dsl::*;
-    let connection = &mut rocket_chat::establish_connection();
 
-    if let Ok(Some(user)) = session.get().await {
-        let userform = form.into_inner();
-        if user.0 == userform.user_id {
-            if let Ok(_) = diesel::delete(
-                directs.filter(
-                    user1_id
-                        .eq(userform.user_id)
-                        .and(user2_id.eq(userform.recipient_id))
-                        .or(user1_id
-                            .eq(userform.recipient_id)
-                            .and(user2_id.eq(userform.user_id))),
-                ),
-            )
-            .execute(connection)
-            {
-                Ok(())
-            } else {
-                Err(status::Custom(
-                    Status::InternalServerError,
-                    "Database error",
-                ))
-            }
+    let userform = form.into_inner();
+    run_db(db, move |connection| {
+        if diesel::delete(
+            directs.filter(
+                user1_id
+                    .eq(user.id)
+                    .and(user2_id.eq(userform.recipient_id))
+                    .or(user1_id.eq(userform.recipient_id).and(user2_id.eq(user.id))),
+            ),
+        )
+        .execute(connection)
+        .is_ok()
+        {
+            Ok(())
         } else {
             Err(status::Custom(
-                Status::Unauthorized,
-                "session not matching user",
+                Status::InternalServerError,
+                "Database error",
             ))
         }
-    } else {
-        Err(status::Custom(Status::Unauthorized, "no valid session"))
-    }
+    })
+    .await
 }
 
 #[post("/get-directs", data = "<form>")]
 async fn get_directs(
     form: Form<GetPersonalChats>,
-    session: Session<'_, (i32, i32, String)>,
+    user: AuthUser,
+    db: &State<DbPool>,
+    config: &State<ChatConfig>,
 ) -> Result<Json<Vec<Direct>>, status::Custom<&'static str>> {
     let userform = form.into_inner();
     let rsa_key = userform.rsa_key;
-    let connection = &mut rocket_chat::establish_connection();
-    if let Ok(Some(user)) = session.get().await {
-        if user.0 == userform.user_id {
-            if let Ok(directs) = rocket_chat::schema::directs::table
-                .filter(
-                    rocket_chat::schema::directs::user1_id
-                        .eq(userform.user_id)
-                        .or(rocket_chat::schema::directs::user2_id.eq(userform.user_id)),
-                )
-                .select(DirectDB::as_select())
-                .load(connection)
-            {
-                if let Ok(recipient) = rocket_chat::schema::users::table
-                    .filter(rocket_chat::schema::users::id.eq(
-                        if directs[0].user1_id == userform.user_id {
-                            directs[0].user2_id
-                        } else {
-                            directs[0].user1_id
-                        },
-                    ))
-                    .select(UserDB::as_select())
-                    .limit(1)
-                    .load::<UserDB>(connection)
-                {
-                    let mut pub_directs: Vec<Direct> = Vec::new();
-                    for direct in directs {
-                        if let Ok(messages) = DirectMessageDB::belonging_to(&direct)
-                            .select(DirectMessageDB::as_select())
-                            .load(connection)
-                        {
-                            pub_directs.push(Direct::new(
-                                recipient[0].id,
-                                recipient[0].username.clone(),
-                                encrypt_rsa(direct.aes_key, rsa_key.clone()).unwrap(),
-                                messages
-                                    .iter()
-                                    .map(|m| DirectMessage::new(m.sender_id, m.message.clone()))
-                                    .collect::<Vec<DirectMessage>>(),
-                            ));
-                        } else {
-                            return Err(status::Custom(
-                                Status::InternalServerError,
-                                "Database error",
-                            ));
-                        }
-                    }
-
-                    Ok(Json(pub_directs))
-                } else {
-                    Err(status::Custom(
-                        Status::InternalServerError,
-                        "Database error",
-                    ))
-                }
+    let page_size = config.chat_limits.history_page_size;
+    run_db(db, move |connection| {
+        let directs = rocket_chat::schema::directs::table
+            .filter(
+                rocket_chat::schema::directs::user1_id
+                    .eq(user.id)
+                    .or(rocket_chat::schema::directs::user2_id.eq(user.id)),
+            )
+            .select(DirectDB::as_select())
+            .load(connection)
+            .map_err(database_error)?;
+        let other = |direct: &DirectDB| {
+            if direct.user1_id == user.id {
+                direct.user2_id
             } else {
-                Err(status::Custom(
-                    Status::InternalServerError,
-                    "Database error",
-                ))
+                direct.user1_id
             }
-        } else {
-            Err(status::Custom(
-                Status::Unauthorized,
-                "session not matching user",
-            ))
+        };
+        let other_ids: Vec<i32> = directs.iter().map(other).collect();
+        let recipients: HashMap<i32, UserDB> = rocket_chat::schema::users::table
+            .filter(rocket_chat::schema::users::id.eq_any(&other_ids))
+            .select(UserDB::as_select())
+            .load::<UserDB>(connection)
+            .map_err(database_error)?
+            .into_iter()
+            .map(|u| (u.id, u))
+            .collect();
+
+        let direct_ids: Vec<i32> = directs.iter().map(|direct| direct.id).collect();
+        let mut histories =
+            latest_direct_histories(connection, &direct_ids, page_size).map_err(database_error)?;
+        let mut marker_users = other_ids.clone();
+        marker_users.push(user.id);
+        let markers =
+            read_markers::for_chats(connection, MessageKind::Direct, &direct_ids, &marker_users)
+                .map_err(database_error)?;
+        let own_markers: HashMap<i32, i32> = direct_ids
+            .iter()
+            .filter_map(|&id| markers.get(&(user.id, id)).map(|&marker| (id, marker)))
+            .collect();
+        let unread = read_markers::unread_counts(
+            connection,
+            user.id,
+            MessageKind::Direct,
+            &direct_ids,
+            &own_markers,
+        )
+        .map_err(database_error)?;
+
+        let mut pub_directs: Vec<Direct> = Vec::new();
+        for direct in directs {
+            let Some(recipient) = recipients.get(&other(&direct)) else {
+                continue;
+            };
+            let mut pub_direct = Direct::new(
+                recipient.id,
+                recipient.username.clone(),
+                encrypt_rsa(direct.aes_key, rsa_key.clone()).map_err(rsa_error)?,
+                histories.remove(&direct.id).unwrap_or_default(),
+            );
+            pub_direct.last_read_id = own_markers.get(&direct.id).copied();
+            pub_direct.unread = unread.get(&direct.id).copied().unwrap_or(0);
+            pub_direct.peer_last_read_id = markers.get(&(recipient.id, direct.id)).copied();
+            pub_directs.push(pub_direct);
         }
-    } else {
-        Err(status::Custom(Status::Unauthorized, "no valid session"))
+        Ok(Json(pub_directs))
+    })
+    .await
+}
+
+// A page of a room's messages, or of the replies to its `thread` message,
+// with their attachments, reactions and reply counts, see `history`
+fn room_history(
+    connection: &mut MysqlConnection,
+    room_id: i32,
+    thread: Option<i32>,
+    before: Option<i32>,
+    limit: i64,
+) -> QueryResult<Vec<GroupMessage>> {
+    let page = history::room_page(connection, room_id, thread, before, limit)?;
+    room_details(connection, page)
+}
+
+// The newest page of each of the given rooms, with the details fetched once
+// for all of them
+fn latest_room_histories(
+    connection: &mut MysqlConnection,
+    room_ids: &[i32],
+    limit: i64,
+) -> QueryResult<HashMap<i32, Vec<GroupMessage>>> {
+    let pages = history::latest_room_pages(connection, room_ids, limit)?;
+    let (rooms, page): (Vec<i32>, Vec<(MessageDB, UserDB)>) = pages
+        .into_iter()
+        .flat_map(|(room_id, page)| page.into_iter().map(move |row| (room_id, row)))
+        .unzip();
+    let mut histories: HashMap<i32, Vec<GroupMessage>> = HashMap::new();
+    for (room_id, message) in rooms.into_iter().zip(room_details(connection, page)?) {
+        histories.entry(room_id).or_default().push(message);
+    }
+    Ok(histories)
+}
+
+// Room messages with their attachments, reactions and reply counts
+fn room_details(
+    connection: &mut MysqlConnection,
+    messages_with_user: Vec<(MessageDB, UserDB)>,
+) -> QueryResult<Vec<GroupMessage>> {
+    let ids: Vec<i32> = messages_with_user
+        .iter()
+        .map(|(m, _)| m.message_id)
+        .collect();
+    let mut files = attachments::for_messages(connection, MessageKind::Room, &ids)?;
+    let mut reacted = reactions::for_messages(connection, MessageKind::Room, &ids)?;
+    let replies = history::reply_counts(connection, MessageKind::Room, &ids)?;
+    Ok(messages_with_user
+        .into_iter()
+        .map(|(m, u)| {
+            let found = files.remove(&m.message_id);
+            let reactions = reacted.remove(&m.message_id);
+            let reply_count = replies.get(&m.message_id).copied().unwrap_or(0);
+            GroupMessage::from_db(m, u.username, found, reactions, reply_count)
+        })
+        .collect())
+}
+
+// The same for a direct chat
+fn direct_history(
+    connection: &mut MysqlConnection,
+    direct_id: i32,
+    thread: Option<i32>,
+    before: Option<i32>,
+    limit: i64,
+) -> QueryResult<Vec<DirectMessage>> {
+    let page = history::direct_page(connection, direct_id, thread, before, limit)?;
+    direct_details(connection, page)
+}
+
+fn latest_direct_histories(
+    connection: &mut MysqlConnection,
+    direct_ids: &[i32],
+    limit: i64,
+) -> QueryResult<HashMap<i32, Vec<DirectMessage>>> {
+    let pages = history::latest_direct_pages(connection, direct_ids, limit)?;
+    let (directs, page): (Vec<i32>, Vec<DirectMessageDB>) = pages
+        .into_iter()
+        .flat_map(|(direct_id, page)| page.into_iter().map(move |row| (direct_id, row)))
+        .unzip();
+    let mut histories: HashMap<i32, Vec<DirectMessage>> = HashMap::new();
+    for (direct_id, message) in directs.into_iter().zip(direct_details(connection, page)?) {
+        histories.entry(direct_id).or_default().push(message);
+    }
+    Ok(histories)
+}
+
+fn direct_details(
+    connection: &mut MysqlConnection,
+    messages: Vec<DirectMessageDB>,
+) -> QueryResult<Vec<DirectMessage>> {
+    let ids: Vec<i32> = messages.iter().map(|m| m.id).collect();
+    let mut files = attachments::for_messages(connection, MessageKind::Direct, &ids)?;
+    let mut reacted = reactions::for_messages(connection, MessageKind::Direct, &ids)?;
+    let replies = history::reply_counts(connection, MessageKind::Direct, &ids)?;
+    Ok(messages
+        .into_iter()
+        .map(|m| {
+            let found = files.remove(&m.id);
+            let reactions = reacted.remove(&m.id);
+            let reply_count = replies.get(&m.id).copied().unwrap_or(0);
+            DirectMessage::from_db(m, found, reactions, reply_count)
+        })
+        .collect())
+}
+
+// Requested page size, within the configured bounds
+fn page_limit(limit: Option<i64>, config: &ChatConfig) -> i64 {
+    limit
+        .unwrap_or(config.chat_limits.history_page_size)
+        .clamp(1, config.chat_limits.max_history_page_size.max(1))
+}
+
+fn history_error(err: diesel::result::Error) -> status::Custom<&'static str> {
+    match err {
+        diesel::result::Error::NotFound => status::Custom(Status::NotFound, "message not found"),
+        err => database_error(err),
+    }
+}
+
+// Older messages of a room, `limit` of them before the `before` message
+#[get("/rooms/<room_id>/messages?<before>&<limit>")]
+async fn room_messages(
+    room_id: i32,
+    before: Option<i32>,
+    limit: Option<i64>,
+    user: AuthUser,
+    db: &State<DbPool>,
+    config: &State<ChatConfig>,
+) -> Result<Json<Vec<GroupMessage>>, status::Custom<&'static str>> {
+    let limit = page_limit(limit, config);
+    run_db(db, move |connection| {
+        if !chats::is_member(connection, user.id, Scope::Room(room_id)).map_err(database_error)? {
+            return Err(status::Custom(
+                Status::Forbidden,
+                "not a member of this room",
+            ));
+        }
+        room_history(connection, room_id, None, before, limit)
+            .map(Json)
+            .map_err(history_error)
+    })
+    .await
+}
+
+// Replies to a message of a room, `limit` of them before the `before` reply
+#[get("/rooms/<room_id>/messages/<message_id>/thread?<before>&<limit>")]
+async fn room_thread(
+    room_id: i32,
+    message_id: i32,
+    before: Option<i32>,
+    limit: Option<i64>,
+    user: AuthUser,
+    db: &State<DbPool>,
+    config: &State<ChatConfig>,
+) -> Result<Json<Vec<GroupMessage>>, status::Custom<&'static str>> {
+    let limit = page_limit(limit, config);
+    run_db(db, move |connection| {
+        let scope = Scope::Room(room_id);
+        if !chats::is_member(connection, user.id, scope).map_err(database_error)? {
+            return Err(status::Custom(
+                Status::Forbidden,
+                "not a member of this room",
+            ));
+        }
+        if history::thread_of(connection, scope, message_id)
+            .map_err(database_error)?
+            .is_none()
+        {
+            return Err(status::Custom(Status::NotFound, "message not found"));
+        }
+        room_history(connection, room_id, Some(message_id), before, limit)
+            .map(Json)
+            .map_err(history_error)
+    })
+    .await
+}
+
+// Older messages of the direct chat with `user_id`
+#[get("/directs/<user_id>/messages?<before>&<limit>")]
+async fn direct_messages(
+    user_id: i32,
+    before: Option<i32>,
+    limit: Option<i64>,
+    user: AuthUser,
+    db: &State<DbPool>,
+    config: &State<ChatConfig>,
+) -> Result<Json<Vec<DirectMessage>>, status::Custom<&'static str>> {
+    let limit = page_limit(limit, config);
+    run_db(db, move |connection| {
+        let Some(direct) =
+            chats::direct_between(connection, user.id, user_id).map_err(database_error)?
+        else {
+            return Err(status::Custom(
+                Status::NotFound,
+                "no direct chat with this user",
+            ));
+        };
+        direct_history(connection, direct.id, None, before, limit)
+            .map(Json)
+            .map_err(history_error)
+    })
+    .await
+}
+
+// Replies to a message of the direct chat with `user_id`
+#[get("/directs/<user_id>/messages/<message_id>/thread?<before>&<limit>")]
+async fn direct_thread(
+    user_id: i32,
+    message_id: i32,
+    before: Option<i32>,
+    limit: Option<i64>,
+    user: AuthUser,
+    db: &State<DbPool>,
+    config: &State<ChatConfig>,
+) -> Result<Json<Vec<DirectMessage>>, status::Custom<&'static str>> {
+    let limit = page_limit(limit, config);
+    run_db(db, move |connection| {
+        let Some(direct) =
+            chats::direct_between(connection, user.id, user_id).map_err(database_error)?
+        else {
+            return Err(status::Custom(
+                Status::NotFound,
+                "no direct chat with this user",
+            ));
+        };
+        if history::thread_of(connection, Scope::Direct(direct.id), message_id)
+            .map_err(database_error)?
+            .is_none()
+        {
+            return Err(status::Custom(Status::NotFound, "message not found"));
+        }
+        direct_history(connection, direct.id, Some(message_id), before, limit)
+            .map(Json)
+            .map_err(history_error)
+    })
+    .await
+}
+
+// New (encrypted) content of an edited message
+#[derive(Debug, Clone, FromForm)]
+struct EditMessage {
+    #[field(validate = len(1..))]
+    content: String,
+}
+
+// A previous version of a message
+#[derive(Debug, Clone, Serialize)]
+#[serde(crate = "rocket::serde")]
+struct Revision {
+    content: String,
+    changed_by: i32,
+    replaced_at: Option<String>,
+}
+
+fn denied(denied: Denied) -> status::Custom<&'static str> {
+    let code = match denied {
+        Denied::NotFound => Status::NotFound,
+        Denied::Forbidden => Status::Forbidden,
+        Denied::Deleted => Status::Gone,
+        Denied::Invalid => Status::BadRequest,
+    };
+    status::Custom(code, denied.message())
+}
+
+// Apply a change made through the REST routes and deliver it like one made
+// over the websocket, to every device of the user included
+async fn apply_change(
+    db: &DbPool,
+    users: &Users,
+    groups: &Arc<RwLock<Groups>>,
+    user: AuthUser,
+    change: ChatMessage,
+) -> Result<Json<ChatMessage>, status::Custom<&'static str>> {
+    let changed = run_db(db, move |connection| {
+        change_message(connection, user.id, user.is_admin, change)
+            .map_err(database_error)?
+            .map_err(denied)
+    })
+    .await?;
+    if let Ok(text) = serde_json::to_string(&changed) {
+        fan_out(users, groups, &changed, &text, None).await;
+    }
+    Ok(Json(changed))
+}
+
+// The presence of a user, as answered by `GET /presence`
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+struct UserPresence {
+    user_id: i32,
+    status: presence::Status,
+    // Only for offline users
+    last_seen_at: Option<String>,
+}
+
+// Presence of the given users, e.g. `/presence?user_id=2&user_id=5`. Users
+// who share no room or direct chat with the caller are left out
+#[get("/presence?<user_id>")]
+async fn get_presence(
+    user_id: Vec<i32>,
+    user: AuthUser,
+    users: &State<Users>,
+    db: &State<DbPool>,
+) -> Result<Json<Vec<UserPresence>>, status::Custom<&'static str>> {
+    if user_id.len() > presence::MAX_QUERY {
+        return Err(status::Custom(Status::BadRequest, "too many users"));
+    }
+    let connected: HashSet<i32> = {
+        let users = users.read().await;
+        user_id
+            .iter()
+            .copied()
+            .filter(|id| users.is_connected(*id))
+            .collect()
+    };
+    run_db(db, move |connection| {
+        let contacts = presence::contacts(connection, user.id).map_err(database_error)?;
+        let asked: Vec<i32> = user_id
+            .into_iter()
+            .filter(|id| *id == user.id || contacts.contains(id))
+            .collect();
+        let stored = presence::for_users(connection, &asked).map_err(database_error)?;
+        Ok(Json(
+            asked
+                .into_iter()
+                .map(|id| {
+                    let found = stored.get(&id);
+                    let status = presence::shown(found, connected.contains(&id));
+                    let last_seen_at = match status {
+                        presence::Status::Offline => found.and_then(|p| p.last_seen_at),
+                        _ => None,
+                    };
+                    UserPresence {
+                        user_id: id,
+                        status,
+                        last_seen_at: rfc3339(last_seen_at),
+                    }
+                })
+                .collect(),
+        ))
+    })
+    .await
+}
+
+fn revision_list(
+    found: QueryResult<Result<Vec<MessageRevisionDB>, Denied>>,
+) -> Result<Json<Vec<Revision>>, status::Custom<&'static str>> {
+    let found = found.map_err(database_error)?.map_err(denied)?;
+    Ok(Json(
+        found
+            .into_iter()
+            .map(|r| Revision {
+                content: r.content,
+                changed_by: r.changed_by,
+                replaced_at: rfc3339(Some(r.replaced_at)),
+            })
+            .collect(),
+    ))
+}
+
+// The last message read in a chat
+#[derive(Debug, Clone, FromForm)]
+struct ReadUpTo {
+    message_id: i32,
+}
+
+#[post("/rooms/<room_id>/read", data = "<form>")]
+async fn read_room(
+    room_id: i32,
+    form: Form<ReadUpTo>,
+    user: AuthUser,
+    users: &State<Users>,
+    groups: &State<Arc<RwLock<Groups>>>,
+    db: &State<DbPool>,
+) -> Result<Json<ChatMessage>, status::Custom<&'static str>> {
+    let change = ChatMessage::GroupRead {
+        id: form.message_id,
+        group_id: room_id,
+        user_id: user.id,
+    };
+    apply_change(db, users, groups, user, change).await
+}
+
+#[post("/directs/<user_id>/read", data = "<form>")]
+async fn read_direct(
+    user_id: i32,
+    form: Form<ReadUpTo>,
+    user: AuthUser,
+    users: &State<Users>,
+    groups: &State<Arc<RwLock<Groups>>>,
+    db: &State<DbPool>,
+) -> Result<Json<ChatMessage>, status::Custom<&'static str>> {
+    let change = ChatMessage::DirectRead {
+        id: form.message_id,
+        sender: user.id,
+        recipient: user_id,
+    };
+    apply_change(db, users, groups, user, change).await
+}
+
+#[patch("/rooms/<room_id>/messages/<message_id>", data = "<form>")]
+#[allow(clippy::too_many_arguments)]
+async fn edit_room_message(
+    room_id: i32,
+    message_id: i32,
+    form: Form<EditMessage>,
+    user: AuthUser,
+    users: &State<Users>,
+    groups: &State<Arc<RwLock<Groups>>>,
+    db: &State<DbPool>,
+    config: &State<ChatConfig>,
+) -> Result<Json<ChatMessage>, status::Custom<&'static str>> {
+    let content = form.into_inner().content;
+    if content.len() > config.chat_limits.max_message_len {
+        return Err(status::Custom(Status::PayloadTooLarge, "message too long"));
+    }
+    let change = ChatMessage::GroupEdit {
+        id: message_id,
+        group_id: room_id,
+        content,
+        edited_at: None,
+    };
+    apply_change(db, users, groups, user, change).await
+}
+
+#[delete("/rooms/<room_id>/messages/<message_id>")]
+async fn delete_room_message(
+    room_id: i32,
+    message_id: i32,
+    user: AuthUser,
+    users: &State<Users>,
+    groups: &State<Arc<RwLock<Groups>>>,
+    db: &State<DbPool>,
+) -> Result<Json<ChatMessage>, status::Custom<&'static str>> {
+    let change = ChatMessage::GroupDelete {
+        id: message_id,
+        group_id: room_id,
+        deleted_at: None,
+    };
+    apply_change(db, users, groups, user, change).await
+}
+
+#[get("/rooms/<room_id>/messages/<message_id>/revisions")]
+async fn room_message_revisions(
+    room_id: i32,
+    message_id: i32,
+    user: AuthUser,
+    db: &State<DbPool>,
+) -> Result<Json<Vec<Revision>>, status::Custom<&'static str>> {
+    run_db(db, move |connection| {
+        let scope = Scope::Room(room_id);
+        if !user.is_admin
+            && !chats::is_member(connection, user.id, scope).map_err(database_error)?
+        {
+            return Err(status::Custom(
+                Status::Forbidden,
+                "not a member of this room",
+            ));
+        }
+        revision_list(message_edits::revisions(connection, scope, message_id))
+    })
+    .await
+}
+
+#[patch("/directs/<user_id>/messages/<message_id>", data = "<form>")]
+#[allow(clippy::too_many_arguments)]
+async fn edit_direct_message(
+    user_id: i32,
+    message_id: i32,
+    form: Form<EditMessage>,
+    user: AuthUser,
+    users: &State<Users>,
+    groups: &State<Arc<RwLock<Groups>>>,
+    db: &State<DbPool>,
+    config: &State<ChatConfig>,
+) -> Result<Json<ChatMessage>, status::Custom<&'static str>> {
+    let content = form.into_inner().content;
+    if content.len() > config.chat_limits.max_message_len {
+        return Err(status::Custom(Status::PayloadTooLarge, "message too long"));
     }
+    let change = ChatMessage::DirectEdit {
+        id: message_id,
+        sender: user.id,
+        recipient: user_id,
+        content,
+        edited_at: None,
+    };
+    apply_change(db, users, groups, user, change).await
+}
+
+#[delete("/directs/<user_id>/messages/<message_id>")]
+async fn delete_direct_message(
+    user_id: i32,
+    message_id: i32,
+    user: AuthUser,
+    users: &State<Users>,
+    groups: &State<Arc<RwLock<Groups>>>,
+    db: &State<DbPool>,
+) -> Result<Json<ChatMessage>, status::Custom<&'static str>> {
+    let change = ChatMessage::DirectDelete {
+        id: message_id,
+        sender: user.id,
+        recipient: user_id,
+        deleted_at: None,
+    };
+    apply_change(db, users, groups, user, change).await
+}
+
+#[get("/directs/<user_id>/messages/<message_id>/revisions")]
+async fn direct_message_revisions(
+    user_id: i32,
+    message_id: i32,
+    user: AuthUser,
+    db: &State<DbPool>,
+) -> Result<Json<Vec<Revision>>, status::Custom<&'static str>> {
+    run_db(db, move |connection| {
+        let Some(direct) =
+            chats::direct_between(connection, user.id, user_id).map_err(database_error)?
+        else {
+            return Err(status::Custom(
+                Status::NotFound,
+                "no direct chat with this user",
+            ));
+        };
+        revision_list(message_edits::revisions(
+            connection,
+            Scope::Direct(direct.id),
+            message_id,
+        ))
+    })
+    .await
 }
 
 #[post("/get-personal-rooms", data = "<form>")]
 async fn get_rooms(
     form: Form<GetPersonalChats>,
-    session: Session<'_, (i32, i32, String)>,
+    user: AuthUser,
+    db: &State<DbPool>,
+    config: &State<ChatConfig>,
 ) -> Result<Json<Vec<PubRoom>>, status::Custom<&'static str>> {
     let userform = form.into_inner();
     let rsa_key = userform.rsa_key;
-    let connection = &mut rocket_chat::establish_connection();
-    if let Ok(Some(user)) = session.get().await {
-        if user.0 == userform.user_id {
-            if let Ok(room_with_roomuser) = rocket_chat::schema::rooms::table
-                .inner_join(rocket_chat::schema::rooms_users::table)
-                .filter(rocket_chat::schema::rooms_users::user_id.eq(userform.user_id))
-                .select((RoomDB::as_select(), RoomUserDB::as_select()))
-                .load::<(RoomDB, RoomUserDB)>(connection)
-            {
-                let mut pub_rooms: Vec<PubRoom> = Vec::new();
-                for (room, _room_user) in room_with_roomuser {
-                    if let Ok(messages_with_user) = rocket_chat::schema::messages::table
-                        .filter(rocket_chat::schema::messages::room_id.eq(room.id))
-                        .inner_join(rocket_chat::schema::users::table)
-                        .select((MessageDB::as_select(), UserDB::as_select()))
-                        .load::<(MessageDB, UserDB)>(connection)
-                    {
-                        pub_rooms.push(PubRoom::new(
-                            room.id,
-                            room.room_name,
-                            encrypt_rsa(room.aes_key, rsa_key.clone()).unwrap(),
-                            messages_with_user
-                                .iter()
-                                .map(|(m, u)| {
-                                    GroupMessage::new(
-                                        m.room_id,
-                                        m.user_id,
-                                        u.username.clone(),
-                                        m.content.clone(),
-                                    )
-                                })
-                                .collect::<Vec<GroupMessage>>(),
-                        ));
-                    } else {
-                        return Err(status::Custom(
-                            Status::InternalServerError,
-                            "Database error",
-                        ));
-                    }
-                }
-
-                Ok(Json(pub_rooms))
-            } else {
-                Err(status::Custom(
-                    Status::InternalServerError,
-                    "Database error",
-                ))
+    let page_size = config.chat_limits.history_page_size;
+    run_db(db, move |connection| {
+        if let Ok(room_with_roomuser) = rocket_chat::schema::rooms::table
+            .inner_join(rocket_chat::schema::rooms_users::table)
+            .filter(rocket_chat::schema::rooms_users::user_id.eq(user.id))
+            .select((RoomDB::as_select(), RoomUserDB::as_select()))
+            .load::<(RoomDB, RoomUserDB)>(connection)
+        {
+            let room_ids: Vec<i32> = room_with_roomuser.iter().map(|(room, _)| room.id).collect();
+            let mut histories =
+                latest_room_histories(connection, &room_ids, page_size).map_err(database_error)?;
+            let markers: HashMap<i32, i32> =
+                read_markers::for_chats(connection, MessageKind::Room, &room_ids, &[user.id])
+                    .map_err(database_error)?
+                    .into_iter()
+                    .map(|((_, room_id), marker)| (room_id, marker))
+                    .collect();
+            let unread = read_markers::unread_counts(
+                connection,
+                user.id,
+                MessageKind::Room,
+                &room_ids,
+                &markers,
+            )
+            .map_err(database_error)?;
+
+            let mut pub_rooms: Vec<PubRoom> = Vec::new();
+            for (room, _room_user) in room_with_roomuser {
+                let mut pub_room = PubRoom::new(
+                    room.id,
+                    room.room_name,
+                    encrypt_rsa(room.aes_key, rsa_key.clone()).map_err(rsa_error)?,
+                    histories.remove(&room.id).unwrap_or_default(),
+                );
+                pub_room.last_read_id = markers.get(&room.id).copied();
+                pub_room.unread = unread.get(&room.id).copied().unwrap_or(0);
+                pub_rooms.push(pub_room);
             }
+
+            Ok(Json(pub_rooms))
         } else {
             Err(status::Custom(
-                Status::Unauthorized,
-                "session not matching user",
+                Status::InternalServerError,
+                "Database error",
             ))
         }
-    } else {
-        Err(status::Custom(Status::Unauthorized, "no valid session"))
-    }
+    })
+    .await
 }
 
 #[get("/whoami")]
-async fn whoami(session: Session<'_, (i32, i32, String)>) -> Result<Json<WhoAmI>, Redirect> {
-    if let Ok(Some(usr)) = session.get().await {
+async fn whoami(user: Option<AuthUser>) -> Result<Json<WhoAmI>, Redirect> {
+    if let Some(usr) = user {
         Ok(Json(WhoAmI {
-            id: usr.0,
-            admin: usr.1,
-            username: usr.2,
+            id: usr.id,
+            admin: if usr.is_admin { 1 } else { 0 },
+            username: usr.username,
         }))
     } else {
         Err(Redirect::to("/login"))
@@ -934,60 +2609,86 @@ async fn whoami(session: Session<'_, (i32, i32, String)>) -> Result<Json<WhoAmI>
 async fn login(
     form: Form<LoginUser>,
     state: &State<AppState>,
-    session: Session<'_, (i32, i32, String)>,
+    config: &State<ChatConfig>,
+    session: Session<'_, AuthUser>,
+    db: &State<DbPool>,
 ) -> Result<Json<UserId>, status::Custom<&'static str>> {
-    use rocket_chat::schema::users::dsl::*;
-
-    if let Ok(Some(_)) = session.get().await {
-        Err(status::Custom(Status::Unauthorized, "Not authorized"))
-    } else {
-        let userform = form.into_inner();
-        let passw = decrypt_rsa(userform.password, state);
-        let connection = &mut rocket_chat::establish_connection();
-
-        if let Ok(result) = users
-            .limit(1)
-            .filter(username.eq(&userform.username))
-            .select(UserDB::as_select())
-            .load(connection)
-        {
-            if result.len() > 0
-                && hash_password(format!("{}{}{}", passw, result[0].salt, PEPPER))
-                    == result[0].passwd
-            {
-                if result[0].email_verified {
-                    if let Ok(admin) = AdminDB::belonging_to(&result[0])
-                        .select(AdminDB::as_select())
-                        .load(connection)
-                    {
-                        if let Ok(_) = session
-                            .set((
-                                result[0].id,
-                                if admin.len() > 0 { 1 } else { 0 },
-                                userform.username,
-                            ))
-                            .await
-                        {
-                            Ok(Json(UserId { id: result[0].id }))
-                        } else {
-                            Err(status::Custom(
-                                Status::InternalServerError,
-                                "Unable to set cookies",
-                            ))
-                        }
-                    } else {
-                        Err(status::Custom(Status::Unauthorized, "Not authorized"))
-                    }
-                } else {
-                    Err(status::Custom(Status::Unauthorized, "Email not verified"))
+    use rocket_chat::schema::users::dsl::*;
+
+    if let Ok(Some(_)) = session.get().await {
+        Err(status::Custom(Status::Unauthorized, "Not authorized"))
+    } else {
+        let userform = form.into_inner();
+        let passw = decrypt_rsa(userform.password, state).map_err(rsa_error)?;
+        let login_username = userform.username.clone();
+
+        let found = run_db(db, move |connection| {
+            let Some(found) = users
+                .filter(username.eq(&login_username))
+                .select(UserDB::as_select())
+                .first(connection)
+                .optional()
+                .map_err(database_error)?
+            else {
+                return Ok(None);
+            };
+            let admin = AdminDB::belonging_to(&found)
+                .select(AdminDB::as_select())
+                .load(connection)
+                .map_err(database_error)?;
+            Ok(Some((found, !admin.is_empty())))
+        })
+        .await?;
+
+        let pepper = config.pepper.clone();
+        let stored = found
+            .as_ref()
+            .map(|(found, _)| (found.passwd.clone(), found.salt.clone()));
+        let (verification, rehashed) = run_hashing(move || {
+            let verification = match stored {
+                Some((stored, legacy_salt)) => {
+                    password::verify(&passw, &stored, &legacy_salt, &pepper)
                 }
+                // Unknown usernames take as long as wrong passwords
+                None => password::verify_absent(&passw, &pepper),
+            };
+            let rehashed = if verification == Verification::ValidNeedsRehash {
+                password::hash(&passw, &pepper).ok()
             } else {
-                Err(status::Custom(Status::Unauthorized, "Not authorized"))
-            }
+                None
+            };
+            (verification, rehashed)
+        })
+        .await?;
+        let (found, is_admin) = match found {
+            Some(found) if verification.is_valid() => found,
+            _ => return Err(status::Custom(Status::Unauthorized, "Not authorized")),
+        };
+        if let Some(rehashed) = rehashed {
+            let user_id = found.id;
+            run_db(db, move |connection| {
+                let _ = diesel::update(users.filter(id.eq(user_id)))
+                    .set(passwd.eq(rehashed))
+                    .execute(connection);
+                Ok(())
+            })
+            .await?;
+        }
+        if !found.email_verified {
+            return Err(status::Custom(Status::Unauthorized, "Email not verified"));
+        }
+        let logged_id = found.id;
+
+        if session
+            .set(AuthUser::new(logged_id, userform.username, is_admin))
+            .await
+            .is_ok()
+        {
+            Ok(Json(UserId { id: logged_id }))
         } else {
             Err(status::Custom(
                 Status::InternalServerError,
-                "Database error",
+                "Unable to set cookies",
             ))
         }
     }
@@ -997,9 +2698,10 @@ async fn login(
 async fn signup(
     form: Form<SignupUser>,
     state: &State<AppState>,
-    session: Session<'_, (i32, i32, String)>,
+    config: &State<ChatConfig>,
+    session: Session<'_, AuthUser>,
+    db: &State<DbPool>,
 ) -> Result<Json<UserId>, status::Custom<&'static str>> {
-    use rocket_chat::schema::email_tokens::dsl::*;
     use rocket_chat::schema::users::dsl::*;
 
     if let Ok(Some(_)) = session.get().await {
@@ -1010,97 +2712,190 @@ async fn signup(
         let surnamee = userr.surname.clone();
         let usernamee = userr.username.clone();
         let emaill = userr.email.clone();
-        let passw = decrypt_rsa(userr.password, state);
-        let sale = generate_32_byte_random();
-        let connection = &mut rocket_chat::establish_connection();
-
-        if let Ok(1) = diesel::insert_into(users)
-            .values((
-                full_name.eq(full_namee.trim()),
-                surname.eq(surnamee.trim()),
-                username.eq(usernamee.trim()),
-                email.eq(emaill.trim()),
-                passwd.eq(hash_password(format!(
-                    "{}{}{}",
-                    passw.trim(),
-                    &sale,
-                    PEPPER
-                ))),
-                salt.eq(sale),
-            ))
-            .execute(connection)
-        {
-            if let Ok(result) = users
-                .limit(1)
-                .filter(username.eq(&usernamee))
-                .select(UserDB::as_select())
-                .load(connection)
+        let passw = decrypt_rsa(userr.password, state).map_err(rsa_error)?;
+
+        let pepper = config.pepper.clone();
+        let verify_link = config.public_link("/verify-email/");
+        let hashed = run_hashing(move || password::hash(passw.trim(), &pepper))
+            .await?
+            .map_err(|_| status::Custom(Status::InternalServerError, "Password hashing error"))?;
+        let new_id = run_db(db, move |connection| {
+            if let Ok(1) = diesel::insert_into(users)
+                .values((
+                    full_name.eq(full_namee.trim()),
+                    surname.eq(surnamee.trim()),
+                    username.eq(usernamee.trim()),
+                    email.eq(emaill.trim()),
+                    passwd.eq(hashed),
+                    salt.eq(""),
+                ))
+                .execute(connection)
             {
-                if result.len() > 0 {
-                    let random_token = generate_32_byte_random();
-                    if let Ok(1) = diesel::insert_into(email_tokens)
-                        .values((user_id.eq(result[0].id), token.eq(&random_token)))
-                        .execute(connection)
-                    {
-                        while let Err(_) = rocket_chat::send_email(
-                            &emaill,
-                            "Email verification",
-                            &("http://localhost:8000/verify-email/".to_owned() + &random_token),
-                        )
-                        .await
-                        .await
-                        {}
+                if let Ok(result) = users
+                    .limit(1)
+                    .filter(username.eq(&usernamee))
+                    .select(UserDB::as_select())
+                    .load(connection)
+                {
+                    if !result.is_empty() {
+                        // Delivered by the outbox worker
+                        if let Err(err) = verification::issue(
+                            connection,
+                            result[0].id,
+                            &result[0].email,
+                            &verify_link,
+                        ) {
+                            eprintln!("Failed to queue verification email: {}", err);
+                        }
+                        Ok(result[0].id)
+                    } else {
+                        Err(status::Custom(Status::Unauthorized, "Not authorized"))
                     }
-                    Ok(Json(UserId { id: result[0].id }))
                 } else {
-                    Err(status::Custom(Status::Unauthorized, "Not authorized"))
+                    Err(status::Custom(
+                        Status::InternalServerError,
+                        "Database error",
+                    ))
                 }
             } else {
-                Err(status::Custom(
-                    Status::InternalServerError,
-                    "Database error",
-                ))
+                Err(status::Custom(Status::Unauthorized, "Not authorized"))
             }
-        } else {
-            Err(status::Custom(Status::Unauthorized, "Not authorized"))
-        }
+        })
+        .await?;
+
+        Ok(Json(UserId { id: new_id }))
     }
 }
 
+// Older links used standard base64 tokens, which may contain slashes
 #[get("/verify-email/<emailtoken..>")]
-fn confirm_email(emailtoken: PathBuf) -> Redirect {
-    use rocket_chat::schema::email_tokens::dsl::*;
-    use rocket_chat::schema::users::dsl::*;
+async fn confirm_email(
+    emailtoken: PathBuf,
+    config: &State<ChatConfig>,
+    db: &State<DbPool>,
+) -> Redirect {
+    let tokenstring = emailtoken.to_string_lossy().into_owned();
+    let ttl = chrono::Duration::hours(config.chat_limits.verification_ttl_hours);
+
+    let outcome = db
+        .run(move |connection| verification::consume(connection, &tokenstring, ttl))
+        .await
+        .ok()
+        .and_then(|result| result.ok())
+        .unwrap_or(VerifyOutcome::Invalid);
+
+    // The page reads `result` to tell the user what happened
+    Redirect::to(format!("/verify?result={}", outcome.as_str()))
+}
 
-    let connection = &mut rocket_chat::establish_connection();
+#[get("/verify")]
+async fn verify_page() -> Option<NamedFile> {
+    NamedFile::open("pages/verify.html").await.ok()
+}
 
-    let tokenstring = emailtoken.into_os_string().into_string().unwrap();
+#[post("/resend-verification", data = "<form>")]
+async fn resend_verification(
+    form: Form<ResendVerification>,
+    config: &State<ChatConfig>,
+    db: &State<DbPool>,
+) -> Result<&'static str, status::Custom<&'static str>> {
+    let address = form.into_inner().email.trim().to_string();
+    let cooldown = chrono::Duration::seconds(config.chat_limits.email_resend_secs);
+    let verify_link = config.public_link("/verify-email/");
+
+    run_db(db, move |connection| {
+        verification::resend(connection, &address, cooldown, &verify_link)
+            .map_err(|_| status::Custom(Status::InternalServerError, "Database error"))
+    })
+    .await?;
+
+    // Only known addresses can be in their cooldown, so every outcome gets
+    // the same answer
+    Ok("If the address belongs to an unverified account, a new link is on its way")
+}
 
-    if let Ok(result) = email_tokens
-        .limit(1)
-        .filter(token.eq(&tokenstring))
-        .select(user_id)
-        .load::<i32>(connection)
-    {
-        if result.len() > 0 {
-            if let Ok(_) = diesel::update(users.filter(id.eq(result[0])))
-                .set(email_verified.eq(true))
-                .execute(connection)
-            {
-                if let Ok(_) =
-                    diesel::delete(email_tokens.filter(token.eq(tokenstring))).execute(connection)
-                {
-                    return Redirect::to(uri!(login_page));
-                }
-                Redirect::to(uri!(login_page))
-            } else {
-                Redirect::to(uri!(login_page))
-            }
-        } else {
-            Redirect::to(uri!(login_page))
+#[get("/reset-password")]
+async fn reset_password_page() -> Option<NamedFile> {
+    NamedFile::open("pages/reset.html").await.ok()
+}
+
+#[post("/forgot-password", data = "<form>")]
+async fn forgot_password(
+    form: Form<ForgotPassword>,
+    config: &State<ChatConfig>,
+    db: &State<DbPool>,
+) -> Result<&'static str, status::Custom<&'static str>> {
+    let address = form.into_inner().email.trim().to_string();
+    let cooldown = chrono::Duration::seconds(config.chat_limits.email_resend_secs);
+    let reset_link = config.public_link("/reset-password?token=");
+
+    run_db(db, move |connection| {
+        password_reset::request(connection, &address, cooldown, &reset_link)
+            .map_err(|_| status::Custom(Status::InternalServerError, "Database error"))
+    })
+    .await?;
+
+    // Only known addresses can be in their cooldown, so every outcome gets
+    // the same answer
+    Ok("If the address belongs to an account, a reset link is on its way")
+}
+
+#[post("/reset-password", data = "<form>")]
+async fn reset_password(
+    form: Form<ResetPassword>,
+    state: &State<AppState>,
+    config: &State<ChatConfig>,
+    users: &State<Users>,
+    db: &State<DbPool>,
+) -> Result<&'static str, status::Custom<&'static str>> {
+    let reset = form.into_inner();
+    let new_password = decrypt_rsa(reset.new_password, state).map_err(rsa_error)?;
+    if new_password.len() < 8 {
+        return Err(status::Custom(
+            Status::BadRequest,
+            "Password must be at least 8 characters",
+        ));
+    }
+    let pepper = config.pepper.clone();
+    let ttl = chrono::Duration::minutes(config.chat_limits.reset_ttl_minutes);
+
+    let token = reset.token.clone();
+    let checked = run_db(db, move |connection| {
+        password_reset::check(connection, &token, ttl).map_err(database_error)
+    })
+    .await?;
+    let outcome = match checked {
+        Redeemed::Expired => ResetOutcome::Expired,
+        Redeemed::Invalid => ResetOutcome::Invalid,
+        // The link may still be used up by another request meanwhile, `reset`
+        // tells
+        Redeemed::User(_) => {
+            let hashed = run_hashing(move || password::hash(&new_password, &pepper))
+                .await?
+                .map_err(|_| {
+                    status::Custom(Status::InternalServerError, "Password hashing error")
+                })?;
+            run_db(db, move |connection| {
+                password_reset::reset(connection, &reset.token, &hashed, ttl)
+                    .map_err(|_| status::Custom(Status::InternalServerError, "Database error"))
+            })
+            .await?
         }
-    } else {
-        Redirect::to(uri!(login_page))
+    };
+
+    match outcome {
+        ResetOutcome::Reset(reset_user) => {
+            disconnect_user(users, reset_user, "password reset").await;
+            Ok("Password changed, you can now log in")
+        }
+        ResetOutcome::Expired => Err(status::Custom(
+            Status::BadRequest,
+            "This reset link has expired, ask for a new one",
+        )),
+        ResetOutcome::Invalid => Err(status::Custom(
+            Status::BadRequest,
+            "This reset link is not valid or was already used",
+        )),
     }
 }
 
@@ -1108,54 +2903,309 @@ fn confirm_email(emailtoken: PathBuf) -> Redirect {
 async fn change_password(
     form: Form<ChangePassword>,
     state: &State<AppState>,
-    session: Session<'_, (i32, i32, String)>,
+    config: &State<ChatConfig>,
+    user: AuthUser,
+    db: &State<DbPool>,
 ) -> Result<&'static str, status::Custom<&'static str>> {
     use rocket_chat::schema::users::dsl::*;
     let change = form.into_inner();
-    if let Ok(Some(user)) = session.get().await {
-        if user.0 == change.user_id {
-            let old_password = decrypt_rsa(change.old_password, state);
-            let new_password = decrypt_rsa(change.new_password, state);
-            let connection = &mut rocket_chat::establish_connection();
-            if let Ok(results) = users
-                .limit(1)
-                .filter(id.eq(change.user_id))
-                .select(UserDB::as_select())
-                .load(connection)
-            {
-                if results.len() > 0 {
-                    if let Ok(_) =
-                        diesel::update(users.filter(id.eq(change.user_id).and(passwd.eq(
-                            hash_password(format!("{}{}{}", old_password, results[0].salt, PEPPER)),
-                        ))))
-                        .set(passwd.eq(hash_password(format!(
-                            "{}{}{}",
-                            new_password, results[0].salt, PEPPER
-                        ))))
-                        .execute(connection)
-                    {
-                        return Ok("fatto");
-                    } else {
-                        return Err(status::Custom(Status::InternalServerError, "db error"));
-                    }
-                }
-            }
-            return Err(status::Custom(Status::Unauthorized, "used not there"));
+    let old_password = decrypt_rsa(change.old_password, state).map_err(rsa_error)?;
+    let new_password = decrypt_rsa(change.new_password, state).map_err(rsa_error)?;
+    let user_id = user.id;
+    let found = run_db(db, move |connection| {
+        users
+            .filter(id.eq(user_id))
+            .select(UserDB::as_select())
+            .first(connection)
+            .optional()
+            .map_err(|_| status::Custom(Status::InternalServerError, "db error"))
+    })
+    .await?
+    .ok_or(status::Custom(Status::Unauthorized, "used not there"))?;
+
+    let pepper = config.pepper.clone();
+    let hashed = run_hashing(move || {
+        if !password::verify(&old_password, &found.passwd, &found.salt, &pepper).is_valid() {
+            return Err(status::Custom(Status::Unauthorized, "wrong password"));
         }
-        return Err(status::Custom(Status::Unauthorized, "incongruent data"));
-    }
-    Err(status::Custom(Status::Unauthorized, "no session found"))
+        password::hash(&new_password, &pepper)
+            .map_err(|_| status::Custom(Status::InternalServerError, "Password hashing error"))
+    })
+    .await??;
+    run_db(db, move |connection| {
+        diesel::update(users.filter(id.eq(user_id)))
+            .set((passwd.eq(hashed), salt.eq("")))
+            .execute(connection)
+            .map(|_| "fatto")
+            .map_err(|_| status::Custom(Status::InternalServerError, "db error"))
+    })
+    .await
 }
 
 #[get("/logout")]
-async fn logout(session: Session<'_, (i32, i32, String)>) -> Redirect {
-    if let Ok(_) = session.remove().await {
+async fn logout(session: Session<'_, AuthUser>) -> Redirect {
+    if session.remove().await.is_ok() {
         Redirect::to(uri!(login_page))
     } else {
         Redirect::to(uri!(chat_page))
     }
 }
 
+const OUTBOX_PAGE_SIZE: i64 = 100;
+
+#[get("/admin/outbox?<status>")]
+async fn admin_outbox(
+    status: Option<String>,
+    user: AuthUser,
+    db: &State<DbPool>,
+) -> Result<Json<Vec<OutboxSummaryDB>>, status::Custom<&'static str>> {
+    if !user.is_admin {
+        return Err(status::Custom(Status::Forbidden, "admin only"));
+    }
+    run_db(db, move |connection| {
+        outbox::list(connection, status.as_deref(), OUTBOX_PAGE_SIZE)
+            .map(Json)
+            .map_err(|_| status::Custom(Status::InternalServerError, "Database error"))
+    })
+    .await
+}
+
+#[post("/admin/outbox/<outbox_id>/resend")]
+async fn admin_outbox_resend(
+    outbox_id: i32,
+    user: AuthUser,
+    db: &State<DbPool>,
+) -> Result<(), status::Custom<&'static str>> {
+    if !user.is_admin {
+        return Err(status::Custom(Status::Forbidden, "admin only"));
+    }
+    run_db(db, move |connection| {
+        match outbox::resend(connection, outbox_id) {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(status::Custom(
+                Status::NotFound,
+                "no failed email with this id",
+            )),
+            Err(_) => Err(status::Custom(
+                Status::InternalServerError,
+                "Database error",
+            )),
+        }
+    })
+    .await
+}
+
+// Load an attachment the user may read. Others are reported as missing
+fn accessible_attachment(
+    connection: &mut MysqlConnection,
+    user_id: i32,
+    upload_id: &str,
+) -> Result<AttachmentDB, status::Custom<&'static str>> {
+    match attachments::find(connection, upload_id).map_err(database_error)? {
+        Some(attachment)
+            if attachments::can_access(connection, user_id, &attachment)
+                .map_err(database_error)? =>
+        {
+            Ok(attachment)
+        }
+        _ => Err(status::Custom(Status::NotFound, "attachment not found")),
+    }
+}
+
+#[post("/attachments", data = "<form>")]
+async fn new_attachment(
+    form: Form<NewAttachment>,
+    user: AuthUser,
+    db: &State<DbPool>,
+    config: &State<ChatConfig>,
+) -> Result<Json<AttachmentStatus>, status::Custom<&'static str>> {
+    let upload = form.into_inner();
+    if !config.attachments.allows(&upload.mime_type) {
+        return Err(status::Custom(
+            Status::UnsupportedMediaType,
+            "attachment type not allowed",
+        ));
+    }
+    if upload.size > config.attachments.max_size {
+        return Err(status::Custom(
+            Status::PayloadTooLarge,
+            "attachment too large",
+        ));
+    }
+    let chunk_size = config.attachments.chunk_size;
+    let uploader = user.id;
+    run_db(db, move |connection| {
+        let scope = match (upload.room_id, upload.recipient_id) {
+            (Some(room), None) => Scope::Room(room),
+            (None, Some(recipient)) => {
+                match chats::direct_between(connection, uploader, recipient)
+                    .map_err(database_error)?
+                {
+                    Some(direct) => Scope::Direct(direct.id),
+                    None => {
+                        return Err(status::Custom(
+                            Status::NotFound,
+                            "no direct chat with this user",
+                        ))
+                    }
+                }
+            }
+            _ => {
+                return Err(status::Custom(
+                    Status::BadRequest,
+                    "either room_id or recipient_id is required",
+                ))
+            }
+        };
+        if !chats::is_member(connection, uploader, scope).map_err(database_error)? {
+            return Err(status::Custom(
+                Status::Forbidden,
+                "not a member of this chat",
+            ));
+        }
+        attachments::create(connection, uploader, scope, &upload.mime_type, upload.size)
+            .map(|attachment| Json(AttachmentStatus::new(&attachment, chunk_size)))
+            .map_err(database_error)
+    })
+    .await
+}
+
+// Where an upload stands, so an interrupted client knows where to resume
+#[get("/attachments/<upload_id>")]
+async fn attachment_status(
+    upload_id: String,
+    user: AuthUser,
+    db: &State<DbPool>,
+    config: &State<ChatConfig>,
+) -> Result<Json<AttachmentStatus>, status::Custom<&'static str>> {
+    let chunk_size = config.attachments.chunk_size;
+    run_db(db, move |connection| {
+        accessible_attachment(connection, user.id, &upload_id)
+            .map(|attachment| Json(AttachmentStatus::new(&attachment, chunk_size)))
+    })
+    .await
+}
+
+// Store the next chunk of an upload. `offset` must equal the bytes received
+// so far; the last chunk completes the upload
+#[put("/attachments/<upload_id>?<offset>", data = "<data>")]
+async fn upload_chunk(
+    upload_id: String,
+    offset: i64,
+    data: Data<'_>,
+    user: AuthUser,
+    db: &State<DbPool>,
+    config: &State<ChatConfig>,
+    store: &State<BlobStore>,
+) -> Result<Json<AttachmentStatus>, status::Custom<&'static str>> {
+    // Read the upload under its lock, so the offset is checked against what
+    // the previous request for it really wrote
+    let _writing = store.lock_upload(&upload_id).await;
+    let uploader = user.id;
+    let mut attachment = run_db(db, move |connection| {
+        match attachments::find(connection, &upload_id).map_err(database_error)? {
+            Some(attachment) if attachment.uploader_id == uploader => Ok(attachment),
+            _ => Err(status::Custom(Status::NotFound, "attachment not found")),
+        }
+    })
+    .await?;
+    let chunk_size = config.attachments.chunk_size;
+    if attachment.completed_at.is_some() {
+        return Ok(Json(AttachmentStatus::new(&attachment, chunk_size)));
+    }
+    if offset != attachment.received {
+        return Err(status::Custom(
+            Status::Conflict,
+            "offset does not match the received bytes",
+        ));
+    }
+
+    // Nothing left to receive when a previous request stored the last chunk
+    // but failed to complete the upload
+    let remaining = (attachment.size - attachment.received) as u64;
+    if remaining > 0 {
+        let chunk = data
+            .open(chunk_size.min(remaining).bytes())
+            .into_bytes()
+            .await
+            .map_err(|_| status::Custom(Status::BadRequest, "unable to read the chunk"))?;
+        if !chunk.is_complete() {
+            return Err(status::Custom(Status::PayloadTooLarge, "chunk too large"));
+        }
+        let chunk = chunk.into_inner();
+        if chunk.is_empty() {
+            return Err(status::Custom(Status::BadRequest, "empty chunk"));
+        }
+        if let Err(err) = store
+            .write_chunk(&attachment.upload_id, offset as u64, &chunk)
+            .await
+        {
+            eprintln!("Failed to store chunk of {}: {}", attachment.upload_id, err);
+            return Err(status::Custom(
+                Status::InternalServerError,
+                "unable to store the chunk",
+            ));
+        }
+        let received = offset + chunk.len() as i64;
+        let id = attachment.id;
+        let moved = run_db(db, move |connection| {
+            attachments::record_progress(connection, id, offset, received).map_err(database_error)
+        })
+        .await?;
+        if !moved {
+            return Err(status::Custom(
+                Status::Conflict,
+                "offset does not match the received bytes",
+            ));
+        }
+        attachment.received = received;
+    }
+
+    if attachment.received == attachment.size {
+        let sha256 = match store.finish(&attachment.upload_id).await {
+            Ok(sha256) => sha256,
+            Err(err) => {
+                eprintln!(
+                    "Failed to complete upload {}: {}",
+                    attachment.upload_id, err
+                );
+                return Err(status::Custom(
+                    Status::InternalServerError,
+                    "unable to store the attachment",
+                ));
+            }
+        };
+        let id = attachment.id;
+        run_db(db, move |connection| {
+            attachments::mark_complete(connection, id, &sha256).map_err(database_error)
+        })
+        .await?;
+        attachment.completed_at = Some(chrono::Utc::now().naive_utc());
+    }
+    Ok(Json(AttachmentStatus::new(&attachment, chunk_size)))
+}
+
+// The encrypted content of a completed upload
+#[get("/attachments/<upload_id>/content")]
+async fn attachment_content(
+    upload_id: String,
+    user: AuthUser,
+    db: &State<DbPool>,
+    store: &State<BlobStore>,
+) -> Result<(ContentType, NamedFile), status::Custom<&'static str>> {
+    let attachment = run_db(db, move |connection| {
+        accessible_attachment(connection, user.id, &upload_id)
+    })
+    .await?;
+    let sha256 = attachment
+        .sha256
+        .ok_or(status::Custom(Status::NotFound, "upload not complete"))?;
+    NamedFile::open(store.blob_path(&sha256))
+        .await
+        .map(|file| (ContentType::Binary, file))
+        .map_err(|_| status::Custom(Status::NotFound, "attachment not found"))
+}
+
 #[get("/events")]
 async fn events(queue: &State<Sender<GroupMessage>>, mut end: Shutdown) -> EventStream![] {
     let mut rx = queue.subscribe();
@@ -1176,75 +3226,32 @@ async fn events(queue: &State<Sender<GroupMessage>>, mut end: Shutdown) -> Event
     }
 }
 
-// Hash a passed password with SHA-512 and returns a String
-fn hash_password(password: String) -> String {
-    let mut hasher = Sha512::new();
-    hasher.update(password);
-    let result = hasher.finalize();
-    String::from_utf8_lossy(&result).to_string()
-}
-
-// Decode a base64 encoded string with RSA
-fn decrypt_rsa(encoded: String, state: &State<AppState>) -> String {
-    let enc_data = BASE64_STANDARD.decode(encoded).unwrap();
-
-    match get_rsa_priv_key_from_state(state).decrypt(Pkcs1v15Encrypt, &enc_data) {
-        Ok(dec_data) => {
-            if let Ok(dec_str) = String::from_utf8(dec_data) {
-                dec_str
-            } else {
-                String::from("ERROR DECODING")
-            }
-        }
-        Err(err) => {
-            format!("ERROR DECRYPTING: {}", err)
-        }
-    }
-}
-
-// Encrypt a message to a base64 string with RSA
-fn encrypt_rsa(message: String, public_key_pem: String) -> Result<String, Box<dyn Error>> {
-    let public_key = RsaPublicKey::from_public_key_pem(&public_key_pem)
-        .map_err(|e| format!("Errore durante la lettura della chiave pubblica PEM: {}", e))?;
-
-    let message_bytes = message.as_bytes();
-
-    let mut rng = rand::thread_rng();
-    let encrypted_message = public_key
-        .encrypt(&mut rng, Pkcs1v15Encrypt, message_bytes)
-        .map_err(|e| format!("Errore durante la crittografia del messaggio: {}", e))?;
-
-    let encrypted_message_base64 = BASE64_STANDARD.encode(encrypted_message);
-    Ok(encrypted_message_base64)
-}
-
-fn generate_key_pair() -> KeyPair {
-    let bits = 2048;
-    let mut rng = rand::thread_rng();
-    let priv_key: RsaPrivateKey =
-        RsaPrivateKey::new(&mut rng, bits).expect("failed to generate a key");
-    let pub_key: RsaPublicKey = RsaPublicKey::from(&priv_key);
-    KeyPair { pub_key, priv_key }
+// Decrypt an RSA envelope sent by a client with the server key it names
+fn decrypt_rsa(encoded: String, state: &State<AppState>) -> Result<String, RsaError> {
+    crypto::decrypt(&state.keys, &encoded, state.allow_pkcs1v15)
 }
 
-fn get_keys(state: &State<AppState>) -> Option<KeyPair> {
-    let data = state.keys.lock().unwrap();
-    data.clone()
+// Encrypt a message for a client, returning an RSA envelope
+fn encrypt_rsa(message: String, public_key_pem: String) -> Result<String, RsaError> {
+    crypto::encrypt_for_client(&message, &public_key_pem)
 }
 
-fn get_rsa_pub_key_from_state(state: &State<AppState>) -> RsaPublicKey {
-    get_keys(state).unwrap().pub_key
+// Reject requests carrying a payload the server cannot decrypt
+fn rsa_error(err: RsaError) -> status::Custom<&'static str> {
+    status::Custom(Status::BadRequest, err.message())
 }
 
-fn get_rsa_priv_key_from_state(state: &State<AppState>) -> RsaPrivateKey {
-    get_keys(state).unwrap().priv_key
+fn database_error(err: diesel::result::Error) -> status::Custom<&'static str> {
+    eprintln!("{}", err);
+    status::Custom(Status::InternalServerError, "Database error")
 }
 
 #[get("/rsa-pub-key")]
-fn get_rsa_pub_key(state: &State<AppState>) -> String {
-    get_rsa_pub_key_from_state(state)
-        .to_pkcs1_pem(LineEnding::default())
-        .unwrap()
+fn get_rsa_pub_key(state: &State<AppState>) -> Json<ServerPubKey> {
+    Json(ServerPubKey {
+        kid: state.keys.current.kid.clone(),
+        key: state.keys.current.public_pem(),
+    })
 }
 
 fn generate_32_byte_random() -> String {
@@ -1255,67 +3262,293 @@ fn generate_32_byte_random() -> String {
     }
 }
 
-fn save_msg_db(msg: ChatMessage) {
-    let connection = &mut rocket_chat::establish_connection();
-
-    match msg {
-        ChatMessage::Direct {
-            sender,
-            recipient,
-            content,
-        } => {
-            if let Ok(direct) = rocket_chat::schema::directs::table
-                .filter(
-                    rocket_chat::schema::directs::user1_id
-                        .eq(sender)
-                        .or(rocket_chat::schema::directs::user2_id.eq(sender)),
-                )
-                .filter(
-                    rocket_chat::schema::directs::user1_id
-                        .eq(recipient)
-                        .or(rocket_chat::schema::directs::user2_id.eq(recipient)),
-                )
-                .select(DirectDB::as_select())
-                .load::<DirectDB>(connection)
-            {
-                if let Ok(_) =
-                    diesel::insert_into(rocket_chat::schema::direct_messages::dsl::direct_messages)
-                        .values((
-                            rocket_chat::schema::direct_messages::chat_id.eq(direct[0].id),
-                            rocket_chat::schema::direct_messages::sender_id.eq(sender),
-                            rocket_chat::schema::direct_messages::message.eq(content),
-                        ))
-                        .execute(connection)
-                {
-                    return;
+// Save a new message. Room messages need their sender to be a member, direct
+// messages an existing direct chat. A `client_msg_id` the sender already used
+// gives back the message saved with it
+async fn save_msg_db(
+    db: &DbPool,
+    msg: ChatMessage,
+    client_msg_id: Option<String>,
+) -> Result<SavedMessage, Refused> {
+    let result = db
+        .run(move |connection| {
+            let scope = match authorize(connection, &msg)? {
+                Ok(scope) => scope,
+                Err(refused) => return Ok(Err(refused)),
+            };
+            let client_msg_id = client_msg_id.as_deref();
+            if let Some(client_msg_id) = client_msg_id {
+                match already_saved(connection, scope, &msg, client_msg_id)? {
+                    Ok(None) => {}
+                    Ok(Some(saved)) => return Ok(Ok(saved)),
+                    Err(refused) => return Ok(Err(refused)),
                 }
             }
-        }
-        ChatMessage::Group {
-            sender_id,
-            group_id,
-            content,
-            ..
-        } => {
-            if let Ok(_) = diesel::insert_into(rocket_chat::schema::messages::dsl::messages)
-                .values((
-                    rocket_chat::schema::messages::room_id.eq(group_id),
-                    rocket_chat::schema::messages::user_id.eq(sender_id),
-                    rocket_chat::schema::messages::content.eq(content),
-                ))
-                .execute(connection)
-            {
-                return;
+            match (
+                insert_chat_message(connection, scope, &msg, client_msg_id),
+                client_msg_id,
+            ) {
+                // Another connection saved the same retry first
+                (
+                    Err(diesel::result::Error::DatabaseError(
+                        diesel::result::DatabaseErrorKind::UniqueViolation,
+                        _,
+                    )),
+                    Some(client_msg_id),
+                ) => Ok(already_saved(connection, scope, &msg, client_msg_id)?
+                    .and_then(|saved| saved.ok_or(Refused::FAILED))),
+                (inserted, _) => inserted,
             }
+        })
+        .await;
+
+    match result {
+        Ok(Ok(saved)) => saved,
+        // Answering a message of another chat
+        Ok(Err(diesel::result::Error::NotFound)) => Err(Refused::denied(Denied::NotFound)),
+        Ok(Err(err)) => {
+            eprintln!("Failed to save message: {:?}", err);
+            Err(Refused::FAILED)
         }
+        Err(err) => {
+            eprintln!("Failed to save message: {}", err);
+            Err(Refused::FAILED)
+        }
+    }
+}
+
+// The chat a new message signed with `sent_by` goes to, refused when its
+// sender is not in the room or has no direct chat with its recipient
+fn authorize(
+    connection: &mut MysqlConnection,
+    msg: &ChatMessage,
+) -> QueryResult<Result<Scope, Refused>> {
+    let (Some(sender), Some(target)) = (msg.sender(), msg.target()) else {
+        // Changes go through `change_message`
+        return Ok(Err(Refused::denied(Denied::Invalid)));
+    };
+    Ok(chats::destination(connection, sender, target.recipient())?.map_err(Refused::not_postable))
+}
+
+// Insert a new message into the chat `authorize` found for it
+fn insert_chat_message(
+    connection: &mut MysqlConnection,
+    scope: Scope,
+    msg: &ChatMessage,
+    client_msg_id: Option<&str>,
+) -> QueryResult<Result<SavedMessage, Refused>> {
+    match (scope, msg) {
+        (
+            Scope::Direct(direct_id),
+            ChatMessage::Direct {
+                sender,
+                content,
+                attachments: upload_ids,
+                reply_to_id,
+                ..
+            },
+        ) => connection
+            .transaction(|connection| {
+                insert_direct_message(
+                    connection,
+                    direct_id,
+                    *sender,
+                    content,
+                    upload_ids,
+                    *reply_to_id,
+                    client_msg_id,
+                )
+            })
+            .map(Ok),
+        (
+            Scope::Room(room_id),
+            ChatMessage::Group {
+                sender_id,
+                content,
+                attachments: upload_ids,
+                reply_to_id,
+                ..
+            },
+        ) => connection
+            .transaction(|connection| {
+                insert_room_message(
+                    connection,
+                    room_id,
+                    *sender_id,
+                    content,
+                    upload_ids,
+                    *reply_to_id,
+                    client_msg_id,
+                )
+            })
+            .map(Ok),
+        _ => Ok(Err(Refused::denied(Denied::Invalid))),
+    }
+}
+
+// The message the sender of `msg` saved with `client_msg_id` in the chat,
+// if any. An id the sender already used in another chat is refused rather
+// than answered with a message of that chat
+fn already_saved(
+    connection: &mut MysqlConnection,
+    scope: Scope,
+    msg: &ChatMessage,
+    client_msg_id: &str,
+) -> QueryResult<Result<Option<SavedMessage>, Refused>> {
+    use rocket_chat::schema::{direct_messages, messages};
+
+    let chat_id = match scope {
+        Scope::Room(id) | Scope::Direct(id) => id,
+    };
+    let found: Vec<(i32, i32, Option<NaiveDateTime>, Option<i32>)> = match (scope, msg) {
+        (Scope::Direct(_), ChatMessage::Direct { sender, .. }) => direct_messages::table
+            .filter(direct_messages::sender_id.eq(*sender))
+            .filter(direct_messages::client_msg_id.eq(client_msg_id))
+            .select((
+                direct_messages::chat_id,
+                direct_messages::id,
+                direct_messages::message_time,
+                direct_messages::thread_id,
+            ))
+            .load(connection)?,
+        (Scope::Room(_), ChatMessage::Group { sender_id, .. }) => messages::table
+            .filter(messages::user_id.eq(*sender_id))
+            .filter(messages::client_msg_id.eq(client_msg_id))
+            .select((
+                messages::room_id,
+                messages::message_id,
+                messages::message_time,
+                messages::thread_id,
+            ))
+            .load(connection)?,
+        _ => return Ok(Ok(None)),
+    };
+    if let Some(&(_, id, sent_at, thread_id)) = found.iter().find(|(chat, ..)| *chat == chat_id) {
+        return Ok(Ok(Some(SavedMessage {
+            id,
+            sent_at,
+            thread_id,
+            duplicate: true,
+        })));
+    }
+    if found.is_empty() {
+        Ok(Ok(None))
+    } else {
+        Ok(Err(Refused::REUSED_CLIENT_ID))
+    }
+}
+
+// Where a new message ended up
+struct SavedMessage {
+    id: i32,
+    sent_at: Option<NaiveDateTime>,
+    thread_id: Option<i32>,
+    // Saved earlier, by a previous try of the same client message
+    duplicate: bool,
+}
+
+// The thread a new message answering `reply_to` joins. Answering a message
+// of another chat fails with `NotFound`
+fn reply_thread(
+    connection: &mut MysqlConnection,
+    scope: Scope,
+    reply_to: Option<i32>,
+) -> QueryResult<Option<i32>> {
+    match reply_to {
+        Some(reply_to) => history::thread_of(connection, scope, reply_to)?
+            .map(Some)
+            .ok_or(diesel::result::Error::NotFound),
+        None => Ok(None),
     }
 }
 
+// Insert a room message and link its attachments, returning its id, time and
+// thread
+fn insert_room_message(
+    connection: &mut MysqlConnection,
+    room_id: i32,
+    sender_id: i32,
+    content: &str,
+    upload_ids: &[String],
+    reply_to: Option<i32>,
+    client_msg_id: Option<&str>,
+) -> QueryResult<SavedMessage> {
+    let thread_id = reply_thread(connection, Scope::Room(room_id), reply_to)?;
+    diesel::insert_into(rocket_chat::schema::messages::table)
+        .values((
+            rocket_chat::schema::messages::room_id.eq(room_id),
+            rocket_chat::schema::messages::user_id.eq(sender_id),
+            rocket_chat::schema::messages::content.eq(content),
+            rocket_chat::schema::messages::reply_to_id.eq(reply_to),
+            rocket_chat::schema::messages::thread_id.eq(thread_id),
+            rocket_chat::schema::messages::client_msg_id.eq(client_msg_id),
+        ))
+        .execute(connection)?;
+    let message_id = diesel::select(last_insert_id()).get_result::<u64>(connection)? as i32;
+    attachments::link(
+        connection,
+        upload_ids,
+        sender_id,
+        Scope::Room(room_id),
+        MessageRef::Room(message_id),
+    )?;
+    let message_time = rocket_chat::schema::messages::table
+        .filter(rocket_chat::schema::messages::message_id.eq(message_id))
+        .select(rocket_chat::schema::messages::message_time)
+        .first(connection)?;
+    Ok(SavedMessage {
+        id: message_id,
+        sent_at: message_time,
+        thread_id,
+        duplicate: false,
+    })
+}
+
+// Insert a direct message and link its attachments, returning its id, time
+// and thread
+fn insert_direct_message(
+    connection: &mut MysqlConnection,
+    direct_id: i32,
+    sender_id: i32,
+    content: &str,
+    upload_ids: &[String],
+    reply_to: Option<i32>,
+    client_msg_id: Option<&str>,
+) -> QueryResult<SavedMessage> {
+    let thread_id = reply_thread(connection, Scope::Direct(direct_id), reply_to)?;
+    diesel::insert_into(rocket_chat::schema::direct_messages::table)
+        .values((
+            rocket_chat::schema::direct_messages::chat_id.eq(direct_id),
+            rocket_chat::schema::direct_messages::sender_id.eq(sender_id),
+            rocket_chat::schema::direct_messages::message.eq(content),
+            rocket_chat::schema::direct_messages::reply_to_id.eq(reply_to),
+            rocket_chat::schema::direct_messages::thread_id.eq(thread_id),
+            rocket_chat::schema::direct_messages::client_msg_id.eq(client_msg_id),
+        ))
+        .execute(connection)?;
+    let message_id = diesel::select(last_insert_id()).get_result::<u64>(connection)? as i32;
+    attachments::link(
+        connection,
+        upload_ids,
+        sender_id,
+        Scope::Direct(direct_id),
+        MessageRef::Direct(message_id),
+    )?;
+    let message_time = rocket_chat::schema::direct_messages::table
+        .filter(rocket_chat::schema::direct_messages::id.eq(message_id))
+        .select(rocket_chat::schema::direct_messages::message_time)
+        .first(connection)?;
+    Ok(SavedMessage {
+        id: message_id,
+        sent_at: message_time,
+        thread_id,
+        duplicate: false,
+    })
+}
+
 #[get("/login")]
-async fn login_page(
-    session: Session<'_, (i32, i32, String)>,
-) -> Result<Option<NamedFile>, Redirect> {
-    if let Ok(Some(_)) = session.get().await {
+async fn login_page(user: Option<AuthUser>) -> Result<Option<NamedFile>, Redirect> {
+    if user.is_some() {
         Err(Redirect::to(uri!(chat_page)))
     } else {
         Ok(NamedFile::open("pages/login.html").await.ok())
@@ -1323,10 +3556,8 @@ async fn login_page(
 }
 
 #[get("/signup")]
-async fn signup_page(
-    session: Session<'_, (i32, i32, String)>,
-) -> Result<Option<NamedFile>, Redirect> {
-    if let Ok(Some(_)) = session.get().await {
+async fn signup_page(user: Option<AuthUser>) -> Result<Option<NamedFile>, Redirect> {
+    if user.is_some() {
         Err(Redirect::to(uri!(chat_page)))
     } else {
         Ok(NamedFile::open("pages/signup.html").await.ok())
@@ -1334,58 +3565,132 @@ async fn signup_page(
 }
 
 #[get("/")]
-async fn chat_page(
-    session: Session<'_, (i32, i32, String)>,
-) -> Result<Option<NamedFile>, Redirect> {
-    if let Ok(Some(_)) = session.get().await {
+async fn chat_page(user: Option<AuthUser>) -> Result<Option<NamedFile>, Redirect> {
+    if user.is_some() {
         Ok(NamedFile::open("pages/chat.html").await.ok())
     } else {
         Err(Redirect::to(uri!(login_page)))
     }
 }
 
-#[launch]
-fn rocket() -> _ {
-    use rocket_chat::schema::rooms::dsl::*;
-    let connection = &mut rocket_chat::establish_connection();
+#[catch(401)]
+fn unauthorized() -> status::Custom<&'static str> {
+    status::Custom(Status::Unauthorized, "no valid session")
+}
 
-    if let Ok(roomsdb) = rooms
-        .filter(room_name.eq("lobby"))
-        .select(RoomDB::as_select())
-        .load(connection)
-    {
-        if roomsdb.len() == 0 {
-            let key = generate_32_byte_random();
-            let sale = generate_32_byte_random();
-            let _ = diesel::insert_into(rooms)
-                .values((
-                    room_name.eq("lobby"),
-                    require_password.eq(false),
-                    hidden_room.eq(false),
-                    aes_key.eq(key),
-                    salt.eq(sale),
-                ))
-                .execute(connection);
+#[rocket::main]
+async fn main() {
+    // Refuse to start without a complete configuration
+    let figment = ChatConfig::figment();
+    let config = match ChatConfig::from_figment(&figment) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    };
+
+    if let Some(result) = run_command(std::env::args().nth(1).as_deref(), &config) {
+        match result {
+            Ok(output) => println!("{}", output),
+            Err(err) => {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            }
         }
+        return;
+    }
+
+    if let Err(err) = rocket(figment, config).launch().await {
+        err.pretty_print();
+        std::process::exit(1);
     }
+}
+
+// Commands that run instead of the server, named by the first argument:
+// `rocket_chat rotate-keys` replaces the server key pair. `None` starts the
+// server
+fn run_command(command: Option<&str>, config: &ChatConfig) -> Option<Result<String, String>> {
+    match command {
+        Some("rotate-keys") => Some(
+            KeyRing::rotate(&config.rsa)
+                .map(|keys| format!("rotated server key, new key id: {}", keys.current.kid))
+                .map_err(|err| err.to_string()),
+        ),
+        _ => None,
+    }
+}
+
+fn rocket(figment: Figment, config: ChatConfig) -> Rocket<Build> {
+    use rocket_chat::schema::rooms::dsl::*;
+
+    let keys = KeyRing::load_or_generate(&config.rsa).expect("failed to load the server key");
+    let db = DbPool::new(&config.database).expect("failed to create the database pool");
+    let mailer = mail::from_config(&config.mail).expect("invalid mail configuration");
+    let blobs =
+        BlobStore::new(&config.attachments.dir).expect("failed to create the attachment directory");
+
+    let lobby = config.lobby_room.clone();
+    db.run_blocking(move |connection| {
+        if let Ok(roomsdb) = rooms
+            .filter(room_name.eq(&lobby))
+            .select(RoomDB::as_select())
+            .load(connection)
+        {
+            if roomsdb.is_empty() {
+                let key = generate_32_byte_random();
+                let sale = generate_32_byte_random();
+                let _ = diesel::insert_into(rooms)
+                    .values((
+                        room_name.eq(&lobby),
+                        require_password.eq(false),
+                        hidden_room.eq(false),
+                        aes_key.eq(key),
+                        salt.eq(sale),
+                    ))
+                    .execute(connection);
+            }
+        }
+    })
+    .expect("failed to connect to the database");
 
-    let memory_store: MemoryStore<(i32, i32, String)> = MemoryStore::default();
-    let store: SessionStore<(i32, i32, String)> = SessionStore {
-        store: Box::new(memory_store),
+    let db_store: DbStore<AuthUser> = DbStore::new(db.clone());
+    let store: SessionStore<AuthUser> = SessionStore {
+        store: Box::new(db_store),
         name: "token".into(),
-        duration: Duration::from_secs(3600 * 24 * 3),
+        duration: Duration::from_secs(3600 * 24 * config.chat_limits.session_days),
         cookie_builder: CookieBuilder::new("", "").path("/"),
     };
 
-    let users: Users = Arc::new(RwLock::new(HashMap::new()));
+    let users: Users = Arc::new(RwLock::new(Hub::new()));
     let groups: Groups = Arc::new(RwLock::new(HashMap::new()));
 
-    rocket::build()
+    rocket::custom(figment)
         .attach(store.fairing())
+        .attach(db_session::purge_fairing(
+            db.clone(),
+            SESSION_PURGE_INTERVAL,
+        ))
+        .attach(attachments::purge_fairing(
+            db.clone(),
+            blobs.clone(),
+            chrono::Duration::hours(config.attachments.abandon_after_hours),
+            UPLOAD_PURGE_INTERVAL,
+        ))
+        .attach(outbox::worker_fairing(
+            db.clone(),
+            mailer,
+            config.outbox.clone(),
+        ))
+        .attach(websocket_shutdown_fairing(users.clone()))
+        .manage(db)
         .manage(channel::<GroupMessage>(1024).0)
         .manage(AppState {
-            keys: Mutex::new(Some(generate_key_pair())),
+            keys,
+            allow_pkcs1v15: config.rsa.allow_pkcs1v15,
         })
+        .manage(blobs)
+        .manage(config)
         .manage(users)
         .manage(Arc::new(RwLock::new(groups)))
         .mount(
@@ -1404,14 +3709,107 @@ fn rocket() -> _ {
                 delete_direct,
                 get_directs,
                 get_rooms,
+                room_messages,
+                direct_messages,
+                room_thread,
+                direct_thread,
+                read_room,
+                read_direct,
+                get_presence,
+                edit_room_message,
+                delete_room_message,
+                room_message_revisions,
+                edit_direct_message,
+                delete_direct_message,
+                direct_message_revisions,
                 login,
                 signup,
                 confirm_email,
+                verify_page,
+                resend_verification,
+                reset_password_page,
+                forgot_password,
+                reset_password,
                 change_password,
                 logout,
                 get_rsa_pub_key,
+                admin_outbox,
+                admin_outbox_resend,
+                new_attachment,
+                attachment_status,
+                upload_chunk,
+                attachment_content,
                 events
             ],
         )
         .mount("/", FileServer::from(relative!("static")))
+        .register("/", catchers![unauthorized])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chat(text: &str) -> ChatMessage {
+        match read_frame(text, protocol::LEGACY) {
+            (_, Ok(Request::Chat(chat_message))) => chat_message,
+            _ => panic!("not a chat message: {}", text),
+        }
+    }
+
+    fn error_code(refused: Refused) -> String {
+        let frame = refused.frame(protocol::LATEST, Some("c1".to_string()));
+        let frame: serde_json::Value = serde_json::from_str(frame.to_text().unwrap()).unwrap();
+        assert_eq!(frame["type"], "error");
+        assert_eq!(frame["client_msg_id"], "c1");
+        frame["code"].as_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn new_messages_are_signed_by_the_session_user() {
+        let mut group = chat(
+            r#"{"Group":{"sender_id":7,"sender_name":"mallory","group_id":3,"content":"hi"}}"#,
+        );
+        group.sent_by(2, "alice");
+        assert_eq!(group.sender(), Some(2));
+        assert!(matches!(
+            &group,
+            ChatMessage::Group { sender_name, group_id: 3, .. } if sender_name == "alice"
+        ));
+
+        let mut direct = chat(r#"{"Direct":{"sender":7,"recipient":3,"content":"hi"}}"#);
+        direct.sent_by(2, "alice");
+        assert_eq!(direct.sender(), Some(2));
+        assert_eq!(direct.target(), Some(TypingTarget::User(3)));
+    }
+
+    #[test]
+    fn rotate_keys_replaces_the_server_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = ChatConfig::default();
+        config.rsa.key_path = dir.path().join("server_key.pem");
+        config.rsa.key_bits = 1024;
+        let old = KeyRing::load_or_generate(&config.rsa).unwrap().current.kid;
+
+        let output = run_command(Some("rotate-keys"), &config).unwrap().unwrap();
+        let keys = KeyRing::load_or_generate(&config.rsa).unwrap();
+        assert_ne!(keys.current.kid, old);
+        assert!(output.ends_with(&keys.current.kid));
+        assert_eq!(keys.previous.unwrap().kid, old);
+
+        assert!(run_command(None, &config).is_none());
+    }
+
+    #[test]
+    fn refusals_carry_stable_codes() {
+        assert_eq!(error_code(Refused::NOT_MEMBER), "forbidden");
+        assert_eq!(error_code(Refused::NO_DIRECT), "not_found");
+        assert_eq!(error_code(Refused::LONG_CLIENT_ID), "invalid");
+        assert_eq!(error_code(Refused::REUSED_CLIENT_ID), "invalid");
+        assert_eq!(error_code(Refused::denied(Denied::NotFound)), "not_found");
+
+        let legacy = Refused::NOT_MEMBER.frame(protocol::LEGACY, None);
+        let legacy: serde_json::Value = serde_json::from_str(legacy.to_text().unwrap()).unwrap();
+        assert_eq!(legacy["Error"]["code"], "forbidden");
+    }
 }
@@ -0,0 +1,381 @@
+use crate::chats::{self, Scope};
+use crate::models::MessageRevisionDB;
+use crate::reactions;
+use crate::schema::{direct_messages, message_revisions, messages};
+use chrono::{NaiveDateTime, Utc};
+use diesel::mysql::MysqlConnection;
+use diesel::prelude::*;
+
+// Why a change to a message was refused
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Denied {
+    NotFound,
+    Forbidden,
+    Deleted,
+    // E.g. a reaction that isn't an emoji
+    Invalid,
+}
+
+impl Denied {
+    pub fn message(&self) -> &'static str {
+        match self {
+            Denied::NotFound => "message not found",
+            Denied::Forbidden => "not allowed to change this message",
+            Denied::Deleted => "message deleted",
+            Denied::Invalid => "invalid change",
+        }
+    }
+
+    // Stable name for clients, e.g. in websocket error frames
+    pub fn code(&self) -> &'static str {
+        match self {
+            Denied::NotFound => "not_found",
+            Denied::Forbidden => "forbidden",
+            Denied::Deleted => "deleted",
+            Denied::Invalid => "invalid",
+        }
+    }
+}
+
+// What a change needs to know about the message, locked until the end of
+// the transaction so concurrent edits keep every revision
+struct Current {
+    author_id: i32,
+    content: String,
+    deleted: bool,
+}
+
+fn now() -> NaiveDateTime {
+    Utc::now().naive_utc()
+}
+
+fn current(
+    connection: &mut MysqlConnection,
+    scope: Scope,
+    message_id: i32,
+) -> QueryResult<Option<Current>> {
+    let row = match scope {
+        Scope::Room(room_id) => messages::table
+            .filter(messages::message_id.eq(message_id))
+            .filter(messages::room_id.eq(room_id))
+            .select((messages::user_id, messages::content, messages::deleted_at))
+            .for_update()
+            .first::<(i32, String, Option<NaiveDateTime>)>(connection)
+            .optional()?,
+        Scope::Direct(direct_id) => direct_messages::table
+            .filter(direct_messages::id.eq(message_id))
+            .filter(direct_messages::chat_id.eq(direct_id))
+            .select((
+                direct_messages::sender_id,
+                direct_messages::message,
+                direct_messages::deleted_at,
+            ))
+            .for_update()
+            .first::<(i32, String, Option<NaiveDateTime>)>(connection)
+            .optional()?,
+    };
+    Ok(row.map(|(author_id, content, deleted_at)| Current {
+        author_id,
+        content,
+        deleted: deleted_at.is_some(),
+    }))
+}
+
+// Whether the message exists in `scope` and is not deleted. Like every
+// change, it stays locked until the end of the transaction
+pub fn check_live(
+    connection: &mut MysqlConnection,
+    scope: Scope,
+    message_id: i32,
+) -> QueryResult<Result<(), Denied>> {
+    Ok(match current(connection, scope, message_id)? {
+        None => Err(Denied::NotFound),
+        Some(current) if current.deleted => Err(Denied::Deleted),
+        Some(_) => Ok(()),
+    })
+}
+
+// Keep the content a change is about to replace
+fn keep_revision(
+    connection: &mut MysqlConnection,
+    scope: Scope,
+    message_id: i32,
+    changed_by: i32,
+    content: &str,
+) -> QueryResult<usize> {
+    let (room_message, direct_message) = match scope {
+        Scope::Room(_) => (Some(message_id), None),
+        Scope::Direct(_) => (None, Some(message_id)),
+    };
+    diesel::insert_into(message_revisions::table)
+        .values((
+            message_revisions::message_id.eq(room_message),
+            message_revisions::direct_message_id.eq(direct_message),
+            message_revisions::changed_by.eq(changed_by),
+            message_revisions::content.eq(content),
+        ))
+        .execute(connection)
+}
+
+fn set_content(
+    connection: &mut MysqlConnection,
+    scope: Scope,
+    message_id: i32,
+    content: &str,
+    edited_at: Option<NaiveDateTime>,
+    deleted_at: Option<NaiveDateTime>,
+) -> QueryResult<usize> {
+    match scope {
+        Scope::Room(_) => {
+            let target = messages::table.filter(messages::message_id.eq(message_id));
+            match deleted_at {
+                Some(_) => diesel::update(target)
+                    .set((
+                        messages::content.eq(content),
+                        messages::deleted_at.eq(deleted_at),
+                    ))
+                    .execute(connection),
+                None => diesel::update(target)
+                    .set((
+                        messages::content.eq(content),
+                        messages::edited_at.eq(edited_at),
+                    ))
+                    .execute(connection),
+            }
+        }
+        Scope::Direct(_) => {
+            let target = direct_messages::table.filter(direct_messages::id.eq(message_id));
+            match deleted_at {
+                Some(_) => diesel::update(target)
+                    .set((
+                        direct_messages::message.eq(content),
+                        direct_messages::deleted_at.eq(deleted_at),
+                    ))
+                    .execute(connection),
+                None => diesel::update(target)
+                    .set((
+                        direct_messages::message.eq(content),
+                        direct_messages::edited_at.eq(edited_at),
+                    ))
+                    .execute(connection),
+            }
+        }
+    }
+}
+
+// Replace the (encrypted) content of a message, keeping the previous one.
+// Only the author may edit, and deleted messages stay deleted. Returns when
+// the message was edited
+pub fn edit(
+    connection: &mut MysqlConnection,
+    scope: Scope,
+    message_id: i32,
+    editor_id: i32,
+    content: &str,
+) -> QueryResult<Result<NaiveDateTime, Denied>> {
+    connection.transaction(|connection| {
+        let Some(current) = current(connection, scope, message_id)? else {
+            return Ok(Err(Denied::NotFound));
+        };
+        if current.deleted {
+            return Ok(Err(Denied::Deleted));
+        }
+        if current.author_id != editor_id {
+            return Ok(Err(Denied::Forbidden));
+        }
+        keep_revision(connection, scope, message_id, editor_id, &current.content)?;
+        let edited_at = now();
+        set_content(
+            connection,
+            scope,
+            message_id,
+            content,
+            Some(edited_at),
+            None,
+        )?;
+        Ok(Ok(edited_at))
+    })
+}
+
+// Retract a message: its content is emptied and kept as a revision. The
+// author, the room moderators and admins may delete. Returns when the
+// message was deleted
+pub fn delete(
+    connection: &mut MysqlConnection,
+    scope: Scope,
+    message_id: i32,
+    user_id: i32,
+    is_admin: bool,
+) -> QueryResult<Result<NaiveDateTime, Denied>> {
+    connection.transaction(|connection| {
+        let Some(current) = current(connection, scope, message_id)? else {
+            return Ok(Err(Denied::NotFound));
+        };
+        if current.deleted {
+            return Ok(Err(Denied::Deleted));
+        }
+        let allowed = current.author_id == user_id
+            || is_admin
+            || match scope {
+                Scope::Room(room_id) => chats::is_moderator(connection, user_id, room_id)?,
+                Scope::Direct(_) => false,
+            };
+        if !allowed {
+            return Ok(Err(Denied::Forbidden));
+        }
+        keep_revision(connection, scope, message_id, user_id, &current.content)?;
+        reactions::clear(connection, scope, message_id)?;
+        let deleted_at = now();
+        set_content(connection, scope, message_id, "", None, Some(deleted_at))?;
+        Ok(Ok(deleted_at))
+    })
+}
+
+// Previous versions of a message, oldest first. Those of deleted messages
+// are not shown
+pub fn revisions(
+    connection: &mut MysqlConnection,
+    scope: Scope,
+    message_id: i32,
+) -> QueryResult<Result<Vec<MessageRevisionDB>, Denied>> {
+    connection.transaction(|connection| {
+        if let Err(denied) = check_live(connection, scope, message_id)? {
+            return Ok(Err(denied));
+        }
+        let query = message_revisions::table
+            .select(MessageRevisionDB::as_select())
+            .order(message_revisions::id.asc())
+            .into_boxed();
+        let query = match scope {
+            Scope::Room(_) => query.filter(message_revisions::message_id.eq(message_id)),
+            Scope::Direct(_) => query.filter(message_revisions::direct_message_id.eq(message_id)),
+        };
+        query.load(connection).map(Ok)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::testing;
+
+    #[test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    fn edits_keep_every_previous_version() {
+        let mut connection = testing::connection();
+        let author = testing::user(&mut connection, "edit_author");
+        let room_id = testing::room(&mut connection, "edits", &[author]);
+        let scope = Scope::Room(room_id);
+        let id = testing::room_message(&mut connection, room_id, author, "first");
+
+        assert!(edit(&mut connection, scope, id, author, "second")
+            .unwrap()
+            .is_ok());
+        assert!(edit(&mut connection, scope, id, author, "third")
+            .unwrap()
+            .is_ok());
+
+        let kept: Vec<String> = revisions(&mut connection, scope, id)
+            .unwrap()
+            .unwrap()
+            .into_iter()
+            .map(|revision| revision.content)
+            .collect();
+        assert_eq!(kept, ["first", "second"]);
+        let (content, edited_at) = messages::table
+            .filter(messages::message_id.eq(id))
+            .select((messages::content, messages::edited_at))
+            .first::<(String, Option<NaiveDateTime>)>(&mut connection)
+            .unwrap();
+        assert_eq!(content, "third");
+        assert!(edited_at.is_some());
+    }
+
+    #[test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    fn only_the_author_edits() {
+        let mut connection = testing::connection();
+        let author = testing::user(&mut connection, "edit_owner");
+        let other = testing::user(&mut connection, "edit_other");
+        let direct_id = testing::direct(&mut connection, author, other);
+        let scope = Scope::Direct(direct_id);
+        let id = testing::direct_message(&mut connection, direct_id, author, "hello");
+
+        assert_eq!(
+            edit(&mut connection, scope, id, other, "changed").unwrap(),
+            Err(Denied::Forbidden)
+        );
+        // A message of another chat is not found through this one
+        assert_eq!(
+            edit(
+                &mut connection,
+                Scope::Direct(direct_id + 1),
+                id,
+                author,
+                "x"
+            )
+            .unwrap(),
+            Err(Denied::NotFound)
+        );
+        assert!(revisions(&mut connection, scope, id)
+            .unwrap()
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    fn deletion_is_for_authors_moderators_and_admins() {
+        let mut connection = testing::connection();
+        let author = testing::user(&mut connection, "delete_author");
+        let member = testing::user(&mut connection, "delete_member");
+        let moderator = testing::user(&mut connection, "delete_moderator");
+        let room_id = testing::room(&mut connection, "deletes", &[author, member, moderator]);
+        diesel::update(
+            crate::schema::rooms_users::table
+                .filter(crate::schema::rooms_users::room_id.eq(room_id))
+                .filter(crate::schema::rooms_users::user_id.eq(moderator)),
+        )
+        .set(crate::schema::rooms_users::moderator.eq(true))
+        .execute(&mut connection)
+        .unwrap();
+        let scope = Scope::Room(room_id);
+        let first = testing::room_message(&mut connection, room_id, author, "one");
+        let second = testing::room_message(&mut connection, room_id, author, "two");
+        let third = testing::room_message(&mut connection, room_id, author, "three");
+
+        assert_eq!(
+            delete(&mut connection, scope, first, member, false).unwrap(),
+            Err(Denied::Forbidden)
+        );
+        assert!(delete(&mut connection, scope, first, moderator, false)
+            .unwrap()
+            .is_ok());
+        assert!(delete(&mut connection, scope, second, member, true)
+            .unwrap()
+            .is_ok());
+        assert!(delete(&mut connection, scope, third, author, false)
+            .unwrap()
+            .is_ok());
+
+        // Deleted messages can't be changed, nor their revisions read
+        assert_eq!(
+            delete(&mut connection, scope, third, author, false).unwrap(),
+            Err(Denied::Deleted)
+        );
+        assert_eq!(
+            edit(&mut connection, scope, third, author, "back").unwrap(),
+            Err(Denied::Deleted)
+        );
+        assert_eq!(
+            revisions(&mut connection, scope, third).unwrap(),
+            Err(Denied::Deleted)
+        );
+        let content: String = messages::table
+            .filter(messages::message_id.eq(third))
+            .select(messages::content)
+            .first(&mut connection)
+            .unwrap();
+        assert_eq!(content, "");
+    }
+}
@@ -1,9 +1,11 @@
 use chrono::NaiveDateTime;
 
 use diesel::prelude::*;
+use rocket::serde::Serialize;
 
 use crate::schema::{
-    admins, direct_messages, directs, email_tokens, messages, rooms, rooms_users, users,
+    admins, attachments, direct_messages, directs, email_outbox, email_tokens, message_reactions,
+    message_revisions, messages, presence, rooms, rooms_users, sessions, users,
 };
 #[derive(Queryable, Selectable, Identifiable, Debug, PartialEq, Associations)]
 #[diesel(belongs_to(UserDB, foreign_key = sender_id))]
@@ -16,6 +18,11 @@ pub struct DirectMessageDB {
     pub sender_id: i32,
     pub message: String,
     pub message_time: Option<NaiveDateTime>,
+    pub edited_at: Option<NaiveDateTime>,
+    pub deleted_at: Option<NaiveDateTime>,
+    // The message this one answers, and the first message of its thread
+    pub reply_to_id: Option<i32>,
+    pub thread_id: Option<i32>,
 }
 
 #[derive(Queryable, Selectable, Identifiable, Debug, PartialEq)]
@@ -40,6 +47,11 @@ pub struct MessageDB {
     pub user_id: i32,
     pub content: String,
     pub message_time: Option<NaiveDateTime>,
+    pub edited_at: Option<NaiveDateTime>,
+    pub deleted_at: Option<NaiveDateTime>,
+    // The message this one answers, and the first message of its thread
+    pub reply_to_id: Option<i32>,
+    pub thread_id: Option<i32>,
 }
 
 #[derive(Queryable, Selectable, Identifiable, Debug, PartialEq)]
@@ -63,6 +75,7 @@ pub struct RoomDB {
 pub struct RoomUserDB {
     pub room_id: i32,
     pub user_id: i32,
+    pub moderator: bool,
 }
 
 #[derive(Queryable, Identifiable, Selectable, Debug, PartialEq)]
@@ -90,8 +103,106 @@ pub struct AdminDB {
 #[derive(Queryable, Identifiable, Selectable, Debug, PartialEq)]
 #[diesel(belongs_to(UserDB, foreign_key = user_id))]
 #[diesel(table_name = email_tokens)]
-#[diesel(primary_key(user_id))]
+#[diesel(primary_key(user_id, purpose))]
 pub struct EmailTokenDB {
     pub user_id: i32,
-    pub token: String,
+    pub created_at: NaiveDateTime,
+    pub purpose: String,
+    pub token_hash: String,
+}
+
+#[derive(Queryable, Identifiable, Selectable, Debug, PartialEq)]
+#[diesel(table_name = sessions)]
+#[diesel(primary_key(id))]
+pub struct SessionDB {
+    pub id: String,
+    pub data: String,
+    pub expires_at: NaiveDateTime,
+    pub user_id: Option<i32>,
+}
+
+#[derive(Queryable, Selectable, Identifiable, Debug, PartialEq)]
+#[diesel(table_name = email_outbox)]
+#[diesel(primary_key(id))]
+pub struct OutboxEmailDB {
+    pub id: i32,
+    pub recipient: String,
+    pub subject: String,
+    pub body: String,
+    pub status: String,
+    pub attempts: i32,
+    pub last_error: Option<String>,
+    pub next_attempt_at: NaiveDateTime,
+    pub created_at: NaiveDateTime,
+    pub sent_at: Option<NaiveDateTime>,
+}
+
+// An email as admins see it. Bodies carry live reset and verification links,
+// so they are never loaded for them
+#[derive(Queryable, Selectable, Identifiable, Debug, PartialEq, Serialize)]
+#[diesel(table_name = email_outbox)]
+#[diesel(primary_key(id))]
+#[serde(crate = "rocket::serde")]
+pub struct OutboxSummaryDB {
+    pub id: i32,
+    pub recipient: String,
+    pub subject: String,
+    pub status: String,
+    pub attempts: i32,
+    pub last_error: Option<String>,
+    pub next_attempt_at: NaiveDateTime,
+    pub created_at: NaiveDateTime,
+    pub sent_at: Option<NaiveDateTime>,
+}
+
+#[derive(Queryable, Selectable, Identifiable, Debug, PartialEq)]
+#[diesel(table_name = attachments)]
+#[diesel(primary_key(id))]
+pub struct AttachmentDB {
+    pub id: i32,
+    pub upload_id: String,
+    pub uploader_id: i32,
+    pub room_id: Option<i32>,
+    pub direct_id: Option<i32>,
+    pub message_id: Option<i32>,
+    pub direct_message_id: Option<i32>,
+    pub mime_type: String,
+    pub size: i64,
+    pub received: i64,
+    pub sha256: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub completed_at: Option<NaiveDateTime>,
+}
+
+#[derive(Queryable, Selectable, Identifiable, Debug, PartialEq)]
+#[diesel(table_name = message_revisions)]
+#[diesel(primary_key(id))]
+pub struct MessageRevisionDB {
+    pub id: i32,
+    pub message_id: Option<i32>,
+    pub direct_message_id: Option<i32>,
+    pub changed_by: i32,
+    pub content: String,
+    pub replaced_at: NaiveDateTime,
+}
+
+#[derive(Queryable, Selectable, Identifiable, Debug, PartialEq)]
+#[diesel(table_name = message_reactions)]
+#[diesel(primary_key(id))]
+pub struct MessageReactionDB {
+    pub id: i32,
+    pub message_id: Option<i32>,
+    pub direct_message_id: Option<i32>,
+    pub user_id: i32,
+    pub emoji: String,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Queryable, Selectable, Identifiable, Debug, PartialEq)]
+#[diesel(table_name = presence)]
+#[diesel(primary_key(user_id))]
+pub struct PresenceDB {
+    pub user_id: i32,
+    pub status: String,
+    pub last_seen_at: Option<NaiveDateTime>,
 }
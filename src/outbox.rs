@@ -0,0 +1,341 @@
+use crate::config::OutboxConfig;
+use crate::db::DbPool;
+use crate::mail::{Email, SharedMailer};
+use crate::models::{OutboxEmailDB, OutboxSummaryDB};
+use crate::schema::email_outbox;
+use chrono::{NaiveDateTime, Utc};
+use diesel::mysql::MysqlConnection;
+use diesel::prelude::*;
+use rocket::fairing::AdHoc;
+use rocket::tokio::time;
+use std::time::Duration;
+
+pub const STATUS_PENDING: &str = "pending";
+pub const STATUS_SENT: &str = "sent";
+pub const STATUS_FAILED: &str = "failed";
+
+// How long a claimed email stays hidden from other workers. If the instance
+// dies while sending, the email is picked up again after this
+const CLAIM_LEASE: chrono::Duration = chrono::Duration::minutes(5);
+
+// Queue an email. Takes a connection so it can run in the same transaction
+// as the change that triggered it
+pub fn enqueue(connection: &mut MysqlConnection, email: &Email) -> QueryResult<usize> {
+    diesel::insert_into(email_outbox::table)
+        .values((
+            email_outbox::recipient.eq(&email.to),
+            email_outbox::subject.eq(&email.subject),
+            email_outbox::body.eq(&email.body),
+            email_outbox::status.eq(STATUS_PENDING),
+            email_outbox::next_attempt_at.eq(now()),
+        ))
+        .execute(connection)
+}
+
+// Emails for the admin view, newest first, optionally only those with
+// `status`. Bodies are left out
+pub fn list(
+    connection: &mut MysqlConnection,
+    status: Option<&str>,
+    limit: i64,
+) -> QueryResult<Vec<OutboxSummaryDB>> {
+    let mut query = email_outbox::table
+        .select(OutboxSummaryDB::as_select())
+        .order(email_outbox::id.desc())
+        .limit(limit)
+        .into_boxed();
+    if let Some(status) = status {
+        query = query.filter(email_outbox::status.eq(status));
+    }
+    query.load(connection)
+}
+
+// Put a failed email back in the queue with a fresh set of attempts.
+// Returns false when there is no failed email with that id
+pub fn resend(connection: &mut MysqlConnection, outbox_id: i32) -> QueryResult<bool> {
+    diesel::update(
+        email_outbox::table
+            .filter(email_outbox::id.eq(outbox_id))
+            .filter(email_outbox::status.eq(STATUS_FAILED)),
+    )
+    .set((
+        email_outbox::status.eq(STATUS_PENDING),
+        email_outbox::attempts.eq(0),
+        email_outbox::last_error.eq(None::<String>),
+        email_outbox::next_attempt_at.eq(now()),
+    ))
+    .execute(connection)
+    .map(|updated| updated == 1)
+}
+
+fn now() -> NaiveDateTime {
+    Utc::now().naive_utc()
+}
+
+// Delay before the next attempt once `attempts` have failed
+fn backoff(config: &OutboxConfig, attempts: i32) -> Duration {
+    let exponent = attempts.saturating_sub(1).clamp(0, 32) as u32;
+    let secs = config
+        .base_backoff_secs
+        .saturating_mul(1u64 << exponent)
+        .min(config.max_backoff_secs);
+    Duration::from_secs(secs)
+}
+
+// Lock the due emails, skipping those another instance is working on, and
+// push them forward by the lease so they are not picked twice
+fn claim_due(connection: &mut MysqlConnection, limit: i64) -> QueryResult<Vec<OutboxEmailDB>> {
+    connection.transaction(|connection| {
+        let due = email_outbox::table
+            .filter(email_outbox::status.eq(STATUS_PENDING))
+            .filter(email_outbox::next_attempt_at.le(now()))
+            .order(email_outbox::next_attempt_at.asc())
+            .limit(limit)
+            .select(OutboxEmailDB::as_select())
+            .for_update()
+            .skip_locked()
+            .load(connection)?;
+        let ids: Vec<i32> = due.iter().map(|e| e.id).collect();
+        diesel::update(email_outbox::table.filter(email_outbox::id.eq_any(ids)))
+            .set(email_outbox::next_attempt_at.eq(now() + CLAIM_LEASE))
+            .execute(connection)?;
+        Ok(due)
+    })
+}
+
+fn record_result(
+    connection: &mut MysqlConnection,
+    email: &OutboxEmailDB,
+    result: Result<(), String>,
+    config: &OutboxConfig,
+) -> QueryResult<usize> {
+    let attempts = email.attempts + 1;
+    let target = email_outbox::table.filter(email_outbox::id.eq(email.id));
+    match result {
+        Ok(()) => diesel::update(target)
+            .set((
+                email_outbox::status.eq(STATUS_SENT),
+                email_outbox::attempts.eq(attempts),
+                email_outbox::last_error.eq(None::<String>),
+                email_outbox::sent_at.eq(Some(now())),
+            ))
+            .execute(connection),
+        Err(err) if attempts >= config.max_attempts => diesel::update(target)
+            .set((
+                email_outbox::status.eq(STATUS_FAILED),
+                email_outbox::attempts.eq(attempts),
+                email_outbox::last_error.eq(Some(err)),
+            ))
+            .execute(connection),
+        Err(err) => {
+            let retry_at = now()
+                + chrono::Duration::from_std(backoff(config, attempts))
+                    .unwrap_or(chrono::Duration::zero());
+            diesel::update(target)
+                .set((
+                    email_outbox::attempts.eq(attempts),
+                    email_outbox::last_error.eq(Some(err)),
+                    email_outbox::next_attempt_at.eq(retry_at),
+                ))
+                .execute(connection)
+        }
+    }
+}
+
+// Send every due email once, returning how many were attempted
+pub async fn deliver_due(
+    db: &DbPool,
+    mailer: &SharedMailer,
+    config: &OutboxConfig,
+) -> Result<usize, String> {
+    let limit = config.batch_size;
+    let due = db
+        .run(move |connection| claim_due(connection, limit))
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| e.to_string())?;
+
+    let attempted = due.len();
+    for email in due {
+        let result = mailer
+            .send(&Email::new(&email.recipient, &email.subject, &email.body))
+            .await
+            .map_err(|e| e.to_string());
+        let outbox_id = email.id;
+        let config = config.clone();
+        // The rest of the batch was claimed too, so keep going. The lease
+        // brings this one back if its result couldn't be stored
+        let recorded = db
+            .run(move |connection| record_result(connection, &email, result, &config))
+            .await
+            .map_err(|e| e.to_string())
+            .and_then(|r| r.map_err(|e| e.to_string()));
+        if let Err(err) = recorded {
+            eprintln!("Failed to record delivery of email {}: {}", outbox_id, err);
+        }
+    }
+    Ok(attempted)
+}
+
+// Fairing that delivers queued emails in the background once the server is up
+pub fn worker_fairing(db: DbPool, mailer: SharedMailer, config: OutboxConfig) -> AdHoc {
+    AdHoc::on_liftoff("Email outbox", move |_| {
+        Box::pin(async move {
+            rocket::tokio::spawn(async move {
+                let mut interval =
+                    time::interval(Duration::from_secs(config.poll_interval_secs.max(1)));
+                loop {
+                    interval.tick().await;
+                    if let Err(err) = deliver_due(&db, &mailer, &config).await {
+                        eprintln!("Failed to deliver queued emails: {}", err);
+                    }
+                }
+            });
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{last_insert_id, testing};
+    use crate::mail::{MailError, Mailer, MemoryMailer};
+    use std::sync::Arc;
+
+    struct FailingMailer;
+
+    #[rocket::async_trait]
+    impl Mailer for FailingMailer {
+        async fn send(&self, _email: &Email) -> Result<(), MailError> {
+            Err(MailError::Send("connection refused".to_string()))
+        }
+    }
+
+    fn queue(connection: &mut MysqlConnection, to: &str) -> i32 {
+        enqueue(connection, &Email::new(to, "Hello", "Body")).unwrap();
+        diesel::select(last_insert_id())
+            .get_result::<u64>(connection)
+            .unwrap() as i32
+    }
+
+    fn load(connection: &mut MysqlConnection, outbox_id: i32) -> OutboxEmailDB {
+        email_outbox::table
+            .find(outbox_id)
+            .select(OutboxEmailDB::as_select())
+            .first(connection)
+            .unwrap()
+    }
+
+    // Make a waiting email due right away
+    fn make_due(connection: &mut MysqlConnection, outbox_id: i32) {
+        diesel::update(email_outbox::table.find(outbox_id))
+            .set(email_outbox::next_attempt_at.eq(now() - chrono::Duration::seconds(1)))
+            .execute(connection)
+            .unwrap();
+    }
+
+    #[test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    fn claimed_emails_are_not_claimed_again() {
+        let mut connection = testing::connection();
+        let outbox_id = queue(&mut connection, "claim@example.com");
+        let claimed = claim_due(&mut connection, i64::MAX).unwrap();
+        assert!(claimed.iter().any(|e| e.id == outbox_id));
+        let again = claim_due(&mut connection, i64::MAX).unwrap();
+        assert!(again.iter().all(|e| e.id != outbox_id));
+        assert!(load(&mut connection, outbox_id).next_attempt_at > now());
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn sent_emails_are_marked_sent() {
+        let db = testing::pool();
+        let outbox_id = db
+            .run(|connection| queue(connection, "sent@example.com"))
+            .await
+            .unwrap();
+        let memory = MemoryMailer::new();
+        let mailer: SharedMailer = Arc::new(memory.clone());
+        let config = OutboxConfig {
+            batch_size: i64::MAX,
+            ..OutboxConfig::default()
+        };
+        deliver_due(&db, &mailer, &config).await.unwrap();
+
+        let email = db
+            .run(move |connection| load(connection, outbox_id))
+            .await
+            .unwrap();
+        assert_eq!(email.status, STATUS_SENT);
+        assert_eq!(email.attempts, 1);
+        assert!(email.sent_at.is_some());
+        assert!(memory.sent().iter().any(|e| e.to == "sent@example.com"));
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn failing_emails_are_retried_then_failed_and_resent() {
+        let db = testing::pool();
+        let outbox_id = db
+            .run(|connection| queue(connection, "fail@example.com"))
+            .await
+            .unwrap();
+        let mailer: SharedMailer = Arc::new(FailingMailer);
+        let config = OutboxConfig {
+            batch_size: i64::MAX,
+            max_attempts: 2,
+            ..OutboxConfig::default()
+        };
+
+        deliver_due(&db, &mailer, &config).await.unwrap();
+        let email = db
+            .run(move |connection| load(connection, outbox_id))
+            .await
+            .unwrap();
+        assert_eq!(email.status, STATUS_PENDING);
+        assert_eq!(email.attempts, 1);
+        assert!(email.last_error.is_some());
+        assert!(email.next_attempt_at > now());
+
+        db.run(move |connection| make_due(connection, outbox_id))
+            .await
+            .unwrap();
+        deliver_due(&db, &mailer, &config).await.unwrap();
+        let email = db
+            .run(move |connection| load(connection, outbox_id))
+            .await
+            .unwrap();
+        assert_eq!(email.status, STATUS_FAILED);
+        assert_eq!(email.attempts, 2);
+
+        let resent = db
+            .run(move |connection| resend(connection, outbox_id))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(resent);
+        let email = db
+            .run(move |connection| load(connection, outbox_id))
+            .await
+            .unwrap();
+        assert_eq!(email.status, STATUS_PENDING);
+        assert_eq!(email.attempts, 0);
+        assert_eq!(email.last_error, None);
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum() {
+        let config = OutboxConfig {
+            base_backoff_secs: 30,
+            max_backoff_secs: 3600,
+            ..OutboxConfig::default()
+        };
+        assert_eq!(backoff(&config, 0), Duration::from_secs(30));
+        assert_eq!(backoff(&config, 1), Duration::from_secs(30));
+        assert_eq!(backoff(&config, 2), Duration::from_secs(60));
+        assert_eq!(backoff(&config, 3), Duration::from_secs(120));
+        assert_eq!(backoff(&config, 8), Duration::from_secs(3600));
+        // Never overflows, however many attempts failed
+        assert_eq!(backoff(&config, i32::MAX), Duration::from_secs(3600));
+    }
+}
@@ -0,0 +1,207 @@
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::{Algorithm, Argon2, Params, Version};
+use rand::RngCore;
+use sha2::{Digest, Sha512};
+use std::fmt;
+use std::sync::OnceLock;
+
+// Outcome of checking a password against a stored hash
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verification {
+    Invalid,
+    Valid,
+    // Valid, but stored with a legacy scheme or outdated parameters: the
+    // caller should store a fresh `hash` of the password
+    ValidNeedsRehash,
+}
+
+impl Verification {
+    pub fn is_valid(self) -> bool {
+        self != Verification::Invalid
+    }
+}
+
+#[derive(Debug)]
+pub struct PasswordError(argon2::password_hash::Error);
+
+impl fmt::Display for PasswordError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "password hashing failed: {}", self.0)
+    }
+}
+
+impl std::error::Error for PasswordError {}
+
+// Current hashing parameters. Stored hashes are PHC strings
+// (`$argon2id$v=19$m=...,t=...,p=...$salt$hash`), so they carry their own
+// version and parameters and can be upgraded when these change
+fn params() -> Params {
+    Params::DEFAULT
+}
+
+// The pepper is passed to Argon2 as its secret key, so it never ends up in the
+// stored hash
+fn hasher(pepper: &str) -> Result<Argon2<'_>, PasswordError> {
+    Argon2::new_with_secret(
+        pepper.as_bytes(),
+        Algorithm::Argon2id,
+        Version::V0x13,
+        params(),
+    )
+    .map_err(|e| PasswordError(e.into()))
+}
+
+// Hash a password with Argon2id, returning a PHC string
+pub fn hash(password: &str, pepper: &str) -> Result<String, PasswordError> {
+    let mut salt = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut salt);
+    let salt = SaltString::encode_b64(&salt).map_err(PasswordError)?;
+
+    hasher(pepper)?
+        .hash_password(password.as_bytes(), &salt)
+        .map(|h| h.to_string())
+        .map_err(PasswordError)
+}
+
+// Check a password against a stored hash. `legacy_salt` is only used for
+// hashes created before Argon2 was introduced. Those are raw SHA-512 bytes,
+// which may start with `$` too, so anything that isn't a PHC string is one
+pub fn verify(password: &str, stored: &str, legacy_salt: &str, pepper: &str) -> Verification {
+    let parsed = match PasswordHash::new(stored) {
+        Ok(parsed) => parsed,
+        Err(_) => {
+            return if legacy_hash(format!("{}{}{}", password, legacy_salt, pepper)) == stored {
+                Verification::ValidNeedsRehash
+            } else {
+                Verification::Invalid
+            };
+        }
+    };
+    let argon2 = match hasher(pepper) {
+        Ok(argon2) => argon2,
+        Err(_) => return Verification::Invalid,
+    };
+    if argon2
+        .verify_password(password.as_bytes(), &parsed)
+        .is_err()
+    {
+        return Verification::Invalid;
+    }
+
+    let current = params();
+    let up_to_date = parsed.algorithm == Algorithm::Argon2id.ident()
+        && parsed.version == Some(Version::V0x13.into())
+        && Params::try_from(&parsed).is_ok_and(|p| {
+            p.m_cost() == current.m_cost()
+                && p.t_cost() == current.t_cost()
+                && p.p_cost() == current.p_cost()
+        });
+    if up_to_date {
+        Verification::Valid
+    } else {
+        Verification::ValidNeedsRehash
+    }
+}
+
+// Spend the time of a `verify` when there is no stored hash to check, e.g.
+// for an unknown username, so response times don't tell which exist. Always
+// `Invalid`
+pub fn verify_absent(password: &str, pepper: &str) -> Verification {
+    let _ = verify(password, dummy_hash(), "", pepper);
+    Verification::Invalid
+}
+
+// A hash with the current parameters that no real password matches
+fn dummy_hash() -> &'static str {
+    static DUMMY: OnceLock<String> = OnceLock::new();
+    DUMMY.get_or_init(|| hash("no such user", "").unwrap_or_default())
+}
+
+// The original scheme: one round of SHA-512 over password + salt + pepper
+fn legacy_hash(password: String) -> String {
+    let mut hasher = Sha512::new();
+    hasher.update(password);
+    let result = hasher.finalize();
+    String::from_utf8_lossy(&result).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PEPPER: &str = "pepper";
+
+    #[test]
+    fn fresh_hashes_verify() {
+        let stored = hash("correct horse", PEPPER).unwrap();
+        assert!(stored.starts_with("$argon2id$"));
+        assert_eq!(
+            verify("correct horse", &stored, "", PEPPER),
+            Verification::Valid
+        );
+        assert_eq!(
+            verify("wrong horse", &stored, "", PEPPER),
+            Verification::Invalid
+        );
+        // The pepper is part of the key
+        assert_eq!(
+            verify("correct horse", &stored, "", "other"),
+            Verification::Invalid
+        );
+    }
+
+    #[test]
+    fn legacy_hashes_verify_and_need_a_rehash() {
+        let stored = legacy_hash(format!("{}{}{}", "secret", "salt", PEPPER));
+        assert_eq!(
+            verify("secret", &stored, "salt", PEPPER),
+            Verification::ValidNeedsRehash
+        );
+        assert_eq!(
+            verify("secret", &stored, "other salt", PEPPER),
+            Verification::Invalid
+        );
+    }
+
+    #[test]
+    fn legacy_hashes_starting_with_a_dollar_verify() {
+        let (password, stored) = (0..)
+            .map(|n| format!("password {}", n))
+            .map(|password| {
+                let stored = legacy_hash(format!("{}{}{}", password, "salt", PEPPER));
+                (password, stored)
+            })
+            .find(|(_, stored)| stored.starts_with('$'))
+            .unwrap();
+        assert_eq!(
+            verify(&password, &stored, "salt", PEPPER),
+            Verification::ValidNeedsRehash
+        );
+    }
+
+    #[test]
+    fn absent_users_cost_a_full_verification() {
+        assert_eq!(verify_absent("anything", PEPPER), Verification::Invalid);
+        // The dummy is a real, up to date hash, so checking it costs as much
+        assert_eq!(
+            verify("no such user", dummy_hash(), "", ""),
+            Verification::Valid
+        );
+        assert_eq!(verify_absent("no such user", ""), Verification::Invalid);
+    }
+
+    #[test]
+    fn outdated_parameters_need_a_rehash() {
+        let weak = Params::new(8 * 1024, 1, 1, None).unwrap();
+        let argon2 =
+            Argon2::new_with_secret(PEPPER.as_bytes(), Algorithm::Argon2id, Version::V0x13, weak)
+                .unwrap();
+        let salt = SaltString::encode_b64(b"sixteen byte slt").unwrap();
+        let stored = argon2.hash_password(b"secret", &salt).unwrap().to_string();
+        assert_eq!(
+            verify("secret", &stored, "", PEPPER),
+            Verification::ValidNeedsRehash
+        );
+        assert!(!verify("other", &stored, "", PEPPER).is_valid());
+    }
+}
@@ -0,0 +1,85 @@
+use crate::email_tokens::{self, Redeemed, PURPOSE_RESET};
+use crate::mail::Email;
+use crate::outbox;
+use crate::schema::users;
+use crate::session;
+use crate::verification::{self, ResendOutcome};
+use chrono::Duration;
+use diesel::mysql::MysqlConnection;
+use diesel::prelude::*;
+
+// Result of submitting a new password with a reset link
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResetOutcome {
+    // The password was changed for this user and their sessions revoked
+    Reset(i32),
+    Expired,
+    Invalid,
+}
+
+// Email a reset link for the account owning `address`, at most once per
+// `cooldown`
+pub fn request(
+    connection: &mut MysqlConnection,
+    address: &str,
+    cooldown: Duration,
+    link_prefix: &str,
+) -> QueryResult<ResendOutcome> {
+    let user = match verification::user_for_resend(connection, address, PURPOSE_RESET, cooldown)? {
+        Ok(user) => user,
+        Err(outcome) => return Ok(outcome),
+    };
+    connection.transaction(|connection| {
+        let token = email_tokens::issue(connection, user.id, PURPOSE_RESET)?;
+        outbox::enqueue(
+            connection,
+            &Email::new(
+                &user.email,
+                "Password reset",
+                &format!(
+                    "Someone asked to reset the password of your account. If it was you, open this link: {}{}\n\
+                     If it wasn't, you can ignore this email.",
+                    link_prefix, token
+                ),
+            ),
+        )?;
+        Ok(ResendOutcome::Queued)
+    })
+}
+
+// Whether a reset link can still be used, without using it up. Checked
+// before hashing the new password, so dead links never cost a hash
+pub fn check(
+    connection: &mut MysqlConnection,
+    token: &str,
+    ttl: Duration,
+) -> QueryResult<Redeemed> {
+    email_tokens::check(connection, token, PURPOSE_RESET, ttl)
+}
+
+// Redeem a reset token and store `new_hash` as the password. Following the
+// link proves the user owns the address, so it also counts as verification
+pub fn reset(
+    connection: &mut MysqlConnection,
+    token: &str,
+    new_hash: &str,
+    ttl: Duration,
+) -> QueryResult<ResetOutcome> {
+    connection.transaction(|connection| {
+        match email_tokens::redeem(connection, token, PURPOSE_RESET, ttl)? {
+            Redeemed::User(user_id) => {
+                diesel::update(users::table.filter(users::id.eq(user_id)))
+                    .set((
+                        users::passwd.eq(new_hash),
+                        users::salt.eq(""),
+                        users::email_verified.eq(true),
+                    ))
+                    .execute(connection)?;
+                session::revoke_user(connection, user_id)?;
+                Ok(ResetOutcome::Reset(user_id))
+            }
+            Redeemed::Expired => Ok(ResetOutcome::Expired),
+            Redeemed::Invalid => Ok(ResetOutcome::Invalid),
+        }
+    })
+}
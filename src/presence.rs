@@ -0,0 +1,203 @@
+use crate::models::PresenceDB;
+use crate::schema::{directs, presence, rooms_users};
+use chrono::{NaiveDateTime, Utc};
+use diesel::mysql::MysqlConnection;
+use diesel::prelude::*;
+use rocket::serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+
+// Largest number of users a single presence query may ask about
+pub const MAX_QUERY: usize = 200;
+
+// What others see of a user. Online, away and do not disturb are chosen by
+// the user and only shown while they are connected
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum Status {
+    Online,
+    Away,
+    #[serde(rename = "dnd")]
+    DoNotDisturb,
+    Offline,
+}
+
+impl Status {
+    fn as_str(self) -> &'static str {
+        match self {
+            Status::Online => "online",
+            Status::Away => "away",
+            Status::DoNotDisturb => "dnd",
+            Status::Offline => "offline",
+        }
+    }
+
+    fn parse(status: &str) -> Status {
+        match status {
+            "away" => Status::Away,
+            "dnd" => Status::DoNotDisturb,
+            _ => Status::Online,
+        }
+    }
+}
+
+// The status chosen by the user, online unless they picked another
+pub fn chosen(connection: &mut MysqlConnection, user_id: i32) -> QueryResult<Status> {
+    presence::table
+        .filter(presence::user_id.eq(user_id))
+        .select(presence::status)
+        .first::<String>(connection)
+        .optional()
+        .map(|status| status.map_or(Status::Online, |status| Status::parse(&status)))
+}
+
+// Remember the status the user picked. Offline isn't a choice, it only
+// comes from disconnecting
+pub fn choose(connection: &mut MysqlConnection, user_id: i32, status: Status) -> QueryResult<bool> {
+    if status == Status::Offline {
+        return Ok(false);
+    }
+    diesel::insert_or_ignore_into(presence::table)
+        .values(presence::user_id.eq(user_id))
+        .execute(connection)?;
+    diesel::update(presence::table.filter(presence::user_id.eq(user_id)))
+        .set(presence::status.eq(status.as_str()))
+        .execute(connection)?;
+    Ok(true)
+}
+
+// Record that the user was last seen now, returning the time
+pub fn seen(connection: &mut MysqlConnection, user_id: i32) -> QueryResult<NaiveDateTime> {
+    let now = Utc::now().naive_utc();
+    diesel::insert_or_ignore_into(presence::table)
+        .values(presence::user_id.eq(user_id))
+        .execute(connection)?;
+    diesel::update(presence::table.filter(presence::user_id.eq(user_id)))
+        .set(presence::last_seen_at.eq(Some(now)))
+        .execute(connection)?;
+    Ok(now)
+}
+
+// The stored presence of the given users, by user id. Users who never
+// connected have none
+pub fn for_users(
+    connection: &mut MysqlConnection,
+    user_ids: &[i32],
+) -> QueryResult<HashMap<i32, PresenceDB>> {
+    if user_ids.is_empty() {
+        return Ok(HashMap::new());
+    }
+    let found: Vec<PresenceDB> = presence::table
+        .filter(presence::user_id.eq_any(user_ids))
+        .select(PresenceDB::as_select())
+        .load(connection)?;
+    Ok(found.into_iter().map(|p| (p.user_id, p)).collect())
+}
+
+// Users sharing a room or a direct chat with `user_id`, who get to see their
+// presence
+pub fn contacts(connection: &mut MysqlConnection, user_id: i32) -> QueryResult<BTreeSet<i32>> {
+    let rooms: Vec<i32> = rooms_users::table
+        .filter(rooms_users::user_id.eq(user_id))
+        .select(rooms_users::room_id)
+        .load(connection)?;
+    let mut contacts: BTreeSet<i32> = rooms_users::table
+        .filter(rooms_users::room_id.eq_any(&rooms))
+        .select(rooms_users::user_id)
+        .distinct()
+        .load::<i32>(connection)?
+        .into_iter()
+        .collect();
+    let directs: Vec<(i32, i32)> = directs::table
+        .filter(
+            directs::user1_id
+                .eq(user_id)
+                .or(directs::user2_id.eq(user_id)),
+        )
+        .select((directs::user1_id, directs::user2_id))
+        .load(connection)?;
+    for (user1_id, user2_id) in directs {
+        contacts.insert(user1_id);
+        contacts.insert(user2_id);
+    }
+    contacts.remove(&user_id);
+    Ok(contacts)
+}
+
+// The status others see: the chosen one while connected, offline otherwise
+pub fn shown(stored: Option<&PresenceDB>, connected: bool) -> Status {
+    match (connected, stored) {
+        (false, _) => Status::Offline,
+        (true, Some(stored)) => Status::parse(&stored.status),
+        (true, None) => Status::Online,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::testing;
+
+    #[test]
+    fn others_see_the_chosen_status_only_while_connected() {
+        let away = PresenceDB {
+            user_id: 1,
+            status: String::from("away"),
+            last_seen_at: None,
+        };
+        assert_eq!(shown(Some(&away), true), Status::Away);
+        assert_eq!(shown(Some(&away), false), Status::Offline);
+        assert_eq!(shown(None, true), Status::Online);
+        assert_eq!(shown(None, false), Status::Offline);
+    }
+
+    #[test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    fn chosen_statuses_are_kept() {
+        let mut connection = testing::connection();
+        let user_id = testing::user(&mut connection, "presence_chooser");
+        assert_eq!(chosen(&mut connection, user_id).unwrap(), Status::Online);
+        assert!(choose(&mut connection, user_id, Status::DoNotDisturb).unwrap());
+        assert!(!choose(&mut connection, user_id, Status::Offline).unwrap());
+        assert_eq!(
+            chosen(&mut connection, user_id).unwrap(),
+            Status::DoNotDisturb
+        );
+
+        // Being seen keeps the status
+        let seen_at = seen(&mut connection, user_id).unwrap();
+        let stored = for_users(&mut connection, &[user_id]).unwrap();
+        assert_eq!(stored[&user_id].status, "dnd");
+        assert_eq!(
+            stored[&user_id]
+                .last_seen_at
+                .map(|at| at.and_utc().timestamp()),
+            Some(seen_at.and_utc().timestamp())
+        );
+    }
+
+    #[test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    fn contacts_share_a_room_or_a_direct_chat() {
+        let mut connection = testing::connection();
+        let user_id = testing::user(&mut connection, "presence_user");
+        let roommate = testing::user(&mut connection, "presence_roommate");
+        let peer = testing::user(&mut connection, "presence_peer");
+        let stranger = testing::user(&mut connection, "presence_stranger");
+        // New users join the lobby, which would make everyone a contact
+        diesel::delete(
+            rooms_users::table
+                .filter(rooms_users::user_id.eq_any([user_id, roommate, peer, stranger])),
+        )
+        .execute(&mut connection)
+        .unwrap();
+        testing::room(&mut connection, "presence", &[user_id, roommate]);
+        testing::direct(&mut connection, peer, user_id);
+
+        let found = contacts(&mut connection, user_id).unwrap();
+        assert_eq!(found, BTreeSet::from([roommate, peer]));
+        assert_eq!(
+            contacts(&mut connection, stranger).unwrap(),
+            BTreeSet::new()
+        );
+    }
+}
@@ -0,0 +1,245 @@
+use rocket::serde::json::serde_json::{self, Value};
+use rocket::serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::collections::HashMap;
+
+// Versions of the websocket protocol. Version 1 sends bare chat messages both
+// ways. Version 2 wraps every frame in an `Envelope`, names client messages
+// with a `client_msg_id` and answers each of them with an `ack` or an `error`
+pub const LEGACY: u32 = 1;
+pub const LATEST: u32 = 2;
+
+// Longest `client_msg_id` kept with a message
+pub const MAX_CLIENT_MSG_ID_LEN: usize = 64;
+
+// The version spoken on a connection, from the `v` the client asked for when
+// connecting. Clients that don't ask speak version 1
+pub fn negotiate(requested: Option<u32>) -> u32 {
+    requested.unwrap_or(LEGACY).clamp(LEGACY, LATEST)
+}
+
+// A version 2 frame: `{"v": 2, "type": "...", ...}`
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Envelope<T> {
+    pub v: u32,
+    #[serde(flatten)]
+    pub frame: Frame<T>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde", tag = "type", rename_all = "lowercase")]
+pub enum Frame<T> {
+    // First frame from the server, with the newest version it speaks
+    Hello {
+        latest: u32,
+    },
+    // A chat message from the client. `client_msg_id` is generated by the
+    // client and kept on retries, so a message is saved once
+    Chat {
+        #[serde(default)]
+        client_msg_id: Option<String>,
+        body: T,
+    },
+    // Sent by a client that reconnects, with the newest message id it has of
+    // each room, and of each direct chat by the other user's id. Chats left
+    // out are caught up from the stored delivery cursor. JSON keys are
+    // strings, and tagged enums can't read them as numbers
+    Resume {
+        #[serde(default)]
+        rooms: HashMap<String, i32>,
+        #[serde(default)]
+        directs: HashMap<String, i32>,
+    },
+    // The messages of one chat missed since, oldest first, in the history
+    // format. `truncated` when older ones were left out and the chat should
+    // be reloaded
+    Missed {
+        room_id: Option<i32>,
+        user_id: Option<i32>,
+        messages: Value,
+        truncated: bool,
+    },
+    // Every missed message was sent, live ones follow
+    Resumed,
+    // A chat message from the server, on behalf of someone else
+    Event {
+        body: T,
+    },
+    // The client message was handled. New messages carry the `id` and
+    // `sent_at` they were saved with, and `duplicate` when a retry found the
+    // message already saved
+    Ack {
+        client_msg_id: Option<String>,
+        id: Option<i32>,
+        sent_at: Option<String>,
+        #[serde(default)]
+        duplicate: bool,
+        body: Option<T>,
+    },
+    // The client message was refused, see `code`
+    Error {
+        client_msg_id: Option<String>,
+        code: String,
+        message: String,
+    },
+}
+
+impl<T: Serialize> Envelope<T> {
+    pub fn new(frame: Frame<T>) -> Envelope<T> {
+        Envelope { v: LATEST, frame }
+    }
+
+    pub fn to_text(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
+
+// Why an incoming frame can't be handled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unreadable {
+    Malformed,
+    WrongVersion,
+    // Only `chat` and `resume` frames come from clients
+    WrongType,
+}
+
+// What a client frame asks for
+#[derive(Debug)]
+pub enum Request<T> {
+    Chat(T),
+    Resume {
+        rooms: HashMap<i32, i32>,
+        directs: HashMap<i32, i32>,
+    },
+}
+
+// A client frame with its `client_msg_id`. The id is also returned on
+// failure when it could be read, so the error names it
+pub type Parsed<T> = Result<(Option<String>, Request<T>), (Option<String>, Unreadable)>;
+
+pub fn parse_request<T: DeserializeOwned>(text: &str, version: u32) -> Parsed<T> {
+    let value: Value = serde_json::from_str(text).map_err(|_| (None, Unreadable::Malformed))?;
+    let client_msg_id = value
+        .get("client_msg_id")
+        .and_then(Value::as_str)
+        .map(str::to_string);
+    if value.get("v").and_then(Value::as_u64) != Some(u64::from(version)) {
+        return Err((client_msg_id, Unreadable::WrongVersion));
+    }
+    match serde_json::from_value::<Envelope<T>>(value) {
+        Ok(Envelope {
+            frame: Frame::Chat {
+                client_msg_id,
+                body,
+            },
+            ..
+        }) => Ok((client_msg_id, Request::Chat(body))),
+        Ok(Envelope {
+            frame: Frame::Resume { rooms, directs },
+            ..
+        }) => match (chat_ids(rooms), chat_ids(directs)) {
+            (Some(rooms), Some(directs)) => Ok((client_msg_id, Request::Resume { rooms, directs })),
+            _ => Err((client_msg_id, Unreadable::Malformed)),
+        },
+        Ok(_) => Err((client_msg_id, Unreadable::WrongType)),
+        Err(_) => Err((client_msg_id, Unreadable::Malformed)),
+    }
+}
+
+// Chat ids keyed by their JSON string, `None` when one isn't a number
+fn chat_ids(ids: HashMap<String, i32>) -> Option<HashMap<i32, i32>> {
+    ids.into_iter()
+        .map(|(chat, id)| chat.parse().ok().map(|chat| (chat, id)))
+        .collect()
+}
+
+// Wrap a chat message serialized for version 1 into an `event` frame
+pub fn wrap_event(text: &str) -> Option<String> {
+    let body: Value = serde_json::from_str(text).ok()?;
+    Some(Envelope::new(Frame::Event { body }).to_text())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    #[serde(crate = "rocket::serde")]
+    struct Body {
+        text: String,
+    }
+
+    fn parse(text: &str) -> Parsed<Body> {
+        parse_request(text, LATEST)
+    }
+
+    #[test]
+    fn versions_are_clamped() {
+        assert_eq!(negotiate(None), LEGACY);
+        assert_eq!(negotiate(Some(0)), LEGACY);
+        assert_eq!(negotiate(Some(2)), LATEST);
+        assert_eq!(negotiate(Some(99)), LATEST);
+    }
+
+    #[test]
+    fn chat_frames_carry_their_client_id() {
+        let parsed =
+            parse(r#"{"v": 2, "type": "chat", "client_msg_id": "m1", "body": {"text": "hi"}}"#);
+        match parsed {
+            Ok((Some(id), Request::Chat(body))) => {
+                assert_eq!(id, "m1");
+                assert_eq!(body.text, "hi");
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(matches!(
+            parse(r#"{"v": 2, "type": "chat", "body": {"text": "hi"}}"#),
+            Ok((None, Request::Chat(_)))
+        ));
+    }
+
+    #[test]
+    fn resume_frames_default_to_no_chats() {
+        match parse(r#"{"v": 2, "type": "resume", "rooms": {"3": 40}}"#) {
+            Ok((None, Request::Resume { rooms, directs })) => {
+                assert_eq!(rooms.get(&3), Some(&40));
+                assert!(directs.is_empty());
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(matches!(
+            parse(r#"{"v": 2, "type": "resume", "directs": {"x": 40}}"#),
+            Err((None, Unreadable::Malformed))
+        ));
+    }
+
+    #[test]
+    fn unreadable_frames_keep_the_client_id_when_they_can() {
+        assert!(matches!(
+            parse("not json"),
+            Err((None, Unreadable::Malformed))
+        ));
+        assert!(matches!(
+            parse(r#"{"v": 1, "type": "chat", "client_msg_id": "m1", "body": {"text": "hi"}}"#),
+            Err((Some(id), Unreadable::WrongVersion)) if id == "m1"
+        ));
+        assert!(matches!(
+            parse(r#"{"v": 2, "type": "chat", "client_msg_id": "m1", "body": {}}"#),
+            Err((Some(id), Unreadable::Malformed)) if id == "m1"
+        ));
+        assert!(matches!(
+            parse(r#"{"v": 2, "type": "hello", "latest": 2}"#),
+            Err((None, Unreadable::WrongType))
+        ));
+    }
+
+    #[test]
+    fn events_are_wrapped_in_an_envelope() {
+        let event = wrap_event(r#"{"Group": {"id": 1}}"#).unwrap();
+        let value: Value = serde_json::from_str(&event).unwrap();
+        assert_eq!(value["v"], 2);
+        assert_eq!(value["type"], "event");
+        assert_eq!(value["body"]["Group"]["id"], 1);
+        assert_eq!(wrap_event("not json"), None);
+    }
+}
@@ -0,0 +1,152 @@
+use crate::chats::{MessageKind, Scope};
+use crate::message_edits::{self, Denied};
+use crate::models::MessageReactionDB;
+use crate::schema::message_reactions;
+use diesel::mysql::MysqlConnection;
+use diesel::prelude::*;
+use std::collections::HashMap;
+
+// Size of the `emoji` column, in bytes
+pub const MAX_EMOJI_LEN: usize = 32;
+
+// A reaction is a single emoji, skin tones included. Returns its fully
+// qualified form, so "☺" and "☺️" count as the same reaction
+pub fn canonical_emoji(emoji: &str) -> Option<&'static str> {
+    emojis::get(emoji)
+        .map(|found| found.as_str())
+        .filter(|found| found.len() <= MAX_EMOJI_LEN)
+}
+
+// Add the reaction of `user_id` to a message. Returns false when the user
+// had already reacted with the same emoji
+pub fn add(
+    connection: &mut MysqlConnection,
+    scope: Scope,
+    message_id: i32,
+    user_id: i32,
+    emoji: &str,
+) -> QueryResult<Result<bool, Denied>> {
+    connection.transaction(|connection| {
+        if let Err(denied) = message_edits::check_live(connection, scope, message_id)? {
+            return Ok(Err(denied));
+        }
+        let (room_message, direct_message) = match scope {
+            Scope::Room(_) => (Some(message_id), None),
+            Scope::Direct(_) => (None, Some(message_id)),
+        };
+        diesel::insert_or_ignore_into(message_reactions::table)
+            .values((
+                message_reactions::message_id.eq(room_message),
+                message_reactions::direct_message_id.eq(direct_message),
+                message_reactions::user_id.eq(user_id),
+                message_reactions::emoji.eq(emoji),
+            ))
+            .execute(connection)
+            .map(|inserted| Ok(inserted == 1))
+    })
+}
+
+// Remove the reaction of `user_id` from a message. Returns false when there
+// was none
+pub fn remove(
+    connection: &mut MysqlConnection,
+    scope: Scope,
+    message_id: i32,
+    user_id: i32,
+    emoji: &str,
+) -> QueryResult<Result<bool, Denied>> {
+    connection.transaction(|connection| {
+        if let Err(denied) = message_edits::check_live(connection, scope, message_id)? {
+            return Ok(Err(denied));
+        }
+        let mine = message_reactions::table
+            .filter(message_reactions::user_id.eq(user_id))
+            .filter(message_reactions::emoji.eq(emoji));
+        let removed = match scope {
+            Scope::Room(_) => {
+                diesel::delete(mine.filter(message_reactions::message_id.eq(message_id)))
+                    .execute(connection)?
+            }
+            Scope::Direct(_) => {
+                diesel::delete(mine.filter(message_reactions::direct_message_id.eq(message_id)))
+                    .execute(connection)?
+            }
+        };
+        Ok(Ok(removed > 0))
+    })
+}
+
+// Drop every reaction to a message, when it is deleted
+pub fn clear(
+    connection: &mut MysqlConnection,
+    scope: Scope,
+    message_id: i32,
+) -> QueryResult<usize> {
+    match scope {
+        Scope::Room(_) => diesel::delete(
+            message_reactions::table.filter(message_reactions::message_id.eq(message_id)),
+        )
+        .execute(connection),
+        Scope::Direct(_) => diesel::delete(
+            message_reactions::table.filter(message_reactions::direct_message_id.eq(message_id)),
+        )
+        .execute(connection),
+    }
+}
+
+// Reactions to the given messages, oldest first, grouped by message id
+pub fn for_messages(
+    connection: &mut MysqlConnection,
+    kind: MessageKind,
+    message_ids: &[i32],
+) -> QueryResult<HashMap<i32, Vec<MessageReactionDB>>> {
+    let mut grouped: HashMap<i32, Vec<MessageReactionDB>> = HashMap::new();
+    if message_ids.is_empty() {
+        return Ok(grouped);
+    }
+    let query = message_reactions::table
+        .select(MessageReactionDB::as_select())
+        .order(message_reactions::id.asc())
+        .into_boxed();
+    let found = match kind {
+        MessageKind::Room => query
+            .filter(message_reactions::message_id.eq_any(message_ids))
+            .load(connection)?,
+        MessageKind::Direct => query
+            .filter(message_reactions::direct_message_id.eq_any(message_ids))
+            .load(connection)?,
+    };
+    for reaction in found {
+        let message_id = match kind {
+            MessageKind::Room => reaction.message_id,
+            MessageKind::Direct => reaction.direct_message_id,
+        };
+        if let Some(message_id) = message_id {
+            grouped.entry(message_id).or_default().push(reaction);
+        }
+    }
+    Ok(grouped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn emojis_are_accepted_in_their_qualified_form() {
+        assert_eq!(canonical_emoji("👍"), Some("👍"));
+        assert_eq!(canonical_emoji("👍🏽"), Some("👍🏽"));
+        assert_eq!(canonical_emoji("\u{263a}"), Some("\u{263a}\u{fe0f}"));
+        assert_eq!(canonical_emoji("👨‍👩‍👧‍👦"), Some("👨‍👩‍👧‍👦"));
+    }
+
+    #[test]
+    fn other_strings_are_refused() {
+        assert_eq!(canonical_emoji(""), None);
+        assert_eq!(canonical_emoji("a"), None);
+        assert_eq!(canonical_emoji("lol"), None);
+        assert_eq!(canonical_emoji("👍👍"), None);
+        assert_eq!(canonical_emoji("👍 "), None);
+        assert_eq!(canonical_emoji("<b>"), None);
+    }
+}
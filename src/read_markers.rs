@@ -0,0 +1,338 @@
+use crate::chats::{MessageKind, Scope};
+use crate::history;
+use crate::message_edits::Denied;
+use crate::schema::{direct_messages, messages, read_markers};
+use diesel::dsl::count_star;
+use diesel::mysql::MysqlConnection;
+use diesel::prelude::*;
+use std::collections::HashMap;
+
+// Read markers hold, per user and conversation, the id of the last message
+// the user has seen. Message ids grow with time, so everything above the
+// marker is unread
+
+// The marker of `user_id` in the room or direct chat, if any
+pub fn last_read(
+    connection: &mut MysqlConnection,
+    user_id: i32,
+    scope: Scope,
+) -> QueryResult<Option<i32>> {
+    let query = read_markers::table
+        .filter(read_markers::user_id.eq(user_id))
+        .select(read_markers::last_read_id)
+        .into_boxed();
+    match scope {
+        Scope::Room(room_id) => query.filter(read_markers::room_id.eq(room_id)),
+        Scope::Direct(direct_id) => query.filter(read_markers::direct_id.eq(direct_id)),
+    }
+    .first(connection)
+    .optional()
+}
+
+// Move the marker of `user_id` up to `message_id`, never back. Returns the
+// marker, which may already be past `message_id`
+pub fn advance(
+    connection: &mut MysqlConnection,
+    user_id: i32,
+    scope: Scope,
+    message_id: i32,
+) -> QueryResult<Result<i32, Denied>> {
+    connection.transaction(|connection| {
+        if history::thread_of(connection, scope, message_id)?.is_none() {
+            return Ok(Err(Denied::NotFound));
+        }
+        let (room_id, direct_id) = match scope {
+            Scope::Room(room_id) => (Some(room_id), None),
+            Scope::Direct(direct_id) => (None, Some(direct_id)),
+        };
+        // Two first reads racing both end up updating the same row
+        diesel::insert_or_ignore_into(read_markers::table)
+            .values((
+                read_markers::user_id.eq(user_id),
+                read_markers::room_id.eq(room_id),
+                read_markers::direct_id.eq(direct_id),
+                read_markers::last_read_id.eq(message_id),
+            ))
+            .execute(connection)?;
+        let behind = read_markers::table
+            .filter(read_markers::user_id.eq(user_id))
+            .filter(read_markers::last_read_id.lt(message_id));
+        match scope {
+            Scope::Room(room_id) => {
+                diesel::update(behind.filter(read_markers::room_id.eq(room_id)))
+                    .set(read_markers::last_read_id.eq(message_id))
+                    .execute(connection)?
+            }
+            Scope::Direct(direct_id) => {
+                diesel::update(behind.filter(read_markers::direct_id.eq(direct_id)))
+                    .set(read_markers::last_read_id.eq(message_id))
+                    .execute(connection)?
+            }
+        };
+        last_read(connection, user_id, scope).map(|marker| Ok(marker.unwrap_or(message_id)))
+    })
+}
+
+// Messages of others after the marker of `user_id`. Deleted ones don't count
+pub fn unread_count(
+    connection: &mut MysqlConnection,
+    user_id: i32,
+    scope: Scope,
+    last_read_id: Option<i32>,
+) -> QueryResult<i64> {
+    let after = last_read_id.unwrap_or(0);
+    match scope {
+        Scope::Room(room_id) => messages::table
+            .filter(messages::room_id.eq(room_id))
+            .filter(messages::message_id.gt(after))
+            .filter(messages::user_id.ne(user_id))
+            .filter(messages::deleted_at.is_null())
+            .count()
+            .get_result(connection),
+        Scope::Direct(direct_id) => direct_messages::table
+            .filter(direct_messages::chat_id.eq(direct_id))
+            .filter(direct_messages::id.gt(after))
+            .filter(direct_messages::sender_id.ne(user_id))
+            .filter(direct_messages::deleted_at.is_null())
+            .count()
+            .get_result(connection),
+    }
+}
+
+// The markers of `user_ids` in the given rooms or direct chats, by user and
+// chat
+pub fn for_chats(
+    connection: &mut MysqlConnection,
+    kind: MessageKind,
+    chat_ids: &[i32],
+    user_ids: &[i32],
+) -> QueryResult<HashMap<(i32, i32), i32>> {
+    if chat_ids.is_empty() || user_ids.is_empty() {
+        return Ok(HashMap::new());
+    }
+    let query = read_markers::table
+        .filter(read_markers::user_id.eq_any(user_ids))
+        .into_boxed();
+    let markers: Vec<(i32, Option<i32>, i32)> = match kind {
+        MessageKind::Room => query
+            .filter(read_markers::room_id.eq_any(chat_ids))
+            .select((
+                read_markers::user_id,
+                read_markers::room_id,
+                read_markers::last_read_id,
+            ))
+            .load(connection)?,
+        MessageKind::Direct => query
+            .filter(read_markers::direct_id.eq_any(chat_ids))
+            .select((
+                read_markers::user_id,
+                read_markers::direct_id,
+                read_markers::last_read_id,
+            ))
+            .load(connection)?,
+    };
+    Ok(markers
+        .into_iter()
+        .filter_map(|(user_id, chat_id, last_read_id)| {
+            chat_id.map(|chat_id| ((user_id, chat_id), last_read_id))
+        })
+        .collect())
+}
+
+// `unread_count` for each of the given chats, with the markers of `user_id`
+// by chat. Chats without unread messages are left out
+pub fn unread_counts(
+    connection: &mut MysqlConnection,
+    user_id: i32,
+    kind: MessageKind,
+    chat_ids: &[i32],
+    markers: &HashMap<i32, i32>,
+) -> QueryResult<HashMap<i32, i64>> {
+    if chat_ids.is_empty() {
+        return Ok(HashMap::new());
+    }
+    let (marked, unmarked): (Vec<i32>, Vec<i32>) =
+        chat_ids.iter().partition(|id| markers.contains_key(id));
+    let counts: Vec<(i32, i64)> = match kind {
+        MessageKind::Room => {
+            let mut query = messages::table
+                .group_by(messages::room_id)
+                .select((messages::room_id, count_star()))
+                .into_boxed();
+            if !unmarked.is_empty() {
+                query = query.or_filter(messages::room_id.eq_any(unmarked));
+            }
+            for room_id in marked {
+                query = query.or_filter(
+                    messages::room_id
+                        .eq(room_id)
+                        .and(messages::message_id.gt(markers[&room_id])),
+                );
+            }
+            query
+                .filter(messages::user_id.ne(user_id))
+                .filter(messages::deleted_at.is_null())
+                .load(connection)?
+        }
+        MessageKind::Direct => {
+            let mut query = direct_messages::table
+                .group_by(direct_messages::chat_id)
+                .select((direct_messages::chat_id, count_star()))
+                .into_boxed();
+            if !unmarked.is_empty() {
+                query = query.or_filter(direct_messages::chat_id.eq_any(unmarked));
+            }
+            for direct_id in marked {
+                query = query.or_filter(
+                    direct_messages::chat_id
+                        .eq(direct_id)
+                        .and(direct_messages::id.gt(markers[&direct_id])),
+                );
+            }
+            query
+                .filter(direct_messages::sender_id.ne(user_id))
+                .filter(direct_messages::deleted_at.is_null())
+                .load(connection)?
+        }
+    };
+    Ok(counts.into_iter().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::testing;
+
+    #[test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    fn markers_only_move_forward() {
+        let mut connection = testing::connection();
+        let reader = testing::user(&mut connection, "marker_reader");
+        let writer = testing::user(&mut connection, "marker_writer");
+        let room_id = testing::room(&mut connection, "markers", &[reader, writer]);
+        let scope = Scope::Room(room_id);
+        let first = testing::room_message(&mut connection, room_id, writer, "one");
+        let second = testing::room_message(&mut connection, room_id, writer, "two");
+
+        assert_eq!(last_read(&mut connection, reader, scope).unwrap(), None);
+        assert_eq!(
+            advance(&mut connection, reader, scope, second).unwrap(),
+            Ok(second)
+        );
+        assert_eq!(
+            advance(&mut connection, reader, scope, first).unwrap(),
+            Ok(second)
+        );
+        assert_eq!(
+            last_read(&mut connection, reader, scope).unwrap(),
+            Some(second)
+        );
+        // The marker of another user is their own
+        assert_eq!(last_read(&mut connection, writer, scope).unwrap(), None);
+    }
+
+    #[test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    fn markers_need_a_message_of_the_chat() {
+        let mut connection = testing::connection();
+        let reader = testing::user(&mut connection, "marker_stranger");
+        let writer = testing::user(&mut connection, "marker_author");
+        let third = testing::user(&mut connection, "marker_third");
+        let direct_id = testing::direct(&mut connection, reader, writer);
+        let other_direct = testing::direct(&mut connection, writer, third);
+        let elsewhere = testing::direct_message(&mut connection, other_direct, writer, "hi");
+
+        assert_eq!(
+            advance(&mut connection, reader, Scope::Direct(direct_id), elsewhere).unwrap(),
+            Err(Denied::NotFound)
+        );
+        assert_eq!(
+            last_read(&mut connection, reader, Scope::Direct(direct_id)).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    fn unread_counts_leave_out_own_and_deleted_messages() {
+        let mut connection = testing::connection();
+        let reader = testing::user(&mut connection, "unread_reader");
+        let writer = testing::user(&mut connection, "unread_writer");
+        let direct_id = testing::direct(&mut connection, reader, writer);
+        let scope = Scope::Direct(direct_id);
+        let first = testing::direct_message(&mut connection, direct_id, writer, "one");
+        testing::direct_message(&mut connection, direct_id, reader, "mine");
+        let deleted = testing::direct_message(&mut connection, direct_id, writer, "gone");
+        testing::direct_message(&mut connection, direct_id, writer, "three");
+        diesel::update(direct_messages::table.filter(direct_messages::id.eq(deleted)))
+            .set(direct_messages::deleted_at.eq(Some(chrono::Utc::now().naive_utc())))
+            .execute(&mut connection)
+            .unwrap();
+
+        assert_eq!(
+            unread_count(&mut connection, reader, scope, None).unwrap(),
+            2
+        );
+        let marker = advance(&mut connection, reader, scope, first)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            unread_count(&mut connection, reader, scope, Some(marker)).unwrap(),
+            1
+        );
+        // The writer's own messages are never unread for them
+        assert_eq!(
+            unread_count(&mut connection, writer, scope, None).unwrap(),
+            1
+        );
+    }
+
+    #[test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    fn batched_read_state_matches_the_single_lookups() {
+        let mut connection = testing::connection();
+        let reader = testing::user(&mut connection, "batch_reader");
+        let writer = testing::user(&mut connection, "batch_writer");
+        let marked = testing::room(&mut connection, "batch_marked", &[reader, writer]);
+        let unmarked = testing::room(&mut connection, "batch_unmarked", &[reader, writer]);
+        let caught_up = testing::room(&mut connection, "batch_caught_up", &[reader, writer]);
+        let first = testing::room_message(&mut connection, marked, writer, "one");
+        testing::room_message(&mut connection, marked, writer, "two");
+        testing::room_message(&mut connection, unmarked, writer, "one");
+        let last = testing::room_message(&mut connection, caught_up, writer, "one");
+        advance(&mut connection, reader, Scope::Room(marked), first)
+            .unwrap()
+            .unwrap();
+        advance(&mut connection, reader, Scope::Room(caught_up), last)
+            .unwrap()
+            .unwrap();
+        // Markers of others are not asked for
+        advance(&mut connection, writer, Scope::Room(marked), first)
+            .unwrap()
+            .unwrap();
+
+        let rooms = [marked, unmarked, caught_up];
+        let markers = for_chats(&mut connection, MessageKind::Room, &rooms, &[reader]).unwrap();
+        assert_eq!(
+            markers,
+            HashMap::from([((reader, marked), first), ((reader, caught_up), last)])
+        );
+        let markers: HashMap<i32, i32> = markers
+            .into_iter()
+            .map(|((_, room_id), marker)| (room_id, marker))
+            .collect();
+        let counts =
+            unread_counts(&mut connection, reader, MessageKind::Room, &rooms, &markers).unwrap();
+        for room_id in rooms {
+            let single = unread_count(
+                &mut connection,
+                reader,
+                Scope::Room(room_id),
+                markers.get(&room_id).copied(),
+            )
+            .unwrap();
+            assert_eq!(counts.get(&room_id).copied().unwrap_or(0), single);
+        }
+        assert_eq!(counts, HashMap::from([(marked, 1), (unmarked, 1)]));
+    }
+}
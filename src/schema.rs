@@ -6,6 +6,38 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    attachments (id) {
+        id -> Integer,
+        #[max_length = 64]
+        upload_id -> Varchar,
+        uploader_id -> Integer,
+        room_id -> Nullable<Integer>,
+        direct_id -> Nullable<Integer>,
+        message_id -> Nullable<Integer>,
+        direct_message_id -> Nullable<Integer>,
+        #[max_length = 127]
+        mime_type -> Varchar,
+        size -> Bigint,
+        received -> Bigint,
+        #[max_length = 64]
+        sha256 -> Nullable<Char>,
+        created_at -> Datetime,
+        completed_at -> Nullable<Datetime>,
+    }
+}
+
+diesel::table! {
+    delivery_cursors (id) {
+        id -> Integer,
+        user_id -> Integer,
+        room_id -> Nullable<Integer>,
+        direct_id -> Nullable<Integer>,
+        last_delivered_id -> Integer,
+        updated_at -> Datetime,
+    }
+}
+
 diesel::table! {
     direct_messages (id) {
         id -> Integer,
@@ -13,6 +45,12 @@ diesel::table! {
         sender_id -> Integer,
         message -> Text,
         message_time -> Nullable<Datetime>,
+        edited_at -> Nullable<Datetime>,
+        deleted_at -> Nullable<Datetime>,
+        reply_to_id -> Nullable<Integer>,
+        thread_id -> Nullable<Integer>,
+        #[max_length = 64]
+        client_msg_id -> Nullable<Varchar>,
     }
 }
 
@@ -26,9 +64,31 @@ diesel::table! {
 }
 
 diesel::table! {
-    email_tokens (user_id) {
+    email_outbox (id) {
+        id -> Integer,
+        #[max_length = 100]
+        recipient -> Varchar,
+        #[max_length = 255]
+        subject -> Varchar,
+        body -> Text,
+        #[max_length = 16]
+        status -> Varchar,
+        attempts -> Integer,
+        last_error -> Nullable<Text>,
+        next_attempt_at -> Datetime,
+        created_at -> Datetime,
+        sent_at -> Nullable<Datetime>,
+    }
+}
+
+diesel::table! {
+    email_tokens (user_id, purpose) {
         user_id -> Integer,
-        token -> Text,
+        created_at -> Datetime,
+        #[max_length = 16]
+        purpose -> Varchar,
+        #[max_length = 64]
+        token_hash -> Char,
     }
 }
 
@@ -39,6 +99,55 @@ diesel::table! {
         user_id -> Integer,
         content -> Text,
         message_time -> Nullable<Datetime>,
+        edited_at -> Nullable<Datetime>,
+        deleted_at -> Nullable<Datetime>,
+        reply_to_id -> Nullable<Integer>,
+        thread_id -> Nullable<Integer>,
+        #[max_length = 64]
+        client_msg_id -> Nullable<Varchar>,
+    }
+}
+
+diesel::table! {
+    message_reactions (id) {
+        id -> Integer,
+        message_id -> Nullable<Integer>,
+        direct_message_id -> Nullable<Integer>,
+        user_id -> Integer,
+        #[max_length = 32]
+        emoji -> Varchar,
+        created_at -> Datetime,
+    }
+}
+
+diesel::table! {
+    message_revisions (id) {
+        id -> Integer,
+        message_id -> Nullable<Integer>,
+        direct_message_id -> Nullable<Integer>,
+        changed_by -> Integer,
+        content -> Text,
+        replaced_at -> Datetime,
+    }
+}
+
+diesel::table! {
+    presence (user_id) {
+        user_id -> Integer,
+        #[max_length = 16]
+        status -> Varchar,
+        last_seen_at -> Nullable<Datetime>,
+    }
+}
+
+diesel::table! {
+    read_markers (id) {
+        id -> Integer,
+        user_id -> Integer,
+        room_id -> Nullable<Integer>,
+        direct_id -> Nullable<Integer>,
+        last_read_id -> Integer,
+        updated_at -> Datetime,
     }
 }
 
@@ -59,6 +168,17 @@ diesel::table! {
     rooms_users (room_id, user_id) {
         room_id -> Integer,
         user_id -> Integer,
+        moderator -> Bool,
+    }
+}
+
+diesel::table! {
+    sessions (id) {
+        #[max_length = 64]
+        id -> Varchar,
+        data -> Text,
+        expires_at -> Datetime,
+        user_id -> Nullable<Integer>,
     }
 }
 
@@ -80,21 +200,35 @@ diesel::table! {
 }
 
 diesel::joinable!(admins -> users (id));
+diesel::joinable!(attachments -> users (uploader_id));
+diesel::joinable!(delivery_cursors -> users (user_id));
 diesel::joinable!(direct_messages -> directs (chat_id));
 diesel::joinable!(direct_messages -> users (sender_id));
 diesel::joinable!(email_tokens -> users (user_id));
+diesel::joinable!(message_reactions -> users (user_id));
+diesel::joinable!(message_revisions -> users (changed_by));
 diesel::joinable!(messages -> rooms (room_id));
 diesel::joinable!(messages -> users (user_id));
+diesel::joinable!(presence -> users (user_id));
+diesel::joinable!(read_markers -> users (user_id));
 diesel::joinable!(rooms_users -> rooms (room_id));
 diesel::joinable!(rooms_users -> users (user_id));
 
 diesel::allow_tables_to_appear_in_same_query!(
     admins,
+    attachments,
+    delivery_cursors,
     direct_messages,
     directs,
+    email_outbox,
     email_tokens,
+    message_reactions,
+    message_revisions,
     messages,
+    presence,
+    read_markers,
     rooms,
     rooms_users,
+    sessions,
     users,
 );
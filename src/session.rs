@@ -0,0 +1,286 @@
+use crate::db::DbPool;
+use crate::models::SessionDB;
+use crate::schema::sessions;
+use chrono::{NaiveDateTime, Utc};
+use diesel::mysql::MysqlConnection;
+use diesel::prelude::*;
+use rocket::fairing::AdHoc;
+use rocket::serde::{de::DeserializeOwned, Serialize};
+use rocket::tokio::time;
+use rocket_session_store::{SessionError, SessionResult, Store};
+use std::marker::PhantomData;
+use std::time::Duration;
+
+// Session store backed by the `sessions` table, so sessions survive restarts
+// and are shared between every instance pointing at the same database
+pub struct DbStore<T> {
+    db: DbPool,
+    _marker: PhantomData<T>,
+}
+
+// Session values belonging to a user, recorded next to the session so every
+// session of that user can be revoked at once
+pub trait SessionOwner {
+    fn owner_id(&self) -> i32;
+}
+
+impl<T> DbStore<T> {
+    pub fn new(db: DbPool) -> Self {
+        DbStore {
+            db,
+            _marker: PhantomData,
+        }
+    }
+}
+
+fn expiry_from_now(duration: Duration) -> NaiveDateTime {
+    let duration = chrono::Duration::from_std(duration).unwrap_or(chrono::Duration::MAX);
+    Utc::now().naive_utc() + duration
+}
+
+#[rocket::async_trait]
+impl<T> Store for DbStore<T>
+where
+    T: Serialize + DeserializeOwned + SessionOwner + Send + Sync + Clone + 'static,
+{
+    type Value = T;
+
+    async fn get(&self, id: &str) -> SessionResult<Option<Self::Value>> {
+        let id = id.to_owned();
+        let session = self
+            .db
+            .run(move |connection| {
+                sessions::table
+                    .filter(sessions::id.eq(id))
+                    .filter(sessions::expires_at.gt(Utc::now().naive_utc()))
+                    .select(SessionDB::as_select())
+                    .first::<SessionDB>(connection)
+                    .optional()
+            })
+            .await
+            .map_err(|_| SessionError)?
+            .map_err(|_| SessionError)?;
+
+        Ok(session.and_then(|s| serde_json::from_str(&s.data).ok()))
+    }
+
+    async fn set(&self, id: &str, value: Self::Value, duration: Duration) -> SessionResult<()> {
+        let id = id.to_owned();
+        let owner = value.owner_id();
+        let data = serde_json::to_string(&value).map_err(|_| SessionError)?;
+        let expires_at = expiry_from_now(duration);
+        self.db
+            .run(move |connection| {
+                diesel::replace_into(sessions::table)
+                    .values((
+                        sessions::id.eq(id),
+                        sessions::data.eq(data),
+                        sessions::expires_at.eq(expires_at),
+                        sessions::user_id.eq(Some(owner)),
+                    ))
+                    .execute(connection)
+            })
+            .await
+            .map_err(|_| SessionError)?
+            .map_err(|_| SessionError)?;
+        Ok(())
+    }
+
+    async fn touch(&self, id: &str, duration: Duration) -> SessionResult<()> {
+        let id = id.to_owned();
+        let expires_at = expiry_from_now(duration);
+        self.db
+            .run(move |connection| {
+                diesel::update(sessions::table.filter(sessions::id.eq(id)))
+                    .set(sessions::expires_at.eq(expires_at))
+                    .execute(connection)
+            })
+            .await
+            .map_err(|_| SessionError)?
+            .map_err(|_| SessionError)?;
+        Ok(())
+    }
+
+    async fn remove(&self, id: &str) -> SessionResult<()> {
+        let id = id.to_owned();
+        self.db
+            .run(move |connection| {
+                diesel::delete(sessions::table.filter(sessions::id.eq(id))).execute(connection)
+            })
+            .await
+            .map_err(|_| SessionError)?
+            .map_err(|_| SessionError)?;
+        Ok(())
+    }
+}
+
+// Delete every session of a user, e.g. after their password was reset
+pub fn revoke_user(connection: &mut MysqlConnection, user_id: i32) -> QueryResult<usize> {
+    diesel::delete(sessions::table.filter(sessions::user_id.eq(user_id))).execute(connection)
+}
+
+// Delete every expired session, returning how many rows were removed
+pub async fn purge_expired(db: &DbPool) -> Result<usize, String> {
+    db.run(|connection| {
+        diesel::delete(sessions::table.filter(sessions::expires_at.le(Utc::now().naive_utc())))
+            .execute(connection)
+    })
+    .await
+    .map_err(|e| e.to_string())?
+    .map_err(|e| e.to_string())
+}
+
+// Fairing that periodically purges expired sessions once the server is up
+pub fn purge_fairing(db: DbPool, every: Duration) -> AdHoc {
+    AdHoc::on_liftoff("Session purge", move |_| {
+        Box::pin(async move {
+            rocket::tokio::spawn(async move {
+                let mut interval = time::interval(every);
+                loop {
+                    interval.tick().await;
+                    if let Err(err) = purge_expired(&db).await {
+                        eprintln!("Failed to purge expired sessions: {}", err);
+                    }
+                }
+            });
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::testing;
+    use rocket::serde::Deserialize;
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    #[serde(crate = "rocket::serde")]
+    struct Value {
+        user_id: i32,
+    }
+
+    impl SessionOwner for Value {
+        fn owner_id(&self) -> i32 {
+            self.user_id
+        }
+    }
+
+    async fn expire(db: &DbPool, id: &'static str) {
+        db.run(move |connection| {
+            diesel::update(sessions::table.filter(sessions::id.eq(id)))
+                .set(sessions::expires_at.eq(Utc::now().naive_utc() - chrono::Duration::seconds(1)))
+                .execute(connection)
+        })
+        .await
+        .unwrap()
+        .unwrap();
+    }
+
+    // Rows left for `id`, expired or not
+    async fn count(db: &DbPool, id: &'static str) -> i64 {
+        db.run(move |connection| {
+            sessions::table
+                .filter(sessions::id.eq(id))
+                .count()
+                .get_result(connection)
+        })
+        .await
+        .unwrap()
+        .unwrap()
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn sessions_persist_until_removed() {
+        let db = testing::pool();
+        let store = DbStore::<Value>::new(db.clone());
+        let hour = Duration::from_secs(3600);
+        store.set("a", Value { user_id: 1 }, hour).await.unwrap();
+        assert_eq!(store.get("a").await.unwrap(), Some(Value { user_id: 1 }));
+
+        // A second store over the same database, as after a restart
+        let restarted = DbStore::<Value>::new(db);
+        assert_eq!(
+            restarted.get("a").await.unwrap(),
+            Some(Value { user_id: 1 })
+        );
+        restarted
+            .set("a", Value { user_id: 2 }, hour)
+            .await
+            .unwrap();
+        assert_eq!(store.get("a").await.unwrap(), Some(Value { user_id: 2 }));
+
+        store.remove("a").await.unwrap();
+        assert_eq!(restarted.get("a").await.unwrap(), None);
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn expired_sessions_are_gone_until_touched() {
+        let db = testing::pool();
+        let store = DbStore::<Value>::new(db.clone());
+        store
+            .set("a", Value { user_id: 1 }, Duration::from_secs(3600))
+            .await
+            .unwrap();
+        expire(&db, "a").await;
+        assert_eq!(store.get("a").await.unwrap(), None);
+
+        store.touch("a", Duration::from_secs(3600)).await.unwrap();
+        assert_eq!(store.get("a").await.unwrap(), Some(Value { user_id: 1 }));
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn revoking_a_user_removes_only_their_sessions() {
+        let db = testing::pool();
+        let store = DbStore::<Value>::new(db.clone());
+        let hour = Duration::from_secs(3600);
+        store.set("a", Value { user_id: 1 }, hour).await.unwrap();
+        store.set("b", Value { user_id: 1 }, hour).await.unwrap();
+        store.set("c", Value { user_id: 2 }, hour).await.unwrap();
+
+        let revoked = db
+            .run(|connection| revoke_user(connection, 1))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(revoked, 2);
+        assert_eq!(store.get("a").await.unwrap(), None);
+        assert_eq!(store.get("c").await.unwrap(), Some(Value { user_id: 2 }));
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn purge_removes_only_expired_sessions() {
+        let db = testing::pool();
+        let store = DbStore::<Value>::new(db.clone());
+        let hour = Duration::from_secs(3600);
+        store.set("a", Value { user_id: 1 }, hour).await.unwrap();
+        store.set("b", Value { user_id: 1 }, hour).await.unwrap();
+        expire(&db, "a").await;
+
+        assert!(purge_expired(&db).await.unwrap() >= 1);
+        assert_eq!(count(&db, "a").await, 0);
+        assert_eq!(store.get("b").await.unwrap(), Some(Value { user_id: 1 }));
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn purge_fairing_runs_after_liftoff() {
+        let db = testing::pool();
+        let store = DbStore::<Value>::new(db.clone());
+        store
+            .set("a", Value { user_id: 1 }, Duration::from_secs(3600))
+            .await
+            .unwrap();
+        expire(&db, "a").await;
+
+        let rocket = rocket::build().attach(purge_fairing(db.clone(), Duration::from_millis(10)));
+        let _client = rocket::local::asynchronous::Client::tracked(rocket)
+            .await
+            .unwrap();
+        time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(count(&db, "a").await, 0);
+    }
+}
@@ -0,0 +1,176 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+// Most chats tracked for one connection, and checked per interval. Starts in
+// other chats are dropped until the older ones are forgotten
+pub const MAX_CHATS: usize = 64;
+
+// Typing notifications of one websocket connection, by chat. Starting to
+// type is forwarded at most once per `interval` for each chat, and a chat
+// stops by itself `ttl` after the last notification. Nothing is stored
+pub struct Typing<K> {
+    ttl: Duration,
+    interval: Duration,
+    // Last notification of the chats the user is typing in
+    active: HashMap<K, Instant>,
+    // Last start forwarded, kept after stopping so toggling is limited too
+    forwarded: HashMap<K, Instant>,
+    // Start of the current interval and the checks made during it
+    checks: (Instant, usize),
+}
+
+impl<K: Copy + Eq + Hash> Typing<K> {
+    pub fn new(ttl: Duration, interval: Duration) -> Typing<K> {
+        Typing {
+            ttl,
+            interval,
+            active: HashMap::new(),
+            forwarded: HashMap::new(),
+            checks: (Instant::now(), 0),
+        }
+    }
+
+    // Whether the caller may check another chat now, at most `MAX_CHATS` per
+    // interval, so made up chats can't cost a query each
+    pub fn may_check(&mut self, now: Instant) -> bool {
+        if now.duration_since(self.checks.0) >= self.interval {
+            self.checks = (now, 0);
+        }
+        self.checks.1 += 1;
+        self.checks.1 <= MAX_CHATS
+    }
+
+    // Whether starting to type in `target` would be forwarded now. Callers
+    // check the user may type there before calling `update`, which only
+    // records due starts
+    pub fn due(&self, target: K, now: Instant) -> bool {
+        self.forwarded
+            .get(&target)
+            .is_none_or(|last| now.duration_since(*last) >= self.interval)
+    }
+
+    // Record a notification, returning whether to forward it
+    pub fn update(&mut self, target: K, typing: bool, now: Instant) -> bool {
+        if !typing {
+            return self.active.remove(&target).is_some();
+        }
+        let mut due = self.due(target, now);
+        if due && !self.forwarded.contains_key(&target) && self.forwarded.len() >= MAX_CHATS {
+            // Chats no longer limited by the interval need not be remembered
+            let interval = self.interval;
+            let active = &self.active;
+            self.forwarded.retain(|target, last| {
+                active.contains_key(target) || now.duration_since(*last) < interval
+            });
+            due = self.forwarded.len() < MAX_CHATS;
+        }
+        if due {
+            self.forwarded.insert(target, now);
+            self.active.insert(target, now);
+        } else if let Some(last_seen) = self.active.get_mut(&target) {
+            *last_seen = now;
+        }
+        due
+    }
+
+    // Stop without telling anyone, when the message itself is sent
+    pub fn clear(&mut self, target: K) {
+        self.active.remove(&target);
+    }
+
+    // Chats whose notifications timed out, to be told the user stopped
+    pub fn expired(&mut self, now: Instant) -> Vec<K> {
+        let ttl = self.ttl;
+        let expired: Vec<K> = self
+            .active
+            .iter()
+            .filter(|(_, last_seen)| now.duration_since(**last_seen) >= ttl)
+            .map(|(target, _)| *target)
+            .collect();
+        for target in &expired {
+            self.active.remove(target);
+        }
+        expired
+    }
+
+    // Every chat still active, when the connection closes
+    pub fn drain(&mut self) -> Vec<K> {
+        self.active.drain().map(|(target, _)| target).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn typing() -> Typing<i32> {
+        Typing::new(Duration::from_secs(5), Duration::from_secs(2))
+    }
+
+    #[test]
+    fn starts_are_forwarded_once_per_interval() {
+        let mut typing = typing();
+        let now = Instant::now();
+        assert!(typing.update(1, true, now));
+        assert!(!typing.update(1, true, now + Duration::from_secs(1)));
+        assert!(typing.update(1, true, now + Duration::from_secs(2)));
+        // Other chats have their own interval
+        assert!(typing.update(2, true, now));
+    }
+
+    #[test]
+    fn stops_are_forwarded_only_while_active() {
+        let mut typing = typing();
+        let now = Instant::now();
+        assert!(!typing.update(1, false, now));
+        typing.update(1, true, now);
+        assert!(typing.update(1, false, now));
+        assert!(!typing.update(1, false, now));
+    }
+
+    #[test]
+    fn chats_expire_after_the_ttl() {
+        let mut typing = typing();
+        let now = Instant::now();
+        typing.update(1, true, now);
+        typing.update(2, true, now + Duration::from_secs(3));
+        assert!(typing.expired(now + Duration::from_secs(4)).is_empty());
+        assert_eq!(typing.expired(now + Duration::from_secs(5)), vec![1]);
+        assert_eq!(typing.drain(), vec![2]);
+    }
+
+    #[test]
+    fn cleared_chats_do_not_expire() {
+        let mut typing = typing();
+        let now = Instant::now();
+        typing.update(1, true, now);
+        typing.clear(1);
+        assert!(typing.expired(now + Duration::from_secs(10)).is_empty());
+    }
+
+    #[test]
+    fn tracked_chats_are_capped() {
+        let mut typing = typing();
+        let now = Instant::now();
+        for target in 0..MAX_CHATS as i32 {
+            assert!(typing.update(target, true, now));
+        }
+        assert!(!typing.update(-1, true, now));
+        // Chats past their interval and no longer active make room again
+        let later = now + Duration::from_secs(6);
+        typing.expired(later);
+        assert!(typing.update(-1, true, later));
+    }
+
+    #[test]
+    fn checks_are_limited_per_interval() {
+        let mut typing = typing();
+        let now = Instant::now();
+        for _ in 0..MAX_CHATS {
+            assert!(typing.may_check(now));
+        }
+        assert!(!typing.may_check(now));
+        assert!(typing.may_check(now + Duration::from_secs(2)));
+    }
+}
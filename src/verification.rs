@@ -0,0 +1,121 @@
+use crate::email_tokens::{self, Redeemed, PURPOSE_VERIFY};
+use crate::mail::Email;
+use crate::models::UserDB;
+use crate::outbox;
+use crate::schema::users;
+use chrono::{Duration, Utc};
+use diesel::mysql::MysqlConnection;
+use diesel::prelude::*;
+
+// Result of following a verification link
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VerifyOutcome {
+    Verified,
+    Expired,
+    Invalid,
+}
+
+impl VerifyOutcome {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            VerifyOutcome::Verified => "verified",
+            VerifyOutcome::Expired => "expired",
+            VerifyOutcome::Invalid => "invalid",
+        }
+    }
+}
+
+// Result of asking for a new link by email
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResendOutcome {
+    Queued,
+    // A link was issued less than the cooldown ago
+    TooSoon,
+    // Unknown address, or nothing to do for it. Nothing is sent for this or
+    // `TooSoon`, but callers should answer as if it was so addresses can't
+    // be probed
+    Ignored,
+}
+
+// Issue a new verification token for the user and queue the email carrying
+// `link_prefix` + token
+pub fn issue(
+    connection: &mut MysqlConnection,
+    user_id: i32,
+    address: &str,
+    link_prefix: &str,
+) -> QueryResult<()> {
+    connection.transaction(|connection| {
+        let token = email_tokens::issue(connection, user_id, PURPOSE_VERIFY)?;
+        outbox::enqueue(
+            connection,
+            &Email::new(
+                address,
+                "Email verification",
+                &format!("{}{}", link_prefix, token),
+            ),
+        )?;
+        Ok(())
+    })
+}
+
+// Follow a verification link, marking the address as verified
+pub fn consume(
+    connection: &mut MysqlConnection,
+    token: &str,
+    ttl: Duration,
+) -> QueryResult<VerifyOutcome> {
+    connection.transaction(|connection| {
+        match email_tokens::redeem(connection, token, PURPOSE_VERIFY, ttl)? {
+            Redeemed::User(user_id) => {
+                diesel::update(users::table.filter(users::id.eq(user_id)))
+                    .set(users::email_verified.eq(true))
+                    .execute(connection)?;
+                Ok(VerifyOutcome::Verified)
+            }
+            Redeemed::Expired => Ok(VerifyOutcome::Expired),
+            Redeemed::Invalid => Ok(VerifyOutcome::Invalid),
+        }
+    })
+}
+
+// The user owning `address`, and whether a token for `purpose` may be issued
+// again given `cooldown`
+pub fn user_for_resend(
+    connection: &mut MysqlConnection,
+    address: &str,
+    purpose: &str,
+    cooldown: Duration,
+) -> QueryResult<Result<UserDB, ResendOutcome>> {
+    let user = users::table
+        .filter(users::email.eq(address))
+        .select(UserDB::as_select())
+        .first(connection)
+        .optional()?;
+    let user = match user {
+        Some(user) => user,
+        None => return Ok(Err(ResendOutcome::Ignored)),
+    };
+    let last_issued = email_tokens::issued_at(connection, user.id, purpose)?;
+    if last_issued.is_some_and(|at| at + cooldown > Utc::now().naive_utc()) {
+        return Ok(Err(ResendOutcome::TooSoon));
+    }
+    Ok(Ok(user))
+}
+
+// Issue a fresh link for an unverified address, at most once per `cooldown`
+pub fn resend(
+    connection: &mut MysqlConnection,
+    address: &str,
+    cooldown: Duration,
+    link_prefix: &str,
+) -> QueryResult<ResendOutcome> {
+    match user_for_resend(connection, address, PURPOSE_VERIFY, cooldown)? {
+        Ok(user) if !user.email_verified => {
+            issue(connection, user.id, &user.email, link_prefix)?;
+            Ok(ResendOutcome::Queued)
+        }
+        Ok(_) => Ok(ResendOutcome::Ignored),
+        Err(outcome) => Ok(outcome),
+    }
+}
@@ -7,10 +7,31 @@ var STATE = {
     rooms: {},
     users: {},
     connected: false,
+    // Decrypted attachments, as object URLs by upload id
+    attachmentUrls: {},
+    // The thread shown instead of the whole chat: { direct, chat_id, id, messages }
+    thread: null,
+    // The message the next one answers: { id, label }
+    replyTo: null,
+    // Who is typing, by chat key: { user_id: { name, until } }
+    typing: {},
+    // When we last said we were typing, and the timer saying we stopped
+    typingSent: 0,
+    typingIdle: null,
+    // Presence of the users we have a direct chat with, by user id
+    presence: {},
 };
 
+// How long someone is shown typing without news, and how long we can stay
+// idle before saying we stopped
+const TYPING_SHOWN_MS = 6000;
+const TYPING_IDLE_MS = 3000;
+
 var ws = null;
 
+// The websocket protocol version this client speaks
+const PROTOCOL_VERSION = 2;
+
 // Generate a color from a "hash" of a string. Thanks, internet.
 function hashColor(str) {
     let hash = 0;
@@ -68,13 +89,11 @@ function removeRoom(name) {
     }
 
     const room_id = id;
-    const user_id = STATE.user_id;
     if (STATE.connected) {
         fetch("/remove-room", {
             method: "POST",
             body: new URLSearchParams({
                 room_id,
-                user_id,
             }),
         })
             .then((response) => {
@@ -111,6 +130,7 @@ function removeRoom(name) {
 // Change the current room to `name`, restoring its messages.
 function changeRoom(id) {
     if (STATE.room_id == id) return;
+    stopTyping();
 
     let roomListDiv = document.getElementById("room-list");
     let messagesDiv = document.getElementById("messages");
@@ -136,13 +156,24 @@ function changeRoom(id) {
     }
 
     STATE.room_id = id;
+    STATE.thread = null;
+    setReplyTo(null);
     messagesDiv.querySelectorAll(".container-message").forEach((msg) => {
         messagesDiv.removeChild(msg);
     });
 
     STATE.rooms[id].messages.forEach((data) =>
-        addMessageGroup(id, data.user_id, data.username, data.message)
+        addMessageGroup(
+            id,
+            data.user_id,
+            data.username,
+            data.message,
+            false,
+            data
+        )
     );
+    markRead(false, id);
+    renderTyping();
 }
 
 function addUser(id, name, key) {
@@ -176,13 +207,11 @@ function removeUser(name) {
     }
 
     const recipient_id = id;
-    const user_id = STATE.user_id;
     if (STATE.connected) {
         fetch("/remove-direct", {
             method: "POST",
             body: new URLSearchParams({
                 recipient_id,
-                user_id,
             }),
         })
             .then((response) => {
@@ -218,6 +247,7 @@ function removeUser(name) {
 
 function changeUser(id) {
     if (STATE.recipient_id == id) return;
+    stopTyping();
 
     let userListDiv = document.getElementById("user-list");
     let messagesDiv = document.getElementById("messages");
@@ -243,27 +273,45 @@ function changeUser(id) {
     }
 
     STATE.recipient_id = id;
+    STATE.thread = null;
+    setReplyTo(null);
     messagesDiv.querySelectorAll(".container-message").forEach((msg) => {
         messagesDiv.removeChild(msg);
     });
 
     STATE.users[id].messages.forEach((data) =>
-        addMessageDirect(id, data.sender_id, data.message)
+        addMessageDirect(
+            id,
+            data.sender_id,
+            data.message,
+            false,
+            data
+        )
     );
+    markRead(true, id);
+    renderTyping();
 }
 
 function swapRoomsToUsersMessages() {
+    STATE.thread = null;
     let messagesDiv = document.getElementById("messages");
     messagesDiv.querySelectorAll(".container-message").forEach((msg) => {
         messagesDiv.removeChild(msg);
     });
 
     STATE.users[STATE.recipient_id].messages.forEach((data) =>
-        addMessageDirect(STATE.recipient_id, data.sender_id, data.message)
+        addMessageDirect(
+            STATE.recipient_id,
+            data.sender_id,
+            data.message,
+            false,
+            data
+        )
     );
 }
 
 function swapUsersToRoomMessages() {
+    STATE.thread = null;
     let messagesDiv = document.getElementById("messages");
     messagesDiv.querySelectorAll(".container-message").forEach((msg) => {
         messagesDiv.removeChild(msg);
@@ -274,22 +322,158 @@ function swapUsersToRoomMessages() {
             STATE.room_id,
             data.user_id,
             data.username,
-            data.message
+            data.message,
+            false,
+            data
         )
     );
 }
 
-// Add `message` from `username` to `room`. If `push`, then actually store the
-// message. If the current room is `room`, render the message.
-function addMessageDirect(chat_id, sender_id, message, push = false) {
+// The server side details of a message: id, times and attachments
+function messageMeta(message) {
+    return {
+        id: message.id,
+        sent_at: message.sent_at,
+        edited_at: message.edited_at,
+        deleted_at: message.deleted_at,
+        attachments: message.attachments || [],
+        reactions: message.reactions || [],
+        reply_to_id: message.reply_to_id,
+        thread_id: message.thread_id,
+        reply_count: message.reply_count || 0,
+        // Only on our own messages, until the server acknowledges them
+        client_msg_id: message.client_msg_id,
+    };
+}
+
+// Send a chat message to the server, returning the id the server will
+// acknowledge it with
+function sendChat(body) {
+    const client_msg_id = forge.util.bytesToHex(forge.random.getBytesSync(16));
+    ws.send(
+        JSON.stringify({
+            v: PROTOCOL_VERSION,
+            type: "chat",
+            client_msg_id,
+            body,
+        })
+    );
+    return client_msg_id;
+}
+
+// Fill the text, time, attachments and actions of a rendered message
+function fillMessage(node, data, chat, mine, send) {
+    const key = chat.key;
+    if (data.reply_to_id && !STATE.thread) {
+        const parent = chat.messages.find((m) => m.id == data.reply_to_id);
+        node.querySelector(".message .reply-to").textContent =
+            "\u21AA " + (parent ? quote(parent) : "reply");
+    }
+    const text = node.querySelector(".message .text");
+    if (data.deleted_at) {
+        text.textContent = "Message deleted";
+        text.classList.add("deleted");
+    } else {
+        text.textContent = data.message;
+        renderAttachments(
+            node.querySelector(".message .attachments"),
+            data.attachments,
+            key
+        );
+    }
+    let time = formatTime(data.sent_at) + (data.edited_at ? " (edited)" : "");
+    // Direct messages show when the other user has read them
+    if (mine && data.sender_id != null && data.id != null) {
+        time += chat.peer_read_id >= data.id ? " \u2713\u2713" : " \u2713";
+    }
+    node.querySelector(".message .time").textContent = time;
+
+    const actions = node.querySelector(".message .actions");
+    if (data.id == null) return;
+    if (data.reply_count > 0 && !STATE.thread) {
+        const thread = document.createElement("input");
+        thread.type = "button";
+        thread.value =
+            data.reply_count + (data.reply_count == 1 ? " reply" : " replies");
+        thread.addEventListener("click", () => openThread(data));
+        actions.appendChild(thread);
+    }
+    if (data.deleted_at) return;
+    renderReactions(node.querySelector(".message .reactions"), data, send);
+    if (!STATE.thread) {
+        const reply = document.createElement("input");
+        reply.type = "button";
+        reply.value = "Reply";
+        // The server adds replies to a reply to the same thread
+        reply.addEventListener("click", () =>
+            setReplyTo({
+                id: data.id,
+                thread_id: data.thread_id || data.id,
+                label: quote(data),
+            })
+        );
+        actions.appendChild(reply);
+    }
+    const react = document.createElement("input");
+    react.type = "button";
+    react.value = "React";
+    react.addEventListener("click", () => {
+        const emoji = prompt("React with");
+        if (emoji == null || emoji.trim() == "") return;
+        send("ReactionAdd", { emoji: emoji.trim() });
+    });
+    actions.appendChild(react);
+    if (mine) {
+        const edit = document.createElement("input");
+        edit.type = "button";
+        edit.value = "Edit";
+        edit.addEventListener("click", () => {
+            const changed = prompt("Edit message", data.message);
+            if (changed == null || changed.trim() == "") return;
+            send("Edit", { content: encryptAes(changed.trim(), key) });
+        });
+        actions.appendChild(edit);
+    }
+    if (mine || STATE.admin) {
+        const remove = document.createElement("input");
+        remove.type = "button";
+        remove.value = "Delete";
+        remove.addEventListener("click", () => {
+            if (confirm("Delete this message?")) send("Delete", {});
+        });
+        actions.appendChild(remove);
+    }
+}
+
+// Add `message` from `sender_id` to the direct chat with `chat_id`. If
+// `push`, then actually store the message. If the current chat is `chat_id`,
+// render the message.
+function addMessageDirect(chat_id, sender_id, message, push = false, meta = {}) {
+    const data = { ...messageMeta(meta), sender_id, message };
     if (push) {
-        STATE.users[chat_id].messages.push({ sender_id, message });
+        STATE.users[chat_id].messages.push(data);
+        // Redrawing shows the new message along with its thread's count
+        if (
+            addReply(STATE.users[chat_id], true, chat_id, data) &&
+            STATE.recipient_id == chat_id
+        ) {
+            return redrawCurrentChat();
+        }
     }
 
-    if (STATE.recipient_id == chat_id) {
+    if (STATE.recipient_id == chat_id && shownInThread(data)) {
         var node = document.getElementById("direct").content.cloneNode(true);
-        node.querySelector(".message .text").textContent = message;
-        if (sender_id == STATE.user_id) {
+        const mine = sender_id == STATE.user_id;
+        fillMessage(node, data, STATE.users[chat_id], mine, (op, fields) =>
+            sendChat({
+                ["Direct" + op]: {
+                    id: data.id,
+                    recipient: chat_id,
+                    ...fields,
+                },
+            })
+        );
+        if (mine) {
             node.querySelector(".container-message").classList.add("minemess");
         }
         document.getElementById("messages").appendChild(node);
@@ -299,18 +483,37 @@ function addMessageDirect(chat_id, sender_id, message, push = false) {
 
 // Add `message` from `username` to `room`. If `push`, then actually store the
 // message. If the current room is `room`, render the message.
-function addMessageGroup(room_id, _user_id, username, message, push = false) {
+function addMessageGroup(
+    room_id,
+    user_id,
+    username,
+    message,
+    push = false,
+    meta = {}
+) {
+    const data = { ...messageMeta(meta), user_id, username, message };
     if (push) {
-        STATE.rooms[room_id].messages.push({ username, message });
+        STATE.rooms[room_id].messages.push(data);
+        if (
+            addReply(STATE.rooms[room_id], false, room_id, data) &&
+            STATE.room_id == room_id
+        ) {
+            return redrawCurrentChat();
+        }
     }
 
-    if (STATE.room_id == room_id) {
+    if (STATE.room_id == room_id && shownInThread(data)) {
         var node = document.getElementById("message").content.cloneNode(true);
         node.querySelector(".message .username").textContent = username;
         node.querySelector(".message .username").style.color =
             hashColor(username);
-        node.querySelector(".message .text").textContent = message;
-        if (username == STATE.user) {
+        const mine = username == STATE.user;
+        fillMessage(node, data, STATE.rooms[room_id], mine, (op, fields) =>
+            sendChat({
+                ["Group" + op]: { id: data.id, group_id: room_id, ...fields },
+            })
+        );
+        if (mine) {
             node.querySelector(".container-message").classList.add("minemess");
         }
         document.getElementById("messages").appendChild(node);
@@ -318,11 +521,506 @@ function addMessageGroup(room_id, _user_id, username, message, push = false) {
     }
 }
 
+// One button per emoji with its count. Clicking it adds or takes back the
+// user's own reaction
+function renderReactions(container, data, send) {
+    for (const reaction of data.reactions) {
+        const mine = reaction.user_ids.includes(STATE.user_id);
+        const button = document.createElement("input");
+        button.type = "button";
+        button.value = reaction.emoji + " " + reaction.count;
+        if (mine) button.classList.add("mine");
+        button.addEventListener("click", () =>
+            send(mine ? "ReactionRemove" : "ReactionAdd", {
+                emoji: reaction.emoji,
+            })
+        );
+        container.appendChild(button);
+    }
+}
+
+// Apply a reaction of `user_id` to message `id` of `chat`, added or taken back
+function applyReaction(chat, id, user_id, emoji, added) {
+    storedCopies(chat, id).forEach((message) =>
+        react(message, user_id, emoji, added)
+    );
+    redrawCurrentChat();
+}
+
+function react(message, user_id, emoji, added) {
+    let reaction = message.reactions.find((r) => r.emoji == emoji);
+    if (!reaction) {
+        if (!added) return;
+        reaction = { emoji, count: 0, user_ids: [] };
+        message.reactions.push(reaction);
+    }
+    const known = reaction.user_ids.includes(user_id);
+    if (added && !known) {
+        reaction.user_ids.push(user_id);
+    } else if (!added && known) {
+        reaction.user_ids = reaction.user_ids.filter((u) => u != user_id);
+    }
+    reaction.count = reaction.user_ids.length;
+    message.reactions = message.reactions.filter((r) => r.count > 0);
+}
+
+// Message `id` of `chat`, as stored for the chat and for its open thread
+function storedCopies(chat, id) {
+    if (!chat) return [];
+    const thread = STATE.thread;
+    const threadChat =
+        thread &&
+        (thread.direct ? STATE.users[thread.chat_id] : STATE.rooms[thread.chat_id]);
+    return [chat.messages, threadChat === chat ? thread.messages : []]
+        .map((messages) => messages.find((m) => m.id == id))
+        .filter((m) => m);
+}
+
+// A short description of a message, to show what a reply answers
+function quote(message) {
+    const text = message.deleted_at ? "Message deleted" : message.message;
+    const short = text.length > 60 ? text.slice(0, 60) + "\u2026" : text;
+    return message.username ? message.username + ": " + short : short;
+}
+
+// Answer `target` with the next message, or stop answering with `null`
+function setReplyTo(target) {
+    STATE.replyTo = target;
+    const field = document.getElementById("message");
+    if (!field) return;
+    field.placeholder = target
+        ? "Reply to " + target.label + " (Esc to cancel)"
+        : "Send a message...";
+}
+
+// Whether a message belongs on screen: every message of the chat, or only
+// the thread when one is open
+function shownInThread(data) {
+    return (
+        !STATE.thread ||
+        data.id == STATE.thread.id ||
+        data.thread_id == STATE.thread.id
+    );
+}
+
+// Count a new message of `chat` as a reply to its thread's first message,
+// adding it to the open thread. Returns whether a shown count changed
+function addReply(chat, direct, chat_id, data) {
+    if (!data.thread_id) return false;
+    const parent = chat.messages.find((m) => m.id == data.thread_id);
+    if (parent) parent.reply_count = (parent.reply_count || 0) + 1;
+    const thread = STATE.thread;
+    if (
+        thread &&
+        thread.direct == direct &&
+        thread.chat_id == chat_id &&
+        thread.id == data.thread_id
+    ) {
+        thread.messages.push(data);
+        return false;
+    }
+    return parent != null && !thread;
+}
+
+function threadUri(thread, before) {
+    return (
+        (thread.direct ? "/directs/" : "/rooms/") +
+        thread.chat_id +
+        "/messages/" +
+        thread.id +
+        "/thread" +
+        (before ? "?before=" + before : "")
+    );
+}
+
+// Decrypt a page of messages from the server
+function decodePage(page, direct, chat) {
+    return page.map((message) =>
+        direct
+            ? {
+                  ...messageMeta(message),
+                  sender_id: message.user_id,
+                  message: decryptAes(message.message, chat.key),
+              }
+            : {
+                  ...messageMeta(message),
+                  user_id: message.user_id,
+                  username: message.user_name,
+                  message: decryptAes(message.message, chat.key),
+              }
+    );
+}
+
+// Show `parent` and its replies instead of the whole chat
+function openThread(parent) {
+    const direct =
+        document.getElementById("user-list").style.display == "block";
+    const chat_id = direct ? STATE.recipient_id : STATE.room_id;
+    const chat = direct ? STATE.users[chat_id] : STATE.rooms[chat_id];
+    const thread = { direct, chat_id, id: parent.id, messages: [parent] };
+    fetch(threadUri(thread))
+        .then(checkedJson)
+        .then((page) => {
+            thread.messages.push(...decodePage(page, direct, chat));
+            STATE.thread = thread;
+            setReplyTo(null);
+            renderThread();
+        })
+        .catch((err) => console.error(err));
+}
+
+function renderThread() {
+    const thread = STATE.thread;
+    const messagesDiv = document.getElementById("messages");
+    messagesDiv.querySelectorAll(".container-message").forEach((msg) => {
+        messagesDiv.removeChild(msg);
+    });
+    const header = document.createElement("div");
+    header.classList.add("container-message", "thread-header");
+    const back = document.createElement("input");
+    back.type = "button";
+    back.value = "\u2190 Back to the chat";
+    back.addEventListener("click", () => {
+        STATE.thread = null;
+        redrawCurrentChat();
+    });
+    header.appendChild(back);
+    messagesDiv.appendChild(header);
+    thread.messages.forEach((data) =>
+        thread.direct
+            ? addMessageDirect(
+                  thread.chat_id,
+                  data.sender_id,
+                  data.message,
+                  false,
+                  data
+              )
+            : addMessageGroup(
+                  thread.chat_id,
+                  data.user_id,
+                  data.username,
+                  data.message,
+                  false,
+                  data
+              )
+    );
+}
+
+// Fetch the replies before the oldest one shown in the open thread
+function loadOlderReplies() {
+    const thread = STATE.thread;
+    const chat = thread.direct
+        ? STATE.users[thread.chat_id]
+        : STATE.rooms[thread.chat_id];
+    const oldest = thread.messages.find((m) => m.id != null && m.id != thread.id);
+    if (thread.exhausted || !oldest || STATE.loadingOlder) return;
+
+    STATE.loadingOlder = true;
+    fetch(threadUri(thread, oldest.id))
+        .then(checkedJson)
+        .then((page) => {
+            if (page.length == 0) {
+                thread.exhausted = true;
+                return;
+            }
+            thread.messages.splice(1, 0, ...decodePage(page, thread.direct, chat));
+            const messagesDiv = document.getElementById("messages");
+            const fromBottom = messagesDiv.scrollHeight - messagesDiv.scrollTop;
+            renderThread();
+            messagesDiv.scrollTop = messagesDiv.scrollHeight - fromBottom;
+        })
+        .catch((err) => console.error(err))
+        .finally(() => setTimeout(() => (STATE.loadingOlder = false), 150));
+}
+
+// The message the message being sent answers, if any
+function replyTarget() {
+    if (STATE.thread) return STATE.thread.id;
+    return STATE.replyTo ? STATE.replyTo.id : null;
+}
+
+// The thread the message being sent joins, as the server will tell
+function replyThread() {
+    if (STATE.thread) return STATE.thread.id;
+    return STATE.replyTo ? STATE.replyTo.thread_id : null;
+}
+
+// Show the number of unread messages next to the chat's name
+function updateBadge(direct, id) {
+    const chat = direct ? STATE.users[id] : STATE.rooms[id];
+    const button = document.querySelector(
+        (direct ? "#user-list .user" : "#room-list .room") +
+            '[data-id="' +
+            id +
+            '"]'
+    );
+    if (!chat || !button) return;
+    button.value =
+        chat.unread > 0 ? chat.name + " (" + chat.unread + ")" : chat.name;
+}
+
+// Show the presence of a user next to their direct chat
+function updatePresence(user_id) {
+    const presence = STATE.presence[user_id];
+    const button = document.querySelector(
+        '#user-list .user[data-id="' + user_id + '"]'
+    );
+    if (!presence || !button) return;
+    button.dataset.presence = presence.status;
+    button.title =
+        presence.status == "offline" && presence.last_seen_at
+            ? "Last seen " +
+              new Date(presence.last_seen_at).toLocaleString()
+            : presence.status;
+}
+
+function setPresence(presence) {
+    if (presence.user_id == STATE.user_id) {
+        document.getElementById("presence").value =
+            presence.status == "offline" ? "online" : presence.status;
+        return;
+    }
+    STATE.presence[presence.user_id] = presence;
+    updatePresence(presence.user_id);
+}
+
+// Ask for the presence of everyone we have a direct chat with
+function getPresence() {
+    const ids = Object.keys(STATE.users);
+    if (ids.length == 0) return;
+    const query = new URLSearchParams(ids.map((id) => ["user_id", id]));
+    fetch("/presence?" + query, { method: "GET" })
+        .then(checkedJson)
+        .then((found) => found.forEach(setPresence))
+        .catch((err) => console.error(err));
+}
+
+// Whether the chat is the one on screen, with the page visible
+function onScreen(direct, id) {
+    const list = document.getElementById(direct ? "user-list" : "room-list");
+    return (
+        list.style.display == "block" &&
+        (direct ? STATE.recipient_id : STATE.room_id) == id &&
+        document.visibilityState == "visible"
+    );
+}
+
+// Tell the server the user has read the chat up to its newest message
+function markRead(direct, id) {
+    const chat = direct ? STATE.users[id] : STATE.rooms[id];
+    if (!chat || !ws || ws.readyState != WebSocket.OPEN) return;
+    const newest = chat.messages.findLast((m) => m.id != null);
+    if (
+        !newest ||
+        (chat.last_read_id != null && newest.id <= chat.last_read_id)
+    )
+        return;
+    sendChat(
+        direct
+            ? { DirectRead: { id: newest.id, recipient: id } }
+            : { GroupRead: { id: newest.id, group_id: id } }
+    );
+}
+
+// A message from someone else arrived in a chat
+function received(direct, id) {
+    const chat = direct ? STATE.users[id] : STATE.rooms[id];
+    if (!chat) return;
+    if (onScreen(direct, id)) {
+        markRead(direct, id);
+    } else {
+        chat.unread = (chat.unread || 0) + 1;
+        updateBadge(direct, id);
+    }
+}
+
+// The server moved our read marker in `chat`
+function readUpTo(chat, id) {
+    if (!chat) return;
+    chat.last_read_id = Math.max(chat.last_read_id || 0, id);
+    // Only what was loaded can be counted again
+    chat.unread = chat.messages.filter(
+        (m) =>
+            m.id > chat.last_read_id &&
+            !m.deleted_at &&
+            (m.sender_id != null
+                ? m.sender_id != STATE.user_id
+                : m.username != STATE.user)
+    ).length;
+}
+
+// Whether the direct chats are shown, and the id of the open chat
+function openChat() {
+    const direct =
+        document.getElementById("user-list").style.display == "block";
+    return { direct, id: direct ? STATE.recipient_id : STATE.room_id };
+}
+
+function typingKey(direct, id) {
+    return (direct ? "user:" : "room:") + id;
+}
+
+// Tell the open chat we are typing. The server passes it on at most once
+// every few seconds, so it is repeated while the user keeps typing
+function startTyping() {
+    const { direct, id } = openChat();
+    if (id == -1 || !ws || ws.readyState != WebSocket.OPEN) return;
+    clearTimeout(STATE.typingIdle);
+    STATE.typingIdle = setTimeout(stopTyping, TYPING_IDLE_MS);
+    if (Date.now() - STATE.typingSent < 1000) return;
+    STATE.typingSent = Date.now();
+    sendChat(
+        direct
+            ? { DirectTyping: { recipient: id, typing: true } }
+            : { GroupTyping: { group_id: id, typing: true } }
+    );
+}
+
+// Tell the open chat we stopped typing, if we said we started
+function stopTyping() {
+    clearTimeout(STATE.typingIdle);
+    STATE.typingIdle = null;
+    if (STATE.typingSent == 0) return;
+    STATE.typingSent = 0;
+    const { direct, id } = openChat();
+    if (id == -1 || !ws || ws.readyState != WebSocket.OPEN) return;
+    sendChat(
+        direct
+            ? { DirectTyping: { recipient: id, typing: false } }
+            : { GroupTyping: { group_id: id, typing: false } }
+    );
+}
+
+function setTyping(direct, chat_id, user_id, name, typing) {
+    const key = typingKey(direct, chat_id);
+    const chat = (STATE.typing[key] ||= {});
+    if (typing) chat[user_id] = { name, until: Date.now() + TYPING_SHOWN_MS };
+    else delete chat[user_id];
+    renderTyping();
+}
+
+// Show who is typing in the open chat
+function renderTyping() {
+    const { direct, id } = openChat();
+    const now = Date.now();
+    const names = Object.values(STATE.typing[typingKey(direct, id)] || {})
+        .filter((typing) => typing.until > now)
+        .map((typing) => typing.name);
+    document.getElementById("typing").textContent =
+        names.length == 0
+            ? ""
+            : names.join(", ") +
+              (names.length == 1 ? " is typing…" : " are typing…");
+}
+
+function redrawCurrentChat() {
+    if (STATE.thread) {
+        renderThread();
+    } else if (document.getElementById("user-list").style.display == "block") {
+        swapRoomsToUsersMessages();
+    } else {
+        swapUsersToRoomMessages();
+    }
+}
+
+// Apply an edit or deletion of message `id`, sent by the server after it was
+// saved, to the stored copy of `chat`.
+function applyChange(chat, id, change) {
+    storedCopies(chat, id).forEach((message) => {
+        if (change.deleted_at) {
+            message.deleted_at = change.deleted_at;
+            message.message = "";
+            message.attachments = [];
+            message.reactions = [];
+        } else {
+            message.edited_at = change.edited_at;
+            message.message = decryptAes(change.content, chat.key);
+        }
+    });
+    redrawCurrentChat();
+}
+
+// Local time of a message, empty until the server saved it
+function formatTime(sent_at) {
+    if (!sent_at) return "";
+    return new Date(sent_at).toLocaleTimeString([], {
+        hour: "2-digit",
+        minute: "2-digit",
+    });
+}
+
+// The server acknowledges our own messages once saved. Give their id and
+// time to the message sent with `client_msg_id`.
+function confirmSent(chat, client_msg_id, saved) {
+    if (!chat) return;
+    const pending = [chat, STATE.thread || { messages: [] }]
+        .map((c) => c.messages.find((m) => m.client_msg_id == client_msg_id))
+        .filter((m) => m);
+    if (pending.length == 0) return;
+    pending.forEach((m) => {
+        m.id = saved.id;
+        m.sent_at = saved.sent_at;
+        delete m.client_msg_id;
+    });
+    redrawCurrentChat();
+}
+
+// The server handled one of our messages. Changes come back stamped with
+// their author and time, and are applied like everyone else's.
+function acknowledged(ack) {
+    const body = ack.body;
+    if (!body) return;
+    if ("Direct" in body) {
+        confirmSent(STATE.users[body.Direct.recipient], ack.client_msg_id, ack);
+    } else if ("Group" in body) {
+        confirmSent(STATE.rooms[body.Group.group_id], ack.client_msg_id, ack);
+    } else {
+        handleEvent(body);
+    }
+}
+
 function scrollToBottom() {
+    if (STATE.loadingOlder) return;
     let chatContainer = document.getElementById("messages");
     chatContainer.scrollTop = chatContainer.scrollHeight;
 }
 
+// Fetch the page of messages before the oldest one shown in the open chat
+// and add it on top, keeping the scroll position.
+function loadOlderMessages() {
+    if (STATE.thread) return loadOlderReplies();
+    const direct =
+        document.getElementById("user-list").style.display == "block";
+    const chat_id = direct ? STATE.recipient_id : STATE.room_id;
+    const chat = direct ? STATE.users[chat_id] : STATE.rooms[chat_id];
+    if (!chat || chat.exhausted || STATE.loadingOlder) return;
+    const oldest = chat.messages.find((m) => m.id != null);
+    if (!oldest) return;
+
+    STATE.loadingOlder = true;
+    const uri =
+        (direct ? "/directs/" : "/rooms/") +
+        chat_id +
+        "/messages?before=" +
+        oldest.id;
+    fetch(uri)
+        .then(checkedJson)
+        .then((page) => {
+            if (page.length == 0) {
+                chat.exhausted = true;
+                return;
+            }
+            chat.messages.unshift(...decodePage(page, direct, chat));
+
+            const messagesDiv = document.getElementById("messages");
+            const fromBottom = messagesDiv.scrollHeight - messagesDiv.scrollTop;
+            redrawCurrentChat();
+            messagesDiv.scrollTop = messagesDiv.scrollHeight - fromBottom;
+        })
+        .catch((err) => console.error(err))
+        .finally(() => setTimeout(() => (STATE.loadingOlder = false), 150));
+}
+
 // Subscribe to the event source at `uri` with exponential backoff reconnect.
 function subscribe(uri) {
     let retryTime = 1;
@@ -345,7 +1043,8 @@ function subscribe(uri) {
                     msg.user_id,
                     msg.user_name,
                     decryptAes(msg.message, STATE.rooms[msg.room_id].key),
-                    true
+                    true,
+                    messageMeta(msg)
                 );
         });
 
@@ -412,6 +1111,8 @@ function encryptAes(message, key) {
 }
 
 function decryptAes(cipherText, key) {
+    // Deleted messages have no content
+    if (!cipherText) return "";
     var reb64 = CryptoJS.enc.Hex.parse(cipherText);
     var bytes = reb64.toString(CryptoJS.enc.Base64);
     var decrypt = CryptoJS.AES.decrypt(bytes, key);
@@ -419,22 +1120,183 @@ function decryptAes(cipherText, key) {
     return plain;
 }
 
+function wordArrayToBytes(wordArray) {
+    const bytes = new Uint8Array(wordArray.sigBytes);
+    for (let i = 0; i < wordArray.sigBytes; i++) {
+        bytes[i] = (wordArray.words[i >>> 2] >>> (24 - (i % 4) * 8)) & 0xff;
+    }
+    return bytes;
+}
+
+function bytesToWordArray(bytes) {
+    const words = [];
+    for (let i = 0; i < bytes.length; i++) {
+        words[i >>> 2] |= bytes[i] << (24 - (i % 4) * 8);
+    }
+    return CryptoJS.lib.WordArray.create(words, bytes.length);
+}
+
+// Files are encrypted with the chat key like messages, but kept as bytes
+function encryptFile(buffer, key) {
+    const encrypted = CryptoJS.AES.encrypt(
+        bytesToWordArray(new Uint8Array(buffer)),
+        key
+    ).toString();
+    return wordArrayToBytes(CryptoJS.enc.Base64.parse(encrypted));
+}
+
+function decryptFile(buffer, key) {
+    const encrypted = CryptoJS.enc.Base64.stringify(
+        bytesToWordArray(new Uint8Array(buffer))
+    );
+    return wordArrayToBytes(CryptoJS.AES.decrypt(encrypted, key));
+}
+
+function checkedJson(response) {
+    if (response.ok) {
+        return response.json();
+    }
+    return response.text().then((text) => {
+        throw new Error(text);
+    });
+}
+
+// Encrypt `file` and upload it in chunks to the room or direct chat in
+// `target`, resuming from the server's offset when a chunk fails. Resolves
+// to the attachment, ready to be sent with a message.
+async function uploadAttachment(file, target, key) {
+    const encrypted = encryptFile(await file.arrayBuffer(), key);
+    const mime_type = file.type || "application/octet-stream";
+    let status = await fetch("/attachments", {
+        method: "POST",
+        body: new URLSearchParams({
+            ...target,
+            mime_type,
+            size: encrypted.length,
+        }),
+    }).then(checkedJson);
+
+    let failures = 0;
+    while (!status.complete) {
+        const chunk = encrypted.slice(
+            status.received,
+            status.received + status.chunk_size
+        );
+        try {
+            status = await fetch(
+                "/attachments/" + status.id + "?offset=" + status.received,
+                { method: "PUT", body: chunk }
+            ).then(checkedJson);
+            failures = 0;
+        } catch (err) {
+            if (++failures > 5) throw err;
+            await new Promise((resolve) => setTimeout(resolve, 1000 * failures));
+            status = await fetch("/attachments/" + status.id).then(checkedJson);
+        }
+    }
+    STATE.attachmentUrls[status.id] = URL.createObjectURL(
+        new Blob([await file.arrayBuffer()], { type: mime_type })
+    );
+    return { id: status.id, mime_type, size: status.size };
+}
+
+// Download, decrypt and show `attachments` in `container`. Attachments
+// received over the websocket only carry their id.
+function renderAttachments(container, attachments, key) {
+    if (!container || !attachments) return;
+    attachments.forEach((attachment) => {
+        const id = typeof attachment === "string" ? attachment : attachment.id;
+        const element = document.createElement("span");
+        element.className = "attachment";
+        container.appendChild(element);
+
+        const status = attachment.mime_type
+            ? Promise.resolve(attachment)
+            : fetch("/attachments/" + id).then(checkedJson);
+        status
+            .then((status) => {
+                if (STATE.attachmentUrls[id]) {
+                    return [status, STATE.attachmentUrls[id]];
+                }
+                return fetch("/attachments/" + id + "/content")
+                    .then((response) => {
+                        if (!response.ok) throw new Error("download failed");
+                        return response.arrayBuffer();
+                    })
+                    .then((buffer) => {
+                        const url = URL.createObjectURL(
+                            new Blob([decryptFile(buffer, key)], {
+                                type: status.mime_type,
+                            })
+                        );
+                        STATE.attachmentUrls[id] = url;
+                        return [status, url];
+                    });
+            })
+            .then(([status, url]) => {
+                let media;
+                if (status.mime_type.startsWith("image/")) {
+                    media = document.createElement("img");
+                } else if (status.mime_type.startsWith("video/")) {
+                    media = document.createElement("video");
+                    media.controls = true;
+                } else if (status.mime_type.startsWith("audio/")) {
+                    media = document.createElement("audio");
+                    media.controls = true;
+                } else {
+                    media = document.createElement("a");
+                    media.href = url;
+                    media.download = id;
+                    media.textContent = "Download";
+                }
+                if (!media.href) media.src = url;
+                element.appendChild(media);
+                setTimeout(scrollToBottom, 100);
+            })
+            .catch((err) => {
+                element.textContent = "Attachment unavailable";
+                console.error(err);
+            });
+    });
+}
+
+// RSA-OAEP with SHA-256, wrapped in the envelope the server expects
+const RSA_OAEP_OPTIONS = () => ({
+    md: forge.md.sha256.create(),
+    mgf1: { md: forge.md.sha256.create() },
+});
+
 function encryptRsa(message) {
     try {
-        return forge.util.encode64(
-            forge.pki
-                .publicKeyFromPem(STATE.serverPubKey)
-                .encrypt(forge.util.encodeUtf8(message))
-        );
+        return JSON.stringify({
+            v: 1,
+            kid: STATE.serverKeyId,
+            alg: "RSA-OAEP-256",
+            ct: forge.util.encode64(
+                forge.pki
+                    .publicKeyFromPem(STATE.serverPubKey)
+                    .encrypt(
+                        forge.util.encodeUtf8(message),
+                        "RSA-OAEP",
+                        RSA_OAEP_OPTIONS()
+                    )
+            ),
+        });
     } catch (error) {
         console.log("Errore durante la crittografia: ", error);
     }
 }
 
 function decryptRsa(encrypted) {
-    const encryptedMessageBytes = forge.util.decode64(encrypted);
+    const envelope = JSON.parse(encrypted);
+    if (envelope.v !== 1 || envelope.alg !== "RSA-OAEP-256") {
+        throw new Error("Unsupported RSA envelope");
+    }
+    const encryptedMessageBytes = forge.util.decode64(envelope.ct);
     const decryptedMessageBytes = STATE.clientKeys.privateKey.decrypt(
-        encryptedMessageBytes
+        encryptedMessageBytes,
+        "RSA-OAEP",
+        RSA_OAEP_OPTIONS()
     );
     return forge.util.decodeUtf8(decryptedMessageBytes);
 }
@@ -444,19 +1306,19 @@ function getPubKey() {
         fetch("/rsa-pub-key", {
             method: "GET",
         })
-            .then((response) => response.text())
+            .then((response) => response.json())
             .then((data) => {
-                STATE.serverPubKey = data;
+                STATE.serverPubKey = data.key;
+                STATE.serverKeyId = data.kid;
             });
     }
 }
 
 function getRooms() {
-    const user_id = STATE.user_id;
     const rsa_key = forge.pki.publicKeyToPem(STATE.clientKeys.publicKey);
     fetch("/get-personal-rooms", {
         method: "POST",
-        body: new URLSearchParams({ user_id, rsa_key }),
+        body: new URLSearchParams({ rsa_key }),
     })
         .then((response) => {
             if (response.ok) {
@@ -472,6 +1334,9 @@ function getRooms() {
             if (parsed.length > 0) {
                 parsed.forEach((room) => {
                     addRoom(room.room_id, room.room_name, decryptRsa(room.key));
+                    STATE.rooms[room.room_id].last_read_id = room.last_read_id;
+                    STATE.rooms[room.room_id].unread = room.unread;
+                    updateBadge(false, room.room_id);
                     room.messages.forEach((message) => {
                         addMessageGroup(
                             message.room_id,
@@ -481,10 +1346,12 @@ function getRooms() {
                                 message.message,
                                 STATE.rooms[message.room_id].key
                             ),
-                            true
+                            true,
+                            messageMeta(message)
                         );
                     });
                 });
+                if (onScreen(false, STATE.room_id)) markRead(false, STATE.room_id);
                 return;
             }
             return;
@@ -495,11 +1362,10 @@ function getRooms() {
 }
 
 function getDirects() {
-    const user_id = STATE.user_id;
     const rsa_key = forge.pki.publicKeyToPem(STATE.clientKeys.publicKey);
     fetch("/get-directs", {
         method: "POST",
-        body: new URLSearchParams({ user_id, rsa_key }),
+        body: new URLSearchParams({ rsa_key }),
     })
         .then((response) => {
             if (response.ok) {
@@ -520,6 +1386,11 @@ function getDirects() {
                         direct.user_name,
                         decryptRsa(direct.key)
                     );
+                    const chat = STATE.users[direct.user_id];
+                    chat.last_read_id = direct.last_read_id;
+                    chat.unread = direct.unread;
+                    chat.peer_read_id = direct.peer_last_read_id;
+                    updateBadge(true, direct.user_id);
                     direct.messages.forEach((message) => {
                         addMessageDirect(
                             direct.user_id,
@@ -528,10 +1399,13 @@ function getDirects() {
                                 message.message,
                                 STATE.users[direct.user_id].key
                             ),
-                            true
+                            true,
+                            messageMeta(message)
                         );
                     });
                 });
+                if (onScreen(true, STATE.recipient_id)) markRead(true, STATE.recipient_id);
+                getPresence();
                 return;
             }
             return;
@@ -558,42 +1432,10 @@ function setup() {
             data = JSON.parse(data);
             STATE.user_id = data.id;
             STATE.user = data.username;
+            STATE.admin = data.admin == 1;
             document.title += " | " + STATE.user;
 
-            ws = new WebSocket(
-                "ws://" + location.host + "/messages/" + STATE.user_id
-            );
-
-            ws.onmessage = (event) => {
-                const msg = JSON.parse(event.data);
-                if ("Group" in msg) {
-                    addMessageGroup(
-                        msg.Group.group_id,
-                        msg.Group.sender_id,
-                        msg.Group.sender_name,
-                        decryptAes(
-                            msg.Group.content,
-                            STATE.rooms[msg.Group.group_id].key
-                        ),
-                        (document.getElementById("room-list").style.display =
-                            "block" ? true : false)
-                    );
-                } else if ("Direct" in msg) {
-                    addMessageDirect(
-                        msg.Direct.sender,
-                        msg.Direct.sender,
-                        decryptAes(
-                            msg.Direct.content,
-                            STATE.users[msg.Direct.sender].key
-                        ),
-                        (document.getElementById("user-list").style.display =
-                            "block" ? true : false)
-                    );
-                } else {
-                    console.error("unknown message type");
-                }
-            };
-
+            connectSocket();
             getRooms();
             getDirects();
         })
@@ -603,16 +1445,247 @@ function setup() {
     return true;
 }
 
+// Open the websocket, reconnecting with exponential backoff. After a
+// reconnection the server is asked for the messages missed in the meantime.
+function connectSocket() {
+    let retryTime = 1;
+
+    function connect(resume) {
+        ws = new WebSocket(
+            "ws://" +
+                location.host +
+                "/messages/" +
+                STATE.user_id +
+                "?v=" +
+                PROTOCOL_VERSION
+        );
+
+        ws.onopen = () => {
+            retryTime = 1;
+            if (resume)
+                ws.send(
+                    JSON.stringify({
+                        v: PROTOCOL_VERSION,
+                        type: "resume",
+                        ...resumePoints(),
+                    })
+                );
+        };
+
+        ws.onmessage = (event) => {
+            const frame = JSON.parse(event.data);
+            if (frame.type == "event") {
+                handleEvent(frame.body);
+            } else if (frame.type == "ack") {
+                acknowledged(frame);
+            } else if (frame.type == "missed") {
+                caughtUp(frame);
+            } else if (frame.type == "error") {
+                console.error(
+                    "message refused: " + frame.code + ", " + frame.message
+                );
+            }
+        };
+
+        // Also after a server restart (code 1012) or an idle timeout
+        ws.onclose = (event) => {
+            let timeout = retryTime;
+            retryTime = Math.min(64, retryTime * 2);
+            console.log(
+                `websocket closed (${event.code} ${event.reason}). attempting to reconnect in ${timeout}s`
+            );
+            setTimeout(() => connect(true), timeout * 1000);
+        };
+    }
+
+    connect(false);
+}
+
+// The newest message id we have of each room, and of each direct chat by the
+// other user's id
+function resumePoints() {
+    const newest = (chat) =>
+        chat.messages.reduce((max, m) => (m.id > max ? m.id : max), 0);
+    const rooms = {};
+    const directs = {};
+    for (const id in STATE.rooms) {
+        const seen = newest(STATE.rooms[id]);
+        if (seen) rooms[id] = seen;
+    }
+    for (const id in STATE.users) {
+        const seen = newest(STATE.users[id]);
+        if (seen) directs[id] = seen;
+    }
+    return { rooms, directs };
+}
+
+// Messages of one chat missed while disconnected, oldest first. When older
+// ones were left out, the chat starts over from these and the rest can be
+// paged in.
+function caughtUp(frame) {
+    const direct = frame.user_id != null;
+    const id = direct ? frame.user_id : frame.room_id;
+    const chat = direct ? STATE.users[id] : STATE.rooms[id];
+    if (!chat) return;
+    if (frame.truncated) {
+        chat.messages = chat.messages.filter((m) => m.id == null);
+        chat.exhausted = false;
+    }
+    decodePage(frame.messages, direct, chat).forEach((message) => {
+        if (chat.messages.some((m) => m.id == message.id)) return;
+        chat.messages.push(message);
+        const mine = direct
+            ? message.sender_id == STATE.user_id
+            : message.user_id == STATE.user_id;
+        if (!mine) received(direct, id);
+    });
+    redrawCurrentChat();
+}
+
+// Whether `chat` already has message `id`, e.g. sent again live after
+// catching up
+function known(chat, id) {
+    return chat != null && id != null && chat.messages.some((m) => m.id == id);
+}
+
+// A message from the server: someone else's message or change, or one of
+// ours from another device
+function handleEvent(msg) {
+    if ("GroupEdit" in msg || "GroupDelete" in msg) {
+        const change = msg.GroupEdit || msg.GroupDelete;
+        applyChange(STATE.rooms[change.group_id], change.id, change);
+    } else if ("DirectEdit" in msg || "DirectDelete" in msg) {
+        const change = msg.DirectEdit || msg.DirectDelete;
+        const peer =
+            change.sender == STATE.user_id
+                ? change.recipient
+                : change.sender;
+        applyChange(STATE.users[peer], change.id, change);
+    } else if (
+        "GroupReactionAdd" in msg ||
+        "GroupReactionRemove" in msg
+    ) {
+        const reaction =
+            msg.GroupReactionAdd || msg.GroupReactionRemove;
+        applyReaction(
+            STATE.rooms[reaction.group_id],
+            reaction.id,
+            reaction.user_id,
+            reaction.emoji,
+            "GroupReactionAdd" in msg
+        );
+    } else if (
+        "DirectReactionAdd" in msg ||
+        "DirectReactionRemove" in msg
+    ) {
+        const reaction =
+            msg.DirectReactionAdd || msg.DirectReactionRemove;
+        const peer =
+            reaction.sender == STATE.user_id
+                ? reaction.recipient
+                : reaction.sender;
+        applyReaction(
+            STATE.users[peer],
+            reaction.id,
+            reaction.sender,
+            reaction.emoji,
+            "DirectReactionAdd" in msg
+        );
+    } else if ("Presence" in msg) {
+        setPresence(msg.Presence);
+    } else if ("GroupTyping" in msg) {
+        const typing = msg.GroupTyping;
+        setTyping(
+            false,
+            typing.group_id,
+            typing.user_id,
+            typing.user_name,
+            typing.typing
+        );
+    } else if ("DirectTyping" in msg) {
+        const typing = msg.DirectTyping;
+        const peer = STATE.users[typing.sender];
+        if (peer)
+            setTyping(
+                true,
+                typing.sender,
+                typing.sender,
+                peer.name,
+                typing.typing
+            );
+    } else if ("Group" in msg) {
+        if (known(STATE.rooms[msg.Group.group_id], msg.Group.id)) return;
+        setTyping(
+            false,
+            msg.Group.group_id,
+            msg.Group.sender_id,
+            "",
+            false
+        );
+        addMessageGroup(
+            msg.Group.group_id,
+            msg.Group.sender_id,
+            msg.Group.sender_name,
+            decryptAes(
+                msg.Group.content,
+                STATE.rooms[msg.Group.group_id].key
+            ),
+            (document.getElementById("room-list").style.display =
+                "block" ? true : false),
+            messageMeta(msg.Group)
+        );
+        if (msg.Group.sender_id != STATE.user_id)
+            received(false, msg.Group.group_id);
+    } else if ("Direct" in msg) {
+        // Our own messages come from our other devices
+        const mine = msg.Direct.sender == STATE.user_id;
+        const peer = mine ? msg.Direct.recipient : msg.Direct.sender;
+        if (known(STATE.users[peer], msg.Direct.id)) return;
+        setTyping(true, peer, msg.Direct.sender, "", false);
+        addMessageDirect(
+            peer,
+            msg.Direct.sender,
+            decryptAes(msg.Direct.content, STATE.users[peer].key),
+            (document.getElementById("user-list").style.display =
+                "block" ? true : false),
+            messageMeta(msg.Direct)
+        );
+        if (!mine) received(true, peer);
+    } else if ("GroupRead" in msg) {
+        readUpTo(
+            STATE.rooms[msg.GroupRead.group_id],
+            msg.GroupRead.id
+        );
+        updateBadge(false, msg.GroupRead.group_id);
+    } else if ("DirectRead" in msg) {
+        const read = msg.DirectRead;
+        if (read.sender == STATE.user_id) {
+            readUpTo(STATE.users[read.recipient], read.id);
+            updateBadge(true, read.recipient);
+        } else if (STATE.users[read.sender]) {
+            STATE.users[read.sender].peer_read_id = read.id;
+            if (STATE.recipient_id == read.sender)
+                redrawCurrentChat();
+        }
+    } else {
+        console.error("unknown message type");
+    }
+}
+
 function swapUsersRooms(i) {
+    stopTyping();
     if (i == 1) {
         document.getElementById("user-list").style.display = "block";
         document.getElementById("room-list").style.display = "none";
         swapRoomsToUsersMessages();
+        markRead(true, STATE.recipient_id);
     } else if (i == 2) {
         document.getElementById("user-list").style.display = "none";
         document.getElementById("room-list").style.display = "block";
         swapUsersToRoomMessages();
+        markRead(false, STATE.room_id);
     }
+    renderTyping();
 }
 
 function init() {
@@ -637,13 +1710,56 @@ function init() {
         });
     });
 
+    // Catch up on the open chat when coming back to the page
+    document.addEventListener("visibilitychange", () => {
+        if (document.visibilityState != "visible") return;
+        const direct =
+            document.getElementById("user-list").style.display == "block";
+        markRead(direct, direct ? STATE.recipient_id : STATE.room_id);
+    });
+
+    document.getElementById("message").addEventListener("keydown", (e) => {
+        if (e.key == "Escape") setReplyTo(null);
+    });
+
+    document.getElementById("presence").addEventListener("change", (e) => {
+        if (!ws || ws.readyState != WebSocket.OPEN) return;
+        sendChat({ Presence: { status: e.target.value } });
+    });
+
+    document.getElementById("message").addEventListener("input", (e) => {
+        if (e.target.value.trim() == "") stopTyping();
+        else startTyping();
+    });
+    // Drop the names whose notifications ran out
+    setInterval(renderTyping, 1000);
+
+    document.getElementById("messages").addEventListener("scroll", (e) => {
+        if (e.target.scrollTop == 0) {
+            loadOlderMessages();
+        }
+    });
+
     // Set up the handler to post a message.
-    document.getElementById("new-message").addEventListener("submit", (e) => {
+    document
+        .getElementById("attach")
+        .addEventListener("click", () =>
+            document.getElementById("attachment").click()
+        );
+    document.getElementById("attachment").addEventListener("change", (e) => {
+        document
+            .getElementById("attach")
+            .classList.toggle("selected", e.target.files.length > 0);
+    });
+
+    document.getElementById("new-message").addEventListener("submit", async (e) => {
         e.preventDefault();
 
         const newMessageForm = document.getElementById("new-message");
         let messageField = newMessageForm.querySelector("#message");
-        if (messageField.value.trim() == "") {
+        let attachmentField = newMessageForm.querySelector("#attachment");
+        const file = attachmentField.files[0];
+        if (messageField.value.trim() == "" && !file) {
             return;
         }
 
@@ -656,16 +1772,44 @@ function init() {
             );
             if (!content) return;
 
-            ws.send(
-                '{ "Direct" :' +
-                    JSON.stringify({ sender, recipient, content }) +
-                    " }"
-            );
+            let uploaded = [];
+            if (file) {
+                try {
+                    uploaded = [
+                        await uploadAttachment(
+                            file,
+                            { recipient_id: recipient },
+                            STATE.users[recipient].key
+                        ),
+                    ];
+                } catch (err) {
+                    console.error(err);
+                    return;
+                }
+            }
+            const attachments = uploaded.map((a) => a.id);
+            const reply_to_id = replyTarget();
+            const thread_id = replyThread();
+
+            const client_msg_id = sendChat({
+                Direct: {
+                    recipient,
+                    content,
+                    attachments,
+                    reply_to_id,
+                },
+            });
             addMessageDirect(
                 recipient,
                 sender,
                 decryptAes(content, STATE.users[recipient].key),
-                true
+                true,
+                {
+                    attachments: uploaded,
+                    reply_to_id,
+                    thread_id,
+                    client_msg_id,
+                }
             );
         } else if (
             document.getElementById("room-list").style.display == "block"
@@ -679,28 +1823,57 @@ function init() {
             );
             if (!content || !sender_name) return;
 
-            ws.send(
-                '{ "Group" :' +
-                    JSON.stringify({
-                        sender_id,
-                        sender_name,
-                        group_id,
-                        content,
-                    }) +
-                    " }"
-            );
+            let uploaded = [];
+            if (file) {
+                try {
+                    uploaded = [
+                        await uploadAttachment(
+                            file,
+                            { room_id: group_id },
+                            STATE.rooms[group_id].key
+                        ),
+                    ];
+                } catch (err) {
+                    console.error(err);
+                    return;
+                }
+            }
+            const attachments = uploaded.map((a) => a.id);
+            const reply_to_id = replyTarget();
+            const thread_id = replyThread();
+
+            const client_msg_id = sendChat({
+                Group: {
+                    group_id,
+                    content,
+                    attachments,
+                    reply_to_id,
+                },
+            });
             addMessageGroup(
                 group_id,
                 sender_id,
                 sender_name,
                 decryptAes(content, STATE.rooms[group_id].key),
-                true
+                true,
+                {
+                    attachments: uploaded,
+                    reply_to_id,
+                    thread_id,
+                    client_msg_id,
+                }
             );
         } else {
             console.error("unknown message type");
         }
 
         messageField.value = "";
+        // The server stops our typing notification when the message arrives
+        clearTimeout(STATE.typingIdle);
+        STATE.typingSent = 0;
+        setReplyTo(null);
+        attachmentField.value = "";
+        document.getElementById("attach").classList.remove("selected");
     });
 
     // Set up the open popup handler and get available rooms.
@@ -764,7 +1937,6 @@ function init() {
                   )
                 : null;
             const hidden = false;
-            const user_id = STATE.user_id;
             const rsa_client_key = forge.pki.publicKeyToPem(
                 STATE.clientKeys.publicKey
             );
@@ -781,7 +1953,6 @@ function init() {
                     password,
                     require_password,
                     hidden,
-                    user_id,
                     rsa_client_key,
                 }),
             })
@@ -803,13 +1974,15 @@ function init() {
                     );
                     parsed.messages.forEach((message) => {
                         addMessageGroup(
-                            message.room,
-                            message.username,
+                            message.room_id,
+                            message.user_id,
+                            message.user_name,
                             decryptAes(
                                 message.message,
-                                STATE.rooms[message.room].key
+                                STATE.rooms[message.room_id].key
                             ),
-                            true
+                            true,
+                            messageMeta(message)
                         );
                     });
                     closeRoomForm();
@@ -828,7 +2001,6 @@ function init() {
     document.getElementById("add-user").addEventListener("submit", (e) => {
         e.preventDefault();
 
-        const user_id = STATE.user_id;
         const username = document.getElementById("new-user-name").value.trim();
         const rsa_key = forge.pki.publicKeyToPem(STATE.clientKeys.publicKey);
         if (username == "") {
@@ -838,7 +2010,7 @@ function init() {
 
         fetch("/add-direct", {
             method: "POST",
-            body: new URLSearchParams({ user_id, username, rsa_key }),
+            body: new URLSearchParams({ username, rsa_key }),
         })
             .then((response) => {
                 if (response.ok) {
@@ -953,7 +2125,6 @@ function init() {
         let new_password_input = document.getElementById("new-password");
         let repeat_password_input = document.getElementById("confirm-password");
 
-        let user_id = STATE.user_id;
         let old_password = old_password_input.value.trim();
         let new_password = new_password_input.value.trim();
         let repeat_password = repeat_password_input.value.trim();
@@ -974,7 +2145,6 @@ function init() {
         fetch("/change-pass", {
             method: "POST",
             body: new URLSearchParams({
-                user_id,
                 old_password,
                 new_password,
             }),
@@ -0,0 +1,151 @@
+var STATE = {};
+
+const TOKEN = new URLSearchParams(location.search).get("token");
+
+// RSA-OAEP with SHA-256, wrapped in the envelope the server expects
+const RSA_OAEP_OPTIONS = () => ({
+    md: forge.md.sha256.create(),
+    mgf1: { md: forge.md.sha256.create() },
+});
+
+function getPubKey() {
+    fetch("/rsa-pub-key", {
+        method: "GET",
+    })
+        .then((response) => response.json())
+        .then((data) => {
+            STATE.serverPubKey = data.key;
+            STATE.serverKeyId = data.kid;
+        });
+}
+
+function encryptRsa(message) {
+    try {
+        return JSON.stringify({
+            v: 1,
+            kid: STATE.serverKeyId,
+            alg: "RSA-OAEP-256",
+            ct: forge.util.encode64(
+                forge.pki
+                    .publicKeyFromPem(STATE.serverPubKey)
+                    .encrypt(
+                        forge.util.encodeUtf8(message),
+                        "RSA-OAEP",
+                        RSA_OAEP_OPTIONS()
+                    )
+            ),
+        });
+    } catch (error) {
+        console.log("Errore durante la crittografia: ", error);
+    }
+}
+
+function fieldError(m, f) {
+    f.classList.add("errorField");
+    let fLabel = document.querySelector('label[for="' + f.id + '"]');
+    fLabel.classList.add("errorLabel");
+    fLabel.innerText += " | " + m;
+}
+
+function fieldReset(f) {
+    try {
+        f.classList.remove("errorField");
+        let fLabel = document.querySelector('label[for="' + f.id + '"]');
+        fLabel.classList.remove("errorLabel");
+        fLabel.innerText = fLabel.innerText.split(" | ")[0];
+    } catch (error) {}
+}
+
+function showMessage(text) {
+    document.getElementById("reset-message").innerText = text;
+}
+
+function post(uri, params) {
+    return fetch(uri, {
+        method: "POST",
+        body: new URLSearchParams(params),
+    }).then((response) =>
+        response.text().then((text) => {
+            if (!response.ok) {
+                throw new Error(text);
+            }
+            return text;
+        })
+    );
+}
+
+// Ask for a reset link
+function forgot() {
+    const emailField = document.getElementById("email");
+    fieldReset(emailField);
+
+    if (emailField.value.trim() == "") {
+        fieldError("Email is required", emailField);
+        return;
+    }
+
+    post("/forgot-password", { email: emailField.value.trim() })
+        .then((text) => {
+            showMessage(text);
+            document.getElementById("forgot").hidden = true;
+            document.getElementById("signup-button").hidden = true;
+        })
+        .catch((err) => {
+            console.error(err);
+            fieldError(err.message, emailField);
+        });
+}
+
+// Set the new password with the emailed token
+function reset() {
+    const passField = document.getElementById("password");
+    const repeatField = document.getElementById("password-repeat");
+    fieldReset(passField);
+    fieldReset(repeatField);
+
+    if (passField.value.length < 8) {
+        fieldError("At least 8 characters", passField);
+        return;
+    }
+    if (passField.value != repeatField.value) {
+        fieldError("Passwords don't match", repeatField);
+        return;
+    }
+
+    post("/reset-password", {
+        token: TOKEN,
+        new_password: encryptRsa(passField.value),
+    })
+        .then((text) => {
+            showMessage(text);
+            document.getElementById("reset").hidden = true;
+            document.getElementById("signup-button").hidden = true;
+        })
+        .catch((err) => {
+            console.error(err);
+            showMessage(err.message);
+        });
+}
+
+if (TOKEN) {
+    getPubKey();
+    document.getElementById("reset-title").innerText = "Choose a new password";
+    document.getElementById("reset").hidden = false;
+    document.getElementById("signup-button").value = "Change password";
+} else {
+    showMessage("Enter the email you signed up with to get a reset link.");
+    document.getElementById("forgot").hidden = false;
+}
+
+document.querySelector("form").addEventListener("submit", (e) => {
+    e.preventDefault();
+    if (TOKEN) {
+        reset();
+    } else {
+        forgot();
+    }
+});
+
+document.getElementById("login-button").addEventListener("click", () => {
+    location.href = "/login";
+});
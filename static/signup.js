@@ -10,9 +10,10 @@ function getPubKey() {
         fetch("/rsa-pub-key", {
             method: "GET",
         })
-            .then((response) => response.text())
+            .then((response) => response.json())
             .then((data) => {
-                STATE.serverPubKey = data;
+                STATE.serverPubKey = data.key;
+                STATE.serverKeyId = data.kid;
             });
     }
 }
@@ -47,13 +48,28 @@ function subscribe(uri) {
     connect(uri);
 }
 
+// RSA-OAEP with SHA-256, wrapped in the envelope the server expects
+const RSA_OAEP_OPTIONS = () => ({
+    md: forge.md.sha256.create(),
+    mgf1: { md: forge.md.sha256.create() },
+});
+
 function encryptRsa(message) {
     try {
-        return forge.util.encode64(
-            forge.pki
-                .publicKeyFromPem(STATE.serverPubKey)
-                .encrypt(forge.util.encodeUtf8(message))
-        );
+        return JSON.stringify({
+            v: 1,
+            kid: STATE.serverKeyId,
+            alg: "RSA-OAEP-256",
+            ct: forge.util.encode64(
+                forge.pki
+                    .publicKeyFromPem(STATE.serverPubKey)
+                    .encrypt(
+                        forge.util.encodeUtf8(message),
+                        "RSA-OAEP",
+                        RSA_OAEP_OPTIONS()
+                    )
+            ),
+        });
     } catch (error) {
         console.log("Errore durante la crittografia: ", error);
     }
@@ -0,0 +1,82 @@
+const MESSAGES = {
+    verified: {
+        title: "Email verified",
+        text: "Your email address is verified, you can now log in.",
+        resend: false,
+    },
+    expired: {
+        title: "Link expired",
+        text: "This verification link has expired. Enter your email to get a new one.",
+        resend: true,
+    },
+    invalid: {
+        title: "Invalid link",
+        text: "This verification link is not valid or was already used. Enter your email to get a new one.",
+        resend: true,
+    },
+    resend: {
+        title: "Verify your email",
+        text: "Enter the email you signed up with to get a new verification link.",
+        resend: true,
+    },
+};
+
+const result = new URLSearchParams(location.search).get("result");
+const shown = MESSAGES[result] || MESSAGES.resend;
+
+document.getElementById("verify-title").innerText = shown.title;
+document.getElementById("verify-message").innerText = shown.text;
+document.getElementById("resend").hidden = !shown.resend;
+
+function fieldError(m, f) {
+    f.classList.add("errorField");
+    let fLabel = document.querySelector('label[for="' + f.id + '"]');
+    fLabel.classList.add("errorLabel");
+    fLabel.innerText += " | " + m;
+}
+
+function fieldReset(f) {
+    try {
+        f.classList.remove("errorField");
+        let fLabel = document.querySelector('label[for="' + f.id + '"]');
+        fLabel.classList.remove("errorLabel");
+        fLabel.innerText = fLabel.innerText.split(" | ")[0];
+    } catch (error) {}
+}
+
+// Set up handler for the resend form
+document.querySelector("form").addEventListener("submit", (e) => {
+    e.preventDefault();
+
+    const emailField = document.getElementById("email");
+    fieldReset(emailField);
+
+    if (emailField.value.trim() == "") {
+        fieldError("Email is required", emailField);
+        return;
+    }
+
+    fetch("/resend-verification", {
+        method: "POST",
+        body: new URLSearchParams({
+            email: emailField.value.trim(),
+        }),
+    })
+        .then((response) =>
+            response.text().then((text) => {
+                if (!response.ok) {
+                    throw new Error(text);
+                }
+                document.getElementById("verify-message").innerText = text;
+                document.getElementById("resend").hidden = true;
+            })
+        )
+        .catch((err) => {
+            console.error(err);
+            fieldError(err.message, emailField);
+        });
+});
+
+document.getElementById("login-button").addEventListener("click", () => {
+    location.href = "/login";
+});